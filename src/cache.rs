@@ -0,0 +1,193 @@
+//! A disk-backed cache for slow provider requests
+//!
+//! Each entry is a JSON document under `$XDG_CACHE_HOME/xtalk` (or
+//! `~/.cache/xtalk` if `XDG_CACHE_HOME` is unset) which records when it was
+//! written and the fingerprint of the configuration and version of crosstalk
+//! it was written under. Entries older than the time-to-live, or written
+//! under another fingerprint, are ignored. The cache is best-effort: entries
+//! which cannot be read or written are treated as missing, so a broken cache
+//! only costs the time it was meant to save.
+
+use std::fs::{self, DirBuilder};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::utils::dirs::cache_dir;
+use crate::version;
+
+#[derive(Serialize, Deserialize)]
+struct Entry<T> {
+    /// When the entry was written, in seconds since the Unix epoch
+    written: u64,
+    /// The fingerprint of the cache which wrote the entry
+    #[serde(default)]
+    fingerprint: String,
+    value: T,
+}
+
+/// Identifies the configuration and the version of crosstalk. Entries
+/// written under one configuration are not read under another, since the
+/// providers may be configured differently.
+pub(crate) fn fingerprint(config: &Config) -> String {
+    let mut hasher = DefaultHasher::new();
+
+    version::VERSION.hash(&mut hasher);
+
+    toml::to_string(config)
+        .expect("failed to serialize the config")
+        .hash(&mut hasher);
+
+    format!("{:016x}", hasher.finish())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// The directory of the cache, if it can be determined
+fn default_dir() -> Option<PathBuf> {
+    cache_dir().map(|dir| dir.join("xtalk"))
+}
+
+/// Removes every entry from the cache in the default location, returning
+/// the number of entries removed
+pub(crate) fn clear() -> io::Result<usize> {
+    let dir = match default_dir() {
+        Some(dir) => dir,
+        None => return Ok(0),
+    };
+
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+
+    let mut removed = 0;
+
+    for entry in entries {
+        let path = entry?.path();
+
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            fs::remove_file(path)?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Cache {
+    dir: PathBuf,
+    ttl: Duration,
+    fingerprint: String,
+}
+
+impl Cache {
+    /// Opens the cache in the default location. Returns `None` if the
+    /// location cannot be determined.
+    pub(crate) fn open(ttl: Duration, fingerprint: String) -> Option<Cache> {
+        default_dir().map(|dir| Cache::with_dir(dir, ttl, fingerprint))
+    }
+
+    pub(crate) fn with_dir(dir: PathBuf, ttl: Duration, fingerprint: String) -> Cache {
+        Cache {
+            dir,
+            ttl,
+            fingerprint,
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+
+    /// Reads an entry if it exists and has not expired
+    pub(crate) fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let contents = fs::read(self.path(key)).ok()?;
+
+        let entry: Entry<T> = serde_json::from_slice(&contents).ok()?;
+
+        let age = Duration::from_secs(now().saturating_sub(entry.written));
+
+        (age < self.ttl && entry.fingerprint == self.fingerprint).then_some(entry.value)
+    }
+
+    /// Writes an entry, replacing any previous entry with the same key
+    pub(crate) fn put<T: Serialize>(&self, key: &str, value: &T) {
+        let entry = Entry {
+            written: now(),
+            fingerprint: self.fingerprint.clone(),
+            value,
+        };
+
+        let contents = match serde_json::to_vec(&entry) {
+            Ok(contents) => contents,
+            Err(_) => return,
+        };
+
+        if DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.dir)
+            .is_err()
+        {
+            return;
+        }
+
+        // Write to a temporary file first so concurrent readers never see a
+        // partially written entry
+        let path = self.path(key);
+        let partial = path.with_extension(format!("json.{}", std::process::id()));
+
+        if fs::write(&partial, contents).is_err() || fs::rename(&partial, &path).is_err() {
+            let _ = fs::remove_file(&partial);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expiry() {
+        let dir = std::env::temp_dir().join(format!("xtalk-cache-test-{}", std::process::id()));
+
+        let cache = Cache::with_dir(dir.clone(), Duration::from_secs(60), "a".to_string());
+
+        assert_eq!(cache.get::<Vec<String>>("models"), None);
+
+        cache.put("models", &vec!["gemma:2b".to_string()]);
+
+        assert_eq!(
+            cache.get::<Vec<String>>("models"),
+            Some(vec!["gemma:2b".to_string()])
+        );
+
+        let expired = Cache::with_dir(dir.clone(), Duration::ZERO, "a".to_string());
+
+        assert_eq!(expired.get::<Vec<String>>("models"), None);
+
+        // Entries written under another configuration are ignored
+        let reconfigured = Cache::with_dir(dir.clone(), Duration::from_secs(60), "b".to_string());
+
+        assert_eq!(reconfigured.get::<Vec<String>>("models"), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
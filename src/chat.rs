@@ -1,8 +1,11 @@
 //! Type definitions for chat primitives
 //!
 
+use serde::{Deserialize, Serialize};
+
 /// The author of a `Message`
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub(crate) enum Role {
     /// A `System` message is an authoritative message which is used to
     /// instruct the model. Usually, it appears as the first message
@@ -16,17 +19,56 @@ pub(crate) enum Role {
     Model,
 }
 
+/// An image accompanying a `Message`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Image {
+    /// The media type of the image (e.g., `image/png`)
+    pub media_type: String,
+    /// The image, encoded in base64
+    pub data: String,
+}
+
+impl Image {
+    /// The image as a data URL
+    pub(crate) fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.media_type, self.data)
+    }
+}
+
 /// A `Message` in a chat converstation
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct Message {
     /// The author of the message
     pub role: Role,
     /// The contents of the message
     pub content: String,
+    /// Images shown to the model along with the contents. Only models which
+    /// accept images can be sent messages with images.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<Image>,
+    /// The names of the files and images attached to the message. Their
+    /// contents are already in `content` and `images`, so they are resent
+    /// with the message; the names are kept for the transcript.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<String>,
 }
 
 impl Message {
     pub(crate) fn new(role: Role, content: String) -> Message {
-        Message { role, content }
+        Message {
+            role,
+            content,
+            images: Vec::new(),
+            attachments: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_images(role: Role, content: String, images: Vec<Image>) -> Message {
+        Message {
+            role,
+            content,
+            images,
+            attachments: Vec::new(),
+        }
     }
 }
@@ -2,8 +2,20 @@ use std::io::{self, IsTerminal};
 
 use crate::RequestedColorMode;
 
+pub(crate) mod cache;
 pub(crate) mod chat;
+pub(crate) mod complete;
+pub(crate) mod config;
+pub(crate) mod eval;
+pub(crate) mod history;
+pub(crate) mod init;
 pub(crate) mod list;
+pub(crate) mod lsp;
+pub(crate) mod mcp;
+pub(crate) mod models;
+pub(crate) mod prompts;
+pub(crate) mod summarize;
+pub(crate) mod sync;
 
 #[derive(Clone, Copy, strum_macros::Display)]
 pub(crate) enum ColorMode {
@@ -0,0 +1,16 @@
+//! Management of the cache of model listings, resolutions, and provider health
+
+use crate::{cache, die, CacheAction, CacheArgs};
+
+pub(crate) fn cache_cmd(args: &CacheArgs) {
+    match &args.action {
+        CacheAction::Clear => match cache::clear() {
+            Ok(removed) => println!(
+                "removed {} cached {}",
+                removed,
+                if removed == 1 { "entry" } else { "entries" }
+            ),
+            Err(err) => die!("failed to clear the cache: {}", err),
+        },
+    }
+}
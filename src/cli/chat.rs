@@ -1,36 +1,95 @@
+pub(crate) mod attach;
+mod citations;
+mod clipboard;
+mod coalesce;
+mod codeblocks;
+mod events;
+mod export;
+mod files;
+mod fold;
+mod graphics;
 mod highlighter;
-mod prompt;
+mod history;
+mod jsonl;
+mod pager;
+mod palette;
+pub(crate) mod prompt;
+mod protocol;
+mod recorder;
 mod repl;
+pub(crate) mod search;
+mod softwrap;
+mod stall;
+mod stats;
 mod tempfile;
-
-use crate::utils::errors::{fmt_error, fmt_warn};
-use crate::{chat, die, version};
-
+mod template_menu;
+mod timer;
+mod tmux;
+pub(crate) mod trim;
+pub(crate) mod turns;
+
+use crate::cli::ColorMode;
+use crate::color::{self, MaybePaint};
+use crate::utils::errors::{fmt_error, fmt_warn, USAGE_EXIT_CODE};
+use crate::utils::format::truncate_start;
+use crate::utils::markdown::{self, Block};
+use crate::{chat, die, die_with, version};
+
+use chrono::{DateTime, Local};
 use core::fmt;
+use std::collections::{BTreeSet, HashMap};
 use std::error::Error;
 use std::io::{self, IsTerminal, Read, Write};
-use std::path::PathBuf;
-
-use self::repl::Repl;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use self::attach::{Attachment, ImageAttachment};
+use self::citations::{References, StreamAnnotator};
+use self::coalesce::{Coalescer, DEFAULT_COALESCE_MS};
+use self::codeblocks::CodeHighlighter;
+use self::events::EventLog;
+use self::export::export;
+pub(crate) use self::export::ExportFormat;
+pub(crate) use self::export::{render as render_transcript, Metadata, Parameters};
+use self::files::{save_files, SavedFile};
+pub(crate) use self::fold::DEFAULT_FOLD_LINES;
+use self::fold::{fold, Folder};
+use self::graphics::Protocol;
+use self::protocol::{StopReason, TokenUsage};
+use self::recorder::SessionRecorder;
+use self::repl::{Input, Repl};
+pub(crate) use self::repl::{ReplConfig, DEFAULT_HISTORY_ENTRIES};
+use self::stall::StallDetector;
+use self::stats::ResponseStats;
+pub(crate) use self::stats::UsageLog;
+use self::timer::GenerationTimer;
 
 use crate::chat::Role;
-use crate::config;
-use crate::providers::{ChatProvider, ContextManagement, MessageDelta};
-use crate::registry::populate::resolve_once;
+use crate::providers::{
+    self, ChatProvider, Citation, Completion, CompletionOptions, ContextManagement, ErrorKind,
+    FinishReason, GeneratedFile, KeepAlive, MessageDelta, ResponseFormat,
+};
+use crate::registry::populate::resolve_spec;
 use crate::registry::registry::{self, ModelSpec, Registry};
-use crate::ChatArgs;
-use prompt::{model_prompt, user_prompt};
+use crate::sessions::{ResponseMetadata, Session, SessionMessage, SessionStore};
+use crate::templates::{self, Template};
+use crate::{config, warn, ChatArgs};
+use prompt::{model_prompt, user_prompt, UNKNOWN_MODEL};
+use tokio::task::JoinHandle;
 use tokio::{select, signal};
 
-
+#[derive(Clone)]
 pub(crate) enum Severity {
     Error,
     Warn,
     Standard,
 }
 
+#[derive(Clone)]
 pub(crate) enum Message {
-    Chat(chat::Message, Option<String>),
+    /// A message of the conversation, along with the model which authored
+    /// it and what the provider reported about it
+    Chat(chat::Message, Option<String>, ResponseMetadata),
     Command(String),
     Output(Severity, String),
 }
@@ -53,28 +112,54 @@ impl Message {
     }
 
     pub(crate) fn user(msg: String) -> Message {
-        Message::Chat(chat::Message::new(Role::User, msg), None)
+        Message::Chat(
+            chat::Message::new(Role::User, msg),
+            None,
+            ResponseMetadata::default(),
+        )
+    }
+
+    /// A prompt, which may have files and images attached
+    pub(crate) fn prompt(msg: chat::Message) -> Message {
+        Message::Chat(msg, None, ResponseMetadata::default())
     }
 
     pub(crate) fn model(msg: String, model_id: String) -> Message {
-        Message::Chat(chat::Message::new(Role::Model, msg), Some(model_id))
+        Message::Chat(
+            chat::Message::new(Role::Model, msg),
+            Some(model_id),
+            ResponseMetadata::default(),
+        )
     }
 
     pub(crate) fn system(msg: String) -> Message {
-        Message::Chat(chat::Message::new(Role::System, msg), None)
+        Message::Chat(
+            chat::Message::new(Role::System, msg),
+            None,
+            ResponseMetadata::default(),
+        )
+    }
+
+    /// The author of a turn in the conversation. System messages and output
+    /// shown to the user are not turns.
+    fn turn_role(&self) -> Option<&Role> {
+        match self {
+            Message::Chat(msg, ..) if !matches!(msg.role, Role::System) => Some(&msg.role),
+            _ => None,
+        }
     }
 }
 
 impl fmt::Display for Message {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Message::Chat(message, model_id) => match &message.role {
+            Message::Chat(message, model_id, _) => match &message.role {
                 Role::User => write!(f, "{}{}", user_prompt(), message.content),
                 Role::System => Ok(()),
                 Role::Model => write!(
                     f,
                     "{}{}",
-                    model_prompt(model_id.as_ref().unwrap()),
+                    model_prompt(model_id.as_deref().unwrap_or(UNKNOWN_MODEL)),
                     message.content
                 ),
             },
@@ -90,14 +175,18 @@ impl fmt::Display for Message {
     }
 }
 
+#[derive(Clone)]
 pub(crate) struct MessageBuffer {
     buf: Vec<Message>,
+    /// The indices of the pinned messages in `buf`
+    pinned: BTreeSet<usize>,
 }
 
 impl MessageBuffer {
     pub(crate) fn new() -> MessageBuffer {
         MessageBuffer {
             buf: Vec::<Message>::new(),
+            pinned: BTreeSet::new(),
         }
     }
 
@@ -109,32 +198,294 @@ impl MessageBuffer {
         self.buf
             .iter()
             .filter_map(|msg| match msg {
-                Message::Chat(msg, _) => Some(msg.clone()),
+                Message::Chat(msg, ..) => Some(msg.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the chat messages, along with the model which authored them,
+    /// as they are recorded in a session.
+    pub(crate) fn session_messages(&self) -> Vec<SessionMessage> {
+        self.buf
+            .iter()
+            .filter_map(|msg| match msg {
+                Message::Chat(msg, model_id, response) => Some(SessionMessage {
+                    message: msg.clone(),
+                    model_id: model_id.clone(),
+                    response: response.clone(),
+                }),
                 _ => None,
             })
             .collect()
     }
 
+    /// Adds the messages of a saved session
+    pub(crate) fn add_session_messages(&mut self, messages: &[SessionMessage]) {
+        for msg in messages {
+            self.add_message(Message::Chat(
+                msg.message.clone(),
+                msg.model_id.clone(),
+                msg.response.clone(),
+            ));
+        }
+    }
+
+    /// Prints the conversation, omitting system messages.
+    pub(crate) fn print_transcript(&self) {
+        for msg in &self.buf {
+            match msg {
+                Message::Chat(
+                    chat::Message {
+                        role: Role::System, ..
+                    },
+                    ..,
+                ) => {}
+                Message::Chat(..) => println!("{}\n", msg),
+                _ => {}
+            }
+        }
+    }
+
     pub(crate) fn clear(&mut self) {
         self.buf.clear();
+        self.pinned.clear();
+    }
+
+    /// Removes every message except those which are pinned
+    pub(crate) fn clear_unpinned(&mut self) {
+        let mut i = 0;
+
+        self.buf.retain(|_| {
+            i += 1;
+            self.pinned.contains(&(i - 1))
+        });
+
+        self.pinned = (0..self.buf.len()).collect();
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.buf.truncate(len);
+        self.pinned.retain(|&i| i < len);
+    }
+
+    /// Finds the index of a turn, as it is numbered by `/history`
+    fn turn_index(&self, turn: usize) -> Option<usize> {
+        self.buf
+            .iter()
+            .enumerate()
+            .filter(|(_, msg)| msg.turn_role().is_some())
+            .nth(turn.checked_sub(1)?)
+            .map(|(i, _)| i)
+    }
+
+    /// Pins or unpins a turn. Pinned messages are kept by
+    /// `/clear --keep-pinned`. Returns `false` if there is no such turn.
+    pub(crate) fn set_pinned(&mut self, turn: usize, pinned: bool) -> bool {
+        let i = match self.turn_index(turn) {
+            Some(i) => i,
+            None => return false,
+        };
+
+        if pinned {
+            self.pinned.insert(i);
+        } else {
+            self.pinned.remove(&i);
+        }
+
+        true
+    }
+
+    /// The numbers of the pinned turns
+    pub(crate) fn pinned_turns(&self) -> BTreeSet<usize> {
+        self.buf
+            .iter()
+            .enumerate()
+            .filter(|(_, msg)| msg.turn_role().is_some())
+            .enumerate()
+            .filter(|(_, (i, _))| self.pinned.contains(i))
+            .map(|(turn, _)| turn + 1)
+            .collect()
+    }
+
+    /// Removes the last prompt along with the response to it and everything
+    /// shown after it. Returns `false` if there is no prompt to remove.
+    pub(crate) fn undo(&mut self) -> bool {
+        let last_prompt = self
+            .buf
+            .iter()
+            .rposition(|msg| matches!(msg.turn_role(), Some(Role::User)));
+
+        match last_prompt {
+            Some(i) => {
+                self.truncate(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the last response, if the conversation ends with one, so it
+    /// can be generated again. Returns `false` if the conversation does not
+    /// then end with a prompt.
+    pub(crate) fn retract_response(&mut self) -> bool {
+        let last_turn = self.buf.iter().rposition(|msg| msg.turn_role().is_some());
+
+        if let Some(i) = last_turn {
+            if matches!(self.buf[i].turn_role(), Some(Role::Model)) {
+                self.truncate(i);
+            }
+        }
+
+        matches!(
+            self.buf.iter().rev().find_map(Message::turn_role),
+            Some(Role::User)
+        )
+    }
+
+    /// Inserts a message before the message at `position`
+    pub(crate) fn insert_message(&mut self, position: usize, msg: Message) {
+        self.buf.insert(position, msg);
+
+        self.pinned = self
+            .pinned
+            .iter()
+            .map(|&i| if i >= position { i + 1 } else { i })
+            .collect();
+    }
+
+    /// Removes the oldest turn which is not pinned, along with the rest of
+    /// its exchange up to the next prompt. The last prompt is never removed.
+    /// Returns the position of the exchange and the messages removed.
+    pub(crate) fn remove_oldest_exchange(&mut self) -> Option<(usize, Vec<chat::Message>)> {
+        let last_prompt = self
+            .buf
+            .iter()
+            .rposition(|msg| matches!(msg.turn_role(), Some(Role::User)))?;
+
+        let start = (0..last_prompt)
+            .find(|i| self.buf[*i].turn_role().is_some() && !self.pinned.contains(i))?;
+
+        let end = (start + 1..last_prompt)
+            .find(|&i| matches!(self.buf[i].turn_role(), Some(Role::User)))
+            .unwrap_or(last_prompt);
+
+        let removed: BTreeSet<usize> = (start..end)
+            .filter(|i| self.buf[*i].turn_role().is_some() && !self.pinned.contains(i))
+            .collect();
+
+        let messages = removed
+            .iter()
+            .filter_map(|&i| match &self.buf[i] {
+                Message::Chat(msg, ..) => Some(msg.clone()),
+                _ => None,
+            })
+            .collect();
+
+        let mut i = 0;
+
+        self.buf.retain(|_| {
+            i += 1;
+            !removed.contains(&(i - 1))
+        });
+
+        self.pinned = self
+            .pinned
+            .iter()
+            .map(|&i| i - removed.range(..i).count())
+            .collect();
+
+        Some((start, messages))
+    }
+
+    /// Removes the first system message which begins with `prefix`,
+    /// returning its position and the message
+    pub(crate) fn remove_system_message(&mut self, prefix: &str) -> Option<(usize, chat::Message)> {
+        let position = self.buf.iter().position(|msg| match msg {
+            Message::Chat(msg, ..) => {
+                matches!(msg.role, Role::System) && msg.content.starts_with(prefix)
+            }
+            _ => false,
+        })?;
+
+        let msg = match self.buf.remove(position) {
+            Message::Chat(msg, ..) => msg,
+            _ => unreachable!(),
+        };
+
+        self.pinned = self
+            .pinned
+            .iter()
+            .filter(|&&i| i != position)
+            .map(|&i| if i > position { i - 1 } else { i })
+            .collect();
+
+        Some((position, msg))
+    }
+
+    /// Replaces the content of the most recent response of the model,
+    /// returning `false` if there is none
+    pub(crate) fn replace_last_response(&mut self, content: String) -> bool {
+        let response = self.buf.iter_mut().rev().find_map(|msg| match msg {
+            Message::Chat(msg, ..) if matches!(msg.role, Role::Model) => Some(msg),
+            _ => None,
+        });
+
+        match response {
+            Some(response) => {
+                response.content = content;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The most recent response of the model
+    pub(crate) fn last_response(&self) -> Option<&chat::Message> {
+        self.buf.iter().rev().find_map(|msg| match msg {
+            Message::Chat(msg, ..) if matches!(msg.role, Role::Model) => Some(msg),
+            _ => None,
+        })
     }
 }
 
 pub(crate) struct MessageBuilder {
     msg: Option<chat::Message>,
+    citations: Vec<Citation>,
+    files: Vec<GeneratedFile>,
 }
 
 impl MessageBuilder {
     pub(crate) fn new() -> MessageBuilder {
-        MessageBuilder { msg: None }
+        MessageBuilder {
+            msg: None,
+            citations: Vec::new(),
+            files: Vec::new(),
+        }
     }
 
     pub(crate) fn add(&mut self, delta: &MessageDelta) {
         if let Some(msg) = &mut self.msg {
             msg.content.push_str(&delta.content);
         } else {
-            self.msg = Some(chat::Message::new(Role::User, delta.content.clone()));
+            self.msg = Some(chat::Message::new(
+                delta.role.clone(),
+                delta.content.clone(),
+            ));
         }
+
+        self.citations.extend(delta.citations.iter().cloned());
+        self.files.extend(delta.files.iter().cloned());
+    }
+
+    /// The sources cited in the message
+    pub(crate) fn references(&self) -> References {
+        References::new(&self.citations)
+    }
+
+    /// Takes the files generated along with the message
+    pub(crate) fn take_files(&mut self) -> Vec<GeneratedFile> {
+        std::mem::take(&mut self.files)
     }
 }
 
@@ -150,31 +501,211 @@ impl TryFrom<MessageBuilder> for chat::Message {
     }
 }
 
+/// Options controlling how the chat is presented
+struct ChatOptions {
+    /// Prompt the user for input after each response
+    interactive: bool,
+    /// Print the response as it is streamed
+    incremental: bool,
+    /// Report statistics after each response
+    stats: bool,
+    /// Report token usage after each response
+    show_usage: bool,
+    /// Export the transcript to a file after each response
+    output: Option<(PathBuf, ExportFormat)>,
+    /// Require responses to be valid JSON, re-prompting the model up to
+    /// this many times if a response is not
+    json_retries: Option<u32>,
+    /// The persona adopted at the start of the chat
+    persona: Option<String>,
+    /// Exit with an error if the response is empty
+    fail_on_empty: bool,
+    /// The maximum number of tokens in each response
+    max_output_tokens: Option<u32>,
+    /// The seed responses are sampled with
+    seed: Option<u32>,
+    /// Sequences which end each response
+    stop: Vec<String>,
+    /// How long the model stays loaded after each response
+    keep_alive: Option<KeepAlive>,
+    /// Request responses in full rather than streaming them
+    no_stream: bool,
+    /// The least time between writes of a streamed response
+    coalesce: Option<Duration>,
+    /// Files attached to the first prompt read by the REPL
+    attachments: Vec<Attachment>,
+    /// Images attached to the first prompt read by the REPL
+    images: Vec<ImageAttachment>,
+    /// The log the events of the chat are appended to
+    event_log: EventLog,
+}
+
+/// Opens the event log if one was requested, recording the session with each
+/// event if the chat is saved
+fn open_event_log(path: Option<&Path>, recorder: Option<&SessionRecorder>) -> EventLog {
+    let Some(path) = path else {
+        return EventLog::disabled();
+    };
+
+    let mut log = match EventLog::open(path) {
+        Ok(log) => log,
+        Err(err) => die!("failed to open the event log {}: {}", path.display(), err),
+    };
+
+    if let Some(recorder) = recorder {
+        log.set_session(recorder.id());
+    }
+
+    log
+}
+
+/// The maximum number of characters of tool output added to the conversation
+/// when no limit is configured
+pub(crate) const DEFAULT_MAX_TOOL_OUTPUT: usize = 16_000;
+
+const JSON_INSTRUCTIONS: &str = "Respond only with valid JSON.";
+
+/// Asks the model to correct a response which could not be parsed
+fn json_correction(err: &serde_json::Error) -> String {
+    format!(
+        "Your response was not valid JSON ({}). Respond again with only valid JSON.",
+        err
+    )
+}
+
+/// Captures a tmux pane as a system message, truncating long captures
+fn tmux_context(target: Option<&str>, max_tool_output: Option<usize>) -> Message {
+    match tmux::capture_pane(target) {
+        Ok(capture) => {
+            let max_chars = max_tool_output.unwrap_or(DEFAULT_MAX_TOOL_OUTPUT);
+
+            let capture = truncate_start(&capture, max_chars);
+
+            Message::system(tmux::pane_context(&capture))
+        }
+        Err(err) => die!("failed to capture the tmux pane: {}", err),
+    }
+}
+
+/// Renders the template named on the command line. Placeholders assigned
+/// `-` are read from standard input. Returns the prompt and whether standard
+/// input was read.
+fn template_prompt(
+    templates: &[Template],
+    name: &str,
+    vars: &[(String, String)],
+) -> (String, bool) {
+    let template = match templates.iter().find(|template| template.name == name) {
+        Some(template) => template,
+        None => die_with!(
+            USAGE_EXIT_CODE,
+            "there is no template named \"{}\", see `xtalk list templates`",
+            name
+        ),
+    };
+
+    let mut values = HashMap::new();
+    let mut stdin = None;
+
+    for (name, value) in vars {
+        let value = if value == "-" {
+            if stdin.is_none() {
+                if io::stdin().is_terminal() {
+                    die_with!(
+                        USAGE_EXIT_CODE,
+                        "the value of {{{}}} is read from standard input, but it is a terminal",
+                        name
+                    );
+                }
+
+                let mut buf = String::new();
+
+                if let Err(err) = io::stdin().read_to_string(&mut buf) {
+                    die!("failed to read standard input: {}", err);
+                }
+
+                stdin = Some(buf);
+            }
+
+            stdin.clone().unwrap()
+        } else {
+            value.clone()
+        };
+
+        values.insert(name.clone(), value);
+    }
+
+    match template.render_all(&templates::with_builtins(&values)) {
+        Ok(prompt) => (prompt, stdin.is_some()),
+        Err(err) => die!("failed to render the \"{}\" template: {}", name, err),
+    }
+}
+
 pub(crate) async fn chat_cmd(
-    editor: Option<PathBuf>,
-    keybindings: config::Keybindings,
+    repl_config: ReplConfig,
     default_model: Option<String>,
-    registry: Registry,
+    max_tool_output: Option<usize>,
+    registry: JoinHandle<Registry>,
     args: &ChatArgs,
 ) {
+    // Editor plugins and other programs are served with the same instructions
+    // and temperature as the REPL
+    if args.editor_protocol || args.json_lines {
+        let model = args.model.clone().or(default_model);
+
+        let mut msg_buf = MessageBuffer::new();
+
+        if let Some(target) = &args.tmux_pane {
+            msg_buf.add_message(tmux_context(target.as_deref(), max_tool_output));
+        }
+
+        let resolution = spawn_resolution(registry, model);
+        let defaults = repl_config.defaults;
+
+        if args.editor_protocol {
+            // Editor plugins exchange frames over standard input and output
+            protocol::serve(resolution, msg_buf, defaults).await;
+        } else {
+            // Programs exchange JSON lines over standard input and output
+            jsonl::serve(resolution, msg_buf, defaults).await;
+        }
+
+        return;
+    }
+
     let in_terminal = io::stdin().is_terminal();
     let out_terminal = io::stdout().is_terminal();
 
+    // A template stands in for the prompt argument
+    let (prompt, read_stdin) = match &args.template {
+        Some(name) => {
+            let (prompt, read_stdin) = template_prompt(&repl_config.templates, name, &args.vars);
+
+            (Some(prompt), read_stdin)
+        }
+        None => (args.prompt.clone(), false),
+    };
+
+    // Unattended chats (e.g., those run by cron) never fall back to the REPL
+    let unattended = args.max_runtime.is_some() || args.fail_on_empty;
+
     // If standard input is a terminal and interactive mode has not been specified,
     // gather input from standard input with the assumption that we are not running interactively.
-    let interactive = if args.prompt.is_some() {
+    let interactive = if prompt.is_some() {
         args.interactive
     } else {
-        in_terminal && out_terminal
+        in_terminal && out_terminal && !unattended
     };
 
-    if args.prompt.is_some() && !in_terminal {
-        die!("it appears that an initial prompt is being provided both through standard input and the prompt argument");
+    // Standard input is ignored by unattended chats given a prompt, since
+    // schedulers such as cron do not attach it to a terminal
+    if prompt.is_some() && !in_terminal && !read_stdin && !unattended {
+        die_with!(USAGE_EXIT_CODE, "it appears that an initial prompt is being provided both through standard input and the prompt argument");
     }
 
     // Obtain the initial prompt, either from standard input or from a positional argument.
-    let initial_prompt = if let Some(prompt) = &args.prompt {
-        Some(prompt.clone())
+    let initial_prompt = if let Some(prompt) = prompt {
+        Some(prompt)
     } else if !in_terminal {
         let mut buf = String::new();
         io::stdin()
@@ -185,141 +716,1257 @@ pub(crate) async fn chat_cmd(
         None
     };
 
-    let model = args.model.clone().or_else(|| default_model);
-
-    let resolve_result = resolve_once(&registry, model).await;
+    if initial_prompt.is_none() && !interactive {
+        die_with!(
+            USAGE_EXIT_CODE,
+            "no prompt was provided, either as an argument or through standard input"
+        );
+    }
 
-    let (provider, model_id) = match resolve_result {
-        Ok(resolved) => resolved,
-        Err(err) => {
-            // When the default model is unset or a provider is not activate, this
-            // could be due to the complete absense of any provider. This is a more
-            // friendly error message, since the remediation action should be obvious
-            // to newcomers.
-            if registry.empty() {
-                die!("none of the chat providers are active, at least one needs to be active to start a chat");
+    let attachments: Vec<Attachment> = args
+        .files
+        .iter()
+        .map(|path| match Attachment::read(path) {
+            Ok(attachment) => attachment,
+            Err(err) => die!("failed to attach a file: {}", err),
+        })
+        .collect();
+
+    let images: Vec<ImageAttachment> = args
+        .images
+        .iter()
+        .map(|path| match ImageAttachment::read(path) {
+            Ok(image) => image,
+            Err(err) => die!("failed to attach an image: {}", err),
+        })
+        .collect();
+
+    // Files are attached to the initial prompt or, failing that, to the
+    // first prompt read by the REPL
+    let (initial_prompt, attachments, images) = match initial_prompt {
+        Some(prompt) => {
+            // The images are previewed as they are when attached in the REPL
+            let graphics = Protocol::for_previews(&repl_config.files);
+
+            for image in &images {
+                if let Some(preview) = graphics.and_then(|p| p.preview_image(&image.image)) {
+                    println!("{}", preview);
+                }
             }
 
-            die!("failed to resolve model: {}", err);
+            (
+                Some(Turn::Prompt(attach::prompt_message(
+                    &attachments,
+                    images,
+                    &prompt,
+                ))),
+                Vec::new(),
+                Vec::new(),
+            )
         }
+        None => (None, attachments, images),
     };
 
-    // If the output is a terminal (e.g., user-facing), incrementally print it.
-    let incremental = out_terminal;
+    let mut msg_buf = MessageBuffer::new();
 
-    chat(
-        editor,
-        keybindings,
-        provider,
-        &model_id,
-        initial_prompt,
-        interactive,
-        incremental,
-    )
-    .await;
-}
+    // Interactive chats are saved so they can be resumed later. Resumed
+    // sessions continue to be saved, even when the chat is not interactive.
+    let session = if let Some(id) = &args.resume {
+        let session = SessionStore::open().and_then(|store| {
+            let session = store.load(id)?;
 
-async fn chat<'p>(
-    editor: Option<PathBuf>,
-    keybindings: config::Keybindings,
-    provider: &'p Box<dyn ChatProvider>,
-    model_id: &str,
-    initial_prompt: Option<String>,
-    interactive: bool,
-    incremental: bool,
-) {
-    if interactive {
-        println!("{} version {}", version::NAME, version::VERSION);
-    }
+            Ok((store, session))
+        });
 
-    let mut pending_init_prompt = initial_prompt.is_some();
+        match session {
+            Ok(session) => Some(session),
+            Err(err) => die!("failed to resume session: {}", err),
+        }
+    } else if interactive {
+        match SessionStore::open() {
+            Ok(store) => Some((store, Session::new())),
+            Err(err) => {
+                warn!("this chat will not be saved: {}", err);
+                None
+            }
+        }
+    } else {
+        None
+    };
 
-    let spec = ModelSpec::resolved(provider.id(), model_id.to_string());
+    let session_model = session.as_ref().and_then(|(_, s)| s.model.clone());
 
-    // Add the initial prompt to the internal buffer.
-    let mut msg_buf = MessageBuffer::new();
+    // A resumed session continues with its seed, unless another is given
+    let seed = args.seed.or(session.as_ref().and_then(|(_, s)| s.seed));
 
-    match provider.context_management() {
-        ContextManagement::Implicit => {
-            let implicit_warning = Message::warn(
-                "This provider implicity manages context. The context may be truncated without warning.".to_string()
-            );
+    let recorder = session.map(|(store, session)| {
+        msg_buf.add_session_messages(&session.messages);
 
-            eprintln!("{}", implicit_warning);
+        SessionRecorder::new(store, session)
+    });
 
-            msg_buf.add_message(implicit_warning);
+    // The log is opened before the pane is captured, so the capture is logged
+    let mut event_log = open_event_log(args.event_log.as_deref(), recorder.as_ref());
+
+    if let Some(target) = &args.tmux_pane {
+        let context = tmux_context(target.as_deref(), max_tool_output);
+
+        if let Message::Chat(capture, ..) = &context {
+            event_log.tool_call("tmux", target.as_deref(), capture.content.chars().count());
         }
-        ContextManagement::Explicit => {}
+
+        msg_buf.add_message(context);
     }
 
-    if let Some(initial_prompt) = initial_prompt {
-        msg_buf.add_message(Message::user(initial_prompt));
+    let persona = args
+        .persona
+        .as_ref()
+        .map(|name| match repl_config.personas.get(name) {
+            Some(persona) => persona,
+            None => die!(
+                "there is no persona named \"{}\", see `xtalk list personas`",
+                name
+            ),
+        });
+
+    // A resumed session continues with the model it last used, unless another
+    // is requested directly or by the persona
+    let model = args
+        .model
+        .clone()
+        .or_else(|| persona.and_then(|persona| persona.model.clone()))
+        .or(session_model)
+        .or_else(|| default_model);
+
+    let resolution = spawn_resolution(registry, model);
+
+    // If the output is a terminal (e.g., user-facing), incrementally print it.
+    // JSON responses are withheld until they have been validated.
+    let incremental = out_terminal && !args.json;
+
+    // The flag takes precedence over the configuration, and zero disables
+    // coalescing
+    let coalesce = match args.coalesce {
+        Some(Some(ms)) => Some(ms),
+        Some(None) => Some(DEFAULT_COALESCE_MS),
+        None => repl_config.coalesce_ms,
     }
+    .filter(|&ms| ms > 0)
+    .map(Duration::from_millis);
 
-    // Only initialize the REPL if  it is really needed.
-    let mut repl = if interactive {
-        Some(Repl::new(editor, keybindings))
-    } else {
-        None
-    };
+    let chat = chat(
+        repl_config,
+        resolution,
+        msg_buf,
+        initial_prompt,
+        ChatOptions {
+            interactive,
+            incremental,
+            stats: args.stats,
+            show_usage: args.show_usage,
+            output: args.output.clone().map(|path| {
+                let format = args
+                    .output_format
+                    .unwrap_or_else(|| ExportFormat::from_path(&path));
+
+                (path, format)
+            }),
+            json_retries: args.json.then_some(args.json_retries),
+            persona: args.persona.clone(),
+            fail_on_empty: args.fail_on_empty,
+            max_output_tokens: args.max_output_tokens,
+            seed,
+            stop: args.stop.clone(),
+            keep_alive: args.keep_alive,
+            no_stream: args.no_stream,
+            coalesce,
+            attachments,
+            images,
+            event_log,
+        },
+        recorder,
+    );
+
+    // Dropping the chat aborts the request which is in progress
+    match args.max_runtime {
+        Some(max_runtime) => {
+            if tokio::time::timeout(max_runtime, chat).await.is_err() {
+                die!(
+                    "the chat did not finish within its maximum runtime of {}s",
+                    max_runtime.as_secs()
+                );
+            }
+        }
+        None => chat.await,
+    }
+}
 
-    let flush_or_die = || {
-        std::io::stdout()
-            .flush()
-            .expect("Failed to flush the output stream.");
+/// Warns that the responses of the provider may not be reproducible with a seed
+fn seed_warning(provider: &dyn ChatProvider) -> Option<Message> {
+    (!provider.honors_seed()).then(|| {
+        Message::warn(format!(
+            "\"{}\" only makes a best effort to honor the seed, responses may not be reproducible",
+            provider.id()
+        ))
+    })
+}
+
+/// Warns that options of the request are ignored by the model
+fn unsupported_options_warning(
+    provider: &dyn ChatProvider,
+    spec: &ModelSpec,
+    model_id: &str,
+    options: &CompletionOptions,
+) -> Option<Message> {
+    let unsupported = providers::unsupported_options(provider, model_id, options);
+
+    (!unsupported.is_empty()).then(|| {
+        let unsupported: Vec<String> = unsupported.iter().map(ToString::to_string).collect();
+
+        Message::warn(format!(
+            "\"{}\" does not support {}, they are ignored",
+            spec,
+            unsupported.join(", ")
+        ))
+    })
+}
+
+/// Gather statistics for a completion which has run to exhaustion.
+async fn response_stats(
+    provider: &dyn ChatProvider,
+    model_id: &str,
+    completion: &dyn crate::providers::AsyncMessageIterator,
+    started: Instant,
+) -> ResponseStats {
+    let elapsed = started.elapsed();
+
+    let loaded_model = match provider.loaded_models().await {
+        Ok(models) => models.into_iter().find(|m| m.id == model_id),
+        Err(err) => {
+            crate::warn!("failed to query the runtime state of {}: {}", model_id, err);
+            None
+        }
     };
 
-    loop {
-        // Prompt after the initial prompt is dispensed with.
-        if !pending_init_prompt && interactive {
-            let repl = repl.as_mut().unwrap();
+    ResponseStats {
+        elapsed,
+        usage: completion.usage().clone(),
+        loaded_model,
+    }
+}
 
-            let prompt = repl.edit(&mut msg_buf);
+/// What is known about how the chat was conducted, which is reported by
+/// /usage and included in exported transcripts
+struct ChatRecord {
+    started: DateTime<Local>,
+    parameters: Parameters,
+    usage_log: UsageLog,
+}
 
-            let prompt = match prompt {
-                Some(prompt) => prompt,
-                None => break,
-            };
+/// Exports the chat messages in the buffer along with the metadata
+fn export_transcript(
+    msg_buf: &MessageBuffer,
+    record: &ChatRecord,
+    path: &Path,
+    format: ExportFormat,
+) -> io::Result<()> {
+    let messages = msg_buf.session_messages();
+
+    let metadata = Metadata::new(
+        &messages,
+        record.started,
+        record.parameters.clone(),
+        Some(record.usage_log.totals()),
+    );
+
+    export(&messages, &metadata, path, format)
+}
 
-            msg_buf.add_message(Message::user(prompt));
+/// Copies a code block of the response to the clipboard, the last one
+/// unless it is numbered
+fn copy_code_block(response: &str, number: Option<usize>) -> Message {
+    let blocks: Vec<&str> = markdown::blocks(response)
+        .into_iter()
+        .filter_map(|block| match block {
+            Block::Code { code, .. } => Some(code),
+            Block::Text(_) => None,
+        })
+        .collect();
+
+    let (number, code) = match number {
+        _ if blocks.is_empty() => {
+            return Message::warn("the last response has no code blocks".to_string())
         }
-       
-        let completion = provider
-            .stream_completion(&model_id, &msg_buf.chat_messages())
-            .await;
+        Some(number) => match blocks.get(number - 1) {
+            Some(code) => (number, code),
+            None => {
+                return Message::warn(format!(
+                    "the last response has {} code block{}",
+                    blocks.len(),
+                    if blocks.len() == 1 { "" } else { "s" }
+                ))
+            }
+        },
+        None => (blocks.len(), blocks.last().unwrap()),
+    };
 
-        let mut completion = match completion {
-            Ok(completion) => completion,
-            Err(err) => {
-                let mut err_msg = format!("completion for {} failed: {}", spec, err);
+    // A trailing newline would run a copied command once it is pasted
+    let code = code.trim_end_matches('\n');
 
-                if let Some(source) = err.source() {
-                    err_msg.push_str(&format!("\n{}", source));
+    let lines = code.lines().count();
+
+    match clipboard::copy(code) {
+        Ok(method) => Message::output(format!(
+            "copied code block {} ({} line{}) with {}",
+            number,
+            lines,
+            if lines == 1 { "" } else { "s" },
+            method
+        )),
+        Err(err) => Message::error(format!("failed to copy to the clipboard: {}", err)),
+    }
+}
+
+/// What the chat does once the user has finished with the REPL
+enum Turn {
+    /// Send a new prompt to the model, along with any attached files and images
+    Prompt(chat::Message),
+    /// Generate the response to the last prompt again
+    Retry,
+    /// Switch to the named persona
+    Persona(String),
+}
+
+/// Reads input from the REPL until the user provides a prompt, handling
+/// commands which act on the chat. Returns `None` if the user exits.
+fn read_prompt(
+    repl: &mut Repl,
+    msg_buf: &mut MessageBuffer,
+    mut recorder: Option<&mut SessionRecorder>,
+    record: &ChatRecord,
+) -> Option<Turn> {
+    loop {
+        match repl.edit(msg_buf)? {
+            Input::Prompt(prompt) => match turns::expand(&prompt, &msg_buf.chat_messages()) {
+                // References are expanded first, so attached files are sent as they are
+                Ok(prompt) => {
+                    return Some(Turn::Prompt(attach::prompt_message(
+                        &repl.take_attachments(),
+                        repl.take_images(),
+                        &prompt,
+                    )));
                 }
+                Err(err) => {
+                    let msg = Message::error(err.to_string());
 
-                let completion_error = Message::error(err_msg);
+                    eprintln!("{}", msg);
 
-                eprintln!("{}", completion_error);
+                    msg_buf.add_message(msg);
+                }
+            },
+            Input::History => {
+                let history = turns::history(&msg_buf.chat_messages(), &msg_buf.pinned_turns());
 
-                msg_buf.add_message(completion_error);
+                let msg = if history.is_empty() {
+                    Message::warn("the conversation has not started".to_string())
+                } else {
+                    Message::output(history)
+                };
 
-                continue;
+                eprintln!("{}", msg);
+
+                msg_buf.add_message(msg);
             }
-        };
+            Input::Pin { turn, pinned } => {
+                if !msg_buf.set_pinned(turn, pinned) {
+                    let msg =
+                        Message::error(turns::Error::NoSuchTurn(turn.to_string()).to_string());
 
-        let mut msg_builder = MessageBuilder::new();
+                    eprintln!("{}", msg);
 
-        if interactive {
-            let model_prompt = model_prompt(model_id);
-            print!("{} ", model_prompt);
-            flush_or_die();
-        }
+                    msg_buf.add_message(msg);
+                }
+            }
+            Input::Persona(name) => return Some(Turn::Persona(name)),
+            Input::Resume(id) => {
+                let msg = match recorder.as_deref_mut() {
+                    Some(recorder) => match recorder.resume(&id) {
+                        Ok(session) => {
+                            msg_buf.clear();
+                            msg_buf.add_session_messages(&session.messages);
+                            msg_buf.print_transcript();
+
+                            Message::output(format!("resumed session {}", id))
+                        }
+                        Err(err) => {
+                            Message::error(format!("failed to resume the session: {}", err))
+                        }
+                    },
+                    None => Message::warn(
+                        "this chat is not being saved, so no session can be resumed".to_string(),
+                    ),
+                };
+
+                eprintln!("{}", msg);
+
+                msg_buf.add_message(msg);
+            }
+            Input::Retry => {
+                if msg_buf.retract_response() {
+                    return Some(Turn::Retry);
+                }
+
+                let msg = Message::warn("there is no prompt to retry".to_string());
+
+                eprintln!("{}", msg);
+
+                msg_buf.add_message(msg);
+            }
+            Input::Undo => {
+                if !msg_buf.undo() {
+                    let msg = Message::warn("there is no prompt to undo".to_string());
+
+                    eprintln!("{}", msg);
+
+                    msg_buf.add_message(msg);
+
+                    continue;
+                }
+
+                eprintln!(
+                    "{}",
+                    Message::output("removed the last prompt and its response".to_string())
+                );
+
+                if let Some(recorder) = recorder.as_deref_mut() {
+                    if let Err(err) = recorder.record(msg_buf) {
+                        warn!("failed to save the session: {}", err);
+                    }
+                }
+            }
+            Input::Save => {
+                let msg = match recorder.as_deref_mut() {
+                    Some(recorder) => match recorder.record(msg_buf) {
+                        Ok(()) => Message::output(format!("saved session {}", recorder.id())),
+                        Err(err) => Message::error(format!("failed to save the session: {}", err)),
+                    },
+                    None => Message::warn("this chat is not being saved".to_string()),
+                };
+
+                eprintln!("{}", msg);
+
+                msg_buf.add_message(msg);
+            }
+            Input::Export(path) => {
+                let format = ExportFormat::from_path(&path);
+
+                let msg = match export_transcript(msg_buf, record, &path, format) {
+                    Ok(()) => {
+                        Message::output(format!("exported the transcript to {}", path.display()))
+                    }
+                    Err(err) => Message::error(format!(
+                        "failed to export the transcript to {}: {}",
+                        path.display(),
+                        err
+                    )),
+                };
+
+                eprintln!("{}", msg);
+
+                msg_buf.add_message(msg);
+            }
+            Input::Usage => {
+                let msg = Message::output(record.usage_log.to_string());
+
+                eprintln!("{}", msg);
+
+                msg_buf.add_message(msg);
+            }
+            Input::Find { query, sessions } => {
+                let msg = Message::output(find(
+                    msg_buf,
+                    recorder.as_deref().map(SessionRecorder::id),
+                    &query,
+                    sessions,
+                ));
+
+                eprintln!("{}", msg);
+
+                msg_buf.add_message(msg);
+            }
+            Input::Expand => {
+                let result = match msg_buf.last_response() {
+                    Some(response) => pager::page(&response.content),
+                    None => {
+                        let msg = Message::warn("there is no response to expand".to_string());
+
+                        eprintln!("{}", msg);
+
+                        msg_buf.add_message(msg);
+
+                        continue;
+                    }
+                };
+
+                if let Err(err) = result {
+                    let msg = Message::error(format!("failed to launch the pager: {}", err));
+
+                    eprintln!("{}", msg);
+
+                    msg_buf.add_message(msg);
+                }
+            }
+            Input::EditResponse => {
+                let Some(response) = msg_buf.last_response() else {
+                    let msg = Message::warn("there is no response to edit".to_string());
+
+                    eprintln!("{}", msg);
+
+                    msg_buf.add_message(msg);
+
+                    continue;
+                };
+
+                let original = response.content.clone();
+
+                // The REPL checks that an editor is specified
+                let edited = repl.edit_text(&original).unwrap_or_default();
+
+                let msg = if edited == original {
+                    Message::output("the response was not changed".to_string())
+                } else if edited.trim().is_empty() {
+                    Message::warn("the edited response is empty, so it was kept".to_string())
+                } else {
+                    msg_buf.replace_last_response(edited);
+
+                    if let Some(recorder) = recorder.as_deref_mut() {
+                        if let Err(err) = recorder.record(msg_buf) {
+                            warn!("failed to save the session: {}", err);
+                        }
+                    }
+
+                    Message::output("replaced the last response with the edited one".to_string())
+                };
+
+                eprintln!("{}", msg);
+
+                msg_buf.add_message(msg);
+            }
+            Input::Copy(number) => {
+                let msg = match msg_buf.last_response() {
+                    Some(response) => copy_code_block(&response.content, number),
+                    None => Message::warn("there is no response to copy from".to_string()),
+                };
+
+                eprintln!("{}", msg);
+
+                msg_buf.add_message(msg);
+            }
+        }
+    }
+}
+
+/// Flushes what has been printed, exiting if standard output is gone
+fn flush_or_die() {
+    if let Err(err) = io::stdout().flush() {
+        die!("failed to write to standard output: {}", err);
+    }
+}
+
+/// Prints part of a streamed response, keeping the timer clear of it
+fn print_streamed(text: &str, timer: &mut GenerationTimer, shows_timer: bool) {
+    timer.before_print(text);
+
+    print!("{}", text);
+
+    flush_or_die();
+
+    if shows_timer {
+        timer.after_print(text);
+    }
+}
+
+/// Searches the transcript for `/find`. The saved sessions, other than the
+/// one being recorded, are searched if `sessions` is set.
+fn find(msg_buf: &MessageBuffer, current: Option<&str>, query: &str, sessions: bool) -> String {
+    let mut results: Vec<String> = search::search(&msg_buf.chat_messages(), query)
+        .iter()
+        .map(|m| m.to_string())
+        .collect();
+
+    if sessions {
+        let saved = SessionStore::open().and_then(|store| store.list());
+
+        match saved {
+            Ok(saved) => {
+                for session in saved.iter().filter(|s| Some(s.id.as_str()) != current) {
+                    let matches =
+                        search::search(session.messages.iter().map(|m| &m.message), query);
+
+                    if matches.is_empty() {
+                        continue;
+                    }
+
+                    results.push(format!(
+                        "session {} ({}):",
+                        session.id,
+                        session.updated.format("%Y-%m-%d %H:%M")
+                    ));
+
+                    results.extend(matches.iter().map(|m| format!("  {}", m)));
+                }
+            }
+            Err(err) => results.push(format!("failed to search the saved sessions: {}", err)),
+        }
+    }
+
+    if results.is_empty() {
+        return format!("no matches for \"{}\"", query);
+    }
+
+    results.join("\n")
+}
+
+/// Gathers the messages sent with a request: the conversation, preceded by
+/// the instructions of the persona and the JSON instructions, if any
+fn request_messages(
+    msg_buf: &MessageBuffer,
+    instructions: Option<&str>,
+    json_retries: Option<u32>,
+) -> Vec<chat::Message> {
+    let mut messages = msg_buf.chat_messages();
+
+    if let Some(instructions) = instructions {
+        messages.insert(
+            0,
+            chat::Message::new(Role::System, instructions.to_string()),
+        );
+    }
+
+    if json_retries.is_some() {
+        messages.insert(
+            0,
+            chat::Message::new(Role::System, JSON_INSTRUCTIONS.to_string()),
+        );
+    }
+
+    messages
+}
+
+/// The instructions and sampling temperature which apply to every request of
+/// a chat, whether it is held in the REPL or served to another program
+#[derive(Clone, Default, Debug, PartialEq)]
+pub(crate) struct RequestDefaults {
+    pub instructions: Option<String>,
+    pub temperature: Option<f64>,
+}
+
+impl RequestDefaults {
+    /// The defaults of the chat command, after any profile has been applied
+    pub(crate) fn for_chat(config: &config::Config) -> RequestDefaults {
+        RequestDefaults {
+            instructions: config
+                .system_prompts
+                .chat
+                .clone()
+                .or_else(|| config.system_prompt.clone()),
+            temperature: config.temperature,
+        }
+    }
+
+    /// The defaults once the persona is adopted, falling back to these
+    fn with_persona(&self, persona: &config::Persona) -> RequestDefaults {
+        RequestDefaults {
+            instructions: persona
+                .system_prompt
+                .clone()
+                .or_else(|| self.instructions.clone()),
+            temperature: persona.temperature.or(self.temperature),
+        }
+    }
+
+    /// The defaults with the given instructions, if any, in place of these
+    pub(crate) fn with_instructions(&self, instructions: Option<String>) -> RequestDefaults {
+        RequestDefaults {
+            instructions: instructions.or_else(|| self.instructions.clone()),
+            temperature: self.temperature,
+        }
+    }
+
+    /// The conversation as it is sent, led by the instructions
+    pub(crate) fn messages(&self, msg_buf: &MessageBuffer) -> Vec<chat::Message> {
+        request_messages(msg_buf, self.instructions.as_deref(), None)
+    }
+
+    pub(crate) fn options(&self) -> CompletionOptions {
+        CompletionOptions {
+            temperature: self.temperature,
+            ..Default::default()
+        }
+    }
+}
+
+/// Prints the greeting of a persona as though the model had said it
+fn greet(name: &str, persona: &config::Persona) {
+    if let Some(greeting) = &persona.greeting {
+        println!("{} {}\n", model_prompt(name), greeting);
+    }
+}
+
+type Resolution = JoinHandle<(Registry, Result<ModelSpec, registry::Error>)>;
+
+/// Resolves the model in the background once the registry is populated
+fn spawn_resolution(registry: JoinHandle<Registry>, model: Option<String>) -> Resolution {
+    tokio::spawn(async move {
+        let registry = registry.await.expect("failed to populate the registry");
+
+        let spec = resolve_spec(&registry, model).await;
+
+        (registry, spec)
+    })
+}
+
+/// Wait for the model to be resolved. If the providers are still being probed,
+/// an indicator is shown so the user knows why the chat has stalled.
+async fn await_resolution(resolution: Resolution, interactive: bool) -> (Registry, ModelSpec) {
+    let show_indicator = interactive && !resolution.is_finished();
+
+    if show_indicator {
+        eprint!("connecting…");
+    }
+
+    let (registry, spec) = resolution.await.expect("failed to resolve the model");
+
+    if show_indicator {
+        // Erase the indicator
+        eprint!("\r\x1b[2K");
+    }
+
+    match spec {
+        Ok(spec) => (registry, spec),
+        Err(err) => {
+            // When the default model is unset or a provider is not activate, this
+            // could be due to the complete absense of any provider. This is a more
+            // friendly error message, since the remediation action should be obvious
+            // to newcomers.
+            if registry.empty() {
+                die!("none of the chat providers are active, at least one needs to be active to start a chat");
+            }
+
+            die_with!(err.exit_code(), "failed to resolve model: {}", err);
+        }
+    }
+}
+
+async fn chat(
+    repl_config: ReplConfig,
+    resolution: Resolution,
+    mut msg_buf: MessageBuffer,
+    initial_prompt: Option<Turn>,
+    options: ChatOptions,
+    mut recorder: Option<SessionRecorder>,
+) {
+    let ChatOptions {
+        interactive,
+        incremental,
+        stats,
+        show_usage,
+        output,
+        json_retries,
+        persona,
+        fail_on_empty,
+        max_output_tokens,
+        seed,
+        stop,
+        keep_alive,
+        no_stream,
+        coalesce,
+        attachments,
+        images,
+        mut event_log,
+    } = options;
+
+    let fold_lines = if interactive {
+        repl_config.fold_lines
+    } else {
+        0
+    };
+
+    let keep_partial_responses = repl_config.keep_partial_responses;
+
+    let shows_timer = repl_config.timer && interactive && incremental;
+
+    let files_config = repl_config.files.clone();
+
+    let trimming = repl_config.context.trimming;
+
+    let summary_model = repl_config.context.summary_model.clone();
+
+    // Summaries are only written early with the summarize strategy
+    let summarize_at = match (trimming, repl_config.context.summarize_at) {
+        (config::Trimming::Summarize, Some(at)) if at > 0.0 && at <= 1.0 => Some(at),
+        (config::Trimming::Summarize, Some(at)) => {
+            warn!(
+                "summarize_at is ignored, it must be between 0 and 1 but is {}",
+                at
+            );
+            None
+        }
+        _ => None,
+    };
+
+    let personas = repl_config.personas.clone();
+
+    // The instructions and temperature of the persona apply to every request,
+    // falling back to those of the configuration
+    let defaults = repl_config.defaults.clone();
+
+    let RequestDefaults {
+        mut instructions,
+        mut temperature,
+    } = match persona.as_ref().map(|name| &personas[name]) {
+        Some(persona) => defaults.with_persona(persona),
+        None => defaults.clone(),
+    };
+
+    if interactive {
+        println!("{} version {}", version::NAME, version::VERSION);
+
+        msg_buf.print_transcript();
+
+        if let Some(name) = &persona {
+            greet(name, &personas[name]);
+        }
+    }
+
+    // Only initialize the REPL if  it is really needed.
+    let mut repl = if interactive {
+        let mut repl = Repl::new(repl_config);
+
+        for attachment in attachments {
+            repl.attach(attachment);
+        }
+
+        for image in images {
+            repl.attach_image(image);
+        }
+
+        Some(repl)
+    } else {
+        None
+    };
+
+    let mut record = ChatRecord {
+        // A resumed session is exported as one chat
+        started: recorder
+            .as_ref()
+            .map(SessionRecorder::created)
+            .unwrap_or_else(Local::now),
+        parameters: Parameters {
+            persona: persona.clone(),
+            temperature,
+            seed,
+            max_output_tokens,
+        },
+        // A resumed session keeps counting from its saved usage
+        usage_log: UsageLog::from_messages(&msg_buf.session_messages()),
+    };
+
+    // The first prompt is read while the model is resolved in the background.
+    let initial_prompt = match (initial_prompt, repl.as_mut()) {
+        (Some(initial_prompt), _) => Some(initial_prompt),
+        (None, Some(repl)) => match read_prompt(repl, &mut msg_buf, recorder.as_mut(), &record) {
+            Some(prompt) => Some(prompt),
+            None => return,
+        },
+        (None, None) => None,
+    };
+
+    let mut pending_init_prompt = matches!(initial_prompt, Some(Turn::Prompt(..) | Turn::Retry));
+
+    let (registry, spec) = await_resolution(resolution, interactive).await;
+
+    let (mut provider_id, mut model_id) = match spec.provider_model_ids() {
+        Ok(ids) => ids,
+        Err(err) => die_with!(err.exit_code(), "failed to resolve model: {}", err),
+    };
+
+    let mut spec = ModelSpec::resolved(provider_id, model_id.clone());
+
+    color::note_provider(&model_id, &provider_id.to_string());
+
+    if let Some(recorder) = recorder.as_mut() {
+        recorder.set_model(spec.to_string());
+        recorder.set_seed(seed);
+    }
+
+    // The model of the chat writes the summaries unless another is configured
+    let summary_model = match &summary_model {
+        Some(model) if matches!(trimming, config::Trimming::Summarize) => {
+            let resolved = resolve_spec(&registry, Some(model.clone()))
+                .await
+                .and_then(ModelSpec::provider_model_ids);
+
+            match resolved {
+                Ok(ids) => Some(ids),
+                Err(err) => {
+                    let warning = Message::warn(format!(
+                        "the chat model will write summaries, failed to resolve the summary model: {}",
+                        err
+                    ));
+
+                    eprintln!("{}", warning);
+
+                    msg_buf.add_message(warning);
+
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+
+    if seed.is_some() {
+        if let Some(warning) = seed_warning(registry.provider(provider_id).unwrap().as_ref()) {
+            eprintln!("{}", warning);
+
+            msg_buf.add_message(warning);
+        }
+    }
+
+    match registry.provider(provider_id).unwrap().context_management() {
+        ContextManagement::Implicit => {
+            let implicit_warning = Message::warn(
+                "This provider implicity manages context. The context may be truncated without warning.".to_string()
+            );
+
+            eprintln!("{}", implicit_warning);
+
+            msg_buf.add_message(implicit_warning);
+        }
+        ContextManagement::Explicit => {}
+    }
+
+    // A persona chosen before the model was resolved is switched to first
+    let mut next_persona = None;
+
+    // Add the initial prompt to the internal buffer.
+    match initial_prompt {
+        Some(Turn::Prompt(initial_prompt)) => msg_buf.add_message(Message::prompt(initial_prompt)),
+        Some(Turn::Persona(name)) => next_persona = Some(name),
+        Some(Turn::Retry) | None => {}
+    }
+
+    // The number of times the model has been re-prompted for invalid JSON
+    let mut json_attempts = 0;
+
+    // The number of requests for a response, which numbers the logged events
+    let mut turn = 0;
+
+    // The model which was last warned about ignoring options, so the warning
+    // is not repeated with each request
+    let mut warned_options: Option<String> = None;
+
+    loop {
+        if let Some(name) = next_persona.take() {
+            let persona = &personas[&name];
+
+            RequestDefaults {
+                instructions,
+                temperature,
+            } = defaults.with_persona(persona);
+
+            record.parameters.persona = Some(name.clone());
+            record.parameters.temperature = temperature;
+
+            if let Some(model) = &persona.model {
+                let resolved = resolve_spec(&registry, Some(model.clone()))
+                    .await
+                    .and_then(ModelSpec::provider_model_ids);
+
+                match resolved {
+                    Ok(ids) => {
+                        (provider_id, model_id) = ids;
+
+                        spec = ModelSpec::resolved(provider_id, model_id.clone());
+
+                        color::note_provider(&model_id, &provider_id.to_string());
+
+                        if let Some(recorder) = recorder.as_mut() {
+                            recorder.set_model(spec.to_string());
+                        }
+                    }
+                    Err(err) => {
+                        let msg = Message::error(format!(
+                            "failed to resolve the model of the \"{}\" persona: {}",
+                            name, err
+                        ));
+
+                        eprintln!("{}", msg);
+
+                        msg_buf.add_message(msg);
+                    }
+                }
+            }
+
+            if persona.greeting.is_some() {
+                greet(&name, persona);
+            } else {
+                let msg = Message::output(format!("switched to the \"{}\" persona", name));
+
+                eprintln!("{}", msg);
+
+                msg_buf.add_message(msg);
+            }
+        }
+
+        // Prompt after the initial prompt is dispensed with.
+        if !pending_init_prompt && interactive {
+            let repl = repl.as_mut().unwrap();
+
+            let prompt = read_prompt(repl, &mut msg_buf, recorder.as_mut(), &record);
+
+            match prompt {
+                Some(Turn::Prompt(prompt)) => msg_buf.add_message(Message::prompt(prompt)),
+                // The response was removed by the REPL
+                Some(Turn::Retry) => {}
+                Some(Turn::Persona(name)) => {
+                    next_persona = Some(name);
+                    continue;
+                }
+                None => break,
+            }
+
+            json_attempts = 0;
+        }
+
+        let model_id = model_id.as_str();
+
+        let provider = registry.provider(provider_id).unwrap();
+
+        let started = Instant::now();
+
+        let mut messages = request_messages(&msg_buf, instructions.as_deref(), json_retries);
+
+        let summarizer = match &summary_model {
+            Some((summary_provider_id, summary_model_id)) => trim::Summarizer {
+                provider: registry.provider(*summary_provider_id).unwrap().as_ref(),
+                model_id: summary_model_id,
+            },
+            None => trim::Summarizer {
+                provider: provider.as_ref(),
+                model_id,
+            },
+        };
+
+        // The conversation is trimmed before it is sent if it is estimated
+        // to outgrow the context
+        let trims_context = !matches!(trimming, config::Trimming::Fail)
+            && matches!(provider.context_management(), ContextManagement::Explicit);
+
+        if trims_context {
+            if let Some(context_length) = trim::context_length(provider.as_ref(), model_id).await {
+                let mut trimmed = 0;
+
+                if let Some(at) = summarize_at {
+                    let threshold = (context_length as f64 * at) as usize;
+
+                    if trim::estimate_tokens(&messages) > threshold {
+                        let estimate = |msg_buf: &MessageBuffer| {
+                            trim::estimate_tokens(&request_messages(
+                                msg_buf,
+                                instructions.as_deref(),
+                                json_retries,
+                            ))
+                        };
+
+                        trimmed += trim::summarize_oldest(
+                            &summarizer,
+                            &mut msg_buf,
+                            estimate,
+                            threshold / 2,
+                        )
+                        .await;
+
+                        messages =
+                            request_messages(&msg_buf, instructions.as_deref(), json_retries);
+                    }
+                }
+
+                while trim::estimate_tokens(&messages) > context_length
+                    && trim::trim(&summarizer, &mut msg_buf, trimming).await
+                {
+                    trimmed += 1;
+
+                    messages = request_messages(&msg_buf, instructions.as_deref(), json_retries);
+                }
+
+                if trimmed > 0 {
+                    let warning = trim::trimmed_warning(trimmed, trimming);
+
+                    eprintln!("{}", warning);
+
+                    msg_buf.add_message(warning);
+                }
+            }
+        }
+
+        let mut completion_options = CompletionOptions {
+            temperature,
+            max_tokens: max_output_tokens,
+            stop: stop.clone(),
+            seed,
+            keep_alive,
+            ..Default::default()
+        };
+
+        if json_retries.is_some() {
+            completion_options.response_format = ResponseFormat::Json;
+        }
+
+        if warned_options.as_deref() != Some(spec.to_string().as_str()) {
+            if let Some(warning) =
+                unsupported_options_warning(provider.as_ref(), &spec, model_id, &completion_options)
+            {
+                eprintln!("{}", warning);
+
+                msg_buf.add_message(warning);
+
+                warned_options = Some(spec.to_string());
+            }
+        }
+
+        turn += 1;
+
+        event_log.turn_started(turn, &spec.to_string(), messages.len());
+
+        // A response requested in full is presented as a stream of one chunk
+        let request = async {
+            if no_stream {
+                provider
+                    .completion(model_id, &messages, &completion_options)
+                    .await
+                    .map(Completion::into_stream)
+            } else {
+                provider
+                    .stream_completion(model_id, &messages, &completion_options)
+                    .await
+            }
+        };
+
+        // The request is abandoned if it is cancelled before the response begins
+        let completion = select! {
+            completion = request => completion,
+            _ = signal::ctrl_c() => {
+                if !interactive {
+                    break;
+                }
+
+                event_log.turn_finished(turn, StopReason::Cancelled, None, started);
+
+                let cancelled = Message::warn("the request was cancelled".to_string());
+
+                eprintln!("\n{}", cancelled);
+
+                msg_buf.add_message(cancelled);
+
+                pending_init_prompt = false;
+
+                continue;
+            }
+        };
+
+        let mut completion = match completion {
+            Ok(completion) => completion,
+            // The request is sent again once there is room for it
+            Err(err)
+                if matches!(err.kind(), ErrorKind::ContextExceeded)
+                    && trims_context
+                    && trim::trim(&summarizer, &mut msg_buf, trimming).await =>
+            {
+                event_log.error(Some(turn), &err.to_string());
+
+                let warning = trim::trimmed_warning(1, trimming);
+
+                eprintln!("{}", warning);
+
+                msg_buf.add_message(warning);
+
+                pending_init_prompt = true;
+
+                continue;
+            }
+            Err(err) => {
+                let mut err_msg = format!("completion for {} failed: {}", spec, err);
+
+                if let Some(source) = err.source() {
+                    err_msg.push_str(&format!("\n{}", source));
+                }
+
+                event_log.error(Some(turn), &err_msg);
+
+                let completion_error = Message::error(err_msg);
+
+                eprintln!("{}", completion_error);
+
+                msg_buf.add_message(completion_error);
+
+                // The request is not sent again until there is another prompt
+                if !interactive {
+                    std::process::exit(err.exit_code());
+                }
+
+                pending_init_prompt = false;
+
+                continue;
+            }
+        };
+
+        let mut msg_builder = MessageBuilder::new();
+
+        let mut folder = Folder::new(fold_lines);
+
+        let highlight_code = matches!(color::color_mode(), ColorMode::On);
+
+        let mut code = CodeHighlighter::new(highlight_code);
+
+        let mut annotator = StreamAnnotator::new();
+
+        let mut timer = GenerationTimer::new(started);
+
+        // Withheld responses are prefixed with the prompt once they are printed
+        if interactive && incremental {
+            let prompt = format!("{} ", model_prompt(model_id));
+
+            print!("{}", prompt);
+            flush_or_die();
+
+            if shows_timer {
+                timer.after_print(&prompt);
+            }
+        }
 
         let mut skip_response = false;
 
+        // Streamed chunks with content are counted as tokens, so the response
+        // is only cut off if the provider ignored the limit
+        let mut chunks = 0;
+        let mut cut_off = false;
+
+        let mut stall = StallDetector::new();
+
+        let mut coalescer = Coalescer::new(coalesce);
+
+        // An error which ended the response before it was complete
+        let mut stream_error = None;
+
+        completion.on_heartbeat(stall.hook());
+
         loop {
             select! {
                 update = completion.next() => {
+                    stall.erase();
+
                     let update = match update {
                         Some(update) => update,
                         None => break
@@ -327,42 +1974,406 @@ async fn chat<'p>(
 
                     match update {
                         Ok(delta) => {
+                            if !delta.content.is_empty() {
+                                chunks += 1;
+
+                                if max_output_tokens.is_some_and(|max| chunks > max) {
+                                    cut_off = true;
+                                    break;
+                                }
+                            }
+
                             if incremental {
-                                print!("{}", delta.content);
-                                flush_or_die();
+                                let text = code.push(&annotator.push(&delta.content, &delta.citations));
+
+                                if let Some(text) = coalescer.push(folder.push(&text)) {
+                                    print_streamed(&text, &mut timer, shows_timer);
+                                }
                             }
-        
+
+                            event_log.delta(turn, &delta.content);
+
                             msg_builder.add(&delta);
                         }
-                        Err(err) => panic!("failed to decode streaming response: {}", err),
+                        Err(err) => {
+                            let mut message = format!("failed to decode streaming response: {}", err);
+
+                            if let Some(source) = err.source() {
+                                message.push_str(&format!("\n{}", source));
+                            }
+
+                            event_log.error(Some(turn), &message);
+
+                            if !interactive {
+                                die_with!(err.exit_code(), "{}", message);
+                            }
+
+                            stream_error = Some(message);
+                            break;
+                        }
                     }
                 }
+                _ = stall.watch(), if interactive => {}
+                _ = timer.watch(), if shows_timer => {}
+                _ = coalescer.due() => print_streamed(&coalescer.take(), &mut timer, shows_timer),
                 _ = signal::ctrl_c() => {
+                    stall.erase();
                     skip_response = true;
                     break;
-                } 
+                }
             }
         }
 
+        // The text held back is shown, even if the response was cancelled
+        let held_back = coalescer.take();
+
+        if !held_back.is_empty() {
+            print_streamed(&held_back, &mut timer, shows_timer);
+        }
+
+        timer.erase();
+
+        // The partial response is dropped, as a response which failed to
+        // begin would be, and the chat returns to the prompt
+        if let Some(message) = stream_error {
+            let stream_error = Message::error(message);
+
+            eprintln!("\n{}", stream_error);
+
+            msg_buf.add_message(stream_error);
+
+            pending_init_prompt = false;
+
+            continue;
+        }
+
+        // Dropping the stream aborts the request, rather than holding the
+        // connection open until the rest of the response is ignored
+        let completion = (!skip_response && !cut_off).then_some(completion);
+
+        let truncated = cut_off
+            || completion.as_ref().is_some_and(|completion| {
+                matches!(completion.finish_reason(), FinishReason::Length)
+            });
+
+        let marker = if truncated {
+            let marker = color::FOLD_MARKER.maybe_paint("… the response reached its token limit");
+
+            format!("\n{}", marker)
+        } else {
+            String::new()
+        };
+
+        let finish_reason = match &completion {
+            Some(completion) => StopReason::from(completion.finish_reason()),
+            None if cut_off => StopReason::Length,
+            None => StopReason::Cancelled,
+        };
+
+        let response = match &completion {
+            Some(completion) => ResponseMetadata::from_completion(completion.as_ref()),
+            None if cut_off => ResponseMetadata {
+                usage: None,
+                finish_reason: Some(FinishReason::Length),
+            },
+            None => ResponseMetadata::default(),
+        };
+
+        event_log.turn_finished(
+            turn,
+            finish_reason,
+            completion
+                .as_ref()
+                .map(|completion| TokenUsage::from(completion.usage())),
+            started,
+        );
+
+        let references = msg_builder.references();
+
+        let generated = msg_builder.take_files();
+
         let msg: chat::Message = match msg_builder.try_into() {
             Ok(msg) => msg,
-            Err(()) => continue,
+            Err(()) if fail_on_empty && !skip_response => die!("the response was empty"),
+            Err(()) if !interactive => break,
+            Err(()) => {
+                pending_init_prompt = false;
+                continue;
+            }
         };
 
+        if fail_on_empty && !skip_response && msg.content.trim().is_empty() && generated.is_empty()
+        {
+            die!("the response was empty");
+        }
+
+        // Responses which are retried still count towards the usage
+        if let Some(completion) = &completion {
+            record
+                .usage_log
+                .record(model_id, completion.usage().clone());
+        }
+
+        if let (Some(max_retries), false) = (json_retries, skip_response) {
+            if let Err(err) = serde_json::from_str::<serde_json::Value>(&msg.content) {
+                if json_attempts < max_retries {
+                    json_attempts += 1;
+
+                    let retry_warning = Message::warn(format!(
+                        "the response was not valid JSON, retrying ({}/{}): {}",
+                        json_attempts, max_retries, err
+                    ));
+
+                    eprintln!("{}", retry_warning);
+
+                    // The model is shown its mistake so it can be corrected
+                    msg_buf.add_message(Message::Chat(msg, Some(model_id.to_string()), response));
+                    msg_buf.add_message(retry_warning);
+                    msg_buf.add_message(Message::user(json_correction(&err)));
+
+                    pending_init_prompt = true;
+
+                    continue;
+                }
+
+                let invalid_json = format!(
+                    "the response was not valid JSON after {} retries: {}\n{}",
+                    max_retries, err, msg.content
+                );
+
+                if !interactive {
+                    die!("{}", invalid_json);
+                }
+
+                let invalid_json = Message::error(invalid_json);
+
+                eprintln!("{}", invalid_json);
+
+                msg_buf.add_message(invalid_json);
+
+                pending_init_prompt = false;
+
+                continue;
+            }
+        }
+
+        // Footnote markers are placed as the response is streamed, or else
+        // once the full response is known
         if incremental {
-            println!("\n");
+            let rest = code.push(&annotator.finish()) + &code.finish();
+
+            print!("{}", folder.push(&rest));
+            println!("{}{}\n", folder.finish(), marker);
+        } else if interactive {
+            println!(
+                "{} {}{}\n",
+                model_prompt(model_id),
+                fold(
+                    &codeblocks::highlight(&references.annotate(&msg.content), highlight_code),
+                    fold_lines
+                ),
+                marker
+            );
         } else {
-            print!("{}", msg.content);
+            print!("{}{}", references.annotate(&msg.content), marker);
         }
 
-        if !skip_response {
-            msg_buf.add_message(Message::Chat(msg, Some(model_id.to_string())));
+        // The sources of a cancelled response may not have been reported, so
+        // none are listed
+        let references = if references.is_empty() || skip_response {
+            None
+        } else {
+            if incremental {
+                println!("{}\n", references);
+            } else {
+                print!("\n\n{}", references);
+            }
+
+            Some(Message::output(references.to_string()))
+        };
+
+        let saved = save_files(&files_config, &generated);
+
+        let saved = if saved.is_empty() {
+            None
+        } else {
+            let listing: Vec<String> = saved.iter().map(ToString::to_string).collect();
+
+            if incremental {
+                println!("{}\n", listing.join("\n"));
+            } else {
+                print!("\n\n{}", listing.join("\n"));
+            }
+
+            let descriptions: Vec<String> = saved.iter().map(SavedFile::describe).collect();
+
+            Some(Message::output(descriptions.join("\n")))
+        };
+
+        // Cancelled responses are kept if configured, so the conversation
+        // matches what was displayed
+        if !skip_response || keep_partial_responses {
+            msg_buf.add_message(Message::Chat(msg, Some(model_id.to_string()), response));
+
+            if let Some(references) = references {
+                msg_buf.add_message(references);
+            }
+
+            if let Some(saved) = saved {
+                msg_buf.add_message(saved);
+            }
+
+            if let Some(recorder) = recorder.as_mut() {
+                if let Err(err) = recorder.record(&msg_buf) {
+                    warn!("failed to save the session: {}", err);
+                }
+            }
+
+            if let Some((path, format)) = &output {
+                if let Err(err) = export_transcript(&msg_buf, &record, path, *format) {
+                    warn!(
+                        "failed to export the transcript to {}: {}",
+                        path.display(),
+                        err
+                    );
+                }
+            }
+        }
+
+        if let Some(completion) = &completion {
+            if stats {
+                let stats =
+                    response_stats(provider.as_ref(), model_id, completion.as_ref(), started).await;
+
+                let stats = Message::output(stats.to_string());
+
+                eprintln!("{}", stats);
+
+                msg_buf.add_message(stats);
+            }
+
+            if show_usage {
+                let usage = Message::output(record.usage_log.summary());
+
+                eprintln!("{}", usage);
+
+                msg_buf.add_message(usage);
+            }
         }
 
         if !interactive {
             break;
         }
- 
+
         pending_init_prompt = false;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trim::transcript;
+
+    fn conversation() -> MessageBuffer {
+        let mut msg_buf = MessageBuffer::new();
+
+        msg_buf.add_message(Message::system("Be brief.".to_string()));
+        msg_buf.add_message(Message::user("first".to_string()));
+        msg_buf.add_message(Message::model("one".to_string(), "m".to_string()));
+        msg_buf.add_message(Message::user("second".to_string()));
+        msg_buf.add_message(Message::model("two".to_string(), "m".to_string()));
+
+        msg_buf
+    }
+
+    #[test]
+    fn test_request_defaults_of_profile() {
+        let config: config::Config = toml::de::from_str(
+            r#"
+            temperature = 0.7
+
+            [profiles.work]
+            temperature = 0.2
+            system_prompt = "Be brief."
+            "#,
+        )
+        .unwrap();
+
+        let (config, _) = config.with_profile("work").unwrap();
+
+        let defaults = RequestDefaults::for_chat(&config);
+
+        assert_eq!(defaults.options().temperature, Some(0.2));
+
+        let mut msg_buf = MessageBuffer::new();
+        msg_buf.add_message(Message::user("Hello!".to_string()));
+
+        assert_eq!(
+            transcript(&defaults.messages(&msg_buf)),
+            "system: Be brief.\n\nuser: Hello!"
+        );
+    }
+
+    #[test]
+    fn test_display_unknown_model() {
+        let msg = Message::Chat(
+            chat::Message::new(Role::Model, "Hi!".to_string()),
+            None,
+            ResponseMetadata::default(),
+        );
+
+        assert!(msg.to_string().ends_with("[model] Hi!"));
+    }
+
+    #[test]
+    fn test_undo_empty() {
+        let mut msg_buf = MessageBuffer::new();
+
+        assert!(!msg_buf.undo());
+
+        // A system message is not a prompt
+        msg_buf.add_message(Message::system("Be brief.".to_string()));
+
+        assert!(!msg_buf.undo());
+        assert_eq!(transcript(&msg_buf.chat_messages()), "system: Be brief.");
+    }
+
+    #[test]
+    fn test_undo_after_retry() {
+        let mut msg_buf = conversation();
+
+        assert!(msg_buf.retract_response());
+        assert_eq!(
+            transcript(&msg_buf.chat_messages()),
+            "system: Be brief.\n\nuser: first\n\nmodel: one\n\nuser: second"
+        );
+
+        // The prompt being retried is removed, along with what was shown
+        // after it
+        msg_buf.add_message(Message::warn("the request failed".to_string()));
+
+        assert!(msg_buf.undo());
+        assert_eq!(msg_buf.buf.len(), 3);
+        assert_eq!(
+            transcript(&msg_buf.chat_messages()),
+            "system: Be brief.\n\nuser: first\n\nmodel: one"
+        );
+    }
+
+    #[test]
+    fn test_undo_pinned() {
+        let mut msg_buf = conversation();
+
+        assert!(msg_buf.set_pinned(1, true));
+        assert!(msg_buf.set_pinned(4, true));
+
+        assert!(msg_buf.undo());
+        assert_eq!(msg_buf.pinned_turns().into_iter().collect::<Vec<_>>(), [1]);
+
+        // A message added in place of the removed one is not pinned
+        msg_buf.add_message(Message::user("third".to_string()));
+        msg_buf.add_message(Message::model("three".to_string(), "m".to_string()));
+
+        assert_eq!(msg_buf.pinned_turns().into_iter().collect::<Vec<_>>(), [1]);
+    }
+}
@@ -0,0 +1,495 @@
+//! Files attached to a prompt
+//!
+//! Attachments are read when they are attached and sent at the start of the
+//! next prompt, each in a fenced code block headed by its path. The fence is
+//! made longer than any run of backticks in the file so the file cannot close
+//! it early. Images are sent alongside the prompt rather than in it, for
+//! models which accept images.
+//!
+//! The text of PDF documents is extracted locally and attached in place of
+//! the document. A range of pages can be selected by appending it to the
+//! path, as in `report.pdf#3-7`.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::panic;
+use std::path::{Path, PathBuf};
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use thiserror::Error;
+
+use crate::chat;
+
+/// The largest file which can be attached
+pub(crate) const MAX_ATTACHMENT_BYTES: u64 = 256 * 1024;
+
+/// The largest image which can be attached
+pub(crate) const MAX_IMAGE_BYTES: u64 = 20 * 1024 * 1024;
+
+/// The largest document which text can be extracted from. The extracted
+/// text is limited to [`MAX_ATTACHMENT_BYTES`] like any other file.
+pub(crate) const MAX_DOCUMENT_BYTES: u64 = 20 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub(crate) enum Error {
+    #[error("failed to read {}: {}", .0.display(), .1)]
+    Read(PathBuf, #[source] io::Error),
+
+    #[error("{} is {} bytes, which is more than the limit of {} bytes", .path.display(), .size, .limit)]
+    TooLarge {
+        path: PathBuf,
+        size: u64,
+        limit: u64,
+    },
+
+    #[error("{} is not a text file", .0.display())]
+    NotText(PathBuf),
+
+    #[error("{} is not a PNG, JPEG, GIF, or WebP image", .0.display())]
+    NotImage(PathBuf),
+
+    #[error("\"{0}\" is not a range of pages, pages are numbered from 1 (e.g., 3-7)")]
+    InvalidPages(String),
+
+    #[error("{} is not a PDF document, so pages cannot be selected from it", .0.display())]
+    NotPaged(PathBuf),
+
+    #[error("{} has {} pages, so pages {} cannot be selected", .path.display(), .count, .pages)]
+    PagesOutOfRange {
+        path: PathBuf,
+        pages: Pages,
+        count: usize,
+    },
+
+    #[error("failed to extract the text of {}: {}", .0.display(), .1)]
+    Extract(PathBuf, String),
+
+    #[error("the text of {} is {} bytes, which is more than the limit of {} bytes, select fewer pages", .path.display(), .size, .limit)]
+    TextTooLarge {
+        path: PathBuf,
+        size: u64,
+        limit: u64,
+    },
+}
+
+/// An inclusive range of pages, numbered from one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Pages {
+    first: usize,
+    last: usize,
+}
+
+impl fmt::Display for Pages {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.first == self.last {
+            write!(f, "{}", self.first)
+        } else {
+            write!(f, "{}-{}", self.first, self.last)
+        }
+    }
+}
+
+/// Splits a range of pages off the end of a path, as in `report.pdf#3-7`.
+/// Paths which do not end in something resembling a range, or which name an
+/// existing file such as `notes#1.pdf`, are left whole.
+pub(crate) fn split_pages(spec: &Path) -> Result<(PathBuf, Option<Pages>), Error> {
+    if spec.exists() {
+        return Ok((spec.to_path_buf(), None));
+    }
+
+    let split = spec.to_str().and_then(|spec| spec.rsplit_once('#'));
+
+    let (path, range) = match split {
+        Some((path, range))
+            if !range.is_empty() && range.chars().all(|c| c.is_ascii_digit() || c == '-') =>
+        {
+            (path, range)
+        }
+        _ => return Ok((spec.to_path_buf(), None)),
+    };
+
+    let invalid = || Error::InvalidPages(range.to_string());
+
+    let parse = |page: &str| page.parse::<usize>().map_err(|_| invalid());
+
+    let pages = match range.split_once('-') {
+        Some((first, last)) => Pages {
+            first: parse(first)?,
+            last: parse(last)?,
+        },
+        None => {
+            let page = parse(range)?;
+            Pages {
+                first: page,
+                last: page,
+            }
+        }
+    };
+
+    if pages.first == 0 || pages.last < pages.first {
+        return Err(invalid());
+    }
+
+    Ok((PathBuf::from(path), Some(pages)))
+}
+
+/// Extracts the text of a PDF document, or of the selected pages
+fn extract_pdf(path: &Path, bytes: &[u8], pages: Option<Pages>) -> Result<String, Error> {
+    // The extractor panics on some malformed documents
+    let text = match panic::catch_unwind(|| pdf_extract::extract_text_from_mem_by_pages(bytes)) {
+        Ok(text) => text.map_err(|err| Error::Extract(path.to_path_buf(), err.to_string()))?,
+        Err(payload) => {
+            let reason = payload
+                .downcast_ref::<&str>()
+                .map(|reason| reason.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "the document is malformed".to_string());
+
+            return Err(Error::Extract(path.to_path_buf(), reason));
+        }
+    };
+
+    let selected = match pages {
+        Some(pages) if pages.last > text.len() => {
+            return Err(Error::PagesOutOfRange {
+                path: path.to_path_buf(),
+                pages,
+                count: text.len(),
+            })
+        }
+        Some(pages) => &text[pages.first - 1..pages.last],
+        None => &text[..],
+    };
+
+    let selected: Vec<&str> = selected.iter().map(|page| page.trim()).collect();
+
+    Ok(selected.join("\n\n"))
+}
+
+/// Reads a file, refusing files larger than `limit`
+fn read_limited(path: &Path, limit: u64) -> Result<Vec<u8>, Error> {
+    let size = fs::metadata(path)
+        .map_err(|err| Error::Read(path.to_path_buf(), err))?
+        .len();
+
+    if size > limit {
+        return Err(Error::TooLarge {
+            path: path.to_path_buf(),
+            size,
+            limit,
+        });
+    }
+
+    fs::read(path).map_err(|err| Error::Read(path.to_path_buf(), err))
+}
+
+/// Where the content of an attachment came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Source {
+    Text,
+    /// Text extracted from a PDF document, or from the selected pages
+    Pdf(Option<Pages>),
+}
+
+pub(crate) struct Attachment {
+    pub path: PathBuf,
+    pub source: Source,
+    pub content: String,
+}
+
+impl Attachment {
+    /// Reads a file to be attached. The text of PDF documents is extracted,
+    /// and a range of pages may follow the path of one.
+    pub(crate) fn read(spec: &Path) -> Result<Attachment, Error> {
+        let (path, pages) = split_pages(spec)?;
+
+        let bytes = read_limited(&path, MAX_DOCUMENT_BYTES)?;
+
+        if bytes.starts_with(b"%PDF-") {
+            let content = extract_pdf(&path, &bytes, pages)?;
+
+            if content.len() as u64 > MAX_ATTACHMENT_BYTES {
+                return Err(Error::TextTooLarge {
+                    path,
+                    size: content.len() as u64,
+                    limit: MAX_ATTACHMENT_BYTES,
+                });
+            }
+
+            return Ok(Attachment {
+                path,
+                source: Source::Pdf(pages),
+                content,
+            });
+        }
+
+        if pages.is_some() {
+            return Err(Error::NotPaged(path));
+        }
+
+        if bytes.len() as u64 > MAX_ATTACHMENT_BYTES {
+            return Err(Error::TooLarge {
+                path,
+                size: bytes.len() as u64,
+                limit: MAX_ATTACHMENT_BYTES,
+            });
+        }
+
+        match String::from_utf8(bytes) {
+            Ok(content) => Ok(Attachment {
+                path,
+                source: Source::Text,
+                content,
+            }),
+            Err(_) => Err(Error::NotText(path)),
+        }
+    }
+
+    /// The path of the file, followed by the selected pages
+    pub(crate) fn name(&self) -> String {
+        match self.source {
+            Source::Pdf(Some(pages)) => format!("{}#{}", self.path.display(), pages),
+            _ => self.path.display().to_string(),
+        }
+    }
+
+    /// Writes the file out in a fenced code block
+    fn fenced(&self) -> String {
+        let mut longest = 0;
+        let mut run = 0;
+
+        for c in self.content.chars() {
+            run = if c == '`' { run + 1 } else { 0 };
+            longest = longest.max(run);
+        }
+
+        let fence = "`".repeat(longest.max(2) + 1);
+
+        // The text of a document is not in the language of its extension
+        let language = match self.source {
+            Source::Text => self
+                .path
+                .extension()
+                .map(|extension| extension.to_string_lossy())
+                .unwrap_or_default(),
+            Source::Pdf(_) => Default::default(),
+        };
+
+        format!(
+            "{}:\n{}{}\n{}\n{}",
+            self.name(),
+            fence,
+            language,
+            self.content.trim_end_matches('\n'),
+            fence
+        )
+    }
+}
+
+pub(crate) struct ImageAttachment {
+    pub path: PathBuf,
+    pub image: chat::Image,
+}
+
+impl ImageAttachment {
+    /// Reads an image to be attached. The format is recognized from the
+    /// contents of the file rather than its extension.
+    pub(crate) fn read(path: &Path) -> Result<ImageAttachment, Error> {
+        let bytes = read_limited(path, MAX_IMAGE_BYTES)?;
+
+        let media_type = match media_type(&bytes) {
+            Some(media_type) => media_type,
+            None => return Err(Error::NotImage(path.to_path_buf())),
+        };
+
+        Ok(ImageAttachment {
+            path: path.to_path_buf(),
+            image: chat::Image {
+                media_type: media_type.to_string(),
+                data: BASE64_STANDARD.encode(&bytes),
+            },
+        })
+    }
+}
+
+/// Recognizes the formats of images which models commonly accept by their
+/// signatures
+fn media_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(b"\xff\xd8\xff") {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+/// Places the attachments before the prompt
+pub(crate) fn attach(attachments: &[Attachment], prompt: &str) -> String {
+    if attachments.is_empty() {
+        return prompt.to_string();
+    }
+
+    let mut message: Vec<String> = attachments.iter().map(Attachment::fenced).collect();
+
+    message.push(prompt.to_string());
+
+    message.join("\n\n")
+}
+
+/// The prompt as a message, with the files placed before it and the images
+/// alongside it. The names of both are recorded in the message, so they are
+/// saved with the session.
+pub(crate) fn prompt_message(
+    attachments: &[Attachment],
+    images: Vec<ImageAttachment>,
+    prompt: &str,
+) -> chat::Message {
+    let mut names: Vec<String> = attachments.iter().map(Attachment::name).collect();
+
+    names.extend(images.iter().map(|image| image.path.display().to_string()));
+
+    let images = images.into_iter().map(|image| image.image).collect();
+
+    let mut message =
+        chat::Message::with_images(chat::Role::User, attach(attachments, prompt), images);
+
+    message.attachments = names;
+
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attach() {
+        let attachments = [
+            Attachment {
+                path: PathBuf::from("src/main.rs"),
+                source: Source::Text,
+                content: "fn main() {}\n".to_string(),
+            },
+            Attachment {
+                path: PathBuf::from("README"),
+                source: Source::Text,
+                content: "```bash\nxtalk\n```".to_string(),
+            },
+            Attachment {
+                path: PathBuf::from("report.pdf"),
+                source: Source::Pdf(Some(Pages { first: 3, last: 7 })),
+                content: "Summary".to_string(),
+            },
+        ];
+
+        assert_eq!(
+            attach(&attachments, "Explain this."),
+            "src/main.rs:\n```rs\nfn main() {}\n```\n\nREADME:\n````\n```bash\nxtalk\n```\n````\n\nreport.pdf#3-7:\n```\nSummary\n```\n\nExplain this."
+        );
+
+        assert_eq!(attach(&[], "Hello"), "Hello");
+    }
+
+    #[test]
+    fn test_split_pages() {
+        let split = |spec: &str| split_pages(Path::new(spec));
+
+        assert_eq!(
+            split("report.pdf#3-7").unwrap(),
+            (
+                PathBuf::from("report.pdf"),
+                Some(Pages { first: 3, last: 7 })
+            )
+        );
+        assert_eq!(
+            split("report.pdf#2").unwrap().1,
+            Some(Pages { first: 2, last: 2 })
+        );
+        assert_eq!(
+            split("notes#todo.md").unwrap(),
+            (PathBuf::from("notes#todo.md"), None)
+        );
+        assert_eq!(split("report.pdf").unwrap().1, None);
+
+        assert!(split("report.pdf#0-2").is_err());
+        assert!(split("report.pdf#7-3").is_err());
+        assert!(split("report.pdf#3-").is_err());
+
+        // A file whose name resembles a range is attached whole
+        let dir = std::env::temp_dir().join(format!("xtalk-split-pages-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let literal = dir.join("notes#1");
+        fs::write(&literal, "notes").unwrap();
+
+        assert_eq!(split_pages(&literal).unwrap(), (literal.clone(), None));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_extract_malformed_pdf() {
+        // A page which uses a font that is never declared makes the
+        // extractor panic
+        let content = "BT /F1 12 Tf 72 712 Td (Hello) Tj ET";
+
+        let objects = [
+            "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+            "<< /Type /Pages /Kids [3 0 R] /Count 1 >>".to_string(),
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 612 792] /Contents 4 0 R >>".to_string(),
+            format!(
+                "<< /Length {} >>\nstream\n{}\nendstream",
+                content.len(),
+                content
+            ),
+        ];
+
+        let mut pdf = "%PDF-1.4\n".to_string();
+        let mut offsets = Vec::new();
+
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(pdf.len());
+            pdf.push_str(&format!("{} 0 obj\n{}\nendobj\n", i + 1, object));
+        }
+
+        let xref = pdf.len();
+
+        pdf.push_str(&format!(
+            "xref\n0 {}\n0000000000 65535 f \n",
+            objects.len() + 1
+        ));
+
+        for offset in offsets {
+            pdf.push_str(&format!("{:010} 00000 n \n", offset));
+        }
+
+        pdf.push_str(&format!(
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF",
+            objects.len() + 1,
+            xref
+        ));
+
+        assert!(matches!(
+            extract_pdf(Path::new("broken.pdf"), pdf.as_bytes(), None),
+            Err(Error::Extract(..))
+        ));
+    }
+
+    #[test]
+    fn test_media_type() {
+        assert_eq!(
+            media_type(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+            Some("image/png")
+        );
+        assert_eq!(media_type(b"\xff\xd8\xff\xe0"), Some("image/jpeg"));
+        assert_eq!(media_type(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(media_type(b"RIFF\0\0\0\0WAVE"), None);
+        assert_eq!(media_type(b"fn main() {}"), None);
+    }
+}
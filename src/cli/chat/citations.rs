@@ -0,0 +1,232 @@
+//! Rendering of sources cited by the model
+//!
+//! Sources are numbered in the order they are first cited. Each source is
+//! listed once in a references block below the response, even if it is
+//! cited several times.
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::providers::Citation;
+
+struct Reference {
+    url: String,
+    title: Option<String>,
+}
+
+pub(crate) struct References {
+    references: Vec<Reference>,
+    /// Footnote markers, as pairs of character offsets and reference numbers
+    markers: Vec<(usize, usize)>,
+}
+
+impl References {
+    pub(crate) fn new(citations: &[Citation]) -> References {
+        let mut references: Vec<Reference> = Vec::new();
+        let mut markers = Vec::new();
+
+        for citation in citations {
+            let number = match references.iter().position(|r| r.url == citation.url) {
+                Some(i) => i + 1,
+                None => {
+                    references.push(Reference {
+                        url: citation.url.clone(),
+                        title: citation.title.clone(),
+                    });
+
+                    references.len()
+                }
+            };
+
+            if let Some(end_index) = citation.end_index {
+                if !markers.contains(&(end_index, number)) {
+                    markers.push((end_index, number));
+                }
+            }
+        }
+
+        References {
+            references,
+            markers,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.references.is_empty()
+    }
+
+    /// Inserts footnote markers (e.g., "[1]") into the content after the cited text
+    pub(crate) fn annotate(&self, content: &str) -> String {
+        let mut markers = self.markers.clone();
+
+        // Markers at the same offset are listed in order of their reference number
+        markers.sort();
+
+        let mut annotated = String::with_capacity(content.len());
+        let mut markers = markers.into_iter().peekable();
+
+        for (i, c) in content.chars().enumerate() {
+            while let Some((_, number)) = markers.next_if(|(offset, _)| *offset <= i) {
+                annotated.push_str(&format!("[{}]", number));
+            }
+
+            annotated.push(c);
+        }
+
+        for (_, number) in markers {
+            annotated.push_str(&format!("[{}]", number));
+        }
+
+        annotated
+    }
+}
+
+/// Places footnote markers in a response as it is streamed. Sources are often
+/// reported after the text which cites them has been printed, in which case
+/// their markers are placed where the stream has reached.
+pub(crate) struct StreamAnnotator {
+    citations: Vec<Citation>,
+    /// The markers which have been placed
+    placed: BTreeSet<(usize, usize)>,
+    /// The number of characters streamed so far
+    offset: usize,
+}
+
+impl StreamAnnotator {
+    pub(crate) fn new() -> StreamAnnotator {
+        StreamAnnotator {
+            citations: Vec::new(),
+            placed: BTreeSet::new(),
+            offset: 0,
+        }
+    }
+
+    /// Annotates the next chunk of the response with the markers which fall
+    /// within or before it
+    pub(crate) fn push(&mut self, content: &str, citations: &[Citation]) -> String {
+        self.citations.extend(citations.iter().cloned());
+
+        let mut markers = References::new(&self.citations).markers;
+        markers.retain(|marker| !self.placed.contains(marker));
+        markers.sort();
+
+        let mut annotated = String::with_capacity(content.len());
+        let mut markers = markers.into_iter().peekable();
+
+        for c in content.chars() {
+            while let Some(marker) = markers.next_if(|(offset, _)| *offset <= self.offset) {
+                annotated.push_str(&format!("[{}]", marker.1));
+                self.placed.insert(marker);
+            }
+
+            annotated.push(c);
+            self.offset += 1;
+        }
+
+        while let Some(marker) = markers.next_if(|(offset, _)| *offset <= self.offset) {
+            annotated.push_str(&format!("[{}]", marker.1));
+            self.placed.insert(marker);
+        }
+
+        annotated
+    }
+
+    /// The markers which point past the end of the response
+    pub(crate) fn finish(&mut self) -> String {
+        let markers: Vec<(usize, usize)> = References::new(&self.citations)
+            .markers
+            .into_iter()
+            .filter(|marker| !self.placed.contains(marker))
+            .collect();
+
+        let mut annotated = String::new();
+
+        for marker in markers {
+            annotated.push_str(&format!("[{}]", marker.1));
+            self.placed.insert(marker);
+        }
+
+        annotated
+    }
+}
+
+impl fmt::Display for References {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "References:")?;
+
+        for (i, reference) in self.references.iter().enumerate() {
+            match &reference.title {
+                Some(title) => write!(f, "\n[{}] {} <{}>", i + 1, title, reference.url)?,
+                None => write!(f, "\n[{}] <{}>", i + 1, reference.url)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn citation(url: &str, end_index: usize) -> Citation {
+        Citation {
+            url: url.to_string(),
+            title: Some(url.trim_start_matches("https://").to_string()),
+            end_index: Some(end_index),
+        }
+    }
+
+    #[test]
+    fn test_references_are_deduplicated() {
+        let refs = References::new(&[
+            citation("https://a.example", 5),
+            citation("https://b.example", 11),
+            citation("https://a.example", 17),
+        ]);
+
+        assert_eq!(
+            refs.to_string(),
+            "References:\n[1] a.example <https://a.example>\n[2] b.example <https://b.example>"
+        );
+    }
+
+    #[test]
+    fn test_annotate() {
+        let refs = References::new(&[
+            citation("https://a.example", 5),
+            citation("https://b.example", 12),
+            citation("https://a.example", 18),
+        ]);
+
+        assert_eq!(
+            refs.annotate("First second thírd"),
+            "First[1] second[2] thírd[1]"
+        );
+    }
+
+    #[test]
+    fn test_stream_annotator() {
+        let mut annotator = StreamAnnotator::new();
+
+        // The first source arrives with the text which cites it
+        assert_eq!(
+            annotator.push("First sec", &[citation("https://a.example", 5)]),
+            "First[1] sec"
+        );
+
+        // The second arrives once its text has been printed
+        assert_eq!(
+            annotator.push(
+                "ond thírd",
+                &[
+                    citation("https://b.example", 7),
+                    citation("https://a.example", 18)
+                ]
+            ),
+            "[2]ond thírd[1]"
+        );
+
+        assert_eq!(annotator.finish(), "");
+    }
+}
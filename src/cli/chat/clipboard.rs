@@ -0,0 +1,121 @@
+//! Copying text to the system clipboard
+//!
+//! The clipboard is reached through the first of the usual command-line
+//! tools which is installed: `wl-copy` under Wayland, `xclip` or `xsel`
+//! under X11, and `pbcopy` on macOS. Over SSH, or where none of them is
+//! available, the text is instead sent to the terminal in an OSC 52 escape
+//! sequence, which terminals which support it place on the clipboard of the
+//! machine they run on. Within tmux, the sequence is passed through to the
+//! terminal outside of it.
+
+use std::env;
+use std::fmt;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+
+/// How the text reached the clipboard
+pub(crate) enum Method {
+    /// A clipboard tool, by name
+    Tool(&'static str),
+    /// An OSC 52 escape sequence written to the terminal
+    Terminal,
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Method::Tool(name) => write!(f, "{}", name),
+            Method::Terminal => write!(f, "the terminal"),
+        }
+    }
+}
+
+/// The clipboard tools, in order of preference, with the environment
+/// variable which must be set for each to be of use
+const TOOLS: [(&str, &[&str], Option<&str>); 4] = [
+    ("wl-copy", &[], Some("WAYLAND_DISPLAY")),
+    ("xclip", &["-selection", "clipboard"], Some("DISPLAY")),
+    ("xsel", &["--clipboard", "--input"], Some("DISPLAY")),
+    ("pbcopy", &[], None),
+];
+
+fn is_set(var: &str) -> bool {
+    env::var_os(var).is_some_and(|value| !value.is_empty())
+}
+
+/// Pipes the text to the tool, returning `false` if it is not installed
+fn copy_with(tool: &str, args: &[&str], text: &str) -> io::Result<bool> {
+    let child = Command::new(tool)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+
+    let mut child = match child {
+        Ok(child) => child,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes())?;
+    }
+
+    let status = child.wait()?;
+
+    if !status.success() {
+        return Err(io::Error::other(format!("{} exited with {}", tool, status)));
+    }
+
+    Ok(true)
+}
+
+/// The OSC 52 sequence which sets the clipboard to the text, wrapped so
+/// tmux passes it through if `tmux` is set
+fn osc52(text: &str, tmux: bool) -> String {
+    let sequence = format!("\x1b]52;c;{}\x07", BASE64_STANDARD.encode(text));
+
+    if tmux {
+        format!("\x1bPtmux;{}\x1b\\", sequence.replace('\x1b', "\x1b\x1b"))
+    } else {
+        sequence
+    }
+}
+
+/// Places the text on the clipboard
+pub(crate) fn copy(text: &str) -> io::Result<Method> {
+    let remote = is_set("SSH_TTY") || is_set("SSH_CONNECTION");
+
+    if !remote {
+        for (tool, args, requires) in TOOLS {
+            if requires.is_some_and(|var| !is_set(var)) {
+                continue;
+            }
+
+            if copy_with(tool, args, text)? {
+                return Ok(Method::Tool(tool));
+            }
+        }
+    }
+
+    let mut stdout = io::stdout();
+
+    stdout.write_all(osc52(text, is_set("TMUX")).as_bytes())?;
+    stdout.flush()?;
+
+    Ok(Method::Terminal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_osc52() {
+        assert_eq!(osc52("hi", false), "\x1b]52;c;aGk=\x07");
+        assert_eq!(osc52("hi", true), "\x1bPtmux;\x1b\x1b]52;c;aGk=\x07\x1b\\");
+    }
+}
@@ -0,0 +1,95 @@
+//! Batching of streamed responses
+//!
+//! Each chunk of a response is usually printed as it arrives, which over a
+//! slow connection, such as SSH to a distant host, sends many small writes
+//! for the terminal to redraw. When an interval is configured, chunks which
+//! arrive within the interval of the last write are held back and printed
+//! together once it has passed. A chunk which arrives after a quiet period is
+//! printed at once, so the response still begins without delay.
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// The interval in milliseconds used when coalescing is enabled without one
+pub(crate) const DEFAULT_COALESCE_MS: u64 = 100;
+
+pub(crate) struct Coalescer {
+    /// The least time between writes, or `None` to write each chunk
+    interval: Option<Duration>,
+    /// Text which has been held back
+    pending: String,
+    /// When the text which is held back may be written
+    next_write: Instant,
+}
+
+impl Coalescer {
+    pub(crate) fn new(interval: Option<Duration>) -> Coalescer {
+        Coalescer {
+            interval,
+            pending: String::new(),
+            next_write: Instant::now(),
+        }
+    }
+
+    /// Takes the next chunk, returning the text which should be printed now
+    pub(crate) fn push(&mut self, chunk: &str) -> Option<String> {
+        let Some(interval) = self.interval else {
+            return Some(chunk.to_string());
+        };
+
+        self.pending.push_str(chunk);
+
+        let now = Instant::now();
+
+        if now < self.next_write || self.pending.is_empty() {
+            return None;
+        }
+
+        self.next_write = now + interval;
+
+        Some(self.take())
+    }
+
+    /// Waits until the text which is held back should be printed. This never
+    /// completes if there is none; it is meant to be raced against the next
+    /// chunk.
+    pub(crate) async fn due(&self) {
+        if self.pending.is_empty() {
+            std::future::pending::<()>().await;
+        }
+
+        tokio::time::sleep_until(self.next_write).await;
+    }
+
+    /// Takes the text which is held back, to be printed
+    pub(crate) fn take(&mut self) -> String {
+        if let Some(interval) = self.interval {
+            self.next_write = Instant::now() + interval;
+        }
+
+        std::mem::take(&mut self.pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push() {
+        let mut coalescer = Coalescer::new(Some(Duration::from_secs(3600)));
+
+        // The first chunk is printed at once, and those which follow it
+        // within the interval are held back
+        assert_eq!(coalescer.push("The").as_deref(), Some("The"));
+        assert_eq!(coalescer.push(" quick"), None);
+        assert_eq!(coalescer.push(" fox"), None);
+        assert_eq!(coalescer.take(), " quick fox");
+
+        let mut coalescer = Coalescer::new(None);
+
+        assert_eq!(coalescer.push("The").as_deref(), Some("The"));
+        assert_eq!(coalescer.push(" quick").as_deref(), Some(" quick"));
+    }
+}
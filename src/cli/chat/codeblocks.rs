@@ -0,0 +1,238 @@
+//! Highlighting of code blocks as a response streams in
+//!
+//! Fenced code blocks are highlighted with the lexical highlighter of the
+//! HTML export, in the language named on the opening fence. Lines of code
+//! are withheld until they are complete, and the code of the block is
+//! highlighted again as each line is added, so comments which span lines
+//! are highlighted throughout. Text outside of code blocks is printed as it
+//! arrives, except for the start of a line which may yet turn out to be a
+//! fence.
+
+use nu_ansi_term::Style;
+
+use crate::color;
+use crate::utils::highlight::{self, Kind};
+use crate::utils::markdown::fence;
+
+/// A code block which has been opened but not closed
+struct OpenBlock {
+    /// The character and length of the opening fence
+    fence: (char, usize),
+    lang: String,
+    /// The complete lines of code received so far
+    code: String,
+}
+
+pub(crate) struct CodeHighlighter {
+    /// Text is passed through untouched if highlighting is disabled
+    enabled: bool,
+    /// The part of the current line which has been withheld
+    line: String,
+    /// Whether the start of the current line has been printed, in which
+    /// case it is not a fence
+    mid_line: bool,
+    block: Option<OpenBlock>,
+}
+
+fn style(kind: Kind) -> Option<Style> {
+    match kind {
+        Kind::Plain => None,
+        Kind::Keyword => Some(*color::CODE_KEYWORD),
+        Kind::String => Some(*color::CODE_STRING),
+        Kind::Comment => Some(*color::CODE_COMMENT),
+        Kind::Number => Some(*color::CODE_NUMBER),
+    }
+}
+
+/// Highlights the code from byte `start` onward. Styles are reset at the
+/// end of each line, so the lines can be folded independently.
+fn paint(code: &str, start: usize, lang: &str) -> String {
+    let mut painted = String::new();
+    let mut offset = 0;
+
+    for (kind, text) in highlight::tokens(code, lang) {
+        let end = offset + text.len();
+
+        if end > start {
+            let text = &text[start.saturating_sub(offset)..];
+
+            match style(kind) {
+                Some(style) => {
+                    for piece in text.split_inclusive('\n') {
+                        let (body, newline) = match piece.strip_suffix('\n') {
+                            Some(body) => (body, "\n"),
+                            None => (piece, ""),
+                        };
+
+                        if !body.is_empty() {
+                            painted.push_str(&style.paint(body).to_string());
+                        }
+
+                        painted.push_str(newline);
+                    }
+                }
+                None => painted.push_str(text),
+            }
+        }
+
+        offset = end;
+    }
+
+    painted
+}
+
+/// Whether the start of a line could still become a fence
+fn may_be_fence(start: &str) -> bool {
+    let start = start.trim_start_matches(' ');
+
+    match start.chars().next() {
+        Some(c @ ('`' | '~')) => {
+            let run = start.chars().take_while(|&x| x == c).count();
+
+            run >= 3 || run == start.len()
+        }
+        Some(_) => false,
+        None => true,
+    }
+}
+
+impl CodeHighlighter {
+    pub(crate) fn new(enabled: bool) -> CodeHighlighter {
+        CodeHighlighter {
+            enabled,
+            line: String::new(),
+            mid_line: false,
+            block: None,
+        }
+    }
+
+    /// Takes the next chunk of the response, returning the text which can
+    /// be printed, with the code which has been completed highlighted
+    pub(crate) fn push(&mut self, chunk: &str) -> String {
+        if !self.enabled {
+            return chunk.to_string();
+        }
+
+        let mut printable = String::new();
+
+        for piece in chunk.split_inclusive('\n') {
+            if self.block.is_none() && self.mid_line {
+                printable.push_str(piece);
+                self.mid_line = !piece.ends_with('\n');
+                continue;
+            }
+
+            self.line.push_str(piece);
+
+            if piece.ends_with('\n') {
+                let line = std::mem::take(&mut self.line);
+
+                printable.push_str(&self.complete_line(line));
+            } else if self.block.is_none() && !may_be_fence(&self.line) {
+                printable.push_str(&std::mem::take(&mut self.line));
+                self.mid_line = true;
+            }
+        }
+
+        printable
+    }
+
+    /// Opens or closes a block at a fence, and highlights a line of code
+    fn complete_line(&mut self, line: String) -> String {
+        let content = line.trim_end_matches(['\n', '\r']);
+
+        match (&mut self.block, fence(content)) {
+            (None, Some(opened)) => {
+                let lang = content.trim_start_matches(' ')[opened.1..].trim();
+
+                self.block = Some(OpenBlock {
+                    fence: opened,
+                    lang: lang.to_string(),
+                    code: String::new(),
+                });
+
+                line
+            }
+            (Some(block), Some((c, len)))
+                if c == block.fence.0
+                    && len >= block.fence.1
+                    && content.trim().chars().all(|x| x == c) =>
+            {
+                self.block = None;
+
+                line
+            }
+            (Some(block), _) => {
+                let start = block.code.len();
+
+                block.code.push_str(&line);
+
+                paint(&block.code, start, &block.lang)
+            }
+            (None, None) => line,
+        }
+    }
+
+    /// Returns the text which has been withheld, once the response is
+    /// complete
+    pub(crate) fn finish(mut self) -> String {
+        let line = std::mem::take(&mut self.line);
+
+        match &mut self.block {
+            Some(block) if !line.is_empty() => {
+                let start = block.code.len();
+
+                block.code.push_str(&line);
+
+                paint(&block.code, start, &block.lang)
+            }
+            _ => line,
+        }
+    }
+}
+
+/// Highlights the code blocks of a complete response
+pub(crate) fn highlight(text: &str, enabled: bool) -> String {
+    let mut highlighter = CodeHighlighter::new(enabled);
+
+    let mut highlighted = highlighter.push(text);
+
+    highlighted.push_str(&highlighter.finish());
+
+    highlighted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_highlight() {
+        let response = "Use fn:\n```rust\nfn main() {} // entry\n```\nfn is a keyword.";
+
+        let keyword = color::CODE_KEYWORD.paint("fn").to_string();
+        let comment = color::CODE_COMMENT.paint("// entry").to_string();
+
+        assert_eq!(
+            highlight(response, true),
+            format!(
+                "Use fn:\n```rust\n{} main() {{}} {}\n```\nfn is a keyword.",
+                keyword, comment
+            )
+        );
+
+        assert_eq!(highlight(response, false), response);
+
+        // Fences and lines of code are found across chunks
+        let mut highlighter = CodeHighlighter::new(true);
+
+        assert_eq!(highlighter.push("Use fn:\n``"), "Use fn:\n");
+        assert_eq!(highlighter.push("`rust\nfn ma"), "```rust\n");
+        assert_eq!(
+            highlighter.push("in() {}\n``` "),
+            format!("{} main() {{}}\n", keyword)
+        );
+        assert_eq!(highlighter.push("\nfn"), "``` \nfn");
+        assert_eq!(highlighter.finish(), "");
+    }
+}
@@ -0,0 +1,173 @@
+//! An append-only log of the events of the chat loop
+//!
+//! `xtalk chat --event-log PATH` appends one JSON object per line to `PATH`
+//! as the chat progresses, so other programs can follow a chat by tailing
+//! the file. Each event has a `time`, an `event` type, and the `session` if
+//! the chat is being saved. For example:
+//!
+//! ```text
+//! {"time":"…","session":"3f2a9c01b7de","event":"turn_started","turn":1,"model":"ollama/llama3.2","messages":1}
+//! {"time":"…","session":"3f2a9c01b7de","event":"delta","turn":1,"content":"Hi"}
+//! {"time":"…","session":"3f2a9c01b7de","event":"turn_finished","turn":1,"finish_reason":"stop","usage":{…},"elapsed_ms":412}
+//! ```
+//!
+//! Tool calls, such as the capture of a tmux pane, are logged as `tool_call`
+//! events, and requests which fail as `error` events. Writing to the log
+//! never interrupts the chat; if a write fails, a warning is printed and
+//! the log is closed.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::Instant;
+
+use chrono::{DateTime, Local};
+use serde::Serialize;
+
+use super::protocol::{StopReason, TokenUsage};
+use crate::warn;
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    /// A request for a response was sent
+    TurnStarted {
+        turn: usize,
+        model: &'a str,
+        /// The number of messages sent with the request
+        messages: usize,
+    },
+    /// A chunk of the response
+    Delta { turn: usize, content: &'a str },
+    /// Output of a tool was added to the conversation
+    ToolCall {
+        tool: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        target: Option<&'a str>,
+        /// The number of characters added to the conversation
+        output_chars: usize,
+    },
+    /// A request failed
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        turn: Option<usize>,
+        message: &'a str,
+    },
+    /// The response is complete, or was cut short
+    TurnFinished {
+        turn: usize,
+        finish_reason: StopReason,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        usage: Option<TokenUsage>,
+        elapsed_ms: u64,
+    },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    time: DateTime<Local>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session: Option<&'a str>,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+pub(crate) struct EventLog {
+    file: Option<File>,
+    session: Option<String>,
+}
+
+impl EventLog {
+    /// Opens the log for appending, creating it if it does not exist. The
+    /// log holds the conversation, so only its owner may read it.
+    pub(crate) fn open(path: &Path) -> io::Result<EventLog> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)?;
+
+        Ok(EventLog {
+            file: Some(file),
+            session: None,
+        })
+    }
+
+    /// A log which discards every event
+    pub(crate) fn disabled() -> EventLog {
+        EventLog {
+            file: None,
+            session: None,
+        }
+    }
+
+    /// Sets the session which is recorded with each event
+    pub(crate) fn set_session(&mut self, id: &str) {
+        self.session = Some(id.to_string());
+    }
+
+    pub(super) fn turn_started(&mut self, turn: usize, model: &str, messages: usize) {
+        self.log(&Event::TurnStarted {
+            turn,
+            model,
+            messages,
+        });
+    }
+
+    pub(super) fn delta(&mut self, turn: usize, content: &str) {
+        self.log(&Event::Delta { turn, content });
+    }
+
+    pub(super) fn tool_call(&mut self, tool: &str, target: Option<&str>, output_chars: usize) {
+        self.log(&Event::ToolCall {
+            tool,
+            target,
+            output_chars,
+        });
+    }
+
+    pub(super) fn error(&mut self, turn: Option<usize>, message: &str) {
+        self.log(&Event::Error { turn, message });
+    }
+
+    /// Logs the end of a turn which began at `started`
+    pub(super) fn turn_finished(
+        &mut self,
+        turn: usize,
+        finish_reason: StopReason,
+        usage: Option<TokenUsage>,
+        started: Instant,
+    ) {
+        self.log(&Event::TurnFinished {
+            turn,
+            finish_reason,
+            usage,
+            elapsed_ms: started.elapsed().as_millis().try_into().unwrap_or(u64::MAX),
+        });
+    }
+
+    fn log(&mut self, event: &Event) {
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => return,
+        };
+
+        let record = Record {
+            time: Local::now(),
+            session: self.session.as_deref(),
+            event,
+        };
+
+        let mut line = serde_json::to_vec(&record).expect("failed to serialize an event");
+
+        line.push(b'\n');
+
+        // Each event is written at once, so readers never see a partial line
+        if let Err(err) = file.write_all(&line) {
+            warn!("failed to write to the event log, closing it: {}", err);
+
+            self.file = None;
+        }
+    }
+}
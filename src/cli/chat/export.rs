@@ -0,0 +1,276 @@
+//! Export of the chat transcript to a file
+
+mod html;
+
+use std::io;
+use std::path::Path;
+
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::chat::Role;
+use crate::sessions::SessionMessage;
+use crate::version;
+
+/// Formats in which a transcript can be exported
+#[derive(Clone, Copy, ValueEnum, strum_macros::Display)]
+#[strum(serialize_all = "lowercase")]
+pub(crate) enum ExportFormat {
+    /// A markdown document with a heading for each message
+    Markdown,
+    /// A JSON document containing each message, its role, and its model
+    Json,
+    /// A standalone web page with highlighted code
+    Html,
+}
+
+impl ExportFormat {
+    /// Infers the format from the extension of the path, defaulting to markdown
+    pub(crate) fn from_path(path: &Path) -> ExportFormat {
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => ExportFormat::Json,
+            Some(ext) if ext.eq_ignore_ascii_case("html") || ext.eq_ignore_ascii_case("htm") => {
+                ExportFormat::Html
+            }
+            _ => ExportFormat::Markdown,
+        }
+    }
+}
+
+/// The settings responses are generated with
+#[derive(Clone, Default, Serialize)]
+pub(crate) struct Parameters {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub persona: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<u32>,
+}
+
+/// Describes how a transcript was produced, so an exported transcript can be
+/// understood without the chat it came from
+#[derive(Serialize)]
+pub(crate) struct Metadata {
+    /// The version of crosstalk which exported the transcript
+    pub crosstalk: &'static str,
+    pub started: DateTime<Local>,
+    pub exported: DateTime<Local>,
+    /// The models which responded, in the order they first responded
+    pub models: Vec<String>,
+    #[serde(flatten)]
+    pub parameters: Parameters,
+    /// The tokens used by the responses generated in this chat, which are
+    /// only counted when the provider reports them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub completion_tokens: Option<usize>,
+}
+
+impl Metadata {
+    pub(crate) fn new(
+        messages: &[SessionMessage],
+        started: DateTime<Local>,
+        parameters: Parameters,
+        tokens: Option<(usize, usize)>,
+    ) -> Metadata {
+        let mut models: Vec<String> = Vec::new();
+
+        for model_id in messages.iter().filter_map(|msg| msg.model_id.as_ref()) {
+            if !models.contains(model_id) {
+                models.push(model_id.clone());
+            }
+        }
+
+        Metadata {
+            crosstalk: version::VERSION,
+            started,
+            exported: Local::now(),
+            models,
+            parameters,
+            prompt_tokens: tokens.map(|(prompt, _)| prompt),
+            completion_tokens: tokens.map(|(_, completion)| completion),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Transcript<'m> {
+    metadata: &'m Metadata,
+    messages: &'m [SessionMessage],
+}
+
+fn json<T: Serialize + ?Sized>(value: &T) -> String {
+    serde_json::to_string(value).expect("failed to serialize the metadata")
+}
+
+/// Writes the metadata as YAML front matter. The values are written as JSON,
+/// which YAML accepts, so strings are quoted and escaped.
+fn front_matter(metadata: &Metadata) -> String {
+    let Parameters {
+        persona,
+        temperature,
+        seed,
+        max_output_tokens,
+    } = &metadata.parameters;
+
+    let mut fields = vec![
+        ("crosstalk", json(&metadata.crosstalk)),
+        ("started", json(&metadata.started)),
+        ("exported", json(&metadata.exported)),
+        ("models", json(&metadata.models)),
+    ];
+
+    if let Some(persona) = persona {
+        fields.push(("persona", json(persona)));
+    }
+
+    if let Some(temperature) = temperature {
+        fields.push(("temperature", json(temperature)));
+    }
+
+    if let Some(seed) = seed {
+        fields.push(("seed", json(seed)));
+    }
+
+    if let Some(max_output_tokens) = max_output_tokens {
+        fields.push(("max_output_tokens", json(max_output_tokens)));
+    }
+
+    if let (Some(prompt), Some(completion)) = (metadata.prompt_tokens, metadata.completion_tokens) {
+        fields.push(("prompt_tokens", json(&prompt)));
+        fields.push(("completion_tokens", json(&completion)));
+    }
+
+    let mut doc = String::from("---\n");
+
+    for (key, value) in fields {
+        doc.push_str(&format!("{}: {}\n", key, value));
+    }
+
+    doc.push_str("---\n");
+
+    doc
+}
+
+fn markdown(messages: &[SessionMessage], metadata: &Metadata) -> String {
+    let mut doc = front_matter(metadata);
+
+    for msg in messages {
+        let heading = match (&msg.message.role, &msg.model_id) {
+            (Role::System, _) => "System".to_string(),
+            (Role::User, _) => "User".to_string(),
+            (Role::Model, Some(model_id)) => format!("Model ({})", model_id),
+            (Role::Model, None) => "Model".to_string(),
+        };
+
+        doc.push('\n');
+
+        doc.push_str(&format!("## {}\n\n", heading));
+
+        if !msg.message.attachments.is_empty() {
+            doc.push_str(&format!(
+                "*Attached: {}*\n\n",
+                msg.message.attachments.join(", ")
+            ));
+        }
+
+        doc.push_str(&format!("{}\n", msg.message.content.trim_end()));
+    }
+
+    doc
+}
+
+/// Renders the messages in the specified format
+pub(crate) fn render(
+    messages: &[SessionMessage],
+    metadata: &Metadata,
+    format: ExportFormat,
+) -> String {
+    match format {
+        ExportFormat::Markdown => markdown(messages, metadata),
+        ExportFormat::Html => html::html(messages, metadata),
+        ExportFormat::Json => {
+            let mut json = serde_json::to_string_pretty(&Transcript { metadata, messages })
+                .expect("failed to serialize the transcript");
+
+            json.push('\n');
+
+            json
+        }
+    }
+}
+
+/// Writes the messages to a file in the specified format
+pub(crate) fn export(
+    messages: &[SessionMessage],
+    metadata: &Metadata,
+    path: &Path,
+    format: ExportFormat,
+) -> io::Result<()> {
+    std::fs::write(path, render(messages, metadata, format))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::Message;
+
+    #[test]
+    fn test_markdown() {
+        let messages = [
+            SessionMessage {
+                message: Message {
+                    attachments: vec!["notes.md".to_string()],
+                    ..Message::new(Role::User, "Hello!".to_string())
+                },
+                model_id: None,
+                response: Default::default(),
+            },
+            SessionMessage {
+                message: Message::new(Role::Model, "Hi!\n".to_string()),
+                model_id: Some("gemma:2b".to_string()),
+                response: Default::default(),
+            },
+        ];
+
+        let started = DateTime::parse_from_rfc3339("2024-06-01T12:00:00+00:00")
+            .unwrap()
+            .with_timezone(&Local);
+
+        let mut metadata = Metadata::new(
+            &messages,
+            started,
+            Parameters {
+                seed: Some(7),
+                ..Default::default()
+            },
+            Some((12, 3)),
+        );
+
+        metadata.exported = started;
+
+        let started = json(&started);
+
+        assert_eq!(
+            markdown(&messages, &metadata),
+            format!(
+                "---\n\
+                crosstalk: \"{}\"\n\
+                started: {started}\n\
+                exported: {started}\n\
+                models: [\"gemma:2b\"]\n\
+                seed: 7\n\
+                prompt_tokens: 12\n\
+                completion_tokens: 3\n\
+                ---\n\
+                \n## User\n\n*Attached: notes.md*\n\nHello!\n\n## Model (gemma:2b)\n\nHi!\n",
+                version::VERSION
+            )
+        );
+    }
+}
@@ -0,0 +1,275 @@
+//! Export of the transcript as a standalone HTML page
+//!
+//! The page has no external resources: the style is inlined and attached
+//! images are embedded as data URLs, so it can be shared as a single file.
+//! Code blocks are highlighted and reasoning which a model wrapped in
+//! `<think>` tags is collapsed.
+
+use std::fmt::Write;
+
+use super::Metadata;
+use crate::chat::Role;
+use crate::sessions::SessionMessage;
+use crate::utils::highlight::{self, Kind};
+use crate::utils::markdown::{self, Block};
+use crate::version;
+
+const STYLE: &str = r#"
+:root { color-scheme: light dark; --fg: #1f2328; --bg: #ffffff; --muted: #59636e; --border: #d1d9e0; --user: #ddf4ff; --code: #f6f8fa; --kw: #cf222e; --str: #0a3069; --com: #59636e; --num: #0550ae; }
+@media (prefers-color-scheme: dark) { :root { --fg: #e6edf3; --bg: #0d1117; --muted: #9198a1; --border: #3d444d; --user: #121d2f; --code: #151b23; --kw: #ff7b72; --str: #a5d6ff; --com: #9198a1; --num: #79c0ff; } }
+body { margin: 0; background: var(--bg); color: var(--fg); font: 16px/1.5 system-ui, sans-serif; }
+main { max-width: 52rem; margin: 0 auto; padding: 2rem 1rem; }
+h1 { font-size: 1.5rem; margin: 0 0 0.5rem; }
+.metadata { display: grid; grid-template-columns: max-content 1fr; gap: 0.1rem 1rem; margin: 0 0 2rem; color: var(--muted); font-size: 0.875rem; }
+.metadata dt { font-weight: 600; }
+.metadata dd { margin: 0; }
+.message { border: 1px solid var(--border); border-radius: 0.5rem; padding: 0.75rem 1rem; margin: 0 0 1rem; }
+.message.user { background: var(--user); }
+.message.system { border-style: dashed; }
+.role { font-size: 0.875rem; font-weight: 600; color: var(--muted); margin: 0 0 0.25rem; }
+.message p { margin: 0.5rem 0; white-space: pre-wrap; overflow-wrap: anywhere; }
+code { font: 0.875em ui-monospace, monospace; background: var(--code); border-radius: 0.25rem; padding: 0.1em 0.3em; }
+pre { background: var(--code); border: 1px solid var(--border); border-radius: 0.375rem; padding: 0.75rem; overflow-x: auto; }
+pre code { padding: 0; background: none; }
+.kw { color: var(--kw); }
+.str { color: var(--str); }
+.com { color: var(--com); font-style: italic; }
+.num { color: var(--num); }
+details.reasoning { color: var(--muted); border-left: 3px solid var(--border); padding-left: 0.75rem; margin: 0.5rem 0; }
+details.reasoning summary { cursor: pointer; font-size: 0.875rem; }
+.message img { max-width: 100%; border-radius: 0.375rem; }
+.attachments { font-size: 0.875rem; color: var(--muted); margin: 0 0 0.25rem; }
+"#;
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Writes a paragraph, marking up `inline code`
+fn paragraph(out: &mut String, text: &str) {
+    out.push_str("<p>");
+
+    let parts: Vec<&str> = text.split('`').collect();
+
+    for (i, part) in parts.iter().enumerate() {
+        if i % 2 == 0 {
+            out.push_str(&escape(part));
+        } else if i + 1 < parts.len() {
+            let _ = write!(out, "<code>{}</code>", escape(part));
+        } else {
+            // The last backtick is unpaired
+            out.push('`');
+            out.push_str(&escape(part));
+        }
+    }
+
+    out.push_str("</p>\n");
+}
+
+fn code_block(out: &mut String, lang: &str, code: &str) {
+    let lang = lang.split_whitespace().next().unwrap_or("");
+
+    if lang.is_empty() {
+        out.push_str("<pre><code>");
+    } else {
+        let _ = write!(out, "<pre><code class=\"language-{}\">", escape(lang));
+    }
+
+    for (kind, text) in highlight::tokens(code.trim_end_matches('\n'), lang) {
+        let class = match kind {
+            Kind::Plain => {
+                out.push_str(&escape(text));
+                continue;
+            }
+            Kind::Keyword => "kw",
+            Kind::String => "str",
+            Kind::Comment => "com",
+            Kind::Number => "num",
+        };
+
+        let _ = write!(out, "<span class=\"{}\">{}</span>", class, escape(text));
+    }
+
+    out.push_str("</code></pre>\n");
+}
+
+/// Writes markdown text as paragraphs and code blocks
+fn markdown(out: &mut String, text: &str) {
+    for block in markdown::blocks(text) {
+        match block {
+            Block::Text(text) => {
+                for para in text.split("\n\n") {
+                    let para = para.trim_start_matches('\n').trim_end();
+
+                    if !para.is_empty() {
+                        paragraph(out, para);
+                    }
+                }
+            }
+            Block::Code { lang, code } => code_block(out, lang, code),
+        }
+    }
+}
+
+/// Writes the content of a message, collapsing `<think>` sections
+fn content(out: &mut String, mut text: &str) {
+    while let Some(start) = text.find("<think>") {
+        markdown(out, &text[..start]);
+
+        let reasoning = &text[start + "<think>".len()..];
+
+        let (reasoning, rest) = match reasoning.find("</think>") {
+            Some(end) => (&reasoning[..end], &reasoning[end + "</think>".len()..]),
+            None => (reasoning, ""),
+        };
+
+        out.push_str("<details class=\"reasoning\"><summary>Reasoning</summary>\n");
+        markdown(out, reasoning);
+        out.push_str("</details>\n");
+
+        text = rest;
+    }
+
+    markdown(out, text);
+}
+
+fn metadata(out: &mut String, metadata: &Metadata) {
+    let mut field = |name: &str, value: String| {
+        let _ = writeln!(out, "<dt>{}</dt><dd>{}</dd>", name, escape(&value));
+    };
+
+    let format = "%Y-%m-%d %H:%M:%S %:z";
+
+    field("Started", metadata.started.format(format).to_string());
+    field("Exported", metadata.exported.format(format).to_string());
+
+    if !metadata.models.is_empty() {
+        field("Models", metadata.models.join(", "));
+    }
+
+    let parameters = &metadata.parameters;
+
+    if let Some(persona) = &parameters.persona {
+        field("Persona", persona.clone());
+    }
+
+    if let Some(temperature) = parameters.temperature {
+        field("Temperature", temperature.to_string());
+    }
+
+    if let Some(seed) = parameters.seed {
+        field("Seed", seed.to_string());
+    }
+
+    if let Some(max_output_tokens) = parameters.max_output_tokens {
+        field("Max output tokens", max_output_tokens.to_string());
+    }
+
+    if let (Some(prompt), Some(completion)) = (metadata.prompt_tokens, metadata.completion_tokens) {
+        field(
+            "Tokens",
+            format!("{} prompt, {} completion", prompt, completion),
+        );
+    }
+
+    field("Exported by", format!("crosstalk {}", metadata.crosstalk));
+}
+
+/// Renders the messages as a standalone HTML page
+pub(super) fn html(messages: &[SessionMessage], meta: &Metadata) -> String {
+    let mut out = String::new();
+
+    out.push_str("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n");
+    out.push_str("<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n");
+    let _ = writeln!(
+        out,
+        "<meta name=\"generator\" content=\"crosstalk {}\">",
+        version::VERSION
+    );
+    out.push_str("<title>Transcript</title>\n");
+    let _ = writeln!(out, "<style>{}</style>", STYLE);
+    out.push_str(
+        "</head>\n<body>\n<main>\n<header>\n<h1>Transcript</h1>\n<dl class=\"metadata\">\n",
+    );
+
+    metadata(&mut out, meta);
+
+    out.push_str("</dl>\n</header>\n");
+
+    for msg in messages {
+        let (class, role) = match (&msg.message.role, &msg.model_id) {
+            (Role::System, _) => ("system", "System".to_string()),
+            (Role::User, _) => ("user", "User".to_string()),
+            (Role::Model, Some(model_id)) => ("model", format!("Model ({})", model_id)),
+            (Role::Model, None) => ("model", "Model".to_string()),
+        };
+
+        let _ = writeln!(
+            out,
+            "<section class=\"message {}\">\n<div class=\"role\">{}</div>",
+            class,
+            escape(&role)
+        );
+
+        if !msg.message.attachments.is_empty() {
+            let _ = writeln!(
+                out,
+                "<div class=\"attachments\">Attached: {}</div>",
+                escape(&msg.message.attachments.join(", "))
+            );
+        }
+
+        content(&mut out, &msg.message.content);
+
+        for image in &msg.message.images {
+            let _ = writeln!(
+                out,
+                "<img src=\"{}\" alt=\"attached image\">",
+                escape(&image.data_url())
+            );
+        }
+
+        out.push_str("</section>\n");
+    }
+
+    out.push_str("</main>\n</body>\n</html>\n");
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content() {
+        let mut out = String::new();
+
+        content(
+            &mut out,
+            "<think>Plan.</think>Use `ls`:\n\n```sh\nls # list\n```\n",
+        );
+
+        assert_eq!(
+            out,
+            "<details class=\"reasoning\"><summary>Reasoning</summary>\n\
+            <p>Plan.</p>\n\
+            </details>\n\
+            <p>Use <code>ls</code>:</p>\n\
+            <pre><code class=\"language-sh\">ls <span class=\"com\"># list</span></code></pre>\n"
+        );
+    }
+}
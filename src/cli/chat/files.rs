@@ -0,0 +1,130 @@
+//! Files generated by the model
+//!
+//! Files which accompany a response, such as images, are saved in the files
+//! directory once the response is complete. Each is named after the time it
+//! was saved and given an extension matching its media type. Images are
+//! previewed inline below the response in terminals which support it.
+
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::Local;
+
+use crate::config;
+use crate::providers::GeneratedFile;
+use crate::utils::dirs::{data_dir, expand_home};
+use crate::warn;
+
+use super::graphics::Protocol;
+
+/// The directory generated files are saved in
+pub(crate) fn files_dir(config: &config::Files) -> Option<PathBuf> {
+    match &config.dir {
+        Some(dir) => Some(expand_home(dir)),
+        None => Some(data_dir()?.join("xtalk/files")),
+    }
+}
+
+fn extension(media_type: &str) -> &str {
+    match media_type {
+        "image/png" => "png",
+        "image/jpeg" => "jpg",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/svg+xml" => "svg",
+        "application/pdf" => "pdf",
+        "text/plain" => "txt",
+        "text/csv" => "csv",
+        "application/json" => "json",
+        _ => "bin",
+    }
+}
+
+/// Saves a file in the directory without replacing an existing file
+fn save(dir: &Path, file: &GeneratedFile) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+
+    let stem = Local::now().format("%Y%m%d-%H%M%S").to_string();
+    let extension = extension(&file.media_type);
+
+    for n in 1.. {
+        let path = dir.join(format!("{}-{}.{}", stem, n, extension));
+
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut out) => {
+                out.write_all(&file.data)?;
+
+                return Ok(path);
+            }
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+
+    unreachable!()
+}
+
+pub(crate) struct SavedFile {
+    media_type: String,
+    path: PathBuf,
+    /// The escape sequences which display the file, if it is an image and
+    /// the terminal can display it
+    preview: Option<String>,
+}
+
+impl SavedFile {
+    /// Where the file was saved, as it is recorded in the conversation
+    pub(crate) fn describe(&self) -> String {
+        format!("saved {} to {}", self.media_type, self.path.display())
+    }
+}
+
+impl fmt::Display for SavedFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(preview) = &self.preview {
+            writeln!(f, "{}", preview)?;
+        }
+
+        write!(f, "{}", self.describe())
+    }
+}
+
+/// Saves the files which accompany a response. Files which cannot be saved
+/// are skipped with a warning.
+pub(crate) fn save_files(config: &config::Files, files: &[GeneratedFile]) -> Vec<SavedFile> {
+    if files.is_empty() {
+        return Vec::new();
+    }
+
+    let dir = match files_dir(config) {
+        Some(dir) => dir,
+        None => {
+            warn!("could not determine the files directory, neither XDG_DATA_HOME nor HOME is set");
+            return Vec::new();
+        }
+    };
+
+    let protocol = Protocol::for_previews(config);
+
+    files
+        .iter()
+        .filter_map(|file| match save(&dir, file) {
+            Ok(path) => Some(SavedFile {
+                media_type: file.media_type.clone(),
+                path,
+                preview: protocol.and_then(|p| p.preview(&file.media_type, &file.data)),
+            }),
+            Err(err) => {
+                warn!(
+                    "failed to save {} in {}: {}",
+                    file.media_type,
+                    dir.display(),
+                    err
+                );
+                None
+            }
+        })
+        .collect()
+}
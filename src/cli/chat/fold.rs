@@ -0,0 +1,147 @@
+//! Folding of long responses
+//!
+//! Responses which run longer than the configured number of lines are
+//! printed with their middle collapsed into a marker, so earlier turns of the
+//! conversation stay within reach of the scrollback. The head of a response
+//! is printed as it streams in; the remainder is withheld until the response
+//! is complete, at which point either all of it or only its last lines are
+//! printed. The full response can be read again with `/expand`.
+
+use crate::color::{self, MaybePaint};
+
+/// The number of lines after which responses are folded unless configured
+/// otherwise
+pub(crate) const DEFAULT_FOLD_LINES: usize = 100;
+
+pub(crate) struct Folder {
+    /// The number of lines printed before the fold
+    head_lines: usize,
+    /// The number of lines printed after the fold
+    tail_lines: usize,
+    /// The number of complete lines printed so far
+    printed: usize,
+    /// Text which has been withheld
+    withheld: String,
+}
+
+impl Folder {
+    /// Folds responses longer than `max_lines`. Zero disables folding.
+    pub(crate) fn new(max_lines: usize) -> Folder {
+        let tail_lines = max_lines / 4;
+
+        Folder {
+            head_lines: max_lines - tail_lines,
+            tail_lines,
+            printed: 0,
+            withheld: String::new(),
+        }
+    }
+
+    fn disabled(&self) -> bool {
+        self.head_lines == 0
+    }
+
+    /// Takes the next chunk of the response, returning the part of it which
+    /// should be printed immediately
+    pub(crate) fn push<'a>(&mut self, chunk: &'a str) -> &'a str {
+        if self.disabled() {
+            return chunk;
+        }
+
+        if self.printed == self.head_lines {
+            self.withheld.push_str(chunk);
+            return "";
+        }
+
+        for (i, _) in chunk.match_indices('\n') {
+            self.printed += 1;
+
+            if self.printed == self.head_lines {
+                let (shown, withheld) = chunk.split_at(i + 1);
+
+                self.withheld.push_str(withheld);
+
+                return shown;
+            }
+        }
+
+        chunk
+    }
+
+    /// Returns the remainder of the response, which is folded if it is too
+    /// long to be printed in full
+    pub(crate) fn finish(self) -> String {
+        // A trailing newline does not begin another line
+        let (body, newline) = match self.withheld.strip_suffix('\n') {
+            Some(body) => (body, "\n"),
+            None => (self.withheld.as_str(), ""),
+        };
+
+        let lines: Vec<&str> = body.split('\n').collect();
+
+        if body.is_empty() || lines.len() <= self.tail_lines {
+            return self.withheld.clone();
+        }
+
+        let hidden = lines.len() - self.tail_lines;
+
+        let marker = color::FOLD_MARKER.maybe_paint(format!(
+            "… {} line{} hidden (use /expand)",
+            hidden,
+            if hidden == 1 { "" } else { "s" }
+        ));
+
+        let mut folded = marker.to_string();
+
+        for line in &lines[hidden..] {
+            folded.push('\n');
+            folded.push_str(line);
+        }
+
+        folded.push_str(newline);
+
+        folded
+    }
+}
+
+/// Folds a complete response
+pub(crate) fn fold(text: &str, max_lines: usize) -> String {
+    let mut folder = Folder::new(max_lines);
+
+    let mut folded = folder.push(text).to_string();
+
+    folded.push_str(&folder.finish());
+
+    folded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::ColorMode;
+
+    #[test]
+    fn test_fold() {
+        color::configure_color(ColorMode::Off);
+
+        let text = (1..=10).map(|i| i.to_string()).collect::<Vec<_>>();
+
+        // Four lines are shown before the fold and one after it
+        assert_eq!(
+            fold(&text.join("\n"), 5),
+            "1\n2\n3\n4\n… 5 lines hidden (use /expand)\n10"
+        );
+
+        assert_eq!(fold(&text[..5].join("\n"), 5), "1\n2\n3\n4\n5");
+
+        assert_eq!(fold(&text.join("\n"), 0), text.join("\n"));
+
+        // The fold is found across chunks
+        let mut folder = Folder::new(5);
+
+        assert_eq!(folder.push("1\n2\n3"), "1\n2\n3");
+        assert_eq!(folder.push("\n4\n5\n"), "\n4\n");
+        assert_eq!(folder.push("6\n7"), "");
+        assert_eq!(folder.finish(), "… 2 lines hidden (use /expand)\n7");
+    }
+}
@@ -0,0 +1,152 @@
+//! Inline images in terminals which support a graphics protocol
+//!
+//! Images are previewed with the kitty graphics protocol, which is also
+//! spoken by Ghostty, or with iTerm2's inline images protocol, which is also
+//! spoken by WezTerm. The terminal is recognized from its environment rather
+//! than queried, so nothing is printed to terminals which would show the
+//! escape sequences as text. Terminal multiplexers are skipped since they do
+//! not pass the sequences through by default. Sixel is not supported, since
+//! the image would need to be decoded into pixels first.
+
+use std::io::{self, IsTerminal};
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+
+use crate::{chat, config};
+
+/// The width of a preview in columns. The height follows from the aspect
+/// ratio of the image.
+const PREVIEW_COLUMNS: usize = 32;
+
+/// The largest payload of an escape sequence of the kitty graphics protocol
+const KITTY_CHUNK_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Protocol {
+    Kitty,
+    Iterm2,
+}
+
+impl Protocol {
+    /// The protocol used to preview images, unless previews are disabled in
+    /// the configuration
+    pub(crate) fn for_previews(config: &config::Files) -> Option<Protocol> {
+        if config.preview.unwrap_or(true) {
+            Protocol::detect()
+        } else {
+            None
+        }
+    }
+
+    /// The protocol spoken by the terminal on standard output, if it is
+    /// known to speak one
+    pub(crate) fn detect() -> Option<Protocol> {
+        if !io::stdout().is_terminal() {
+            return None;
+        }
+
+        let var = |name| std::env::var(name).ok();
+
+        if var("TMUX").is_some() || var("STY").is_some() {
+            return None;
+        }
+
+        let term = var("TERM").unwrap_or_default();
+        let term_program = var("TERM_PROGRAM").unwrap_or_default();
+
+        if term == "xterm-kitty" || var("KITTY_WINDOW_ID").is_some() || term_program == "ghostty" {
+            Some(Protocol::Kitty)
+        } else if term_program == "iTerm.app"
+            || term_program == "WezTerm"
+            || var("LC_TERMINAL").as_deref() == Some("iTerm2")
+        {
+            Some(Protocol::Iterm2)
+        } else {
+            None
+        }
+    }
+
+    /// The escape sequences which display the image, or `None` if the
+    /// protocol cannot display images of its type. The kitty graphics
+    /// protocol only accepts PNG images without decoding them first.
+    pub(crate) fn preview(self, media_type: &str, data: &[u8]) -> Option<String> {
+        self.preview_encoded(media_type, &BASE64_STANDARD.encode(data))
+    }
+
+    /// Like [`Protocol::preview`], for an image which is already encoded
+    pub(crate) fn preview_image(self, image: &chat::Image) -> Option<String> {
+        self.preview_encoded(&image.media_type, &image.data)
+    }
+
+    fn preview_encoded(self, media_type: &str, encoded: &str) -> Option<String> {
+        match self {
+            Protocol::Kitty if media_type == "image/png" => Some(kitty(encoded)),
+            Protocol::Kitty => None,
+            Protocol::Iterm2 if media_type.starts_with("image/") => Some(iterm2(encoded)),
+            Protocol::Iterm2 => None,
+        }
+    }
+}
+
+/// Transmits and displays a PNG image. The payload is split into chunks,
+/// each of which is marked with whether more follow.
+fn kitty(encoded: &str) -> String {
+    // Base64 is ASCII, so the payload can be split at any byte
+    let chunks: Vec<&[u8]> = encoded.as_bytes().chunks(KITTY_CHUNK_SIZE).collect();
+
+    let mut sequence = String::new();
+
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = u8::from(i + 1 < chunks.len());
+        let chunk = std::str::from_utf8(chunk).unwrap();
+
+        if i == 0 {
+            sequence.push_str(&format!(
+                "\x1b_Gf=100,a=T,c={},m={};{}\x1b\\",
+                PREVIEW_COLUMNS, more, chunk
+            ));
+        } else {
+            sequence.push_str(&format!("\x1b_Gm={};{}\x1b\\", more, chunk));
+        }
+    }
+
+    sequence
+}
+
+fn iterm2(encoded: &str) -> String {
+    format!(
+        "\x1b]1337;File=inline=1;width={};preserveAspectRatio=1:{}\x07",
+        PREVIEW_COLUMNS, encoded
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kitty_chunks() {
+        let data = vec![0u8; KITTY_CHUNK_SIZE];
+
+        let sequence = Protocol::Kitty.preview("image/png", &data).unwrap();
+
+        // 4096 bytes are 5464 characters of base64, which is two chunks
+        let chunks: Vec<&str> = sequence.split_terminator("\x1b\\").collect();
+
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].starts_with("\x1b_Gf=100,a=T,c=32,m=1;"));
+        assert!(chunks[1].starts_with("\x1b_Gm=0;"));
+
+        assert_eq!(Protocol::Kitty.preview("image/jpeg", &data), None);
+    }
+
+    #[test]
+    fn test_iterm2() {
+        assert_eq!(
+            Protocol::Iterm2.preview("image/jpeg", b"abc").unwrap(),
+            "\x1b]1337;File=inline=1;width=32;preserveAspectRatio=1:YWJj\x07"
+        );
+
+        assert_eq!(Protocol::Iterm2.preview("application/pdf", b"abc"), None);
+    }
+}
@@ -1,12 +1,231 @@
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use nu_ansi_term::Style;
+
+use crate::cli::ColorMode;
 use crate::color;
+use crate::providers::providers::ProviderIdentifier;
 
-#[derive(Default)]
-pub(crate) struct Highlighter;
+use super::repl::command;
 
-impl reedline::Highlighter for Highlighter {
-    fn highlight(&self, line: &str, cursor: usize) -> reedline::StyledText {
-        reedline::StyledText {
-            buffer: vec![(color::USER_TEXT.clone(), line.to_string())],
+/// Styles the prompt as it is typed. Recognized slash commands, model specs,
+/// `@file` references, and template placeholders are highlighted. Unknown
+/// commands, invalid command arguments, and references to files which do
+/// not exist are flagged. The lines of the buffer are counted for the
+/// prompt.
+pub(crate) struct Highlighter {
+    lines: Arc<AtomicUsize>,
+}
+
+impl Highlighter {
+    pub(crate) fn new(lines: Arc<AtomicUsize>) -> Highlighter {
+        Highlighter { lines }
+    }
+}
+
+fn is_model_spec(word: &str) -> bool {
+    match word.split_once('/') {
+        Some((provider, model)) => {
+            !model.is_empty() && ProviderIdentifier::from_str(provider).is_ok()
+        }
+        None => false,
+    }
+}
+
+/// Splits a word into text and `{placeholder}` segments
+fn placeholders(word: &str) -> Vec<(Style, &str)> {
+    let mut segments = Vec::new();
+    let mut rest = word;
+
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+
+        let name = &rest[start + 1..end];
+
+        let is_placeholder = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+        if is_placeholder {
+            segments.push((*color::USER_TEXT, &rest[..start]));
+            segments.push((*color::PLACEHOLDER, &rest[start..=end]));
+        } else {
+            segments.push((*color::USER_TEXT, &rest[..=end]));
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    segments.push((*color::USER_TEXT, rest));
+
+    segments
+}
+
+/// Styles a slash command and its argument
+fn command_segments(line: &str) -> Vec<(Style, &str)> {
+    let (name, argument) = match line.find(char::is_whitespace) {
+        Some(end) => line.split_at(end),
+        None => (line, ""),
+    };
+
+    let command = match command(name) {
+        Some(command) => command,
+        None => return vec![(*color::INVALID_INPUT, name), (*color::USER_TEXT, argument)],
+    };
+
+    let valid = match command.validate {
+        _ if argument.trim().is_empty() => true,
+        Some(validate) => validate(argument),
+        None => false,
+    };
+
+    let argument_style = if valid {
+        *color::USER_TEXT
+    } else {
+        *color::INVALID_INPUT
+    };
+
+    vec![(*color::COMMAND, name), (argument_style, argument)]
+}
+
+/// Splits the line into styled segments. The existence of referenced files
+/// is checked with `exists`.
+fn segments(line: &str, exists: impl Fn(&Path) -> bool) -> Vec<(Style, &str)> {
+    if line.starts_with('/') {
+        let mut segments = command_segments(line);
+
+        segments.retain(|(_, text)| !text.is_empty());
+
+        return segments;
+    }
+
+    let mut segments = Vec::new();
+    let mut start = 0;
+
+    // Alternate between runs of whitespace and words
+    while start < line.len() {
+        let rest = &line[start..];
+
+        let length =
+            match rest.find(|c: char| c.is_whitespace() != rest.starts_with(char::is_whitespace)) {
+                Some(length) => length,
+                None => rest.len(),
+            };
+
+        let word = &rest[..length];
+
+        if word.starts_with(char::is_whitespace) {
+            segments.push((*color::USER_TEXT, word));
+        } else if word.len() > 1 && word.starts_with('@') {
+            let style = if exists(Path::new(&word[1..])) {
+                *color::FILE_REFERENCE
+            } else {
+                *color::INVALID_INPUT
+            };
+
+            segments.push((style, word));
+        } else if is_model_spec(word) {
+            segments.push((*color::MODEL_SPEC, word));
+        } else {
+            segments.extend(placeholders(word));
         }
+
+        start += length;
+    }
+
+    segments.retain(|(_, text)| !text.is_empty());
+
+    segments
+}
+
+impl reedline::Highlighter for Highlighter {
+    fn highlight(&self, line: &str, _cursor: usize) -> reedline::StyledText {
+        self.lines
+            .store(line.split('\n').count(), Ordering::Relaxed);
+
+        let buffer = match color::color_mode() {
+            ColorMode::On => segments(line, |path| path.exists())
+                .into_iter()
+                .map(|(style, text)| (style, text.to_string()))
+                .collect(),
+            ColorMode::Off => vec![(*color::USER_TEXT, line.to_string())],
+        };
+
+        reedline::StyledText { buffer }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_segments() {
+        let exists = |path: &Path| path == Path::new("notes.md");
+
+        let styled = |line| {
+            segments(line, exists)
+                .into_iter()
+                .filter(|(style, _)| *style != *color::USER_TEXT)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(styled("/clear"), vec![(*color::COMMAND, "/clear")]);
+        assert_eq!(styled("/clean"), vec![(*color::INVALID_INPUT, "/clean")]);
+        assert_eq!(styled("use /clear"), vec![]);
+
+        assert_eq!(
+            styled("/clear everything"),
+            vec![
+                (*color::COMMAND, "/clear"),
+                (*color::INVALID_INPUT, " everything")
+            ]
+        );
+
+        assert_eq!(
+            styled("/export chat.md"),
+            vec![(*color::COMMAND, "/export")]
+        );
+
+        assert_eq!(
+            styled("/export /nonexistent/chat.md"),
+            vec![
+                (*color::COMMAND, "/export"),
+                (*color::INVALID_INPUT, " /nonexistent/chat.md")
+            ]
+        );
+
+        assert_eq!(
+            styled("Compare @notes.md with @missing.md"),
+            vec![
+                (*color::FILE_REFERENCE, "@notes.md"),
+                (*color::INVALID_INPUT, "@missing.md")
+            ]
+        );
+
+        assert_eq!(
+            styled("Ask openai/gpt-4o and and/or {lang}:"),
+            vec![
+                (*color::MODEL_SPEC, "openai/gpt-4o"),
+                (*color::PLACEHOLDER, "{lang}")
+            ]
+        );
+
+        let line = "Translate {text} to {{French}}";
+
+        assert_eq!(
+            segments(line, exists)
+                .into_iter()
+                .map(|(_, text)| text)
+                .collect::<String>(),
+            line
+        );
     }
 }
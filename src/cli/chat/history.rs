@@ -0,0 +1,102 @@
+use std::sync::{Arc, Mutex};
+
+use reedline::{Completer, History, SearchDirection, SearchQuery, Span, Suggestion};
+
+/// Prompts shown in the history menu, shared between the REPL and the
+/// menu's completer
+#[derive(Default, Clone)]
+pub(crate) struct RecentPrompts(Arc<Mutex<Vec<String>>>);
+
+impl RecentPrompts {
+    /// Takes a snapshot of the history, from the most to least recent entry.
+    /// Consecutive identical entries are never saved by the line editor.
+    pub(crate) fn refresh(&self, history: &dyn History) {
+        let entries = history
+            .search(SearchQuery::everything(SearchDirection::Backward, None))
+            .map(|items| items.into_iter().map(|item| item.command_line).collect())
+            .unwrap_or_default();
+
+        *self.0.lock().unwrap() = entries;
+    }
+
+    /// Gets an entry, where the most recent entry is zero
+    pub(crate) fn get(&self, index: usize) -> Option<String> {
+        self.0.lock().unwrap().get(index).cloned()
+    }
+}
+
+/// Lists the entries of the history menu which contain `filter`. Pinned
+/// prompts come first, in the order they were configured, followed by the
+/// remaining prompts from the most to least recent. Slash commands are
+/// omitted and each prompt is listed once.
+fn menu_entries<'a>(pinned: &'a [String], recent: &'a [String], filter: &str) -> Vec<&'a String> {
+    let mut entries: Vec<&String> = Vec::new();
+
+    for entry in pinned.iter().chain(recent.iter()) {
+        if entry.starts_with('/') || !entry.contains(filter) || entries.contains(&entry) {
+            continue;
+        }
+
+        entries.push(entry);
+    }
+
+    entries
+}
+
+/// Completes the line with pinned and recent prompts
+pub(crate) struct HistoryCompleter {
+    pinned: Vec<String>,
+    recent: RecentPrompts,
+}
+
+impl HistoryCompleter {
+    pub(crate) fn new(pinned: Vec<String>, recent: RecentPrompts) -> HistoryCompleter {
+        HistoryCompleter { pinned, recent }
+    }
+}
+
+impl Completer for HistoryCompleter {
+    fn complete(&mut self, line: &str, _pos: usize) -> Vec<Suggestion> {
+        let recent = self.recent.0.lock().unwrap();
+
+        menu_entries(&self.pinned, &recent, line)
+            .into_iter()
+            .map(|entry| Suggestion {
+                value: entry.clone(),
+                description: self.pinned.contains(entry).then(|| "pinned".to_string()),
+                style: None,
+                extra: None,
+                span: Span::new(0, line.len()),
+                append_whitespace: false,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_menu_entries() {
+        let pinned = vec!["Summarize this".to_string(), "Explain this".to_string()];
+
+        let recent = vec![
+            "Explain this".to_string(),
+            "/clear".to_string(),
+            "Translate this".to_string(),
+            "Hello".to_string(),
+            "Translate this".to_string(),
+        ];
+
+        assert_eq!(
+            menu_entries(&pinned, &recent, ""),
+            vec!["Summarize this", "Explain this", "Translate this", "Hello"]
+        );
+
+        assert_eq!(
+            menu_entries(&pinned, &recent, "this"),
+            vec!["Summarize this", "Explain this", "Translate this"]
+        );
+    }
+}
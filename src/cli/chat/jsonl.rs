@@ -0,0 +1,303 @@
+//! JSON lines mode: a chat driven by another program
+//!
+//! `xtalk chat --json-lines` reads one JSON message per line from standard
+//! input and writes one JSON event per line to standard output. A message
+//! from the user generates a response, which is streamed as `delta` events
+//! and completed by a `message` event. System and model messages are added
+//! to the conversation without generating a response, so a program can
+//! provide instructions or replay an earlier conversation. For example:
+//!
+//! ```text
+//! -> {"role":"user","content":"Hello!"}
+//! <- {"type":"delta","content":"Hi"}
+//! <- {"type":"delta","content":"!"}
+//! <- {"type":"message","role":"model","content":"Hi!","finish_reason":"stop","usage":{...}}
+//! ```
+
+use std::io::{self, Write};
+
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::chat::{self, Role};
+use crate::providers::ChatProvider;
+use crate::registry::registry::ModelSpec;
+use crate::sessions::ResponseMetadata;
+
+use super::protocol::{StopReason, TokenUsage};
+use super::{MessageBuffer, RequestDefaults, Resolution};
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Event {
+    /// The model has been resolved and messages are accepted
+    Ready { model: String },
+    /// A chunk of the response
+    Delta { content: String },
+    /// The complete response
+    Message {
+        role: Role,
+        content: String,
+        finish_reason: StopReason,
+        usage: TokenUsage,
+    },
+    /// A message could not be served
+    Error { message: String },
+}
+
+fn send(event: &Event) {
+    let line = serde_json::to_string(event).expect("failed to serialize event");
+
+    let mut stdout = io::stdout().lock();
+
+    let written = writeln!(stdout, "{}", line).and_then(|_| stdout.flush());
+
+    // The reader has gone away, so there is nobody left to report to
+    if written.is_err() {
+        std::process::exit(crate::utils::errors::DEFAULT_EXIT_CODE);
+    }
+}
+
+fn send_error(message: String) {
+    send(&Event::Error { message });
+}
+
+pub(crate) async fn serve(
+    resolution: Resolution,
+    mut msg_buf: MessageBuffer,
+    defaults: RequestDefaults,
+) {
+    let (registry, spec) = resolution.await.expect("failed to resolve the model");
+
+    let resolved =
+        spec.and_then(ModelSpec::provider_model_ids)
+            .and_then(|(provider_id, model_id)| {
+                let provider = registry.active_provider(provider_id)?;
+
+                Ok((provider_id, model_id, provider))
+            });
+
+    let (provider_id, model_id, provider) = match resolved {
+        Ok(resolved) => resolved,
+        Err(err) => {
+            send_error(format!("failed to resolve model: {}", err));
+            std::process::exit(err.exit_code());
+        }
+    };
+
+    send(&Event::Ready {
+        model: format!("{}/{}", provider_id, model_id),
+    });
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(err) => {
+                send_error(format!("failed to read a message: {}", err));
+                break;
+            }
+        };
+
+        // Blank lines are tolerated between messages
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let message = match serde_json::from_str::<chat::Message>(&line) {
+            Ok(message) => message,
+            Err(err) => {
+                send_error(format!("invalid message: {}", err));
+                continue;
+            }
+        };
+
+        match message.role {
+            Role::User => {
+                msg_buf.add_message(super::Message::Chat(message, None, Default::default()))
+            }
+            Role::System => {
+                msg_buf.add_message(super::Message::Chat(message, None, Default::default()));
+                continue;
+            }
+            Role::Model => {
+                msg_buf.add_message(super::Message::Chat(
+                    message,
+                    Some(model_id.to_string()),
+                    Default::default(),
+                ));
+                continue;
+            }
+        }
+
+        respond(provider.as_ref(), &model_id, &mut msg_buf, &defaults).await;
+    }
+}
+
+/// Responds to the prompt which ends the conversation. If no response is
+/// generated, the prompt is removed so the program can send it again.
+async fn respond(
+    provider: &dyn ChatProvider,
+    model_id: &str,
+    msg_buf: &mut MessageBuffer,
+    defaults: &RequestDefaults,
+) {
+    let completion = provider
+        .stream_completion(model_id, &defaults.messages(msg_buf), &defaults.options())
+        .await;
+
+    let mut completion = match completion {
+        Ok(completion) => completion,
+        Err(err) => {
+            send_error(format!("completion failed: {}", err));
+            msg_buf.undo();
+            return;
+        }
+    };
+
+    let mut response = String::new();
+
+    while let Some(delta) = completion.next().await {
+        match delta {
+            Ok(delta) => {
+                response.push_str(&delta.content);
+
+                send(&Event::Delta {
+                    content: delta.content,
+                });
+            }
+            Err(err) => {
+                send_error(format!("failed to decode streaming response: {}", err));
+                msg_buf.undo();
+                return;
+            }
+        }
+    }
+
+    send(&Event::Message {
+        role: Role::Model,
+        content: response.clone(),
+        finish_reason: completion.finish_reason().into(),
+        usage: completion.usage().into(),
+    });
+
+    msg_buf.add_message(super::Message::Chat(
+        chat::Message::new(Role::Model, response),
+        Some(model_id.to_string()),
+        ResponseMetadata::from_completion(completion.as_ref()),
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::providers::ProviderIdentifier;
+    use crate::providers::{
+        AsyncMessageIterator, Completion, CompletionOption, CompletionOptions, ContextManagement,
+        Error, ErrorKind, LoadedModel, Model,
+    };
+    use async_trait::async_trait;
+
+    /// A provider whose server cannot be reached
+    struct Unreachable;
+
+    #[async_trait]
+    impl ChatProvider for Unreachable {
+        fn id(&self) -> ProviderIdentifier {
+            ProviderIdentifier::Ollama
+        }
+
+        fn context_management(&self) -> ContextManagement {
+            ContextManagement::Explicit
+        }
+
+        fn honors_seed(&self) -> bool {
+            false
+        }
+
+        fn supported_options(&self, _model: &str) -> &'static [CompletionOption] {
+            &[]
+        }
+
+        async fn models(&self) -> Result<Vec<Model>, Error> {
+            Err(Error::from_kind(ErrorKind::Connection))
+        }
+
+        async fn default_model(&self) -> Result<Option<Model>, Error> {
+            Err(Error::from_kind(ErrorKind::Connection))
+        }
+
+        async fn loaded_models(&self) -> Result<Vec<LoadedModel>, Error> {
+            Err(Error::from_kind(ErrorKind::Connection))
+        }
+
+        async fn unload_model(&self, _model: &str) -> Result<(), Error> {
+            Err(Error::from_kind(ErrorKind::Connection))
+        }
+
+        async fn stream_completion(
+            &self,
+            _model: &str,
+            _messages: &[chat::Message],
+            _options: &CompletionOptions,
+        ) -> Result<Box<dyn AsyncMessageIterator>, Error> {
+            Err(Error::from_kind(ErrorKind::Connection))
+        }
+
+        async fn completion(
+            &self,
+            _model: &str,
+            _messages: &[chat::Message],
+            _options: &CompletionOptions,
+        ) -> Result<Completion, Error> {
+            Err(Error::from_kind(ErrorKind::Connection))
+        }
+
+        async fn fill_in_middle(
+            &self,
+            _model: &str,
+            _prefix: &str,
+            _suffix: &str,
+        ) -> Result<String, Error> {
+            Err(Error::from_kind(ErrorKind::Connection))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_prompt_is_removed() {
+        let mut msg_buf = MessageBuffer::new();
+
+        msg_buf.add_message(super::super::Message::user("Hello!".to_string()));
+
+        respond(&Unreachable, "m", &mut msg_buf, &RequestDefaults::default()).await;
+
+        // The prompt can be sent again without appearing twice
+        assert!(msg_buf.chat_messages().is_empty());
+    }
+
+    #[test]
+    fn test_messages() {
+        let encode = |event: Event| serde_json::to_string(&event).unwrap();
+
+        let message: chat::Message =
+            serde_json::from_str(r#"{"role":"user","content":"Hello!"}"#).unwrap();
+
+        assert!(matches!(message.role, Role::User));
+        assert_eq!(message.content, "Hello!");
+
+        assert_eq!(
+            encode(Event::Message {
+                role: Role::Model,
+                content: "Hi!".to_string(),
+                finish_reason: StopReason::Stop,
+                usage: TokenUsage {
+                    prompt_tokens: Some(12),
+                    completion_tokens: Some(3)
+                }
+            }),
+            r#"{"type":"message","role":"model","content":"Hi!","finish_reason":"stop","usage":{"prompt_tokens":12,"completion_tokens":3}}"#
+        );
+    }
+}
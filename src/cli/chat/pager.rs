@@ -0,0 +1,50 @@
+//! Display text in a pager
+
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+/// The pager used if `PAGER` is not set. Color escape sequences are passed
+/// through, and the pager exits immediately if the text fits on one screen.
+const DEFAULT_PAGER: &str = "less -RF";
+
+/// Pipes `text` to the pager named by the `PAGER` environment variable and
+/// waits for it to exit
+pub(crate) fn page(text: &str) -> io::Result<()> {
+    let pager = std::env::var("PAGER")
+        .ok()
+        .filter(|pager| !pager.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_PAGER.to_string());
+
+    // The pager may be specified with arguments, as it is for other programs
+    let mut child = Command::new("sh")
+        .args(["-c", &pager])
+        .stdin(Stdio::piped())
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // The pager may quit before reading all of its input
+        let written = stdin.write_all(text.as_bytes()).and_then(|_| {
+            if text.ends_with('\n') {
+                Ok(())
+            } else {
+                stdin.write_all(b"\n")
+            }
+        });
+
+        match written {
+            Err(err) if err.kind() != io::ErrorKind::BrokenPipe => return Err(err),
+            _ => {}
+        }
+    }
+
+    let status = child.wait()?;
+
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "{} exited with {}",
+            pager, status
+        )));
+    }
+
+    Ok(())
+}
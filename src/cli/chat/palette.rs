@@ -0,0 +1,184 @@
+//! The command palette, one menu of the slash commands, prompt templates,
+//! and recent sessions
+//!
+//! Choosing an entry carries it out: commands are run, templates are sent as
+//! prompts, and sessions are resumed. Commands which take an argument, and
+//! templates with placeholders which are not built in, are inserted into the
+//! buffer instead, so they can be completed before they are sent.
+//!
+//! A menu only inserts the chosen entry into the buffer, so entries which
+//! are carried out are prefixed with `MARKER`. Enter is followed by the
+//! `SELECTED` host command, upon which the REPL runs a marked buffer.
+
+use reedline::{Completer, Span, Suggestion};
+
+use crate::sessions::SessionStore;
+use crate::templates::{self, Template};
+use crate::warn;
+
+use super::repl::COMMANDS;
+
+/// The host command sent after Enter, in case an entry was chosen
+pub(crate) const SELECTED: &str = "\u{0}palette-selected";
+
+/// Prefixes the entries which are carried out once chosen. The invisible
+/// separator is never typed, so a marked buffer came from the palette.
+pub(crate) const MARKER: char = '\u{2063}';
+
+/// The number of sessions listed, from the most recently updated
+const RECENT_SESSIONS: usize = 10;
+
+/// An entry of the palette
+#[derive(Clone)]
+struct Entry {
+    /// What is inserted into the buffer
+    value: String,
+    /// What kind of entry it is, and what it does
+    description: String,
+}
+
+/// Lists the commands, templates, and sessions whose description or value
+/// contains the text typed since the menu was opened
+pub(crate) struct PaletteCompleter {
+    templates: Vec<Template>,
+    /// The recent sessions, read as the menu is opened
+    sessions: Vec<Entry>,
+}
+
+impl PaletteCompleter {
+    pub(crate) fn new(templates: Vec<Template>) -> PaletteCompleter {
+        // Templates which cannot be parsed are reported by the template menu
+        let templates = templates
+            .into_iter()
+            .filter(|template| template.placeholders().is_ok())
+            .collect();
+
+        PaletteCompleter {
+            templates,
+            sessions: Vec::new(),
+        }
+    }
+
+    fn commands() -> impl Iterator<Item = Entry> {
+        COMMANDS.iter().map(|command| Entry {
+            value: match command.validate {
+                Some(_) => format!("{} ", command.name),
+                None => format!("{}{}", MARKER, command.name),
+            },
+            description: format!("command: {}", command.help),
+        })
+    }
+
+    fn templates(&self) -> Vec<Entry> {
+        // Rendered as the menu is shown, so the date and time are current
+        let vars = templates::builtin_vars();
+
+        self.templates
+            .iter()
+            .filter_map(|template| {
+                let value = match template.render_all(&vars) {
+                    Ok(rendered) => format!("{}{}", MARKER, rendered),
+                    Err(_) => template.render(&vars).ok()?,
+                };
+
+                let description = match &template.description {
+                    Some(description) => format!("template: {}: {}", template.name, description),
+                    None => format!("template: {}", template.name),
+                };
+
+                Some(Entry { value, description })
+            })
+            .collect()
+    }
+
+    /// Reads the most recently updated sessions
+    fn refresh_sessions(&mut self) {
+        let store = SessionStore::open();
+
+        let sessions = store.and_then(|store| Ok((store.list()?, store.labels()?)));
+
+        let (sessions, labels) = match sessions {
+            Ok(sessions) => sessions,
+            Err(err) => {
+                warn!("the palette does not list the sessions: {}", err);
+                self.sessions.clear();
+                return;
+            }
+        };
+
+        self.sessions = sessions
+            .iter()
+            .rev()
+            .filter(|session| !session.messages.is_empty())
+            .take(RECENT_SESSIONS)
+            .map(|session| {
+                let title = session.title(labels.get(&session.id)).unwrap_or_default();
+
+                Entry {
+                    value: format!("{}/resume {}", MARKER, session.id),
+                    description: format!(
+                        "session: {}, {}",
+                        title,
+                        session.updated.format("%Y-%m-%d %H:%M")
+                    ),
+                }
+            })
+            .collect();
+    }
+}
+
+/// Whether the entry contains each word of the typed text, ignoring case
+fn matches(entry: &Entry, typed: &str) -> bool {
+    let text = format!("{} {}", entry.description, entry.value).to_lowercase();
+
+    typed
+        .split_whitespace()
+        .all(|word| text.contains(&word.to_lowercase()))
+}
+
+impl Completer for PaletteCompleter {
+    fn complete(&mut self, line: &str, _pos: usize) -> Vec<Suggestion> {
+        if line.is_empty() {
+            self.refresh_sessions();
+        }
+
+        let templates = self.templates();
+
+        PaletteCompleter::commands()
+            .chain(templates)
+            .chain(self.sessions.iter().cloned())
+            .filter(|entry| matches(entry, line))
+            .map(|entry| Suggestion {
+                value: entry.value,
+                description: Some(entry.description),
+                style: None,
+                extra: None,
+                span: Span::new(0, line.len()),
+                append_whitespace: false,
+            })
+            .collect()
+    }
+}
+
+/// Takes the entry out of a buffer which was filled by choosing it from the
+/// palette, returning `None` for any other buffer
+pub(crate) fn chosen(buffer: &str) -> Option<&str> {
+    buffer.strip_prefix(MARKER)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let entry = Entry {
+            value: format!("{}/resume 0a1b2c", MARKER),
+            description: "session: Fixing the Makefile, 2026-10-18 09:30".to_string(),
+        };
+
+        assert!(matches(&entry, ""));
+        assert!(matches(&entry, "RESUME makefile"));
+        assert!(!matches(&entry, "makefile template"));
+    }
+}
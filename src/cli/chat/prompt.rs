@@ -1,21 +1,31 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::Local;
 use nu_ansi_term::AnsiGenericString;
 use reedline::{
     self, Color, PromptEditMode, PromptHistorySearch, PromptHistorySearchStatus, PromptViMode,
 };
 use std::borrow::Cow;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 use crate::color::{self, MaybePaint};
+use crate::warn;
 
 const USER_PROMPT: &'static str = "[#] ";
 const USER_VI_INSERT_PROMPT: &'static str = USER_PROMPT;
 const USER_VI_NORMAL_PROMPT: &'static str = "[=] ";
+/// Labels responses whose model is not known, such as those of a
+/// conversation replayed by another program
+pub(crate) const UNKNOWN_MODEL: &str = "model";
 const COMPLETION_MARKER: &'static str = "[/] ";
 const USER_MULTLINE_PROMPT: &'static str = "::: ";
 
 pub(crate) fn model_prompt(model_name: &str) -> String {
     let prompt_text = format!("[{}] ", model_name);
 
-    color::MODEL_PROMPT.maybe_paint(prompt_text).to_string()
+    color::model_prompt_style(model_name)
+        .maybe_paint(prompt_text)
+        .to_string()
 }
 
 pub(crate) fn user_prompt() -> AnsiGenericString<'static, str> {
@@ -38,22 +48,61 @@ pub(crate) fn multiline_prompt() -> AnsiGenericString<'static, str> {
     color::USER_PROMPT.maybe_paint(USER_MULTLINE_PROMPT)
 }
 
+/// The number of columns occupied by the prompt which precedes the first
+/// line of input. The vi prompts are the same width.
+pub(crate) fn prompt_width() -> usize {
+    USER_PROMPT.len()
+}
+
+/// The number of columns occupied by the prompt which precedes subsequent
+/// lines of input
+pub(crate) fn multiline_prompt_width() -> usize {
+    USER_MULTLINE_PROMPT.len()
+}
+
 pub(crate) struct Prompt {
     user_prompt: String,
     user_vi_normal_prompt: String,
     user_vi_insert_prompt: String,
     user_multiline_prompt: String,
+    /// The number of lines in the buffer, which the highlighter counts as
+    /// the buffer is painted
+    lines: Arc<AtomicUsize>,
+    /// The strftime format of the clock, if it is shown
+    clock: Option<String>,
 }
 
-impl Default for Prompt {
-    fn default() -> Self {
+impl Prompt {
+    /// A prompt which shows the time in the format given, if any
+    pub(crate) fn new(clock: Option<String>) -> Prompt {
+        // An invalid format would fail each time the prompt is drawn
+        let clock = clock.filter(|format| {
+            let valid = !StrftimeItems::new(format).any(|item| matches!(item, Item::Error));
+
+            if !valid {
+                warn!(
+                    "the clock is not shown, its format is invalid: \"{}\"",
+                    format
+                );
+            }
+
+            valid
+        });
+
         Prompt {
             user_prompt: user_prompt().to_string(),
             user_vi_insert_prompt: user_vi_insert_prompt().to_string(),
             user_vi_normal_prompt: user_vi_normal_prompt().to_string(),
             user_multiline_prompt: multiline_prompt().to_string(),
+            lines: Arc::new(AtomicUsize::new(1)),
+            clock,
         }
     }
+
+    /// The counter of the lines in the buffer, shared with the highlighter
+    pub(crate) fn line_count(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.lines)
+    }
 }
 
 impl reedline::Prompt for Prompt {
@@ -61,8 +110,34 @@ impl reedline::Prompt for Prompt {
         Cow::Borrowed("")
     }
 
+    /// Marks input which spans several lines, such as pasted text, since it
+    /// is not submitted until Enter is pressed. The clock follows, updated
+    /// whenever the prompt is redrawn.
     fn render_prompt_right(&self) -> std::borrow::Cow<str> {
-        Cow::Borrowed("")
+        let mut segments = Vec::new();
+
+        match self.lines.load(Ordering::Relaxed) {
+            lines if lines > 1 => segments.push(
+                color::LINE_COUNT
+                    .maybe_paint(format!("{} lines", lines))
+                    .to_string(),
+            ),
+            _ => {}
+        }
+
+        if let Some(format) = &self.clock {
+            segments.push(
+                color::CLOCK
+                    .maybe_paint(Local::now().format(format).to_string())
+                    .to_string(),
+            );
+        }
+
+        if segments.is_empty() {
+            Cow::Borrowed("")
+        } else {
+            Cow::Owned(segments.join("  "))
+        }
     }
 
     fn render_prompt_indicator(&self, prompt_mode: reedline::PromptEditMode) -> Cow<str> {
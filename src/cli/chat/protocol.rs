@@ -0,0 +1,464 @@
+//! The editor protocol: a framed chat over standard input and output
+//!
+//! `xtalk chat --editor-protocol` holds a conversation with an editor plugin
+//! rather than a user. Each message is a frame consisting of the length of
+//! its payload in bytes as an ASCII decimal number, a newline, and then the
+//! payload: a UTF-8 JSON object with a `type` field. For example:
+//!
+//! ```text
+//! 36
+//! {"type":"prompt","content":"Hello!"}
+//! ```
+//!
+//! Note that the payload is not followed by a newline. The full specification
+//! is found in `docs/editor-protocol.md`; the tests in this module exercise
+//! its examples.
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::sync::mpsc;
+
+use crate::chat;
+use crate::providers::{FinishReason, Usage};
+use crate::registry::registry::ModelSpec;
+use crate::sessions::ResponseMetadata;
+
+use super::{MessageBuffer, RequestDefaults, Resolution};
+
+/// The largest payload which is accepted, to guard against corrupt headers
+const MAX_FRAME_LENGTH: usize = 64 * 1024 * 1024;
+
+/// Frames sent by the editor
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Request {
+    /// Adds a prompt to the conversation and generates a response
+    Prompt { content: String },
+    /// Stops generating the current response
+    Cancel,
+    /// Starts a new conversation
+    Clear,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum StopReason {
+    Stop,
+    Length,
+    ContentFilter,
+    Cancelled,
+}
+
+impl From<FinishReason> for StopReason {
+    fn from(value: FinishReason) -> Self {
+        match value {
+            FinishReason::Stop => StopReason::Stop,
+            FinishReason::Length => StopReason::Length,
+            FinishReason::ContentFilter => StopReason::ContentFilter,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub(super) struct TokenUsage {
+    pub prompt_tokens: Option<usize>,
+    pub completion_tokens: Option<usize>,
+}
+
+impl From<&Usage> for TokenUsage {
+    fn from(value: &Usage) -> Self {
+        TokenUsage {
+            prompt_tokens: value.prompt_tokens,
+            completion_tokens: value.completion_tokens,
+        }
+    }
+}
+
+/// Frames sent by xtalk
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Event {
+    /// The model has been resolved and prompts are accepted
+    Ready { model: String },
+    /// A chunk of the response
+    Delta { content: String },
+    /// The response is complete
+    Done {
+        stop_reason: StopReason,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        usage: Option<TokenUsage>,
+    },
+    /// A request could not be served
+    Error { message: String },
+}
+
+#[derive(Debug)]
+enum FrameError {
+    Io(io::Error),
+    InvalidHeader(String),
+}
+
+/// Reads a frame, returning `None` at the end of the input
+async fn read_frame<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<Vec<u8>>, FrameError> {
+    let mut header = String::new();
+
+    if reader
+        .read_line(&mut header)
+        .await
+        .map_err(FrameError::Io)?
+        == 0
+    {
+        return Ok(None);
+    }
+
+    let length = header
+        .trim_end_matches(['\r', '\n'])
+        .parse::<usize>()
+        .ok()
+        .filter(|length| *length <= MAX_FRAME_LENGTH)
+        .ok_or_else(|| FrameError::InvalidHeader(header.trim_end().to_string()))?;
+
+    let mut payload = vec![0; length];
+
+    reader
+        .read_exact(&mut payload)
+        .await
+        .map_err(FrameError::Io)?;
+
+    Ok(Some(payload))
+}
+
+fn encode_frame(event: &Event) -> Vec<u8> {
+    let payload = serde_json::to_string(event).expect("failed to serialize event");
+
+    let mut frame = format!("{}\n", payload.len()).into_bytes();
+
+    frame.extend_from_slice(payload.as_bytes());
+
+    frame
+}
+
+fn send(event: &Event) {
+    let mut stdout = io::stdout().lock();
+
+    let written = stdout
+        .write_all(&encode_frame(event))
+        .and_then(|_| stdout.flush());
+
+    // The editor has gone away, so there is nobody left to report to
+    if written.is_err() {
+        std::process::exit(crate::utils::errors::DEFAULT_EXIT_CODE);
+    }
+}
+
+fn send_error(message: String) {
+    send(&Event::Error { message });
+}
+
+/// Reads requests from standard input on a separate task so a response can
+/// be cancelled while it is being generated
+fn spawn_reader() -> mpsc::UnboundedReceiver<Result<Request, String>> {
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        let mut reader = BufReader::new(tokio::io::stdin());
+
+        loop {
+            let request = match read_frame(&mut reader).await {
+                Ok(Some(payload)) => serde_json::from_slice::<Request>(&payload)
+                    .map_err(|err| format!("invalid request: {}", err)),
+                Ok(None) => break,
+                Err(FrameError::Io(err)) => {
+                    let _ = tx.send(Err(format!("failed to read a frame: {}", err)));
+                    break;
+                }
+                // The stream cannot be resynchronized after a bad header
+                Err(FrameError::InvalidHeader(header)) => {
+                    let _ = tx.send(Err(format!("invalid frame header \"{}\"", header)));
+                    break;
+                }
+            };
+
+            if tx.send(request).is_err() {
+                break;
+            }
+        }
+    });
+
+    rx
+}
+
+pub(crate) async fn serve(
+    resolution: Resolution,
+    mut msg_buf: MessageBuffer,
+    defaults: RequestDefaults,
+) {
+    let (registry, spec) = resolution.await.expect("failed to resolve the model");
+
+    let resolved =
+        spec.and_then(ModelSpec::provider_model_ids)
+            .and_then(|(provider_id, model_id)| {
+                let provider = registry.active_provider(provider_id)?;
+
+                Ok((provider_id, model_id, provider))
+            });
+
+    let (provider_id, model_id, provider) = match resolved {
+        Ok(resolved) => resolved,
+        Err(err) => {
+            send_error(format!("failed to resolve model: {}", err));
+            std::process::exit(err.exit_code());
+        }
+    };
+
+    send(&Event::Ready {
+        model: format!("{}/{}", provider_id, model_id),
+    });
+
+    let mut requests = spawn_reader();
+
+    // Requests which arrived while a response was being generated
+    let mut pending = VecDeque::new();
+
+    // Whether the editor has closed its end of the stream
+    let mut closed = false;
+
+    loop {
+        let request = match pending.pop_front() {
+            Some(request) => request,
+            None if closed => break,
+            None => match requests.recv().await {
+                Some(request) => request,
+                None => break,
+            },
+        };
+
+        let content = match request {
+            Ok(Request::Prompt { content }) => content,
+            // There is no response to cancel
+            Ok(Request::Cancel) => continue,
+            Ok(Request::Clear) => {
+                msg_buf.clear();
+                continue;
+            }
+            Err(message) => {
+                send_error(message);
+                continue;
+            }
+        };
+
+        msg_buf.add_message(super::Message::user(content));
+
+        let messages = defaults.messages(&msg_buf);
+        let options = defaults.options();
+
+        let started = provider.stream_completion(&model_id, &messages, &options);
+        tokio::pin!(started);
+
+        // The response may be cancelled before the stream starts, such as
+        // while a local model is loaded
+        let completion = loop {
+            tokio::select! {
+                completion = &mut started => break Some(completion),
+                request = requests.recv(), if !closed => match request {
+                    Some(Ok(Request::Cancel)) => break None,
+                    Some(request) => pending.push_back(request),
+                    None => closed = true,
+                },
+            }
+        };
+
+        // Prompts which are not answered are removed from the conversation,
+        // so the editor can send them again
+        let mut completion = match completion {
+            Some(Ok(completion)) => completion,
+            Some(Err(err)) => {
+                send_error(format!("completion failed: {}", err));
+                msg_buf.undo();
+                continue;
+            }
+            None => {
+                send(&Event::Done {
+                    stop_reason: StopReason::Cancelled,
+                    usage: None,
+                });
+                msg_buf.undo();
+                continue;
+            }
+        };
+
+        let mut response = String::new();
+        let mut cancelled = false;
+        let mut failed = false;
+
+        loop {
+            tokio::select! {
+                delta = completion.next() => match delta {
+                    Some(Ok(delta)) => {
+                        response.push_str(&delta.content);
+
+                        send(&Event::Delta { content: delta.content });
+                    }
+                    Some(Err(err)) => {
+                        send_error(format!("failed to decode streaming response: {}", err));
+                        failed = true;
+                        break;
+                    }
+                    None => break,
+                },
+                request = requests.recv(), if !closed => match request {
+                    Some(Ok(Request::Cancel)) => {
+                        cancelled = true;
+                        break;
+                    }
+                    Some(request) => pending.push_back(request),
+                    // Finish the response even though the editor has stopped sending requests
+                    None => closed = true,
+                },
+            }
+        }
+
+        if failed {
+            msg_buf.undo();
+            continue;
+        }
+
+        let event = if cancelled {
+            Event::Done {
+                stop_reason: StopReason::Cancelled,
+                usage: None,
+            }
+        } else {
+            Event::Done {
+                stop_reason: completion.finish_reason().into(),
+                usage: Some(completion.usage().into()),
+            }
+        };
+
+        send(&event);
+
+        // Cancelled responses are kept so the conversation reflects what the editor displayed
+        if !response.is_empty() {
+            let metadata = if cancelled {
+                ResponseMetadata::default()
+            } else {
+                ResponseMetadata::from_completion(completion.as_ref())
+            };
+
+            msg_buf.add_message(super::Message::Chat(
+                chat::Message::new(chat::Role::Model, response),
+                Some(model_id.to_string()),
+                metadata,
+            ));
+        } else if cancelled {
+            msg_buf.undo();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn frames(input: &[u8]) -> Vec<Result<Request, String>> {
+        let mut reader = BufReader::new(input);
+        let mut requests = Vec::new();
+
+        loop {
+            match read_frame(&mut reader).await {
+                Ok(Some(payload)) => requests.push(
+                    serde_json::from_slice::<Request>(&payload).map_err(|err| err.to_string()),
+                ),
+                Ok(None) => break,
+                Err(err) => {
+                    requests.push(Err(format!("{:?}", err)));
+                    break;
+                }
+            }
+        }
+
+        requests
+    }
+
+    #[tokio::test]
+    async fn test_requests() {
+        let input = "36\n{\"type\":\"prompt\",\"content\":\"Hello!\"}17\n{\"type\":\"cancel\"}16\n{\"type\":\"clear\"}";
+
+        let requests = frames(input.as_bytes()).await;
+
+        assert_eq!(
+            requests,
+            vec![
+                Ok(Request::Prompt {
+                    content: "Hello!".to_string()
+                }),
+                Ok(Request::Cancel),
+                Ok(Request::Clear),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lengths_count_bytes() {
+        let input = "41\n{\"type\":\"prompt\",\"content\":\"h\u{e9}llo \u{1f600}\"}";
+
+        let requests = frames(input.as_bytes()).await;
+
+        assert_eq!(
+            requests,
+            vec![Ok(Request::Prompt {
+                content: "h\u{e9}llo \u{1f600}".to_string()
+            })]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_header() {
+        let requests = frames(b"{\"type\":\"clear\"}").await;
+
+        assert!(matches!(&requests[..], [Err(_)]));
+    }
+
+    #[test]
+    fn test_events() {
+        let encode = |event: Event| String::from_utf8(encode_frame(&event)).unwrap();
+
+        assert_eq!(
+            encode(Event::Ready {
+                model: "ollama/gemma:2b".to_string()
+            }),
+            "42\n{\"type\":\"ready\",\"model\":\"ollama/gemma:2b\"}"
+        );
+
+        assert_eq!(
+            encode(Event::Delta {
+                content: "Hi".to_string()
+            }),
+            "31\n{\"type\":\"delta\",\"content\":\"Hi\"}"
+        );
+
+        assert_eq!(
+            encode(Event::Done {
+                stop_reason: StopReason::Stop,
+                usage: Some(TokenUsage {
+                    prompt_tokens: Some(12),
+                    completion_tokens: Some(3)
+                })
+            }),
+            "87\n{\"type\":\"done\",\"stop_reason\":\"stop\",\"usage\":{\"prompt_tokens\":12,\"completion_tokens\":3}}"
+        );
+
+        assert_eq!(
+            encode(Event::Done {
+                stop_reason: StopReason::Cancelled,
+                usage: None
+            }),
+            "41\n{\"type\":\"done\",\"stop_reason\":\"cancelled\"}"
+        );
+    }
+}
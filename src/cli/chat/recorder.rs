@@ -0,0 +1,57 @@
+//! Records the conversation into the session store as it progresses
+
+use chrono::{DateTime, Local};
+
+use crate::sessions::{self, Session, SessionStore};
+
+use super::MessageBuffer;
+
+pub(crate) struct SessionRecorder {
+    store: SessionStore,
+    session: Session,
+}
+
+impl SessionRecorder {
+    pub(crate) fn new(store: SessionStore, session: Session) -> SessionRecorder {
+        SessionRecorder { store, session }
+    }
+
+    pub(crate) fn id(&self) -> &str {
+        &self.session.id
+    }
+
+    /// When the session was started, which precedes the chat if it was resumed
+    pub(crate) fn created(&self) -> DateTime<Local> {
+        self.session.created
+    }
+
+    /// Sets the model spec which is recorded with the session
+    pub(crate) fn set_model(&mut self, spec: String) {
+        self.session.model = Some(spec);
+    }
+
+    /// Sets the seed which is recorded with the session
+    pub(crate) fn set_seed(&mut self, seed: Option<u32>) {
+        self.session.seed = seed;
+    }
+
+    /// Loads a saved session, which is recorded from then on in place of
+    /// the current one. It continues with the model and seed of the chat.
+    pub(crate) fn resume(&mut self, id: &str) -> Result<&Session, sessions::Error> {
+        let session = self.store.load(id)?;
+
+        let previous = std::mem::replace(&mut self.session, session);
+
+        self.session.model = previous.model;
+        self.session.seed = previous.seed;
+
+        Ok(&self.session)
+    }
+
+    /// Saves the chat messages in the buffer to the session store
+    pub(crate) fn record(&mut self, msg_buf: &MessageBuffer) -> Result<(), sessions::Error> {
+        self.session.messages = msg_buf.session_messages();
+
+        self.store.save(&mut self.session)
+    }
+}
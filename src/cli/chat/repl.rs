@@ -1,12 +1,12 @@
+use std::collections::BTreeMap;
 use std::env;
-use std::io::{Read, Seek, SeekFrom};
 use std::os::unix::ffi::OsStrExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use reedline::{
-    default_emacs_keybindings, ColumnarMenu, DefaultCompleter, EditMode, Emacs, KeyCode,
-    KeyModifiers, Keybindings, ReedlineEvent, ReedlineMenu,
+    default_emacs_keybindings, ColumnarMenu, DefaultCompleter, EditMode, Emacs, FileBackedHistory,
+    KeyCode, KeyModifiers, Keybindings, ListMenu, ReedlineEvent, ReedlineMenu,
 };
 use reedline::{
     default_vi_insert_keybindings, default_vi_normal_keybindings, DefaultPrompt,
@@ -15,13 +15,23 @@ use reedline::{
 
 use crate::cli::chat::Message;
 use crate::die;
+use crate::templates::Template;
+use crate::utils::dirs::{data_dir, expand_home};
 use crate::{config, warn};
 use nu_ansi_term::{Color, Style};
 
+use super::attach::{split_pages, Attachment, ImageAttachment};
+use super::graphics::Protocol;
 use super::highlighter::Highlighter;
-use super::prompt::{completion_marker, Prompt};
+use super::history::{HistoryCompleter, RecentPrompts};
+use super::palette::{self, PaletteCompleter};
+use super::prompt::{
+    completion_marker, multiline_prompt, multiline_prompt_width, prompt_width, user_prompt, Prompt,
+};
+use super::softwrap;
 use super::tempfile::Tempfile;
-use super::MessageBuffer;
+use super::template_menu::TemplateCompleter;
+use super::{MessageBuffer, RequestDefaults};
 
 /// Attempts to resolve the preferred editor. If the EDITOR environment variable
 /// is defined, the command specified by it is used. If a Debian-specific editor
@@ -50,17 +60,12 @@ fn resolve_fallback_editor() -> Option<PathBuf> {
 
 /// Launches an interactive editor to edit the contents of a file and return the result.
 /// The `editor` parameter specifies the editor to use, `temp_file` represents the
-/// temporary file where initial contents are stored.
-fn read_from_interactive_editor(editor: &PathBuf, temp_file: &mut Tempfile) -> String {
-    // Remove the previous contents of the file
-    {
-        if let Err(err) = temp_file.file_mut().set_len(0) {
-            die!("failed to truncate the editor file: {}", err);
-        }
-
-        if let Err(err) = temp_file.file_mut().seek(SeekFrom::Start(0)) {
-            die!("failed to reset file cursor: {}", err);
-        }
+/// temporary file where the `initial` contents are stored.
+fn read_from_interactive_editor(editor: &PathBuf, temp_file: &Tempfile, initial: &str) -> String {
+    // Editors which save by replacing the file leave an open handle on the
+    // old one, so the file is written and read by its path
+    if let Err(err) = std::fs::write(temp_file.path(), initial) {
+        die!("failed to write the editor file: {}", err);
     }
 
     // Launch the editor subprocess
@@ -83,17 +88,67 @@ fn read_from_interactive_editor(editor: &PathBuf, temp_file: &mut Tempfile) -> S
     }
 
     // Read the resulting file into a string
-    let mut edited_content = String::new();
-    {
-        if let Err(err) = temp_file.file_mut().read_to_string(&mut edited_content) {
-            die!(
-                "failed to read in the editor file: {}, was it deleted?",
-                err
-            );
+    match std::fs::read_to_string(temp_file.path()) {
+        Ok(edited_content) => edited_content,
+        Err(err) => die!(
+            "failed to read in the editor file: {}, was it deleted?",
+            err
+        ),
+    }
+}
+
+/// The number of entries kept in the history unless configured otherwise
+pub(crate) const DEFAULT_HISTORY_ENTRIES: usize = 1000;
+
+/// Opens the file-backed history, if it is enabled. If the history cannot be
+/// opened, the REPL falls back to a history which only lasts for the session.
+fn file_history(config: &config::History) -> Option<Box<FileBackedHistory>> {
+    if !config.persist.unwrap_or(true) {
+        return None;
+    }
+
+    let path = match &config.path {
+        Some(path) => expand_home(path),
+        None => data_dir()?.join("xtalk/history"),
+    };
+
+    if let Some(parent) = path.parent() {
+        if let Err(err) = std::fs::create_dir_all(parent) {
+            warn!("failed to create the history directory: {}", err);
+            return None;
         }
     }
 
-    edited_content
+    let capacity = config.max_entries.unwrap_or(DEFAULT_HISTORY_ENTRIES);
+
+    match FileBackedHistory::with_file(capacity, path) {
+        Ok(history) => Some(Box::new(history)),
+        Err(err) => {
+            warn!("failed to open the history: {}", err);
+            None
+        }
+    }
+}
+
+/// Binds the up and down keys to move between soft-wrapped rows
+fn add_soft_wrap_bindings(keybindings: &mut Keybindings) {
+    keybindings.add_binding(
+        KeyModifiers::NONE,
+        KeyCode::Up,
+        ReedlineEvent::UntilFound(vec![
+            ReedlineEvent::MenuUp,
+            ReedlineEvent::ExecuteHostCommand(softwrap::UP.to_string()),
+        ]),
+    );
+
+    keybindings.add_binding(
+        KeyModifiers::NONE,
+        KeyCode::Down,
+        ReedlineEvent::UntilFound(vec![
+            ReedlineEvent::MenuDown,
+            ReedlineEvent::ExecuteHostCommand(softwrap::DOWN.to_string()),
+        ]),
+    );
 }
 
 fn edit_mode(keybindings: config::Keybindings) -> Box<dyn EditMode> {
@@ -101,6 +156,8 @@ fn edit_mode(keybindings: config::Keybindings) -> Box<dyn EditMode> {
         config::Keybindings::Vi => {
             let mut insert_bindings = default_vi_insert_keybindings();
 
+            add_soft_wrap_bindings(&mut insert_bindings);
+
             insert_bindings.add_binding(
                 KeyModifiers::NONE,
                 KeyCode::Tab,
@@ -110,11 +167,33 @@ fn edit_mode(keybindings: config::Keybindings) -> Box<dyn EditMode> {
                 ]),
             );
 
+            insert_bindings.add_binding(
+                KeyModifiers::CONTROL,
+                KeyCode::Char('r'),
+                ReedlineEvent::Menu("history_menu".to_string()),
+            );
+
+            insert_bindings.add_binding(
+                KeyModifiers::ALT,
+                KeyCode::Char('t'),
+                ReedlineEvent::Menu("template_menu".to_string()),
+            );
+
+            // Enter cannot be rebound in vi mode, so an entry chosen from the
+            // palette is carried out when the buffer is submitted
+            insert_bindings.add_binding(
+                KeyModifiers::ALT,
+                KeyCode::Char('p'),
+                ReedlineEvent::Menu("palette_menu".to_string()),
+            );
+
             Box::new(Vi::new(insert_bindings, default_vi_normal_keybindings()))
         }
         config::Keybindings::Emacs => {
             let mut keybindings = default_emacs_keybindings();
 
+            add_soft_wrap_bindings(&mut keybindings);
+
             keybindings.add_binding(
                 KeyModifiers::NONE,
                 KeyCode::Tab,
@@ -130,36 +209,682 @@ fn edit_mode(keybindings: config::Keybindings) -> Box<dyn EditMode> {
                 ReedlineEvent::OpenEditor,
             );
 
+            keybindings.add_binding(
+                KeyModifiers::CONTROL,
+                KeyCode::Char('r'),
+                ReedlineEvent::Menu("history_menu".to_string()),
+            );
+
+            // C-t is left to transpose characters
+            keybindings.add_binding(
+                KeyModifiers::ALT,
+                KeyCode::Char('t'),
+                ReedlineEvent::Menu("template_menu".to_string()),
+            );
+
             keybindings.add_binding(
                 KeyModifiers::CONTROL,
                 KeyCode::Char('j'),
                 ReedlineEvent::Edit(vec![EditCommand::InsertNewline]),
             );
 
+            // C-p is left to move up through the history and menus
+            keybindings.add_binding(
+                KeyModifiers::ALT,
+                KeyCode::Char('p'),
+                ReedlineEvent::Menu("palette_menu".to_string()),
+            );
+
+            // Choosing an entry from the palette carries it out at once.
+            // Otherwise, the host command leaves the buffer as it is.
+            keybindings.add_binding(
+                KeyModifiers::NONE,
+                KeyCode::Enter,
+                ReedlineEvent::Multiple(vec![
+                    ReedlineEvent::Enter,
+                    ReedlineEvent::ExecuteHostCommand(palette::SELECTED.to_string()),
+                ]),
+            );
+
             Box::new(Emacs::new(keybindings))
         }
     }
 }
 
+/// What the REPL does once a slash command has run
+enum Action {
+    /// Read another line
+    Continue,
+    /// Print the message, then read another line
+    Show(Message),
+    /// Hand the input to the chat
+    Return(Input),
+    /// End the chat
+    Exit,
+}
+
+/// A slash command understood by the REPL. Commands are added to `COMMANDS`,
+/// which the dispatcher, the tab completer, the highlighter, and `/help` are
+/// all generated from.
+pub(crate) struct SlashCommand {
+    pub name: &'static str,
+    /// A one-line description, listed by `/help`
+    pub help: &'static str,
+    /// The argument of the command, as it is shown in usage messages
+    pub usage: &'static str,
+    /// Options which the tab completer offers in addition to the name
+    pub options: &'static [&'static str],
+    /// Checks the argument of the command as it is typed. Commands without
+    /// a validator do not accept an argument.
+    pub validate: Option<fn(&str) -> bool>,
+    /// Runs the command with its argument, which is empty if none was given
+    handler: fn(&mut Repl, &str, &mut MessageBuffer) -> Action,
+}
+
+impl SlashCommand {
+    fn usage(&self) -> Action {
+        Action::Show(Message::warn(format!(
+            "usage: {} {}",
+            self.name, self.usage
+        )))
+    }
+}
+
+/// An export path is valid if it names a file in an existing directory
+fn is_export_path(path: &str) -> bool {
+    let path = Path::new(path.trim());
+
+    let in_directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.is_dir(),
+        _ => true,
+    };
+
+    path.file_name().is_some() && in_directory && !path.is_dir()
+}
+
+/// `/clear` optionally keeps the pinned messages
+fn is_clear_option(option: &str) -> bool {
+    option.trim() == "--keep-pinned"
+}
+
+/// Turns are numbered from one
+fn is_turn(turn: &str) -> bool {
+    turn.trim().parse::<usize>().is_ok_and(|turn| turn > 0)
+}
+
+/// Any non-empty query is searched for
+fn is_query(query: &str) -> bool {
+    !query.trim().is_empty()
+}
+
+/// Only files can be attached, optionally followed by a range of pages
+fn is_attachment_path(path: &str) -> bool {
+    split_pages(Path::new(path.trim())).is_ok_and(|(path, _)| path.is_file())
+}
+
+/// Only files can be attached as images
+fn is_image_path(path: &str) -> bool {
+    Path::new(path.trim()).is_file()
+}
+
+/// Code blocks are numbered from one
+fn is_block(block: &str) -> bool {
+    block.trim().parse::<usize>().is_ok_and(|block| block > 0)
+}
+
+/// Personas are named in the configuration
+fn is_persona(name: &str) -> bool {
+    !name.trim().is_empty()
+}
+
+/// Session identifiers are alphanumeric, as are the ones generated
+fn is_session(id: &str) -> bool {
+    let id = id.trim();
+
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn edit_cmd(repl: &mut Repl, _: &str, _: &mut MessageBuffer) -> Action {
+    let Some(editor) = repl.editor.as_ref() else {
+        return Action::Show(Message::warn("no editor specified".to_string()));
+    };
+
+    let buffer = read_from_interactive_editor(editor, &repl.tempfile, "");
+
+    if buffer.is_empty() {
+        return Action::Continue;
+    }
+
+    println!("{}", buffer);
+
+    Action::Return(Input::Prompt(buffer))
+}
+
+fn edit_response_cmd(repl: &mut Repl, _: &str, _: &mut MessageBuffer) -> Action {
+    if repl.editor.is_none() {
+        return Action::Show(Message::warn("no editor specified".to_string()));
+    }
+
+    Action::Return(Input::EditResponse)
+}
+
+fn exit_cmd(_: &mut Repl, _: &str, _: &mut MessageBuffer) -> Action {
+    Action::Exit
+}
+
+fn clear_cmd(_: &mut Repl, option: &str, msg_buf: &mut MessageBuffer) -> Action {
+    match option {
+        "" => msg_buf.clear(),
+        "--keep-pinned" => msg_buf.clear_unpinned(),
+        _ => return usage("/clear"),
+    }
+
+    Action::Continue
+}
+
+fn save_cmd(_: &mut Repl, _: &str, _: &mut MessageBuffer) -> Action {
+    Action::Return(Input::Save)
+}
+
+fn usage_cmd(_: &mut Repl, _: &str, _: &mut MessageBuffer) -> Action {
+    Action::Return(Input::Usage)
+}
+
+fn export_cmd(_: &mut Repl, path: &str, _: &mut MessageBuffer) -> Action {
+    if path.is_empty() {
+        return usage("/export");
+    }
+
+    Action::Return(Input::Export(PathBuf::from(path)))
+}
+
+fn expand_cmd(_: &mut Repl, _: &str, _: &mut MessageBuffer) -> Action {
+    Action::Return(Input::Expand)
+}
+
+fn find_cmd(_: &mut Repl, query: &str, _: &mut MessageBuffer) -> Action {
+    let (query, sessions) = match query.strip_prefix("--sessions") {
+        Some(rest) if rest.is_empty() || rest.starts_with(' ') => (rest.trim(), true),
+        _ => (query, false),
+    };
+
+    if query.is_empty() {
+        return usage("/find");
+    }
+
+    Action::Return(Input::Find {
+        query: query.to_string(),
+        sessions,
+    })
+}
+
+fn copy_cmd(_: &mut Repl, block: &str, _: &mut MessageBuffer) -> Action {
+    if block.is_empty() {
+        return Action::Return(Input::Copy(None));
+    }
+
+    match block.parse() {
+        Ok(block) if block > 0 => Action::Return(Input::Copy(Some(block))),
+        _ => Action::Show(Message::warn(format!(
+            "\"{}\" is not a code block number",
+            block
+        ))),
+    }
+}
+
+fn history_cmd(_: &mut Repl, _: &str, _: &mut MessageBuffer) -> Action {
+    Action::Return(Input::History)
+}
+
+fn pin(name: &str, turn: &str, pinned: bool) -> Action {
+    if turn.is_empty() {
+        return usage(name);
+    }
+
+    match turn.parse() {
+        Ok(turn) => Action::Return(Input::Pin { turn, pinned }),
+        Err(_) => Action::Show(Message::warn(format!("\"{}\" is not a turn number", turn))),
+    }
+}
+
+fn pin_cmd(_: &mut Repl, turn: &str, _: &mut MessageBuffer) -> Action {
+    pin("/pin", turn, true)
+}
+
+fn unpin_cmd(_: &mut Repl, turn: &str, _: &mut MessageBuffer) -> Action {
+    pin("/unpin", turn, false)
+}
+
+fn retry_cmd(_: &mut Repl, _: &str, _: &mut MessageBuffer) -> Action {
+    Action::Return(Input::Retry)
+}
+
+fn undo_cmd(_: &mut Repl, _: &str, _: &mut MessageBuffer) -> Action {
+    Action::Return(Input::Undo)
+}
+
+fn resume_cmd(_: &mut Repl, id: &str, _: &mut MessageBuffer) -> Action {
+    if id.is_empty() {
+        return usage("/resume");
+    }
+
+    Action::Return(Input::Resume(id.to_string()))
+}
+
+fn persona_cmd(repl: &mut Repl, name: &str, _: &mut MessageBuffer) -> Action {
+    if name.is_empty() {
+        return Action::Show(if repl.personas.is_empty() {
+            Message::warn("no personas are configured".to_string())
+        } else {
+            Message::output(format!("personas: {}", repl.personas.join(", ")))
+        });
+    }
+
+    if repl.personas.iter().any(|persona| persona == name) {
+        return Action::Return(Input::Persona(name.to_string()));
+    }
+
+    Action::Show(Message::warn(format!(
+        "there is no persona named \"{}\", see `xtalk list personas`",
+        name
+    )))
+}
+
+fn attach_cmd(repl: &mut Repl, path: &str, _: &mut MessageBuffer) -> Action {
+    if path.is_empty() {
+        if repl.attachments.is_empty() {
+            return usage("/attach");
+        }
+
+        let paths: Vec<String> = repl.attachments.iter().map(Attachment::name).collect();
+
+        return Action::Show(Message::output(format!(
+            "attached to the next prompt: {}",
+            paths.join(", ")
+        )));
+    }
+
+    Action::Show(match Attachment::read(Path::new(path)) {
+        Ok(attachment) => {
+            let msg = Message::output(format!(
+                "attached {} ({} bytes) to the next prompt",
+                attachment.name(),
+                attachment.content.len()
+            ));
+
+            repl.attach(attachment);
+
+            msg
+        }
+        Err(err) => Message::error(err.to_string()),
+    })
+}
+
+fn image_cmd(repl: &mut Repl, path: &str, _: &mut MessageBuffer) -> Action {
+    if path.is_empty() {
+        if repl.images.is_empty() {
+            return usage("/image");
+        }
+
+        let paths: Vec<String> = repl
+            .images
+            .iter()
+            .map(|image| image.path.display().to_string())
+            .collect();
+
+        return Action::Show(Message::output(format!(
+            "images attached to the next prompt: {}",
+            paths.join(", ")
+        )));
+    }
+
+    Action::Show(match ImageAttachment::read(Path::new(path)) {
+        Ok(image) => {
+            let msg = Message::output(format!(
+                "attached {} ({}) to the next prompt",
+                image.path.display(),
+                image.image.media_type
+            ));
+
+            repl.attach_image(image);
+
+            msg
+        }
+        Err(err) => Message::error(err.to_string()),
+    })
+}
+
+fn help_cmd(_: &mut Repl, _: &str, _: &mut MessageBuffer) -> Action {
+    Action::Show(Message::output(help()))
+}
+
+/// Lists the commands with their arguments and descriptions
+fn help() -> String {
+    let synopses: Vec<String> = COMMANDS
+        .iter()
+        .map(|command| format!("{} {}", command.name, command.usage))
+        .collect();
+
+    let width = synopses
+        .iter()
+        .map(|s| s.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    COMMANDS
+        .iter()
+        .zip(synopses)
+        .map(|(command, synopsis)| format!("{:width$}  {}", synopsis, command.help))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// The usage message of the named command
+fn usage(name: &str) -> Action {
+    match command(name) {
+        Some(command) => command.usage(),
+        None => Action::Continue,
+    }
+}
+
+/// The slash commands understood by the REPL
+pub(crate) static COMMANDS: [SlashCommand; 20] = [
+    SlashCommand {
+        name: "/attach",
+        help: "attach a file to the next prompt, or list the attached files",
+        usage: "<path>",
+        options: &[],
+        validate: Some(is_attachment_path),
+        handler: attach_cmd,
+    },
+    SlashCommand {
+        name: "/clear",
+        help: "clear the conversation",
+        usage: "[--keep-pinned]",
+        options: &["--keep-pinned"],
+        validate: Some(is_clear_option),
+        handler: clear_cmd,
+    },
+    SlashCommand {
+        name: "/copy",
+        help: "copy the last code block of the last response, or the numbered one",
+        usage: "[block]",
+        options: &[],
+        validate: Some(is_block),
+        handler: copy_cmd,
+    },
+    SlashCommand {
+        name: "/edit",
+        help: "write the next prompt in the editor",
+        usage: "",
+        options: &[],
+        validate: None,
+        handler: edit_cmd,
+    },
+    SlashCommand {
+        name: "/edit-response",
+        help: "edit the last response in the editor",
+        usage: "",
+        options: &[],
+        validate: None,
+        handler: edit_response_cmd,
+    },
+    SlashCommand {
+        name: "/exit",
+        help: "end the chat",
+        usage: "",
+        options: &[],
+        validate: None,
+        handler: exit_cmd,
+    },
+    SlashCommand {
+        name: "/expand",
+        help: "show the last response in the pager",
+        usage: "",
+        options: &[],
+        validate: None,
+        handler: expand_cmd,
+    },
+    SlashCommand {
+        name: "/export",
+        help: "write the transcript to a file",
+        usage: "<path>",
+        options: &[],
+        validate: Some(is_export_path),
+        handler: export_cmd,
+    },
+    SlashCommand {
+        name: "/find",
+        help: "search the conversation, and the saved sessions with --sessions",
+        usage: "[--sessions] <text>",
+        options: &["--sessions"],
+        validate: Some(is_query),
+        handler: find_cmd,
+    },
+    SlashCommand {
+        name: "/help",
+        help: "list the slash commands",
+        usage: "",
+        options: &[],
+        validate: None,
+        handler: help_cmd,
+    },
+    SlashCommand {
+        name: "/history",
+        help: "list the turns of the conversation",
+        usage: "",
+        options: &[],
+        validate: None,
+        handler: history_cmd,
+    },
+    SlashCommand {
+        name: "/image",
+        help: "attach an image to the next prompt, or list the attached images",
+        usage: "<path>",
+        options: &[],
+        validate: Some(is_image_path),
+        handler: image_cmd,
+    },
+    SlashCommand {
+        name: "/persona",
+        help: "switch to a persona, or list the personas",
+        usage: "<name>",
+        options: &[],
+        validate: Some(is_persona),
+        handler: persona_cmd,
+    },
+    SlashCommand {
+        name: "/pin",
+        help: "keep a turn when the conversation is cleared",
+        usage: "<turn>",
+        options: &[],
+        validate: Some(is_turn),
+        handler: pin_cmd,
+    },
+    SlashCommand {
+        name: "/resume",
+        help: "continue a saved session in place of the conversation",
+        usage: "<session>",
+        options: &[],
+        validate: Some(is_session),
+        handler: resume_cmd,
+    },
+    SlashCommand {
+        name: "/retry",
+        help: "generate the last response again",
+        usage: "",
+        options: &[],
+        validate: None,
+        handler: retry_cmd,
+    },
+    SlashCommand {
+        name: "/save",
+        help: "save the session",
+        usage: "",
+        options: &[],
+        validate: None,
+        handler: save_cmd,
+    },
+    SlashCommand {
+        name: "/undo",
+        help: "remove the last prompt and its response",
+        usage: "",
+        options: &[],
+        validate: None,
+        handler: undo_cmd,
+    },
+    SlashCommand {
+        name: "/unpin",
+        help: "unpin a turn",
+        usage: "<turn>",
+        options: &[],
+        validate: Some(is_turn),
+        handler: unpin_cmd,
+    },
+    SlashCommand {
+        name: "/usage",
+        help: "show the tokens used by each response",
+        usage: "",
+        options: &[],
+        validate: None,
+        handler: usage_cmd,
+    },
+];
+
+/// Finds a command by name
+pub(crate) fn command(name: &str) -> Option<&'static SlashCommand> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+/// The words offered by the tab completer: the names of the commands and
+/// their options
+fn completions() -> Vec<String> {
+    COMMANDS
+        .iter()
+        .flat_map(|command| std::iter::once(&command.name).chain(command.options))
+        .map(|word| word.to_string())
+        .collect()
+}
+
+/// Splits a line into a command and its argument, if it invokes one.
+/// Commands which take no argument are not invoked when given one, so the
+/// line is sent to the model as it is.
+fn parse_command(line: &str) -> Option<(&'static SlashCommand, &str)> {
+    let (name, argument) = line.split_once(' ').unwrap_or((line, ""));
+    let command = command(name)?;
+    let argument = argument.trim();
+
+    if command.validate.is_none() && !argument.is_empty() {
+        return None;
+    }
+
+    Some((command, argument))
+}
+
+/// Input gathered from the user
+pub(crate) enum Input {
+    /// A prompt for the model
+    Prompt(String),
+    /// Save the session
+    Save,
+    /// Show the token usage of the chat
+    Usage,
+    /// Export the transcript to a file
+    Export(PathBuf),
+    /// Show the last response in the pager
+    Expand,
+    /// Replace the last response with a version edited in the editor
+    EditResponse,
+    /// Copy a code block of the last response to the clipboard, the last
+    /// one unless it is numbered
+    Copy(Option<usize>),
+    /// Search the transcript, and the saved sessions if `sessions` is set
+    Find { query: String, sessions: bool },
+    /// List the turns of the conversation
+    History,
+    /// Pin or unpin a turn
+    Pin { turn: usize, pinned: bool },
+    /// Regenerate the last response
+    Retry,
+    /// Remove the last prompt and its response
+    Undo,
+    /// Switch to the named persona
+    Persona(String),
+    /// Replace the conversation with the saved session
+    Resume(String),
+}
+
+/// Settings for the line editor and the display of responses
+pub(crate) struct ReplConfig {
+    pub editor: Option<PathBuf>,
+    pub keybindings: config::Keybindings,
+    pub history: config::History,
+    pub templates: Vec<Template>,
+    /// Responses longer than this many lines are folded, zero disables folding
+    pub fold_lines: usize,
+    /// Keep the part of a response which was received before it was
+    /// cancelled in the conversation
+    pub keep_partial_responses: bool,
+    pub personas: BTreeMap<String, config::Persona>,
+    /// What is done when the conversation no longer fits in the context
+    pub context: config::Context,
+    /// The instructions and temperature when no persona is adopted
+    pub defaults: RequestDefaults,
+    /// Where files generated by the model are saved and whether images are previewed
+    pub files: config::Files,
+    /// The format of the clock in the right prompt, if it is shown
+    pub clock: Option<String>,
+    /// Show the time elapsed while a response is generated
+    pub timer: bool,
+    /// The least time between writes of a streamed response, in milliseconds
+    pub coalesce_ms: Option<u64>,
+}
+
 pub(crate) struct Repl {
     line_editor: Reedline,
     prompt: Prompt,
     tempfile: Tempfile,
     editor: Option<PathBuf>,
+    recent: RecentPrompts,
+    /// The history entry shown in the buffer, if the user moved past the
+    /// first row of the buffer into the history
+    history_position: Option<usize>,
+    /// The buffer as it was before the history was entered
+    draft: String,
+    /// The names of the configured personas
+    personas: Vec<String>,
+    /// Files to be sent with the next prompt
+    attachments: Vec<Attachment>,
+    /// Images to be sent with the next prompt
+    images: Vec<ImageAttachment>,
+    /// The protocol attached images are previewed with, if they are
+    graphics: Option<Protocol>,
 }
 
 impl Repl {
-    pub(crate) fn new(editor: Option<PathBuf>, keybindings: config::Keybindings) -> Repl {
-        let prompt = Prompt::default();
+    pub(crate) fn new(config: ReplConfig) -> Repl {
+        let ReplConfig {
+            editor,
+            keybindings,
+            history,
+            templates,
+            personas,
+            files,
+            clock,
+            ..
+        } = config;
+
+        let prompt = Prompt::new(clock);
 
         let tempfile =
             Tempfile::with_base_and_ext("msg", ".xtalk").expect("failed to create temporary file");
 
-        let commands = vec!["/edit".into(), "/exit".into(), "/clear".into()];
+        let mut completer = Box::new(DefaultCompleter::with_inclusions(&['/', '-']));
 
-        let mut completer = Box::new(DefaultCompleter::with_inclusions(&['/']));
-
-        completer.insert(commands);
+        completer.insert(completions());
 
         // Use the interactive menu to select options from the completer
         let completion_menu = Box::new(
@@ -173,6 +898,45 @@ impl Repl {
                 ),
         );
 
+        // Pinned prompts are listed above the history in its menu
+        let recent = RecentPrompts::default();
+
+        let history_menu = Box::new(
+            ListMenu::default()
+                .with_name("history_menu")
+                .with_marker(&completion_marker().to_string())
+                .with_text_style(Style::new().fg(Color::Default))
+                .with_selected_text_style(Style::new().fg(Color::Blue).on(Color::DarkGray)),
+        );
+
+        let history_completer = Box::new(HistoryCompleter::new(
+            history.pinned.clone(),
+            recent.clone(),
+        ));
+
+        // Selecting a template inserts it into the buffer
+        let template_menu = Box::new(
+            ListMenu::default()
+                .with_name("template_menu")
+                .with_marker(&completion_marker().to_string())
+                .with_max_entry_lines(3)
+                .with_text_style(Style::new().fg(Color::Default))
+                .with_selected_text_style(Style::new().fg(Color::Blue).on(Color::DarkGray)),
+        );
+
+        let template_completer = Box::new(TemplateCompleter::new(templates.clone()));
+
+        // Choosing an entry from the palette carries it out
+        let palette_menu = Box::new(
+            ListMenu::default()
+                .with_name("palette_menu")
+                .with_marker(&completion_marker().to_string())
+                .with_text_style(Style::new().fg(Color::Default))
+                .with_selected_text_style(Style::new().fg(Color::Blue).on(Color::DarkGray)),
+        );
+
+        let palette_completer = Box::new(PaletteCompleter::new(templates));
+
         // Set up the required keybindings
         let edit_mode = edit_mode(keybindings);
 
@@ -181,8 +945,26 @@ impl Repl {
         let line_editor = Reedline::create()
             .with_completer(completer)
             .with_menu(ReedlineMenu::EngineCompleter(completion_menu))
+            .with_menu(ReedlineMenu::WithCompleter {
+                menu: history_menu,
+                completer: history_completer,
+            })
+            .with_menu(ReedlineMenu::WithCompleter {
+                menu: template_menu,
+                completer: template_completer,
+            })
+            .with_menu(ReedlineMenu::WithCompleter {
+                menu: palette_menu,
+                completer: palette_completer,
+            })
             .with_edit_mode(edit_mode)
-            .with_highlighter(Box::new(Highlighter::default()));
+            .with_highlighter(Box::new(Highlighter::new(prompt.line_count())))
+            .use_bracketed_paste(true);
+
+        let line_editor = match file_history(&history) {
+            Some(file_history) => line_editor.with_history(file_history),
+            None => line_editor,
+        };
 
         let line_editor = if let Some(editor) = &editor {
             line_editor.with_buffer_editor(Command::new(editor), tempfile.path_buf().clone())
@@ -195,48 +977,134 @@ impl Repl {
             prompt,
             tempfile,
             editor,
+            recent,
+            history_position: None,
+            draft: String::new(),
+            personas: personas.into_keys().collect(),
+            attachments: Vec::new(),
+            images: Vec::new(),
+            graphics: Protocol::for_previews(&files),
         }
     }
 
-    pub(crate) fn edit(&mut self, msg_buf: &mut MessageBuffer) -> Option<String> {
+    /// Attaches a file to the next prompt
+    pub(crate) fn attach(&mut self, attachment: Attachment) {
+        self.attachments.push(attachment);
+    }
+
+    /// Takes the files which were attached since the last prompt
+    pub(crate) fn take_attachments(&mut self) -> Vec<Attachment> {
+        std::mem::take(&mut self.attachments)
+    }
+
+    /// Attaches an image to the next prompt, previewing it so the user can
+    /// confirm the right image was attached
+    pub(crate) fn attach_image(&mut self, image: ImageAttachment) {
+        if let Some(preview) = self.graphics.and_then(|p| p.preview_image(&image.image)) {
+            println!("{}", preview);
+        }
+
+        self.images.push(image);
+    }
+
+    /// Takes the images which were attached since the last prompt
+    pub(crate) fn take_images(&mut self) -> Vec<ImageAttachment> {
+        std::mem::take(&mut self.images)
+    }
+
+    /// Opens the text in the editor, returning it as it was saved. Returns
+    /// `None` if no editor is specified.
+    pub(crate) fn edit_text(&mut self, text: &str) -> Option<String> {
+        let editor = self.editor.as_ref()?;
+
+        Some(read_from_interactive_editor(editor, &self.tempfile, text))
+    }
+
+    /// Replaces the contents of the buffer, leaving the cursor at the end
+    fn replace_buffer(&mut self, contents: String) {
+        self.line_editor
+            .run_edit_commands(&[EditCommand::Clear, EditCommand::InsertString(contents)]);
+    }
+
+    /// Moves the cursor to the adjacent row, as displayed in the terminal.
+    /// Past the first or last row, the history is traversed instead.
+    fn move_vertically(&mut self, up: bool) {
+        let width = crossterm::terminal::size()
+            .map(|(columns, _)| columns as usize)
+            .unwrap_or(80);
+
+        let target = softwrap::move_vertically(
+            self.line_editor.current_buffer_contents(),
+            self.line_editor.current_insertion_point(),
+            up,
+            prompt_width(),
+            multiline_prompt_width(),
+            width,
+        );
+
+        if let Some(position) = target {
+            self.line_editor
+                .run_edit_commands(&[EditCommand::MoveToPosition {
+                    position,
+                    select: false,
+                }]);
+
+            return;
+        }
+
+        match (up, self.history_position) {
+            (true, None) => {
+                if let Some(entry) = self.recent.get(0) {
+                    self.draft = self.line_editor.current_buffer_contents().to_string();
+                    self.history_position = Some(0);
+                    self.replace_buffer(entry);
+                }
+            }
+            (true, Some(position)) => {
+                if let Some(entry) = self.recent.get(position + 1) {
+                    self.history_position = Some(position + 1);
+                    self.replace_buffer(entry);
+                }
+            }
+            (false, Some(0)) => {
+                let draft = std::mem::take(&mut self.draft);
+                self.history_position = None;
+                self.replace_buffer(draft);
+            }
+            (false, Some(position)) => {
+                if let Some(entry) = self.recent.get(position - 1) {
+                    self.history_position = Some(position - 1);
+                    self.replace_buffer(entry);
+                }
+            }
+            (false, None) => {}
+        }
+    }
+
+    pub(crate) fn edit(&mut self, msg_buf: &mut MessageBuffer) -> Option<Input> {
         loop {
+            self.recent.refresh(self.line_editor.history());
+
             let sig = self.line_editor.read_line(&self.prompt);
 
-            match sig {
-                Ok(Signal::Success(command)) => {
-                    let command_msg = Message::command(command.clone());
-                    msg_buf.add_message(command_msg);
-
-                    match command.as_str() {
-                        "/exit" => break,
-                        "/edit" => {
-                            let editor = match self.editor.as_ref() {
-                                Some(editor) => editor,
-                                None => {
-                                    let warning = Message::warn("no editor specified".to_string());
-                                    eprintln!("{}", warning);
-                                    msg_buf.add_message(warning);
-                                    continue;
-                                }
-                            };
-
-                            let buffer = read_from_interactive_editor(editor, &mut self.tempfile);
-
-                            if buffer.is_empty() {
-                                continue;
-                            }
-
-                            println!("{}", buffer);
-
-                            return Some(buffer);
-                        }
-                        "/clear" => {
-                            msg_buf.clear();
-                            continue;
-                        }
-                        _ => return Some(command),
-                    };
+            let command = match sig {
+                Ok(Signal::Success(command))
+                    if command == softwrap::UP || command == softwrap::DOWN =>
+                {
+                    self.move_vertically(command == softwrap::UP);
+                    continue;
+                }
+                Ok(Signal::Success(command)) if command == palette::SELECTED => {
+                    match self.take_palette_entry() {
+                        Some(entry) => entry,
+                        None => continue,
+                    }
                 }
+                // Entries chosen from the palette are submitted with Enter in vi mode
+                Ok(Signal::Success(command)) => match palette::chosen(&command) {
+                    Some(entry) => entry.to_string(),
+                    None => command,
+                },
                 Ok(Signal::CtrlD) => {
                     break;
                 }
@@ -244,9 +1112,88 @@ impl Repl {
                     continue;
                 }
                 _ => break,
+            };
+
+            self.history_position = None;
+
+            let command_msg = Message::command(command.clone());
+            msg_buf.add_message(command_msg);
+
+            let action = match parse_command(&command) {
+                Some((slash, argument)) => (slash.handler)(self, argument, msg_buf),
+                None => Action::Return(Input::Prompt(command)),
+            };
+
+            match action {
+                Action::Continue => continue,
+                Action::Show(msg) => {
+                    eprintln!("{}", msg);
+                    msg_buf.add_message(msg);
+                    continue;
+                }
+                Action::Return(input) => return Some(input),
+                Action::Exit => break,
             }
         }
 
         None
     }
+
+    /// Takes the entry which was chosen from the palette out of the buffer,
+    /// echoing it as though it had been submitted. Returns `None`, leaving
+    /// the buffer as it is, if no entry was chosen.
+    fn take_palette_entry(&mut self) -> Option<String> {
+        let buffer = self.line_editor.current_buffer_contents();
+        let entry = palette::chosen(buffer)?.to_string();
+
+        let width = crossterm::terminal::size()
+            .map(|(columns, _)| columns as usize)
+            .unwrap_or(80);
+
+        let row = softwrap::cursor_row(
+            buffer,
+            self.line_editor.current_insertion_point(),
+            prompt_width(),
+            multiline_prompt_width(),
+            width,
+        );
+
+        self.replace_buffer(String::new());
+
+        // The line editor leaves the buffer and the menu on the screen, so
+        // they are erased from the row of the prompt down
+        if row > 0 {
+            print!("\x1b[{}A", row);
+        }
+
+        println!(
+            "\r\x1b[J{}{}",
+            user_prompt(),
+            entry.replace('\n', &format!("\n{}", multiline_prompt()))
+        );
+
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        let name = |line| parse_command(line).map(|(command, argument)| (command.name, argument));
+
+        assert_eq!(name("/save"), Some(("/save", "")));
+        assert_eq!(name("/export  notes.md "), Some(("/export", "notes.md")));
+        assert_eq!(
+            name("/find --sessions makefile"),
+            Some(("/find", "--sessions makefile"))
+        );
+
+        // Commands without an argument and unknown commands are prompts
+        assert_eq!(name("/save the whales"), None);
+        assert_eq!(name("/unknown"), None);
+        assert_eq!(name("hello"), None);
+    }
 }
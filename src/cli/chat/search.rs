@@ -0,0 +1,173 @@
+//! Search of the transcript for `/find` and `xtalk history search`
+//!
+//! Messages are numbered by turn, as they are listed by `/history`. Each
+//! line of a message which contains the query, ignoring case, is reported
+//! with an excerpt around the first occurrence of the query in the line.
+
+use std::fmt;
+
+use nu_ansi_term::Style;
+
+use crate::chat::{self, Role};
+
+use super::turns::turns;
+
+/// The number of characters shown before the query in an excerpt
+const EXCERPT_BEFORE: usize = 30;
+
+/// The number of characters shown from the start of the query in an excerpt
+const EXCERPT_AFTER: usize = 60;
+
+pub(crate) struct Match {
+    pub turn: usize,
+    pub role: Role,
+    pub excerpt: String,
+}
+
+impl fmt::Display for Match {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let role = match self.role {
+            Role::User => "user",
+            Role::Model => "model",
+            Role::System => "system",
+        };
+
+        write!(f, "#{} {}: {}", self.turn, role, self.excerpt)
+    }
+}
+
+/// Finds the byte offset of `needle` in `haystack`, ignoring case
+fn find_ignore_case(haystack: &str, needle: &str) -> Option<usize> {
+    let needle: Vec<char> = needle.chars().flat_map(char::to_lowercase).collect();
+
+    haystack.char_indices().map(|(i, _)| i).find(|&i| {
+        let mut rest = haystack[i..].chars().flat_map(char::to_lowercase);
+
+        needle.iter().all(|c| rest.next() == Some(*c))
+    })
+}
+
+/// Paints each occurrence of `query` in the text, ignoring case. The caller
+/// passes a plain style when color is disabled.
+pub(crate) fn highlight(text: &str, query: &str, style: Style) -> String {
+    let length = query.chars().count();
+
+    let mut highlighted = String::new();
+    let mut rest = text;
+
+    if length == 0 {
+        return text.to_string();
+    }
+
+    while let Some(at) = find_ignore_case(rest, query) {
+        let end = rest[at..]
+            .char_indices()
+            .nth(length)
+            .map_or(rest.len(), |(i, _)| at + i);
+
+        highlighted.push_str(&rest[..at]);
+        highlighted.push_str(&style.paint(&rest[at..end]).to_string());
+
+        rest = &rest[end..];
+    }
+
+    highlighted.push_str(rest);
+
+    highlighted
+}
+
+/// Shortens a line to the text surrounding the byte offset `at`
+pub(crate) fn excerpt(line: &str, at: usize) -> String {
+    let before: Vec<char> = line[..at].chars().collect();
+    let after: Vec<char> = line[at..].chars().collect();
+
+    let start = before.len().saturating_sub(EXCERPT_BEFORE);
+    let end = after.len().min(EXCERPT_AFTER);
+
+    let mut excerpt = String::new();
+
+    if start > 0 {
+        excerpt.push('…');
+    }
+
+    excerpt.extend(&before[start..]);
+    excerpt.extend(&after[..end]);
+
+    if end < after.len() {
+        excerpt.push('…');
+    }
+
+    excerpt
+}
+
+/// Searches the messages for lines which contain `query`
+pub(crate) fn search<'a>(
+    messages: impl IntoIterator<Item = &'a chat::Message>,
+    query: &str,
+) -> Vec<Match> {
+    let mut matches = Vec::new();
+
+    for (turn, message) in turns(messages) {
+        for line in message.content.lines() {
+            if let Some(at) = find_ignore_case(line, query) {
+                matches.push(Match {
+                    turn,
+                    role: message.role.clone(),
+                    excerpt: excerpt(line.trim_end(), at),
+                });
+            }
+        }
+    }
+
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search() {
+        let messages = [
+            chat::Message::new(Role::System, "The terminal shows a Makefile".to_string()),
+            chat::Message::new(Role::User, "What does the Makefile do?".to_string()),
+            chat::Message::new(
+                Role::Model,
+                format!(
+                    "It builds the project.\n{}the makefile also runs the tests{}",
+                    "x".repeat(40),
+                    "y".repeat(40)
+                ),
+            ),
+        ];
+
+        let matches: Vec<String> = search(&messages, "MAKEFILE")
+            .iter()
+            .map(|m| m.to_string())
+            .collect();
+
+        assert_eq!(
+            matches,
+            vec![
+                "#1 user: What does the Makefile do?".to_string(),
+                format!(
+                    "#2 model: …{}the makefile also runs the tests{}…",
+                    "x".repeat(26),
+                    "y".repeat(32)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_highlight() {
+        let style = Style::new().bold();
+
+        assert_eq!(
+            highlight("Make the makefile", "MAKE", style),
+            format!("{} the {}file", style.paint("Make"), style.paint("make"))
+        );
+        assert_eq!(highlight("no match", "make", style), "no match");
+        assert_eq!(highlight("Make", "make", Style::new()), "Make");
+    }
+}
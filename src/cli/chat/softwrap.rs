@@ -0,0 +1,149 @@
+//! Cursor movement across soft-wrapped rows
+//!
+//! The line editor moves the cursor between the lines of the buffer, but a
+//! long line which the terminal wraps onto several rows is a single line to
+//! it, so the up and down keys would jump straight into the history. Instead,
+//! the keys are bound to host commands and the REPL moves the cursor to the
+//! same column of the adjacent row, as it is displayed.
+
+use unicode_width::UnicodeWidthChar;
+
+/// The host command sent when the cursor should move up a row
+pub(crate) const UP: &str = "\u{0}soft-wrap-up";
+
+/// The host command sent when the cursor should move down a row
+pub(crate) const DOWN: &str = "\u{0}soft-wrap-down";
+
+/// A position in the buffer at which the cursor may be placed
+struct Cell {
+    offset: usize,
+    row: usize,
+    column: usize,
+}
+
+/// Lays out the buffer as the terminal displays it. The first line follows
+/// the prompt, subsequent lines follow the continuation prompt, and rows
+/// which wrap start at the edge of the terminal.
+fn layout(buffer: &str, prompt_width: usize, indent: usize, width: usize) -> Vec<Cell> {
+    let mut cells = Vec::new();
+    let mut row = 0;
+    let mut column = prompt_width;
+
+    for (offset, c) in buffer.char_indices() {
+        if c == '\n' {
+            cells.push(Cell {
+                offset,
+                row,
+                column,
+            });
+
+            row += 1;
+            column = indent;
+
+            continue;
+        }
+
+        let char_width = c.width().unwrap_or(0);
+
+        if column + char_width > width {
+            row += 1;
+            column = 0;
+        }
+
+        cells.push(Cell {
+            offset,
+            row,
+            column,
+        });
+
+        column += char_width;
+    }
+
+    cells.push(Cell {
+        offset: buffer.len(),
+        row,
+        column,
+    });
+
+    cells
+}
+
+/// Finds the offset in the row above or below the cursor which is displayed
+/// closest to the cursor's column. Returns `None` if the cursor is already
+/// in the first or last row.
+pub(crate) fn move_vertically(
+    buffer: &str,
+    cursor: usize,
+    up: bool,
+    prompt_width: usize,
+    indent: usize,
+    width: usize,
+) -> Option<usize> {
+    let cells = layout(buffer, prompt_width, indent, width.max(1));
+
+    let current = cells.iter().find(|cell| cell.offset >= cursor)?;
+
+    let row = if up {
+        current.row.checked_sub(1)?
+    } else {
+        current.row + 1
+    };
+
+    let mut candidates = cells.iter().filter(|cell| cell.row == row).peekable();
+
+    let first = candidates.peek()?.offset;
+
+    let target = candidates
+        .take_while(|cell| cell.column <= current.column)
+        .last()
+        .map_or(first, |cell| cell.offset);
+
+    Some(target)
+}
+
+/// The row of the buffer which the cursor is displayed in, counting from
+/// the row of the prompt
+pub(crate) fn cursor_row(
+    buffer: &str,
+    cursor: usize,
+    prompt_width: usize,
+    indent: usize,
+    width: usize,
+) -> usize {
+    layout(buffer, prompt_width, indent, width.max(1))
+        .iter()
+        .find(|cell| cell.offset >= cursor)
+        .map_or(0, |cell| cell.row)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_move_vertically() {
+        // Displayed in a terminal ten columns wide after a four column prompt:
+        //
+        // [#] abcdef
+        // ghijklmnop
+        // qr
+        // ::: st
+        let buffer = "abcdefghijklmnopqr\nst";
+
+        let move_to = |cursor, up| move_vertically(buffer, cursor, up, 4, 4, 10);
+
+        // From "m" up to "c", and back down
+        assert_eq!(move_to(12, true), Some(2));
+        assert_eq!(move_to(2, false), Some(12));
+        // From "a" up is the first row
+        assert_eq!(move_to(0, true), None);
+        // From "g" up, the column is left of the first row
+        assert_eq!(move_to(6, true), Some(0));
+        // From "p" down, the last wrapped row is shorter
+        assert_eq!(move_to(15, false), Some(18));
+        // From "r" down into the next line
+        assert_eq!(move_to(17, false), Some(19));
+        // From the end of the buffer down is the last row
+        assert_eq!(move_to(buffer.len(), false), None);
+    }
+}
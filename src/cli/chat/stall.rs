@@ -0,0 +1,66 @@
+//! Detection of responses which have stopped arriving
+//!
+//! While a response streams in, an indicator is shown after the model has
+//! gone quiet for a while, so a stalled connection can be told apart from a
+//! model which is still thinking. Chunks of the response and heartbeats sent
+//! by the server reset the timer and erase the indicator.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::select;
+use tokio::sync::Notify;
+
+use crate::color::{self, MaybePaint};
+use crate::providers::HeartbeatHook;
+
+/// How long a response may go without chunks or heartbeats before it is
+/// considered to have stalled
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+const INDICATOR: &str = " [waiting for the model…]";
+
+pub(crate) struct StallDetector {
+    heartbeats: Arc<Notify>,
+    shown: bool,
+}
+
+impl StallDetector {
+    pub(crate) fn new() -> StallDetector {
+        StallDetector {
+            heartbeats: Arc::new(Notify::new()),
+            shown: false,
+        }
+    }
+
+    /// A hook which reports the heartbeats of a completion to the detector
+    pub(crate) fn hook(&self) -> HeartbeatHook {
+        let heartbeats = Arc::clone(&self.heartbeats);
+
+        Box::new(move || heartbeats.notify_one())
+    }
+
+    /// Shows the indicator whenever the response stalls. This never
+    /// completes; it is meant to be raced against the next chunk, which
+    /// restarts the timer.
+    pub(crate) async fn watch(&mut self) {
+        loop {
+            select! {
+                _ = tokio::time::sleep(STALL_TIMEOUT), if !self.shown => {
+                    eprint!("{}", color::FOLD_MARKER.maybe_paint(INDICATOR));
+                    self.shown = true;
+                }
+                _ = self.heartbeats.notified() => self.erase(),
+            }
+        }
+    }
+
+    /// Erases the indicator, if it is shown, so the response continues
+    /// where it left off
+    pub(crate) fn erase(&mut self) {
+        if self.shown {
+            eprint!("\x1b[{}D\x1b[K", INDICATOR.chars().count());
+            self.shown = false;
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! Statistics reported after a completion finishes
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::providers::{LoadedModel, Usage};
+use crate::sessions::SessionMessage;
+use crate::utils::format::human_bytes;
+
+fn fmt_tokens(tokens: Option<usize>) -> String {
+    match tokens {
+        Some(n) => n.to_string(),
+        None => "?".to_string(),
+    }
+}
+
+pub(crate) struct ResponseStats {
+    pub elapsed: Duration,
+    pub usage: Usage,
+    /// Runtime state of the model which served the response, if the
+    /// provider runs models locally.
+    pub loaded_model: Option<LoadedModel>,
+}
+
+impl fmt::Display for ResponseStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.elapsed.as_secs_f64();
+
+        write!(
+            f,
+            "tokens: {} prompt, {} completion | {:.2}s",
+            fmt_tokens(self.usage.prompt_tokens),
+            fmt_tokens(self.usage.completion_tokens),
+            seconds
+        )?;
+
+        if let Some(n) = self.usage.completion_tokens {
+            if seconds > 0.0 {
+                write!(f, " ({:.1} tokens/s)", n as f64 / seconds)?;
+            }
+        }
+
+        if let Some(model) = &self.loaded_model {
+            write!(
+                f,
+                " | memory: {} ({} VRAM), {}",
+                human_bytes(model.size),
+                human_bytes(model.size_vram),
+                model.processor()
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The token usage of each response in the chat
+#[derive(Default)]
+pub(crate) struct UsageLog {
+    responses: Vec<(String, Usage)>,
+}
+
+impl UsageLog {
+    /// Recovers the usage of responses saved with a session. Responses saved
+    /// before usage was recorded are not counted.
+    pub(crate) fn from_messages(messages: &[SessionMessage]) -> UsageLog {
+        let responses = messages
+            .iter()
+            .filter_map(|msg| Some((msg.model_id.clone()?, msg.response.usage.clone()?)))
+            .collect();
+
+        UsageLog { responses }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.responses.is_empty()
+    }
+
+    pub(crate) fn record(&mut self, model_id: &str, usage: Usage) {
+        self.responses.push((model_id.to_string(), usage));
+    }
+
+    /// The total number of prompt and completion tokens. Responses for
+    /// which the provider did not report usage are not counted.
+    pub(crate) fn totals(&self) -> (usize, usize) {
+        self.responses
+            .iter()
+            .fold((0, 0), |(prompt, completion), (_, usage)| {
+                (
+                    prompt + usage.prompt_tokens.unwrap_or(0),
+                    completion + usage.completion_tokens.unwrap_or(0),
+                )
+            })
+    }
+
+    /// Summarizes the usage of the last response along with the totals
+    pub(crate) fn summary(&self) -> String {
+        let (prompt, completion) = self.totals();
+
+        match self.responses.last() {
+            Some((_, usage)) => format!(
+                "tokens: {} prompt, {} completion | total: {} prompt, {} completion",
+                fmt_tokens(usage.prompt_tokens),
+                fmt_tokens(usage.completion_tokens),
+                prompt,
+                completion
+            ),
+            None => "no responses have been generated".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for UsageLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.responses.is_empty() {
+            return write!(f, "no responses have been generated");
+        }
+
+        for (i, (model_id, usage)) in self.responses.iter().enumerate() {
+            writeln!(
+                f,
+                "#{} {}: {} prompt, {} completion",
+                i + 1,
+                model_id,
+                fmt_tokens(usage.prompt_tokens),
+                fmt_tokens(usage.completion_tokens)
+            )?;
+        }
+
+        let (prompt, completion) = self.totals();
+
+        write!(f, "total: {} prompt, {} completion", prompt, completion)
+    }
+}
@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+
+use reedline::{Completer, Span, Suggestion};
+
+use crate::templates::{self, Template};
+use crate::warn;
+
+/// Completes the line with rendered prompt templates whose name or
+/// description contains the text typed since the menu was opened
+pub(crate) struct TemplateCompleter {
+    /// The label and template of each template which can be rendered
+    templates: Vec<(String, Template)>,
+}
+
+impl TemplateCompleter {
+    pub(crate) fn new(templates: Vec<Template>) -> TemplateCompleter {
+        let vars = HashMap::new();
+
+        let templates = templates
+            .into_iter()
+            .filter_map(|template| match template.render(&vars) {
+                Ok(_) => {
+                    let label = match &template.description {
+                        Some(description) => format!("{}: {}", template.name, description),
+                        None => template.name.clone(),
+                    };
+
+                    Some((label, template))
+                }
+                Err(err) => {
+                    warn!("skipping the \"{}\" template: {}", template.name, err);
+                    None
+                }
+            })
+            .collect();
+
+        TemplateCompleter { templates }
+    }
+}
+
+impl Completer for TemplateCompleter {
+    fn complete(&mut self, line: &str, _pos: usize) -> Vec<Suggestion> {
+        // Rendered as the menu is shown, so the date and time are current
+        let vars = templates::builtin_vars();
+
+        self.templates
+            .iter()
+            .filter(|(label, _)| label.contains(line.trim()))
+            .filter_map(|(label, template)| Some((label, template.render(&vars).ok()?)))
+            .map(|(label, rendered)| Suggestion {
+                value: rendered,
+                description: Some(label.clone()),
+                style: None,
+                extra: None,
+                span: Span::new(0, line.len()),
+                append_whitespace: false,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,184 @@
+//! The time elapsed while a response is generated
+//!
+//! While a response streams in, the time since the request was sent is shown
+//! at the right edge of the row the response is printed on, where the right
+//! prompt is shown while typing. The column the response has reached is
+//! tracked from the text printed, so the timer is only drawn where it does
+//! not cover the response, and it is erased before text which would reach
+//! it or move onto another row is printed.
+
+use std::time::{Duration, Instant};
+
+use unicode_width::UnicodeWidthChar;
+
+use crate::color::{self, MaybePaint};
+
+/// How often the timer is redrawn
+const TICK: Duration = Duration::from_millis(100);
+
+/// The columns kept free between the response and the timer
+const MARGIN: usize = 2;
+
+pub(crate) struct GenerationTimer {
+    started: Instant,
+    /// The column the response has reached on the current row
+    column: usize,
+    /// The column the timer is drawn at and the text drawn, if it is shown
+    shown: Option<(usize, String)>,
+}
+
+/// Formats the elapsed time to a tenth of a second, with minutes once it
+/// exceeds a minute
+fn format_elapsed(elapsed: Duration) -> String {
+    let tenths = elapsed.as_millis() / 100;
+
+    let (minutes, seconds, tenths) = (tenths / 600, tenths / 10 % 60, tenths % 10);
+
+    if minutes > 0 {
+        format!("{}m{:02}.{}s", minutes, seconds, tenths)
+    } else {
+        format!("{}.{}s", seconds, tenths)
+    }
+}
+
+/// Finds the column of the cursor after the text is printed from `column`
+/// in a terminal `width` columns wide. Escape sequences take no space.
+fn advance(mut column: usize, text: &str, width: usize) -> usize {
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' | '\r' => column = 0,
+            '\t' => column = (column / 8 + 1) * 8,
+            '\x1b' => {
+                // Only control sequences, such as colors, are printed
+                if chars.next() == Some('[') {
+                    for c in chars.by_ref() {
+                        if ('\x40'..='\x7e').contains(&c) {
+                            break;
+                        }
+                    }
+                }
+            }
+            c => {
+                let char_width = c.width().unwrap_or(0);
+
+                if column + char_width > width {
+                    column = 0;
+                }
+
+                column += char_width;
+            }
+        }
+    }
+
+    column
+}
+
+fn terminal_width() -> usize {
+    crossterm::terminal::size()
+        .map(|(columns, _)| columns as usize)
+        .unwrap_or(80)
+}
+
+impl GenerationTimer {
+    /// Times a response from `started`
+    pub(crate) fn new(started: Instant) -> GenerationTimer {
+        GenerationTimer {
+            started,
+            column: 0,
+            shown: None,
+        }
+    }
+
+    /// Redraws the timer every tick. This never completes; it is meant to be
+    /// raced against the next chunk of the response.
+    pub(crate) async fn watch(&mut self) {
+        loop {
+            // Ticks fall on tenths of a second since the request was sent,
+            // so chunks which arrive in between do not delay them
+            let elapsed = self.started.elapsed();
+            let next = TICK - Duration::from_nanos((elapsed.as_nanos() % TICK.as_nanos()) as u64);
+
+            tokio::time::sleep(next).await;
+
+            self.draw();
+        }
+    }
+
+    /// Draws the timer at the right edge of the current row, if the
+    /// response leaves room for it
+    fn draw(&mut self) {
+        let width = terminal_width();
+
+        let text = format_elapsed(self.started.elapsed());
+        let at = width.saturating_sub(text.len());
+
+        if self.column + MARGIN > at {
+            self.erase();
+            return;
+        }
+
+        if self.shown.as_ref() == Some(&(at, text.clone())) {
+            return;
+        }
+
+        eprint!(
+            "\x1b7\x1b[{}G\x1b[K{}\x1b8",
+            at + 1,
+            color::CLOCK.maybe_paint(&text)
+        );
+
+        self.shown = Some((at, text));
+    }
+
+    /// Erases the timer, if it is shown
+    pub(crate) fn erase(&mut self) {
+        if let Some((at, _)) = self.shown.take() {
+            eprint!("\x1b7\x1b[{}G\x1b[K\x1b8", at + 1);
+        }
+    }
+
+    /// Prepares for the text to be printed, erasing the timer if the text
+    /// would reach it or leave its row
+    pub(crate) fn before_print(&mut self, text: &str) {
+        let Some((at, _)) = &self.shown else {
+            return;
+        };
+
+        let column = advance(self.column, text, terminal_width());
+
+        if text.contains(['\n', '\r']) || column < self.column || column + MARGIN > *at {
+            self.erase();
+        }
+    }
+
+    /// Notes the text which was printed, redrawing the timer at once if it
+    /// was erased so that it does not flicker
+    pub(crate) fn after_print(&mut self, text: &str) {
+        self.column = advance(self.column, text, terminal_width());
+
+        if self.shown.is_none() {
+            self.draw();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_elapsed() {
+        assert_eq!(format_elapsed(Duration::from_millis(3450)), "3.4s");
+        assert_eq!(format_elapsed(Duration::from_millis(65_100)), "1m05.1s");
+    }
+
+    #[test]
+    fn test_advance() {
+        assert_eq!(advance(4, "\x1b[1;32mfn\x1b[0m main", 80), 11);
+        assert_eq!(advance(4, "one\ntwo", 80), 3);
+        assert_eq!(advance(0, &"x".repeat(85), 80), 5);
+        assert_eq!(advance(78, "日本", 80), 2);
+    }
+}
@@ -0,0 +1,45 @@
+//! Capture the contents of a tmux pane so it can be provided as context
+
+use std::io::{self, ErrorKind};
+use std::process::Command;
+
+/// Captures the visible contents of a tmux pane. If `target` is not
+/// specified, the pane from which xtalk was launched is captured.
+pub(crate) fn capture_pane(target: Option<&str>) -> io::Result<String> {
+    if target.is_none() && std::env::var_os("TMUX").is_none() {
+        return Err(io::Error::new(
+            ErrorKind::NotFound,
+            "xtalk is not running inside of tmux and no target pane was specified",
+        ));
+    }
+
+    let mut command = Command::new("tmux");
+
+    // Print the pane to standard output, joining wrapped lines
+    command.args(["capture-pane", "-p", "-J"]);
+
+    if let Some(target) = target {
+        command.args(["-t", target]);
+    }
+
+    let output = command.output()?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        return Err(io::Error::other(stderr.trim().to_string()));
+    }
+
+    let capture = String::from_utf8_lossy(&output.stdout);
+
+    // Empty lines at the bottom of the pane are not meaningful
+    Ok(capture.trim_end().to_string())
+}
+
+/// Formats a pane capture as a message which is provided to the model as context
+pub(crate) fn pane_context(capture: &str) -> String {
+    format!(
+        "The following is the current contents of the user's terminal:\n```\n{}\n```",
+        capture
+    )
+}
@@ -0,0 +1,458 @@
+//! Trimming of conversations which outgrow the context of the model
+//!
+//! Providers which manage the context explicitly reject requests which do
+//! not fit in the context of the model. Before a request is sent, its size is
+//! estimated and compared with the context length of the model, if it is
+//! known. When the estimate exceeds the context, or the provider reports that
+//! it was exceeded, the oldest exchange which is not pinned is removed from
+//! the conversation. With the `summarize` strategy, the model is first asked
+//! to summarize the exchange, and the summary takes its place as a system
+//! message. The prompt being answered is never removed.
+//!
+//! Summaries can be written by a cheaper model than the one chatted with.
+//! They can also be written before the conversation outgrows the context:
+//! once it nears the context length, the older exchanges are summarized
+//! together, so the conversation is not summarized again on every turn.
+
+use crate::chat::{self, Role};
+use crate::config::Trimming;
+use crate::providers::{self, ChatProvider, CompletionOptions};
+
+use super::{Message, MessageBuffer};
+
+/// The approximate number of characters in a token of English text
+pub(crate) const CHARS_PER_TOKEN: usize = 4;
+
+/// The approximate number of tokens used to delimit each message
+const TOKENS_PER_MESSAGE: usize = 4;
+
+const SUMMARY_INSTRUCTIONS: &str = "Summarize the following excerpt of a conversation in a few sentences. Keep the facts, decisions, and open questions which later messages may rely on.";
+
+/// Introduces a summary which stands in for removed messages
+const SUMMARY_PREFIX: &str = "Summary of an earlier part of the conversation:\n\n";
+
+/// Estimates the number of tokens in the messages. This errs on the side of
+/// overestimating for English text, but tokenizers vary between models.
+pub(crate) fn estimate_tokens(messages: &[chat::Message]) -> usize {
+    messages
+        .iter()
+        .map(|msg| msg.content.chars().count().div_ceil(CHARS_PER_TOKEN) + TOKENS_PER_MESSAGE)
+        .sum()
+}
+
+/// Looks up the context length of the model, if the provider knows it
+pub(crate) async fn context_length(provider: &dyn ChatProvider, model_id: &str) -> Option<usize> {
+    let models = provider.models().await.ok()?;
+
+    models
+        .into_iter()
+        .find(|model| model.id == model_id)
+        .and_then(|model| model.context_length)
+        .map(|context_length| context_length as usize)
+}
+
+/// Writes the messages out as a transcript to be summarized
+pub(crate) fn transcript(messages: &[chat::Message]) -> String {
+    messages
+        .iter()
+        .map(|msg| {
+            let role = match msg.role {
+                Role::User => "user",
+                Role::Model => "model",
+                Role::System => "system",
+            };
+
+            format!("{}: {}", role, msg.content)
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// The model which summarizes the exchanges removed from the conversation
+pub(crate) struct Summarizer<'a> {
+    pub provider: &'a dyn ChatProvider,
+    pub model_id: &'a str,
+}
+
+/// Asks the model to summarize the messages
+async fn summarize(
+    summarizer: &Summarizer<'_>,
+    messages: &[chat::Message],
+) -> Result<String, providers::Error> {
+    let request = [
+        chat::Message::new(Role::System, SUMMARY_INSTRUCTIONS.to_string()),
+        chat::Message::new(Role::User, transcript(messages)),
+    ];
+
+    let mut completion = summarizer
+        .provider
+        .stream_completion(summarizer.model_id, &request, &CompletionOptions::default())
+        .await?;
+
+    let mut summary = String::new();
+
+    while let Some(delta) = completion.next().await {
+        summary.push_str(&delta?.content);
+    }
+
+    Ok(summary)
+}
+
+/// Puts a summary of the messages removed at `position` in their place
+async fn replace_with_summary(
+    summarizer: &Summarizer<'_>,
+    msg_buf: &mut MessageBuffer,
+    position: usize,
+    removed: Vec<chat::Message>,
+) -> Result<(), providers::Error> {
+    // The previous summary is folded into the new one, so the summary does
+    // not grow with each exchange which is removed
+    let previous = msg_buf.remove_system_message(SUMMARY_PREFIX);
+
+    let (position, messages) = match previous {
+        Some((previous_position, summary)) => (
+            previous_position.min(position),
+            [vec![summary], removed].concat(),
+        ),
+        None => (position, removed),
+    };
+
+    let summary = summarize(summarizer, &messages).await?;
+
+    msg_buf.insert_message(
+        position,
+        Message::system(format!("{}{}", SUMMARY_PREFIX, summary.trim())),
+    );
+
+    Ok(())
+}
+
+/// Removes the oldest exchange which is not pinned, summarizing it if the
+/// strategy calls for it. Returns `false` if nothing could be removed.
+pub(crate) async fn trim(
+    summarizer: &Summarizer<'_>,
+    msg_buf: &mut MessageBuffer,
+    trimming: Trimming,
+) -> bool {
+    let (position, removed) = match msg_buf.remove_oldest_exchange() {
+        Some(exchange) => exchange,
+        None => return false,
+    };
+
+    if let Trimming::Summarize = trimming {
+        // The exchange is dropped all the same, so the request can proceed
+        if let Err(err) = replace_with_summary(summarizer, msg_buf, position, removed).await {
+            crate::warn!("failed to summarize the removed messages: {}", err);
+        }
+    }
+
+    true
+}
+
+/// Summarizes the oldest exchanges which are not pinned together, until the
+/// conversation is estimated to take at most `target` tokens. Since the
+/// conversation still fits, it is left as it is if the summary cannot be
+/// written. Returns the number of exchanges summarized.
+pub(crate) async fn summarize_oldest(
+    summarizer: &Summarizer<'_>,
+    msg_buf: &mut MessageBuffer,
+    estimate: impl Fn(&MessageBuffer) -> usize,
+    target: usize,
+) -> usize {
+    let mut summarized = msg_buf.clone();
+
+    let mut position = None;
+    let mut removed = Vec::new();
+    let mut exchanges = 0;
+
+    while estimate(&summarized) > target {
+        let Some((at, messages)) = summarized.remove_oldest_exchange() else {
+            break;
+        };
+
+        position = Some(position.map_or(at, |position: usize| position.min(at)));
+        removed.extend(messages);
+        exchanges += 1;
+    }
+
+    let Some(position) = position else {
+        return 0;
+    };
+
+    match replace_with_summary(summarizer, &mut summarized, position, removed).await {
+        Ok(()) => {
+            *msg_buf = summarized;
+
+            exchanges
+        }
+        Err(err) => {
+            crate::warn!("failed to summarize the older exchanges: {}", err);
+
+            0
+        }
+    }
+}
+
+/// Tells the user how many exchanges were removed
+pub(crate) fn trimmed_warning(exchanges: usize, trimming: Trimming) -> Message {
+    let action = match trimming {
+        Trimming::Summarize => "summarized",
+        _ => "removed",
+    };
+
+    Message::warn(format!(
+        "{} the oldest {} of the conversation to fit the context of the model",
+        action,
+        if exchanges == 1 {
+            "exchange".to_string()
+        } else {
+            format!("{} exchanges", exchanges)
+        }
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::providers::providers::ProviderIdentifier;
+    use crate::providers::{
+        AsyncMessageIterator, Completion, CompletionOption, ContextManagement, Error, ErrorKind,
+        FinishReason, LoadedModel, MessageDelta, Model, Usage,
+    };
+
+    /// Summarizes every transcript as "summary", or fails if `fails` is set,
+    /// recording the transcripts it is asked to summarize
+    struct FakeSummarizer {
+        fails: bool,
+        transcripts: Mutex<Vec<String>>,
+    }
+
+    impl FakeSummarizer {
+        fn new(fails: bool) -> FakeSummarizer {
+            FakeSummarizer {
+                fails,
+                transcripts: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl ChatProvider for FakeSummarizer {
+        fn id(&self) -> ProviderIdentifier {
+            ProviderIdentifier::Ollama
+        }
+
+        fn context_management(&self) -> ContextManagement {
+            ContextManagement::Explicit
+        }
+
+        fn honors_seed(&self) -> bool {
+            false
+        }
+
+        fn supported_options(&self, _model: &str) -> &'static [CompletionOption] {
+            &[]
+        }
+
+        async fn models(&self) -> Result<Vec<Model>, Error> {
+            Ok(Vec::new())
+        }
+
+        async fn default_model(&self) -> Result<Option<Model>, Error> {
+            Ok(None)
+        }
+
+        async fn loaded_models(&self) -> Result<Vec<LoadedModel>, Error> {
+            Ok(Vec::new())
+        }
+
+        async fn unload_model(&self, _model: &str) -> Result<(), Error> {
+            Err(Error::from_kind(ErrorKind::Unsupported))
+        }
+
+        async fn stream_completion(
+            &self,
+            model: &str,
+            messages: &[chat::Message],
+            options: &CompletionOptions,
+        ) -> Result<Box<dyn AsyncMessageIterator>, Error> {
+            self.completion(model, messages, options)
+                .await
+                .map(Completion::into_stream)
+        }
+
+        async fn completion(
+            &self,
+            _model: &str,
+            messages: &[chat::Message],
+            _options: &CompletionOptions,
+        ) -> Result<Completion, Error> {
+            if self.fails {
+                return Err(Error::from_kind(ErrorKind::Connection));
+            }
+
+            let transcript = messages.last().unwrap().content.clone();
+            self.transcripts.lock().unwrap().push(transcript);
+
+            Ok(Completion {
+                message: MessageDelta {
+                    role: Role::Model,
+                    content: "summary".to_string(),
+                    citations: Vec::new(),
+                    files: Vec::new(),
+                },
+                finish_reason: FinishReason::Stop,
+                usage: Usage::default(),
+            })
+        }
+
+        async fn fill_in_middle(
+            &self,
+            _model: &str,
+            _prefix: &str,
+            _suffix: &str,
+        ) -> Result<String, Error> {
+            Err(Error::from_kind(ErrorKind::Unsupported))
+        }
+    }
+
+    /// A conversation of two exchanges followed by the prompt being answered
+    fn conversation() -> MessageBuffer {
+        let mut msg_buf = MessageBuffer::new();
+
+        msg_buf.add_message(Message::system("Be brief.".to_string()));
+        msg_buf.add_message(Message::user("first".to_string()));
+        msg_buf.add_message(Message::model("one".to_string(), "m".to_string()));
+        msg_buf.add_message(Message::user("second".to_string()));
+        msg_buf.add_message(Message::model("two".to_string(), "m".to_string()));
+        msg_buf.add_message(Message::user("third".to_string()));
+
+        msg_buf
+    }
+
+    /// Estimates the conversation by its number of messages
+    fn message_count(msg_buf: &MessageBuffer) -> usize {
+        msg_buf.chat_messages().len()
+    }
+
+    #[test]
+    fn test_remove_oldest_exchange() {
+        let mut msg_buf = MessageBuffer::new();
+
+        msg_buf.add_message(Message::system("Be brief.".to_string()));
+        msg_buf.add_message(Message::user("first".to_string()));
+        msg_buf.add_message(Message::model("one".to_string(), "m".to_string()));
+        msg_buf.add_message(Message::user("second".to_string()));
+        msg_buf.add_message(Message::model("two".to_string(), "m".to_string()));
+        msg_buf.add_message(Message::user("third".to_string()));
+
+        // The first prompt is pinned, but the response to it is not
+        assert!(msg_buf.set_pinned(1, true));
+
+        let (position, removed) = msg_buf.remove_oldest_exchange().unwrap();
+
+        assert_eq!(position, 2);
+        assert_eq!(transcript(&removed), "model: one");
+
+        let (position, removed) = msg_buf.remove_oldest_exchange().unwrap();
+
+        assert_eq!(position, 2);
+        assert_eq!(transcript(&removed), "user: second\n\nmodel: two");
+
+        // The prompt being answered is kept
+        assert!(msg_buf.remove_oldest_exchange().is_none());
+
+        assert_eq!(
+            transcript(&msg_buf.chat_messages()),
+            "system: Be brief.\n\nuser: first\n\nuser: third"
+        );
+        assert_eq!(msg_buf.pinned_turns().into_iter().collect::<Vec<_>>(), [1]);
+
+        assert_eq!(estimate_tokens(&msg_buf.chat_messages()), 3 + 2 + 2 + 12);
+    }
+
+    #[tokio::test]
+    async fn test_summarize_oldest() {
+        let provider = FakeSummarizer::new(false);
+        let summarizer = Summarizer {
+            provider: &provider,
+            model_id: "m",
+        };
+
+        // Summarizing stops once the conversation fits the target
+        let mut msg_buf = conversation();
+
+        assert_eq!(
+            summarize_oldest(&summarizer, &mut msg_buf, message_count, 4).await,
+            1
+        );
+        assert_eq!(
+            transcript(&msg_buf.chat_messages()),
+            format!(
+                "system: Be brief.\n\nsystem: {}summary\n\nuser: second\n\nmodel: two\n\nuser: third",
+                SUMMARY_PREFIX
+            )
+        );
+        assert_eq!(
+            *provider.transcripts.lock().unwrap(),
+            ["user: first\n\nmodel: one"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_summarize_oldest_skips_pinned() {
+        let provider = FakeSummarizer::new(false);
+        let summarizer = Summarizer {
+            provider: &provider,
+            model_id: "m",
+        };
+
+        let mut msg_buf = conversation();
+
+        assert!(msg_buf.set_pinned(1, true));
+        assert!(msg_buf.set_pinned(2, true));
+
+        assert_eq!(
+            summarize_oldest(&summarizer, &mut msg_buf, message_count, 0).await,
+            1
+        );
+
+        // The pinned exchange is neither summarized nor removed
+        assert_eq!(
+            *provider.transcripts.lock().unwrap(),
+            ["user: second\n\nmodel: two"]
+        );
+
+        let messages = transcript(&msg_buf.chat_messages());
+
+        assert!(messages.contains("user: first\n\nmodel: one"));
+        assert!(!messages.contains("second"));
+        assert_eq!(
+            msg_buf.pinned_turns().into_iter().collect::<Vec<_>>(),
+            [1, 2]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_summarize_oldest_failure() {
+        let provider = FakeSummarizer::new(true);
+        let summarizer = Summarizer {
+            provider: &provider,
+            model_id: "m",
+        };
+
+        let mut msg_buf = conversation();
+        let before = transcript(&msg_buf.chat_messages());
+
+        // The conversation still fits, so it is left as it was
+        assert_eq!(
+            summarize_oldest(&summarizer, &mut msg_buf, message_count, 0).await,
+            0
+        );
+        assert_eq!(transcript(&msg_buf.chat_messages()), before);
+    }
+}
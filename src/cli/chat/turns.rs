@@ -0,0 +1,195 @@
+//! Numbering of the turns of a conversation and references to them
+//!
+//! The first message of the conversation which is not a system message is
+//! turn one, and each prompt and response after it is the next turn. A
+//! prompt may refer to an earlier turn as `%3`, which is replaced with the
+//! content of the turn before the prompt is sent. A `%` followed by digits
+//! is only a reference if it makes up a whole word, so `50%3` and format
+//! strings such as `%5d` or `%8.2f` are left alone, and `%%3` is written for
+//! a literal `%3`.
+
+use std::collections::BTreeSet;
+
+use thiserror::Error;
+
+use crate::chat::{self, Role};
+
+use super::search::excerpt;
+
+#[derive(Error, Debug, PartialEq)]
+pub(crate) enum Error {
+    /// A reference to a turn which is not in the conversation
+    #[error("there is no turn %{0}, see /history for the turns of the conversation")]
+    NoSuchTurn(String),
+}
+
+/// Numbers the messages by turn, omitting system messages
+pub(crate) fn turns<'a>(
+    messages: impl IntoIterator<Item = &'a chat::Message>,
+) -> impl Iterator<Item = (usize, &'a chat::Message)> {
+    messages
+        .into_iter()
+        .filter(|message| !matches!(message.role, Role::System))
+        .enumerate()
+        .map(|(i, message)| (i + 1, message))
+}
+
+/// Lists the turns for `/history`, showing the first line of each, marking
+/// those which are pinned, and naming the files attached to prompts
+pub(crate) fn history(messages: &[chat::Message], pinned: &BTreeSet<usize>) -> String {
+    let mut listing = Vec::new();
+
+    for (turn, message) in turns(messages) {
+        let role = match message.role {
+            Role::User => "user",
+            _ => "model",
+        };
+
+        let content = message.content.trim();
+
+        let first_line = content.lines().next().unwrap_or_default();
+
+        let mut summary = excerpt(first_line, 0);
+
+        if first_line.len() < content.len() && !summary.ends_with('…') {
+            summary.push('…');
+        }
+
+        let marker = if pinned.contains(&turn) {
+            " (pinned)"
+        } else {
+            ""
+        };
+
+        let attached = if message.attachments.is_empty() {
+            String::new()
+        } else {
+            format!(" (attached {})", message.attachments.join(", "))
+        };
+
+        listing.push(format!(
+            "#{} {}{}{}: {}",
+            turn, role, marker, attached, summary
+        ));
+    }
+
+    listing.join("\n")
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// The length of the turn number which the text begins with, if it ends a
+/// reference. The number must end the word, and must not be followed by a
+/// fraction, as the precision of a format string is.
+fn reference_len(text: &str) -> Option<usize> {
+    let digits = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+
+    let mut following = text[digits..].chars();
+
+    let at_word_end = match following.next() {
+        None => true,
+        Some('.') => !following.next().is_some_and(|c| c.is_ascii_digit()),
+        Some(c) => !is_word_char(c),
+    };
+
+    (digits > 0 && at_word_end).then_some(digits)
+}
+
+/// Replaces the turn references in a prompt with the content of the turns
+pub(crate) fn expand(prompt: &str, messages: &[chat::Message]) -> Result<String, Error> {
+    let turns: Vec<&chat::Message> = turns(messages).map(|(_, message)| message).collect();
+
+    let mut expanded = String::with_capacity(prompt.len());
+    let mut rest = prompt;
+
+    while let Some(at) = rest.find('%') {
+        let (before, after) = rest.split_at(at);
+
+        expanded.push_str(before);
+
+        let after = &after[1..];
+
+        let at_word_start = expanded
+            .chars()
+            .next_back()
+            .is_none_or(|c| !is_word_char(c));
+
+        if let Some(escaped) = after.strip_prefix('%') {
+            // Only a reference needs to be escaped
+            let literal = reference_len(escaped).is_some();
+
+            expanded.push_str(if literal { "%" } else { "%%" });
+
+            rest = escaped;
+        } else if let Some(digits) = reference_len(after).filter(|_| at_word_start) {
+            let number = &after[..digits];
+
+            let message = number
+                .parse::<usize>()
+                .ok()
+                .and_then(|turn| turn.checked_sub(1))
+                .and_then(|i| turns.get(i))
+                .ok_or_else(|| Error::NoSuchTurn(number.to_string()))?;
+
+            expanded.push_str(&message.content);
+
+            rest = &after[digits..];
+        } else {
+            expanded.push('%');
+
+            rest = after;
+        }
+    }
+
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let messages = [
+            chat::Message::new(Role::System, "Be brief.".to_string()),
+            chat::Message::new(Role::User, "Write a haiku".to_string()),
+            chat::Message::new(Role::Model, "An old silent pond".to_string()),
+        ];
+
+        assert_eq!(
+            expand("Rewrite %2 but shorter", &messages),
+            Ok("Rewrite An old silent pond but shorter".to_string())
+        );
+
+        assert_eq!(
+            expand("(%1) is 50%3 of %%2, or 100%", &messages),
+            Ok("(Write a haiku) is 50%3 of %2, or 100%".to_string())
+        );
+
+        // Format strings are not references
+        assert_eq!(
+            expand(r#"printf("%2d: %-8s %1.2f %%3d\n", n)"#, &messages),
+            Ok(r#"printf("%2d: %-8s %1.2f %%3d\n", n)"#.to_string())
+        );
+
+        assert_eq!(
+            expand("Compare %1 and %2.", &messages),
+            Ok("Compare Write a haiku and An old silent pond.".to_string())
+        );
+
+        assert_eq!(
+            expand("%3", &messages),
+            Err(Error::NoSuchTurn("3".to_string()))
+        );
+        assert_eq!(
+            expand("%0", &messages),
+            Err(Error::NoSuchTurn("0".to_string()))
+        );
+    }
+}
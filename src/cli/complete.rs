@@ -0,0 +1,202 @@
+//! One-shot completions for scripts
+//!
+//! `xtalk complete` sends a single prompt, taken from its argument or from
+//! standard input, and prints the response. Unlike `xtalk chat`, it never
+//! enters the REPL. By default, the response is streamed after the model
+//! prompt; `--raw` prints the response alone and `--format json` prints the
+//! response along with its metadata once it is complete. Any failure exits
+//! with a non-zero status.
+
+use std::io::{self, IsTerminal, Read, Write};
+
+use serde::Serialize;
+
+use crate::chat::{Message, Role};
+use crate::cli::chat::prompt::model_prompt;
+use crate::providers::{self, CompletionOptions, FinishReason, Usage};
+use crate::registry::populate::resolve_spec;
+use crate::registry::registry::{ModelSpec, Registry};
+use crate::utils::errors::USAGE_EXIT_CODE;
+use crate::{color, die, die_with, warn, CompleteArgs, CompletionFormat};
+
+/// The response as it is printed by `--format json`
+#[derive(Serialize)]
+struct Response {
+    model: String,
+    content: String,
+    finish_reason: FinishReason,
+    usage: Usage,
+}
+
+/// Reads the prompt from the argument, appending anything piped through
+/// standard input, or from standard input alone
+fn read_prompt(prompt: Option<&str>) -> String {
+    let input = if io::stdin().is_terminal() {
+        None
+    } else {
+        let mut buf = String::new();
+
+        if let Err(err) = io::stdin().read_to_string(&mut buf) {
+            die!("failed to read the prompt from standard input: {}", err);
+        }
+
+        Some(buf)
+    };
+
+    match (prompt, input) {
+        (Some(prompt), Some(input)) if !input.trim().is_empty() => {
+            format!("{}\n\n{}", prompt, input)
+        }
+        (Some(prompt), _) => prompt.to_string(),
+        (None, Some(input)) if !input.trim().is_empty() => input,
+        _ => die_with!(
+            USAGE_EXIT_CODE,
+            "no prompt was provided, either as an argument or through standard input"
+        ),
+    }
+}
+
+pub(crate) async fn complete_cmd(
+    registry: Registry,
+    default_model: Option<String>,
+    system_prompt: Option<String>,
+    temperature: Option<f64>,
+    args: &CompleteArgs,
+) {
+    let prompt = read_prompt(args.prompt.as_deref());
+
+    let resolved = resolve_spec(&registry, args.model.clone().or(default_model))
+        .await
+        .and_then(ModelSpec::provider_model_ids);
+
+    let (provider_id, model_id) = match resolved {
+        Ok(ids) => ids,
+        Err(err) => die_with!(err.exit_code(), "failed to resolve model: {}", err),
+    };
+
+    let spec = ModelSpec::resolved(provider_id, model_id.clone());
+
+    color::note_provider(&model_id, &provider_id.to_string());
+
+    let provider = match registry.active_provider(provider_id) {
+        Ok(provider) => provider,
+        Err(err) => die_with!(err.exit_code(), "completion for {} failed: {}", spec, err),
+    };
+
+    let mut messages = Vec::new();
+
+    if let Some(instructions) = args.system.clone().or(system_prompt) {
+        messages.push(Message::new(Role::System, instructions));
+    }
+
+    messages.push(Message::new(Role::User, prompt));
+
+    let options = CompletionOptions {
+        temperature,
+        max_tokens: args.max_output_tokens,
+        stop: args.stop.clone(),
+        seed: args.seed,
+        ..Default::default()
+    };
+
+    let unsupported = providers::unsupported_options(provider.as_ref(), &model_id, &options);
+
+    if !unsupported.is_empty() {
+        let unsupported: Vec<String> = unsupported.iter().map(ToString::to_string).collect();
+
+        warn!(
+            "\"{}\" does not support {}, they are ignored",
+            spec,
+            unsupported.join(", ")
+        );
+    }
+
+    let mut completion = match provider
+        .stream_completion(&model_id, &messages, &options)
+        .await
+    {
+        Ok(completion) => completion,
+        Err(err) => die_with!(err.exit_code(), "completion for {} failed: {}", spec, err),
+    };
+
+    // JSON is printed once the response is complete, text as it arrives
+    let streaming = matches!(args.format, CompletionFormat::Text);
+
+    let mut stdout = io::stdout().lock();
+
+    if streaming && !args.raw {
+        let _ = write!(stdout, "{}", model_prompt(&model_id));
+    }
+
+    let mut content = String::new();
+
+    // As in chats, streamed chunks with content are counted as tokens and the
+    // response is cut off if the provider ignored the limit
+    let mut chunks = 0;
+    let mut cut_off = false;
+
+    while let Some(delta) = completion.next().await {
+        let delta = match delta {
+            Ok(delta) => delta,
+            Err(err) => {
+                if streaming {
+                    let _ = writeln!(stdout);
+                }
+
+                die_with!(
+                    err.exit_code(),
+                    "failed to decode streaming response: {}",
+                    err
+                )
+            }
+        };
+
+        if !delta.content.is_empty() {
+            chunks += 1;
+
+            if args.max_output_tokens.is_some_and(|max| chunks > max) {
+                cut_off = true;
+                break;
+            }
+        }
+
+        if streaming {
+            let _ = write!(stdout, "{}", delta.content).and_then(|_| stdout.flush());
+        }
+
+        content.push_str(&delta.content);
+    }
+
+    // Dropping the stream of a response which was cut off aborts the
+    // request, and the usage of the rest of it is never reported
+    let (finish_reason, usage) = if cut_off {
+        drop(completion);
+
+        (FinishReason::Length, Usage::default())
+    } else {
+        (completion.finish_reason(), completion.usage().clone())
+    };
+
+    let written = match args.format {
+        CompletionFormat::Text if args.raw => Ok(()),
+        CompletionFormat::Text if content.ends_with('\n') => Ok(()),
+        CompletionFormat::Text => writeln!(stdout),
+        CompletionFormat::Json => {
+            let response = Response {
+                model: spec.to_string(),
+                content,
+                finish_reason,
+                usage,
+            };
+
+            let serialized =
+                serde_json::to_string_pretty(&response).expect("failed to serialize response");
+
+            writeln!(stdout, "{}", serialized)
+        }
+    };
+
+    if let Err(err) = written.and_then(|_| stdout.flush()) {
+        die!("failed to write to standard output: {}", err);
+    }
+}
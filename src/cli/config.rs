@@ -0,0 +1,192 @@
+//! Inspection and creation of the configuration file
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::chat::{DEFAULT_FOLD_LINES, DEFAULT_HISTORY_ENTRIES, DEFAULT_MAX_TOOL_OUTPUT};
+use crate::config::{self, Config, DEFAULT_CONFIG};
+use crate::providers::retry::RetryPolicy;
+use crate::registry::registry::ModelSpec;
+use crate::utils::errors::DEFAULT_EXIT_CODE;
+use crate::{die, error, warn, ConfigAction, ConfigArgs, DEFAULT_CACHE_TTL_SECS};
+
+/// Shown in place of API keys
+const REDACTED: &str = "<redacted>";
+
+fn init(path: Option<PathBuf>, force: bool) {
+    let path = match path.or_else(config::user_config_path) {
+        Some(path) => path,
+        None => die!(
+            "could not determine the configuration directory, pass the path of the configuration with --config"
+        ),
+    };
+
+    if path.exists() && !force {
+        die!(
+            "{} already exists, pass --force to overwrite it",
+            path.display()
+        );
+    }
+
+    if let Some(parent) = path.parent() {
+        if let Err(err) = fs::create_dir_all(parent) {
+            die!("failed to create {}: {}", parent.display(), err);
+        }
+    }
+
+    if let Err(err) = fs::write(&path, DEFAULT_CONFIG) {
+        die!("failed to write {}: {}", path.display(), err);
+    }
+
+    println!("wrote the default configuration to {}", path.display());
+}
+
+/// Reports that no configuration exists at any of the searched paths
+fn no_config() -> ! {
+    let searched: Vec<String> = config::config_search_paths()
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+
+    die!(
+        "no configuration file was found in {}, create one with `xtalk config init`",
+        searched.join(", ")
+    );
+}
+
+fn path(path: Option<PathBuf>) {
+    match config::config_path(path) {
+        Some(path) => println!("{}", path.display()),
+        None => no_config(),
+    }
+}
+
+/// Lists the options which hold model specs which do not parse
+fn invalid_model_specs(config: &Config) -> Vec<String> {
+    let mut specs: Vec<(String, &Option<String>)> = vec![
+        ("default_model".into(), &config.default_model),
+        ("lsp.model".into(), &config.lsp.model),
+    ];
+
+    for (name, persona) in &config.personas {
+        specs.push((format!("personas.{}.model", name), &persona.model));
+    }
+
+    for (name, profile) in &config.profiles {
+        specs.push((format!("profiles.{}.model", name), &profile.model));
+    }
+
+    specs
+        .into_iter()
+        .filter_map(|(option, spec)| {
+            let spec = spec.as_ref()?;
+
+            ModelSpec::parse(Some(spec.clone()))
+                .err()
+                .map(|err| format!("{} = \"{}\": {}", option, spec, err))
+        })
+        .collect()
+}
+
+fn validate(path: &Path) {
+    let (config, mut extra) = match config::load_config(path) {
+        Ok(loaded) => loaded,
+        Err(err) => die!("{}", err),
+    };
+
+    let mut valid = true;
+
+    // The provider overrides of each profile must merge into the providers
+    for name in config.profiles.keys() {
+        match config.clone().with_profile(name) {
+            Ok((_, profile_extra)) => extra.extend(profile_extra),
+            Err(err) => {
+                error!("{}", err);
+                valid = false;
+            }
+        }
+    }
+
+    for key in extra {
+        warn!("config contains extraneous key \"{}\", ignoring", key);
+    }
+
+    let invalid = invalid_model_specs(&config);
+
+    for err in &invalid {
+        error!("invalid model spec {}", err);
+    }
+
+    if !invalid.is_empty() || !valid {
+        std::process::exit(DEFAULT_EXIT_CODE);
+    }
+
+    println!("{} is valid", path.display());
+}
+
+/// Fills in the defaults of unset options and hides the API keys
+fn effective(mut config: Config) -> Config {
+    let retry = RetryPolicy::default();
+
+    config.fold_lines.get_or_insert(DEFAULT_FOLD_LINES);
+    config
+        .max_tool_output
+        .get_or_insert(DEFAULT_MAX_TOOL_OUTPUT);
+    config.history.persist.get_or_insert(true);
+    config
+        .history
+        .max_entries
+        .get_or_insert(DEFAULT_HISTORY_ENTRIES);
+    config.cache.ttl_secs.get_or_insert(DEFAULT_CACHE_TTL_SECS);
+    config.retry.max_attempts.get_or_insert(retry.max_attempts);
+    config
+        .retry
+        .base_delay_ms
+        .get_or_insert(retry.base_delay.as_millis() as u64);
+
+    for api_key in [
+        &mut config.providers.openai.api_key,
+        &mut config.providers.azure_openai.api_key,
+    ] {
+        if api_key.is_some() {
+            *api_key = Some(REDACTED.to_string());
+        }
+    }
+
+    for profile in config.profiles.values_mut() {
+        for (_, provider) in profile.providers.iter_mut() {
+            if let Some(api_key) = provider.get_mut("api_key") {
+                *api_key = toml::Value::String(REDACTED.to_string());
+            }
+        }
+    }
+
+    config
+}
+
+fn show(path: Option<PathBuf>, profile: Option<&str>) {
+    let mut config = config::read_config(path);
+
+    if let Some(name) = profile {
+        config = config::select_profile(config, name);
+    }
+
+    let config = effective(config);
+
+    match toml::to_string_pretty(&config) {
+        Ok(config) => print!("{}", config),
+        Err(err) => die!("failed to serialize the configuration: {}", err),
+    }
+}
+
+pub(crate) fn config_cmd(path: Option<PathBuf>, profile: Option<&str>, args: &ConfigArgs) {
+    match &args.action {
+        ConfigAction::Init { force } => init(path, *force),
+        ConfigAction::Path => self::path(path),
+        ConfigAction::Validate => match config::config_path(path) {
+            Some(path) => validate(&path),
+            None => no_config(),
+        },
+        ConfigAction::Show => show(path, profile),
+    }
+}
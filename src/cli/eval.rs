@@ -0,0 +1,246 @@
+//! Regression testing of prompt templates
+//!
+//! `xtalk eval --golden DIR` renders templates and asks each model for a
+//! response, sampled with a fixed seed. The first response is kept in `DIR`
+//! as the golden response for the template, model, and seed. Later runs
+//! compare their responses with the golden responses and print the lines
+//! which differ, so prompt authors can review the effect of a change. With
+//! `--update`, responses which differ replace the golden responses.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::chat::{Message, Role};
+use crate::color::{self, MaybePaint};
+use crate::providers::CompletionOptions;
+use crate::registry::populate::resolve_spec;
+use crate::registry::registry::{ModelSpec, Registry};
+use crate::templates::{self, Template};
+use crate::utils::diff::{diff_lines, Line};
+use crate::utils::errors::DEFAULT_EXIT_CODE;
+use crate::{die, die_with, warn, EvalArgs};
+
+/// The result of evaluating a template with a model
+enum Outcome {
+    /// The response matches the golden response
+    Pass,
+    /// There was no golden response, so the response became one
+    New,
+    /// The response differs from the golden response
+    Changed { golden: String, response: String },
+    /// The response differs and replaced the golden response
+    Updated { golden: String, response: String },
+    /// The template could not be rendered with the given values
+    Skipped(templates::Error),
+    /// The completion or the golden response failed
+    Failed(String),
+}
+
+impl Outcome {
+    fn label(&self) -> String {
+        match self {
+            Outcome::Pass => color::DIFF_ADDED.maybe_paint("pass").to_string(),
+            Outcome::New => color::WARNING_INDICATOR.maybe_paint("new").to_string(),
+            Outcome::Changed { .. } => color::ERROR_INDICATOR.maybe_paint("changed").to_string(),
+            Outcome::Updated { .. } => color::WARNING_INDICATOR.maybe_paint("updated").to_string(),
+            Outcome::Skipped(_) => color::FOLD_MARKER.maybe_paint("skipped").to_string(),
+            Outcome::Failed(_) => color::ERROR_INDICATOR.maybe_paint("failed").to_string(),
+        }
+    }
+
+    fn is_failure(&self) -> bool {
+        matches!(self, Outcome::Changed { .. } | Outcome::Failed(_))
+    }
+}
+
+/// Replaces characters which cannot appear in a file name, such as the
+/// slashes and colons in model ids
+fn file_name_component(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// The file holding the golden response for the template, model, and seed
+fn golden_path(args: &EvalArgs, template: &str, spec: &ModelSpec) -> PathBuf {
+    args.golden
+        .join(file_name_component(template))
+        .join(format!(
+            "{}-seed{}.txt",
+            file_name_component(&spec.to_string()),
+            args.seed
+        ))
+}
+
+/// Prints the lines of the golden response which changed in the response
+fn print_diff(golden: &str, response: &str) {
+    println!("{}", color::DIFF_REMOVED.maybe_paint("--- golden"));
+    println!("{}", color::DIFF_ADDED.maybe_paint("+++ response"));
+
+    for line in diff_lines(golden, response) {
+        match line {
+            Line::Same(line) => println!(" {}", line),
+            Line::Removed(line) => {
+                println!("{}", color::DIFF_REMOVED.maybe_paint(format!("-{}", line)))
+            }
+            Line::Added(line) => {
+                println!("{}", color::DIFF_ADDED.maybe_paint(format!("+{}", line)))
+            }
+        }
+    }
+}
+
+async fn evaluate(
+    registry: &Registry,
+    args: &EvalArgs,
+    template: &Template,
+    spec: &ModelSpec,
+    vars: &HashMap<String, String>,
+) -> Outcome {
+    let prompt = match template.render_all(&templates::with_builtins(vars)) {
+        Ok(prompt) => prompt,
+        Err(err) => return Outcome::Skipped(err),
+    };
+
+    let provider = registry.provider(spec.provider.unwrap()).unwrap();
+
+    let model_id = spec.model.as_deref().unwrap();
+
+    let options = CompletionOptions {
+        seed: Some(args.seed),
+        ..Default::default()
+    };
+
+    let response = match provider
+        .completion(model_id, &[Message::new(Role::User, prompt)], &options)
+        .await
+    {
+        Ok(completion) => completion.message.content,
+        Err(err) => return Outcome::Failed(format!("completion failed: {}", err)),
+    };
+
+    let path = golden_path(args, &template.name, spec);
+
+    let write = |response: &str| {
+        fs::create_dir_all(path.parent().unwrap())
+            .and_then(|_| fs::write(&path, response))
+            .map_err(|err| format!("failed to write {}: {}", path.display(), err))
+    };
+
+    let golden = match fs::read_to_string(&path) {
+        Ok(golden) => golden,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return match write(&response) {
+                Ok(()) => Outcome::New,
+                Err(err) => Outcome::Failed(err),
+            };
+        }
+        Err(err) => return Outcome::Failed(format!("failed to read {}: {}", path.display(), err)),
+    };
+
+    if golden == response {
+        Outcome::Pass
+    } else if args.update {
+        match write(&response) {
+            Ok(()) => Outcome::Updated { golden, response },
+            Err(err) => Outcome::Failed(err),
+        }
+    } else {
+        Outcome::Changed { golden, response }
+    }
+}
+
+pub(crate) async fn eval_cmd(
+    registry: Registry,
+    templates: &[Template],
+    default_model: Option<String>,
+    args: &EvalArgs,
+) {
+    let templates: Vec<&Template> = if args.templates.is_empty() {
+        templates.iter().collect()
+    } else {
+        args.templates
+            .iter()
+            .map(|name| match templates.iter().find(|t| &t.name == name) {
+                Some(template) => template,
+                None => die!(
+                    "there is no template named \"{}\", see `xtalk list templates`",
+                    name
+                ),
+            })
+            .collect()
+    };
+
+    if templates.is_empty() {
+        die!("there are no templates to evaluate");
+    }
+
+    let models = if args.models.is_empty() {
+        vec![default_model]
+    } else {
+        args.models.iter().cloned().map(Some).collect()
+    };
+
+    let mut specs = Vec::with_capacity(models.len());
+
+    for model in models {
+        let resolved = resolve_spec(&registry, model)
+            .await
+            .and_then(ModelSpec::provider_model_ids)
+            .and_then(|(provider_id, model_id)| {
+                let provider = registry.active_provider(provider_id)?;
+
+                Ok((provider_id, model_id, provider))
+            });
+
+        let (provider_id, model_id, provider) = match resolved {
+            Ok(resolved) => resolved,
+            Err(err) => die_with!(err.exit_code(), "failed to resolve model: {}", err),
+        };
+
+        if !provider.honors_seed() {
+            warn!(
+                "\"{}\" only makes a best effort to honor the seed, responses may change between runs",
+                provider_id
+            );
+        }
+
+        specs.push(ModelSpec::resolved(provider_id, model_id));
+    }
+
+    let vars: HashMap<String, String> = args.vars.iter().cloned().collect();
+
+    let mut failures = 0;
+
+    for template in templates {
+        for spec in &specs {
+            let outcome = evaluate(&registry, args, template, spec, &vars).await;
+
+            println!("{} {} with {}", outcome.label(), template.name, spec);
+
+            match &outcome {
+                Outcome::Changed { golden, response } | Outcome::Updated { golden, response } => {
+                    print_diff(golden, response)
+                }
+                Outcome::Skipped(err) => println!("  {}", err),
+                Outcome::Failed(err) => println!("  {}", err),
+                Outcome::Pass | Outcome::New => {}
+            }
+
+            if outcome.is_failure() {
+                failures += 1;
+            }
+        }
+    }
+
+    if failures > 0 {
+        std::process::exit(DEFAULT_EXIT_CODE);
+    }
+}
@@ -0,0 +1,802 @@
+//! Commands which act on saved chat sessions
+//!
+//! `xtalk history list` lists the sessions with their titles and tags. When a
+//! labeling model is configured, sessions which have not been labeled, or
+//! which have grown since, are labeled before they are listed. Only a few
+//! sessions are labeled per listing so a large history is labeled gradually
+//! rather than with a burst of requests.
+//!
+//! `xtalk history show`, `rename`, and `delete` manage individual sessions.
+//! Titles given with `rename` are recorded as labels, which are marked so
+//! that labeling the session again only refreshes its tags.
+//!
+//! `xtalk history search` searches the transcripts of the saved sessions,
+//! listing the matching sessions from the most recently updated, and can
+//! resume the first of them in a chat.
+//!
+//! `xtalk history tasks` asks a model to extract the tasks which remain from
+//! the transcript of a session. The model is instructed to respond with JSON
+//! in a fixed shape, and is re-prompted with the error if its response cannot
+//! be parsed, as `xtalk chat --json` does.
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::Write;
+
+use chrono::{DateTime, Local};
+use nu_ansi_term::Style;
+use serde::{Deserialize, Serialize};
+
+use crate::cache::Cache;
+use crate::chat::{Message, Role};
+use crate::cli::chat::search::{self, highlight};
+use crate::cli::chat::trim::{transcript, CHARS_PER_TOKEN};
+use crate::cli::chat::turns;
+use crate::cli::chat::{render_transcript, ExportFormat, Metadata, Parameters, UsageLog};
+use crate::cli::list::print_sessions;
+use crate::color::SEARCH_MATCH;
+use crate::config::Config;
+use crate::providers::{self, ChatProvider, CompletionOptions, ResponseFormat};
+use crate::registry::populate::{populated_registry, resolve_spec};
+use crate::registry::registry::ModelSpec;
+use crate::sessions::{Labels, Session, SessionStore};
+use crate::{
+    die, die_with, warn, ColorMode, HistoryAction, HistoryArgs, HistoryListArgs, HistorySearchArgs,
+    TaskFormat, TasksArgs,
+};
+
+const TASK_INSTRUCTIONS: &str = r#"Extract the tasks from the following conversation: the work which the participants agreed on, promised, or left open. Respond only with JSON of the form {"tasks": [{"task": "...", "details": "...", "done": false}]}, where "task" is a short imperative sentence, "details" is any context needed to act on it or null, and "done" is whether the conversation shows it was completed. Respond with {"tasks": []} if there are none."#;
+
+const LABEL_INSTRUCTIONS: &str = r#"Give the following conversation a title and tags so it can be found later. Respond only with JSON of the form {"title": "...", "tags": ["..."]}, where "title" is at most eight words and "tags" are one to five short topics, such as a language, tool, or subject."#;
+
+/// The number of sessions labeled per listing unless configured otherwise
+const DEFAULT_MAX_PER_RUN: usize = 5;
+
+/// The number of messages a session gains before it is labeled again unless
+/// configured otherwise
+const DEFAULT_RELABEL_AFTER: usize = 10;
+
+/// Only the start of a long session is sent to be labeled
+const MAX_LABEL_TOKENS: usize = 2048;
+
+/// The most tags kept for a session
+const MAX_TAGS: usize = 5;
+
+#[derive(Deserialize)]
+struct GeneratedLabels {
+    title: String,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+/// Lowercases the tag and joins its words with dashes, so `--tag` matches
+/// however the model capitalized or spaced it
+fn normalize_tag(tag: &str) -> String {
+    tag.split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn normalize_tags(tags: &[String]) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::new();
+
+    for tag in tags.iter().map(|tag| normalize_tag(tag)) {
+        if !tag.is_empty() && !normalized.contains(&tag) && normalized.len() < MAX_TAGS {
+            normalized.push(tag);
+        }
+    }
+
+    normalized
+}
+
+/// Asks the model to title and tag the conversation
+async fn generate_labels(
+    provider: &dyn ChatProvider,
+    model_id: &str,
+    options: &CompletionOptions,
+    conversation: String,
+) -> Result<Result<GeneratedLabels, serde_json::Error>, providers::Error> {
+    let request = vec![
+        Message::new(Role::System, LABEL_INSTRUCTIONS.to_string()),
+        Message::new(Role::User, conversation),
+    ];
+
+    let mut completion = provider
+        .stream_completion(model_id, &request, options)
+        .await?;
+
+    let mut response = String::new();
+
+    while let Some(delta) = completion.next().await {
+        response.push_str(&delta?.content);
+    }
+
+    Ok(serde_json::from_str(&response))
+}
+
+/// Labels the sessions which are unlabeled or have grown since they were
+/// labeled, most recently updated first, up to the configured limit
+async fn label_sessions(
+    config: &Config,
+    cache: Option<Cache>,
+    store: &SessionStore,
+    sessions: &[Session],
+    labels: &mut HashMap<String, Labels>,
+) {
+    let Some(model) = config.labels.model.clone() else {
+        return;
+    };
+
+    let max_per_run = config.labels.max_per_run.unwrap_or(DEFAULT_MAX_PER_RUN);
+    let relabel_after = config.labels.relabel_after.unwrap_or(DEFAULT_RELABEL_AFTER);
+
+    let due: Vec<&Session> = sessions
+        .iter()
+        .rev()
+        .filter(|session| match labels.get(&session.id) {
+            Some(labels) => session.messages.len() >= labels.messages + relabel_after.max(1),
+            None => !session.messages.is_empty(),
+        })
+        .take(max_per_run)
+        .collect();
+
+    if due.is_empty() {
+        return;
+    }
+
+    let registry = populated_registry(config, cache).await;
+
+    let resolved = resolve_spec(&registry, Some(model))
+        .await
+        .and_then(ModelSpec::provider_model_ids);
+
+    let (provider_id, model_id) = match resolved {
+        Ok(ids) => ids,
+        Err(err) => {
+            warn!(
+                "sessions were not labeled, failed to resolve model: {}",
+                err
+            );
+            return;
+        }
+    };
+
+    let spec = ModelSpec::resolved(provider_id, model_id.clone());
+
+    let provider = match registry.active_provider(provider_id) {
+        Ok(provider) => provider.as_ref(),
+        Err(err) => {
+            warn!("sessions were not labeled by {}: {}", spec, err);
+            return;
+        }
+    };
+
+    let options = CompletionOptions {
+        response_format: ResponseFormat::Json,
+        ..Default::default()
+    };
+
+    let max_chars = MAX_LABEL_TOKENS * CHARS_PER_TOKEN;
+
+    for session in due {
+        let messages: Vec<Message> = session
+            .messages
+            .iter()
+            .map(|msg| msg.message.clone())
+            .collect();
+
+        let conversation: String = transcript(&messages).chars().take(max_chars).collect();
+
+        let generated = match generate_labels(provider, &model_id, &options, conversation).await {
+            Ok(Ok(generated)) => generated,
+            Ok(Err(err)) => {
+                warn!(
+                    "session \"{}\" was not labeled, {} responded with malformed labels: {}",
+                    session.id, spec, err
+                );
+                continue;
+            }
+            Err(err) => {
+                // The remaining sessions would most likely fail the same way
+                warn!("sessions were not labeled by {}: {}", spec, err);
+                return;
+            }
+        };
+
+        // A title given by the user is kept, though the tags are refreshed
+        let renamed = labels.get(&session.id).filter(|labels| labels.renamed);
+
+        let session_labels = Labels {
+            session_id: session.id.clone(),
+            title: match renamed {
+                Some(labels) => labels.title.clone(),
+                None => generated.title.trim().to_string(),
+            },
+            tags: normalize_tags(&generated.tags),
+            messages: session.messages.len(),
+            renamed: renamed.is_some(),
+        };
+
+        if let Err(err) = store.add_labels(&session_labels) {
+            warn!("failed to save the labels: {}", err);
+            return;
+        }
+
+        labels.insert(session.id.clone(), session_labels);
+    }
+}
+
+async fn list_cmd(color: ColorMode, config: &Config, cache: Option<Cache>, args: &HistoryListArgs) {
+    let store = match SessionStore::open() {
+        Ok(store) => store,
+        Err(err) => die!("failed to list sessions: {}", err),
+    };
+
+    let (sessions, mut labels) = match store
+        .list()
+        .and_then(|sessions| Ok((sessions, store.labels()?)))
+    {
+        Ok(listed) => listed,
+        Err(err) => die!("failed to list sessions: {}", err),
+    };
+
+    if !args.no_label {
+        label_sessions(config, cache, &store, &sessions, &mut labels).await;
+    }
+
+    let tags: Vec<String> = args.tag.iter().map(|tag| normalize_tag(tag)).collect();
+
+    let sessions = sessions
+        .into_iter()
+        .filter(|session| {
+            tags.iter().all(|tag| {
+                labels
+                    .get(&session.id)
+                    .is_some_and(|labels| labels.tags.contains(tag))
+            })
+        })
+        .collect();
+
+    print_sessions(sessions, &labels, args.format, color);
+}
+
+/// A session as it is printed by `xtalk history show --json`
+#[derive(Serialize)]
+struct ShownSession<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    tags: &'a [String],
+    #[serde(flatten)]
+    session: &'a Session,
+}
+
+fn open_store() -> SessionStore {
+    match SessionStore::open() {
+        Ok(store) => store,
+        Err(err) => die!("failed to open the session store: {}", err),
+    }
+}
+
+fn load_labels(store: &SessionStore) -> HashMap<String, Labels> {
+    match store.labels() {
+        Ok(labels) => labels,
+        Err(err) => die!("failed to load the session labels: {}", err),
+    }
+}
+
+fn show_cmd(id: &str, json: bool) {
+    let store = open_store();
+
+    let session = match store.load(id) {
+        Ok(session) => session,
+        Err(err) => die!("failed to load the session: {}", err),
+    };
+
+    let labels = load_labels(&store);
+    let labels = labels.get(id);
+
+    let title = session.title(labels);
+    let tags = labels.map_or(&[][..], |labels| &labels.tags[..]);
+
+    if json {
+        let shown = ShownSession {
+            title,
+            tags,
+            session: &session,
+        };
+
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&shown).expect("failed to serialize the session")
+        );
+
+        return;
+    }
+
+    let mut details = vec![("session", session.id.clone())];
+
+    if let Some(title) = title {
+        details.push(("title", title));
+    }
+
+    if !tags.is_empty() {
+        details.push(("tags", tags.join(", ")));
+    }
+
+    details.push((
+        "created",
+        session.created.format("%Y-%m-%d %H:%M").to_string(),
+    ));
+    details.push((
+        "updated",
+        session.updated.format("%Y-%m-%d %H:%M").to_string(),
+    ));
+    details.push((
+        "model",
+        session
+            .model
+            .clone()
+            .unwrap_or_else(|| "unknown".to_string()),
+    ));
+
+    if let Some(seed) = session.seed {
+        details.push(("seed", seed.to_string()));
+    }
+
+    details.push(("messages", session.messages.len().to_string()));
+
+    for (field, value) in details {
+        println!("{:<9} {}", field, value);
+    }
+
+    let messages: Vec<Message> = session
+        .messages
+        .iter()
+        .map(|msg| msg.message.clone())
+        .collect();
+
+    let history = turns::history(&messages, &BTreeSet::new());
+
+    if !history.is_empty() {
+        println!("\n{}", history);
+    }
+}
+
+fn rename_cmd(id: &str, title: &str) {
+    let title = title.trim();
+
+    if title.is_empty() {
+        die!("the title of a session cannot be empty");
+    }
+
+    let store = open_store();
+
+    let session = match store.load(id) {
+        Ok(session) => session,
+        Err(err) => die!("failed to load the session: {}", err),
+    };
+
+    // The tags are kept, and the session is labeled again as it would have
+    // been had it not been renamed
+    let (tags, messages) = load_labels(&store)
+        .remove(id)
+        .map_or((Vec::new(), 0), |labels| (labels.tags, labels.messages));
+
+    let labels = Labels {
+        session_id: session.id,
+        title: title.to_string(),
+        tags,
+        messages,
+        renamed: true,
+    };
+
+    if let Err(err) = store.add_labels(&labels) {
+        die!("failed to rename the session: {}", err);
+    }
+}
+
+fn delete_cmd(ids: &[String]) {
+    let store = open_store();
+
+    for id in ids {
+        if let Err(err) = store.delete(id) {
+            die!("failed to delete the session: {}", err);
+        }
+    }
+}
+
+/// A session which matches a search, as it is printed by
+/// `xtalk history search --json`
+#[derive(Serialize)]
+struct FoundSession {
+    session: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    updated: DateTime<Local>,
+    matches: Vec<FoundMatch>,
+}
+
+#[derive(Serialize)]
+struct FoundMatch {
+    turn: usize,
+    role: Role,
+    excerpt: String,
+}
+
+/// Searches the saved sessions, returning those which match from the most
+/// recently updated
+fn search_sessions(query: &str) -> Vec<FoundSession> {
+    if query.trim().is_empty() {
+        die!("the search query cannot be empty");
+    }
+
+    let store = open_store();
+
+    let sessions = match store.list() {
+        Ok(sessions) => sessions,
+        Err(err) => die!("failed to search the sessions: {}", err),
+    };
+
+    let labels = load_labels(&store);
+
+    sessions
+        .iter()
+        .rev()
+        .filter_map(|session| {
+            let matches = search::search(session.messages.iter().map(|m| &m.message), query);
+
+            if matches.is_empty() {
+                return None;
+            }
+
+            Some(FoundSession {
+                session: session.id.clone(),
+                title: session.title(labels.get(&session.id)),
+                updated: session.updated,
+                matches: matches
+                    .into_iter()
+                    .map(|m| FoundMatch {
+                        turn: m.turn,
+                        role: m.role,
+                        excerpt: m.excerpt,
+                    })
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+fn search_cmd(color: ColorMode, args: &HistorySearchArgs) {
+    let found = search_sessions(&args.query);
+
+    if args.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&found).expect("failed to serialize the matches")
+        );
+
+        return;
+    }
+
+    if found.is_empty() {
+        die!("no sessions match \"{}\"", args.query);
+    }
+
+    let style = match color {
+        ColorMode::On => *SEARCH_MATCH,
+        ColorMode::Off => Style::new(),
+    };
+
+    for (i, session) in found.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+
+        match &session.title {
+            Some(title) => println!(
+                "{} {} ({})",
+                session.session,
+                title,
+                session.updated.format("%Y-%m-%d %H:%M")
+            ),
+            None => println!(
+                "{} ({})",
+                session.session,
+                session.updated.format("%Y-%m-%d %H:%M")
+            ),
+        }
+
+        for m in session.matches.iter().take(args.max_matches) {
+            let shown = search::Match {
+                turn: m.turn,
+                role: m.role.clone(),
+                excerpt: highlight(&m.excerpt, &args.query, style),
+            };
+
+            println!("  {}", shown);
+        }
+
+        let more = session.matches.len().saturating_sub(args.max_matches);
+
+        if more > 0 {
+            println!("  ... and {} more", more);
+        }
+    }
+}
+
+/// The most recently updated session which matches the search, to be
+/// resumed by `xtalk history search --resume`
+pub(crate) fn search_resume_session(args: &HistorySearchArgs) -> String {
+    match search_sessions(&args.query).into_iter().next() {
+        Some(found) => found.session,
+        None => die!("no sessions match \"{}\"", args.query),
+    }
+}
+
+/// A task extracted from a session
+#[derive(Serialize, Deserialize)]
+struct Task {
+    task: String,
+    #[serde(default)]
+    details: Option<String>,
+    #[serde(default)]
+    done: bool,
+}
+
+#[derive(Deserialize)]
+struct Tasks {
+    tasks: Vec<Task>,
+}
+
+fn load_session(id: &str) -> Session {
+    match SessionStore::open().and_then(|store| store.load(id)) {
+        Ok(session) => session,
+        Err(err) => die!("failed to load the session: {}", err),
+    }
+}
+
+/// Asks the model for the tasks, re-prompting it with the error when its
+/// response cannot be parsed
+async fn extract_tasks(
+    provider: &dyn ChatProvider,
+    model_id: &str,
+    options: &CompletionOptions,
+    system_prompt: Option<&str>,
+    conversation: String,
+    retries: u32,
+) -> Result<Result<Vec<Task>, serde_json::Error>, providers::Error> {
+    let mut request = Vec::new();
+
+    if let Some(system_prompt) = system_prompt {
+        request.push(Message::new(Role::System, system_prompt.to_string()));
+    }
+
+    request.push(Message::new(Role::System, TASK_INSTRUCTIONS.to_string()));
+    request.push(Message::new(Role::User, conversation));
+
+    let mut attempts = 0;
+
+    loop {
+        let mut completion = provider
+            .stream_completion(model_id, &request, options)
+            .await?;
+
+        let mut response = String::new();
+
+        while let Some(delta) = completion.next().await {
+            response.push_str(&delta?.content);
+        }
+
+        match serde_json::from_str::<Tasks>(&response) {
+            Ok(tasks) => return Ok(Ok(tasks.tasks)),
+            Err(err) if attempts < retries => {
+                attempts += 1;
+
+                warn!(
+                    "the tasks were malformed, re-prompting ({}/{}): {}",
+                    attempts, retries, err
+                );
+
+                request.push(Message::new(Role::Model, response));
+                request.push(Message::new(
+                    Role::User,
+                    format!(
+                        "Your response was not in the requested form ({}). Respond again with only the JSON.",
+                        err
+                    ),
+                ));
+            }
+            Err(err) => return Ok(Err(err)),
+        }
+    }
+}
+
+/// Writes the tasks out as a markdown checklist
+fn checklist(tasks: &[Task]) -> String {
+    tasks
+        .iter()
+        .map(|task| {
+            let mark = if task.done { 'x' } else { ' ' };
+
+            match &task.details {
+                Some(details) if !details.trim().is_empty() => {
+                    format!("- [{}] {}: {}\n", mark, task.task, details.trim())
+                }
+                _ => format!("- [{}] {}\n", mark, task.task),
+            }
+        })
+        .collect()
+}
+
+async fn tasks_cmd(
+    config: &Config,
+    cache: Option<Cache>,
+    default_model: Option<String>,
+    system_prompt: Option<String>,
+    temperature: Option<f64>,
+    args: &TasksArgs,
+) {
+    let session = load_session(&args.session);
+
+    if session.messages.is_empty() {
+        die!("session \"{}\" has no messages", session.id);
+    }
+
+    let registry = populated_registry(config, cache).await;
+
+    let model = args
+        .model
+        .clone()
+        .or(session.model.clone())
+        .or(default_model);
+
+    let resolved = resolve_spec(&registry, model)
+        .await
+        .and_then(ModelSpec::provider_model_ids);
+
+    let (provider_id, model_id) = match resolved {
+        Ok(ids) => ids,
+        Err(err) => die_with!(err.exit_code(), "failed to resolve model: {}", err),
+    };
+
+    let spec = ModelSpec::resolved(provider_id, model_id.clone());
+
+    let provider = match registry.active_provider(provider_id) {
+        Ok(provider) => provider.as_ref(),
+        Err(err) => die_with!(
+            err.exit_code(),
+            "task extraction by {} failed: {}",
+            spec,
+            err
+        ),
+    };
+
+    let messages: Vec<Message> = session
+        .messages
+        .into_iter()
+        .map(|msg| msg.message)
+        .collect();
+
+    let options = CompletionOptions {
+        response_format: ResponseFormat::Json,
+        temperature,
+        ..Default::default()
+    };
+
+    let tasks = match extract_tasks(
+        provider,
+        &model_id,
+        &options,
+        system_prompt.as_deref(),
+        transcript(&messages),
+        args.retries,
+    )
+    .await
+    {
+        Ok(Ok(tasks)) => tasks,
+        Ok(Err(err)) => die!(
+            "{} did not respond with the tasks in the requested form after {} retries: {}",
+            spec,
+            args.retries,
+            err
+        ),
+        Err(err) => die_with!(
+            err.exit_code(),
+            "task extraction by {} failed: {}",
+            spec,
+            err
+        ),
+    };
+
+    let output = match args.format {
+        TaskFormat::Markdown => checklist(&tasks),
+        TaskFormat::Json => {
+            serde_json::to_string_pretty(&tasks).expect("failed to serialize tasks") + "\n"
+        }
+    };
+
+    if let Err(err) = std::io::stdout().write_all(output.as_bytes()) {
+        die!("failed to write to standard output: {}", err);
+    }
+}
+
+pub(crate) async fn history_cmd(
+    color: ColorMode,
+    config: &Config,
+    cache: Option<Cache>,
+    default_model: Option<String>,
+    system_prompt: Option<String>,
+    temperature: Option<f64>,
+    args: &HistoryArgs,
+) {
+    match &args.action {
+        HistoryAction::List(args) => list_cmd(color, config, cache, args).await,
+        HistoryAction::Show { session, json } => show_cmd(session, *json),
+        HistoryAction::Rename { session, title } => rename_cmd(session, title),
+        HistoryAction::Delete { sessions } => delete_cmd(sessions),
+        HistoryAction::Search(args) => search_cmd(color, args),
+        HistoryAction::Export {
+            session,
+            format,
+            output,
+        } => {
+            let session = load_session(session);
+
+            let format = format.unwrap_or_else(|| match output {
+                Some(path) => ExportFormat::from_path(path),
+                None => ExportFormat::Markdown,
+            });
+
+            // Sessions saved before usage was recorded have no totals
+            let usage = UsageLog::from_messages(&session.messages);
+
+            let metadata = Metadata::new(
+                &session.messages,
+                session.created,
+                Parameters {
+                    seed: session.seed,
+                    ..Default::default()
+                },
+                (!usage.is_empty()).then(|| usage.totals()),
+            );
+
+            let transcript = render_transcript(&session.messages, &metadata, format);
+
+            let written = match output {
+                Some(path) => std::fs::write(path, transcript),
+                None => std::io::stdout().write_all(transcript.as_bytes()),
+            };
+
+            if let Err(err) = written {
+                die!("failed to export the session: {}", err);
+            }
+        }
+        HistoryAction::Tasks(args) => {
+            tasks_cmd(
+                config,
+                cache,
+                default_model,
+                system_prompt,
+                temperature,
+                args,
+            )
+            .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_tags() {
+        let tags = ["Rust", "error handling", "rust", " ", "a", "b", "c", "d"].map(String::from);
+
+        assert_eq!(
+            normalize_tags(&tags),
+            vec!["rust", "error-handling", "a", "b", "c"]
+        );
+    }
+}
@@ -0,0 +1,127 @@
+//! Shell integration snippets
+//!
+//! `xtalk init <shell>` prints a script which is intended to be evaluated
+//! by the user's shell at startup (e.g., `eval "$(xtalk init zsh)"`). The
+//! script defines:
+//!
+//! - `??`: queries the default model in one-shot mode.
+//! - Ctrl-X Ctrl-A: asks the default model to explain the current command line.
+//! - Completions for `xtalk` itself.
+
+use clap::{Command, ValueEnum};
+
+use crate::version;
+
+/// Shells which `xtalk init` can generate integrations for
+#[derive(Clone, Copy, ValueEnum, strum_macros::Display)]
+#[strum(serialize_all = "lowercase")]
+pub(crate) enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+const EXPLAIN_PROMPT: &str = "Briefly explain what the following shell command does:";
+
+const BASH_INTEGRATION: &str = r#"
+# Query the default model, e.g. `?? how do I list open ports`
+__xtalk_query() {
+    set +f
+    xtalk chat "$*"
+}
+
+# Disable globbing so that questions may contain "?" and "*"
+alias '??'='set -f; __xtalk_query'
+
+# Explain the current command line
+__xtalk_explain() {
+    [ -z "$READLINE_LINE" ] && return
+    printf '\n'
+    xtalk chat "@EXPLAIN_PROMPT@ $READLINE_LINE" < /dev/tty
+}
+
+bind -x '"\C-x\C-a": __xtalk_explain'
+"#;
+
+const ZSH_INTEGRATION: &str = r#"
+# Query the default model, e.g. `?? how do I list open ports`
+__xtalk_query() {
+    xtalk chat "$*"
+}
+
+# Disable globbing so that questions may contain "?" and "*"
+alias '??'='noglob __xtalk_query'
+
+# Explain the current command line
+__xtalk_explain() {
+    [[ -z "$BUFFER" ]] && return
+    zle -I
+    print
+    xtalk chat "@EXPLAIN_PROMPT@ $BUFFER" < /dev/tty
+    zle reset-prompt
+}
+
+zle -N __xtalk_explain
+bindkey '^X^A' __xtalk_explain
+"#;
+
+const FISH_INTEGRATION: &str = r#"
+# Query the default model, e.g. `?? how do I list open ports`
+function __xtalk_query
+    xtalk chat "$argv"
+end
+
+abbr --add '??' __xtalk_query
+
+# Explain the current command line
+function __xtalk_explain
+    set -l line (commandline)
+    test -z "$line"; and return
+    echo
+    xtalk chat "@EXPLAIN_PROMPT@ $line" < /dev/tty
+    commandline -f repaint
+end
+
+bind \cx\ca __xtalk_explain
+"#;
+
+fn completions(shell: Shell, mut command: Command) -> String {
+    let generator = match shell {
+        Shell::Bash => clap_complete::Shell::Bash,
+        Shell::Zsh => clap_complete::Shell::Zsh,
+        Shell::Fish => clap_complete::Shell::Fish,
+    };
+
+    let mut buf = Vec::new();
+
+    clap_complete::generate(generator, &mut command, version::NAME, &mut buf);
+
+    String::from_utf8(buf).expect("completions should be valid UTF-8")
+}
+
+/// Builds the integration script for the specified shell
+pub(crate) fn integration_script(shell: Shell, command: Command) -> String {
+    let integration = match shell {
+        Shell::Bash => BASH_INTEGRATION,
+        Shell::Zsh => ZSH_INTEGRATION,
+        Shell::Fish => FISH_INTEGRATION,
+    };
+
+    let mut script = format!(
+        "# {} integration for {}, generated by `{} init {}`\n",
+        shell,
+        version::NAME,
+        version::NAME,
+        shell
+    );
+
+    script.push_str(&integration.replace("@EXPLAIN_PROMPT@", EXPLAIN_PROMPT));
+    script.push('\n');
+    script.push_str(&completions(shell, command));
+
+    script
+}
+
+pub(crate) fn init_cmd(shell: Shell, command: Command) {
+    print!("{}", integration_script(shell, command));
+}
@@ -1,37 +1,65 @@
+use std::collections::{BTreeMap, HashMap};
+
+use filter::ModelFilter;
 use nu_ansi_term::Color;
 use strum::IntoEnumIterator;
 use table::{IntoRow, IntoTable, Row, Table};
-mod table;
+pub(crate) mod filter;
+pub(crate) mod table;
 
 use crate::{
     providers::providers::ProviderIdentifier, registry::registry::Registry, ListArgs, ListObject,
     ListingFormat,
 };
 
+use crate::config;
+use crate::providers::ModelDetails;
+use crate::registry::populate::inactive_models;
+use crate::sessions::{self, Labels, SessionStore};
+use crate::templates;
 use crate::ColorMode;
 
-use crate::die;
+use crate::{die, die_with, warn};
 
 #[derive(serde::Serialize)]
 struct Model {
     model_id: String,
     context: Option<u64>,
+    #[serde(flatten)]
+    details: ModelDetails,
+}
+
+/// Formats the context length, followed by the details of the model
+fn model_columns(context: Option<u64>, details: ModelDetails) -> Vec<String> {
+    vec![
+        match context {
+            Some(context) => context.to_string(),
+            None => "unknown".to_string(),
+        },
+        details.parameter_size.unwrap_or_default(),
+        details.family.unwrap_or_default(),
+        details.quantization.unwrap_or_default(),
+    ]
 }
 
 impl From<Vec<Model>> for Table {
     fn from(value: Vec<Model>) -> Self {
         let mut tab = Table::new();
 
-        tab.set_header(standard_header(vec!["MODEL", "CONTEXT"]));
+        tab.set_header(standard_header(vec![
+            "MODEL",
+            "CONTEXT",
+            "PARAMETERS",
+            "FAMILY",
+            "QUANTIZATION",
+        ]));
 
         for model in value {
-            tab.add_row(standard_body(vec![
-                model.model_id,
-                match model.context {
-                    Some(context) => context.to_string(),
-                    None => "unknown".to_string(),
-                },
-            ]));
+            let mut row = vec![model.model_id];
+
+            row.extend(model_columns(model.context, model.details));
+
+            tab.add_row(standard_body(row));
         }
 
         tab
@@ -43,15 +71,21 @@ struct ProvidedModel {
     model_id: String,
     provider: ProviderIdentifier,
     context: Option<u64>,
+    #[serde(flatten)]
+    details: ModelDetails,
+    /// Whether the provider is activated, only reported when the models of
+    /// inactive providers are listed as well
+    #[serde(skip_serializing_if = "Option::is_none")]
+    activated: Option<bool>,
 }
 
-fn standard_header<R: IntoRow>(v: R) -> Row {
+pub(crate) fn standard_header<R: IntoRow>(v: R) -> Row {
     let row = v.into_row();
 
     row.with_style(Color::Green.into())
 }
 
-fn standard_body<R: IntoRow>(v: R) -> Row {
+pub(crate) fn standard_body<R: IntoRow>(v: R) -> Row {
     let row = v.into_row();
 
     row.with_style(Color::White.into())
@@ -61,17 +95,33 @@ impl From<Vec<ProvidedModel>> for Table {
     fn from(value: Vec<ProvidedModel>) -> Self {
         let mut tab = Table::new();
 
-        tab.set_header(standard_header(vec!["MODEL", "PROVIDER", "CONTEXT"]));
+        let annotated = value.iter().any(|model| model.activated.is_some());
+
+        let mut header = vec![
+            "MODEL",
+            "PROVIDER",
+            "CONTEXT",
+            "PARAMETERS",
+            "FAMILY",
+            "QUANTIZATION",
+        ];
+
+        if annotated {
+            header.push("ACTIVATED");
+        }
+
+        tab.set_header(standard_header(header));
 
         for model in value {
-            tab.add_row(standard_body(vec![
-                model.model_id,
-                model.provider.to_string(),
-                match model.context {
-                    Some(context) => context.to_string(),
-                    None => "unknown".to_string(),
-                },
-            ]));
+            let mut row = vec![model.model_id, model.provider.to_string()];
+
+            row.extend(model_columns(model.context, model.details));
+
+            if let Some(activated) = model.activated {
+                row.push(if activated { "yes" } else { "no" }.to_string());
+            }
+
+            tab.add_row(standard_body(row));
         }
 
         tab
@@ -107,6 +157,170 @@ impl Into<Table> for Vec<Provider> {
     }
 }
 
+#[derive(serde::Serialize)]
+struct Session {
+    session_id: String,
+    created: String,
+    updated: String,
+    messages: usize,
+    model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+}
+
+impl From<Vec<Session>> for Table {
+    fn from(value: Vec<Session>) -> Self {
+        let mut tab = Table::new();
+
+        // Tags are only shown once some session has been labeled
+        let tagged = value.iter().any(|session| !session.tags.is_empty());
+
+        let mut header = vec![
+            "SESSION", "CREATED", "UPDATED", "MESSAGES", "MODEL", "TITLE",
+        ];
+
+        if tagged {
+            header.push("TAGS");
+        }
+
+        tab.set_header(standard_header(header));
+
+        for session in value {
+            let mut row = vec![
+                session.session_id,
+                session.created,
+                session.updated,
+                session.messages.to_string(),
+                session.model.unwrap_or_else(|| "unknown".to_string()),
+                session.title.unwrap_or_default(),
+            ];
+
+            if tagged {
+                row.push(session.tags.join(", "));
+            }
+
+            tab.add_row(standard_body(row));
+        }
+
+        tab
+    }
+}
+
+/// Prints the sessions with their titles, along with the tags of those which
+/// were labeled
+pub(crate) fn print_sessions(
+    sessions: Vec<sessions::Session>,
+    labels: &HashMap<String, Labels>,
+    format: ListingFormat,
+    color: ColorMode,
+) {
+    let sessions: Vec<Session> = sessions
+        .into_iter()
+        .map(|s| {
+            let labels = labels.get(&s.id);
+
+            Session {
+                created: s.created.format("%Y-%m-%d %H:%M").to_string(),
+                updated: s.updated.format("%Y-%m-%d %H:%M").to_string(),
+                messages: s.messages.len(),
+                title: s.title(labels),
+                model: s.model,
+                tags: labels.map(|labels| labels.tags.clone()).unwrap_or_default(),
+                session_id: s.id,
+            }
+        })
+        .collect();
+
+    format_output(sessions, "sessions", format, color);
+}
+
+#[derive(serde::Serialize)]
+struct Template {
+    template: String,
+    placeholders: Vec<String>,
+    description: Option<String>,
+}
+
+impl From<Vec<Template>> for Table {
+    fn from(value: Vec<Template>) -> Self {
+        let mut tab = Table::new();
+
+        tab.set_header(standard_header(vec![
+            "TEMPLATE",
+            "PLACEHOLDERS",
+            "DESCRIPTION",
+        ]));
+
+        for template in value {
+            tab.add_row(standard_body(vec![
+                template.template,
+                template.placeholders.join(", "),
+                template.description.unwrap_or_default(),
+            ]));
+        }
+
+        tab
+    }
+}
+
+fn get_templates(templates: &[templates::Template]) -> Vec<Template> {
+    templates
+        .iter()
+        .filter_map(|template| match template.placeholders() {
+            Ok(names) => Some(Template {
+                template: template.name.clone(),
+                placeholders: names.into_iter().map(str::to_string).collect(),
+                description: template.description.clone(),
+            }),
+            Err(err) => {
+                warn!("skipping the \"{}\" template: {}", template.name, err);
+                None
+            }
+        })
+        .collect()
+}
+
+#[derive(serde::Serialize)]
+struct Persona {
+    persona: String,
+    model: Option<String>,
+    temperature: Option<f64>,
+}
+
+impl From<Vec<Persona>> for Table {
+    fn from(value: Vec<Persona>) -> Self {
+        let mut tab = Table::new();
+
+        tab.set_header(standard_header(vec!["PERSONA", "MODEL", "TEMPERATURE"]));
+
+        for persona in value {
+            tab.add_row(standard_body(vec![
+                persona.persona,
+                persona.model.unwrap_or_default(),
+                persona
+                    .temperature
+                    .map(|temperature| temperature.to_string())
+                    .unwrap_or_default(),
+            ]));
+        }
+
+        tab
+    }
+}
+
+fn get_personas(personas: &BTreeMap<String, config::Persona>) -> Vec<Persona> {
+    personas
+        .iter()
+        .map(|(name, persona)| Persona {
+            persona: name.clone(),
+            model: persona.model.clone(),
+            temperature: persona.temperature,
+        })
+        .collect()
+}
+
 fn get_providers(registry: &Registry) -> Vec<Provider> {
     let mut providers = Vec::new();
 
@@ -134,13 +348,15 @@ async fn get_registered_models(registry: &Registry) -> Vec<ProvidedModel> {
                     model_id: pm.model.id,
                     provider: pm.provider,
                     context: pm.model.context_length,
+                    details: pm.model.details,
+                    activated: None,
                 })
                 .collect();
 
             registered_models
         }
         Err(err) => {
-            die!("failed to list models: {}", err);
+            die_with!(err.exit_code(), "failed to list models: {}", err);
         }
     }
 }
@@ -158,7 +374,7 @@ async fn get_models_for_provider(registry: &Registry, id: ProviderIdentifier) ->
 
     let models = match provider.models().await {
         Ok(models) => models,
-        Err(err) => die!("failed to list models: {}", err),
+        Err(err) => die_with!(err.exit_code(), "failed to list models: {}", err),
     };
 
     let registered_models: Vec<Model> = models
@@ -166,14 +382,18 @@ async fn get_models_for_provider(registry: &Registry, id: ProviderIdentifier) ->
         .map(|m| Model {
             model_id: m.id,
             context: m.context_length,
+            details: m.details,
         })
         .collect();
 
     registered_models
 }
 
-fn format_output<O: IntoTable + serde::Serialize>(
+/// Prints the listing in the format. A TOML document must be a table, so
+/// the listing is placed under `key`, e.g. as an array of `[[models]]`.
+pub(crate) fn format_output<O: IntoTable + serde::Serialize>(
     object: O,
+    key: &str,
     format: ListingFormat,
     color: ColorMode,
 ) {
@@ -183,6 +403,21 @@ fn format_output<O: IntoTable + serde::Serialize>(
 
             println!("{}", output);
         }
+        ListingFormat::Yaml => {
+            let output = serde_yaml::to_string(&object).expect("failed to seralize object");
+
+            print!("{}", output);
+        }
+        ListingFormat::Csv => {
+            print!("{}", object.into_table().to_csv());
+        }
+        ListingFormat::Toml => {
+            let document = BTreeMap::from([(key, &object)]);
+
+            let output = toml::to_string(&document).expect("failed to seralize object");
+
+            print!("{}", output);
+        }
         ListingFormat::Table => {
             let mut tab = object.into_table();
 
@@ -206,22 +441,82 @@ fn format_output<O: IntoTable + serde::Serialize>(
     }
 }
 
-pub(crate) async fn list_cmd(color: ColorMode, registry: Registry, args: &ListArgs) {
+/// Lists the models of the active providers followed by those of the
+/// inactive providers, annotated with whether their provider is activated
+async fn get_all_models(config: &config::Config, registry: &Registry) -> Vec<ProvidedModel> {
+    let mut models = get_registered_models(registry).await;
+
+    for model in &mut models {
+        model.activated = Some(true);
+    }
+
+    models.extend(
+        inactive_models(config, registry)
+            .await
+            .into_iter()
+            .map(|pm| ProvidedModel {
+                model_id: pm.model.id,
+                provider: pm.provider,
+                context: pm.model.context_length,
+                details: pm.model.details,
+                activated: Some(false),
+            }),
+    );
+
+    models
+}
+
+pub(crate) async fn list_cmd(
+    color: ColorMode,
+    config: &config::Config,
+    registry: Registry,
+    templates: &[templates::Template],
+    personas: &BTreeMap<String, config::Persona>,
+    args: &ListArgs,
+) {
     let format = args.format;
 
     match &args.object {
         ListObject::Models(args) => {
+            let filter = ModelFilter {
+                pattern: args.filter.clone(),
+                context_min: args.context_min,
+            };
+
             if let Some(id) = args.provider {
-                let models = get_models_for_provider(&registry, id).await;
-                format_output(models, format, color);
+                let mut models = get_models_for_provider(&registry, id).await;
+                models.retain(|m| filter.matches(&m.model_id, m.context));
+                format_output(models, "models", format, color);
             } else {
-                let models = get_registered_models(&registry).await;
-                format_output(models, format, color);
+                let mut models = if args.all {
+                    get_all_models(config, &registry).await
+                } else {
+                    get_registered_models(&registry).await
+                };
+                models.retain(|m| filter.matches(&m.model_id, m.context));
+                format_output(models, "models", format, color);
             }
         }
         ListObject::Providers => {
             let providers = get_providers(&registry);
-            format_output(providers, format, color);
+            format_output(providers, "providers", format, color);
+        }
+        ListObject::Sessions => {
+            let listed =
+                SessionStore::open().and_then(|store| Ok((store.list()?, store.labels()?)));
+
+            match listed {
+                Ok((sessions, labels)) => print_sessions(sessions, &labels, format, color),
+                Err(err) => die!("failed to list sessions: {}", err),
+            }
+        }
+        ListObject::Templates => {
+            let templates = get_templates(templates);
+            format_output(templates, "templates", format, color);
+        }
+        ListObject::Personas => {
+            let personas = get_personas(personas);
+            format_output(personas, "personas", format, color);
         }
     }
 }
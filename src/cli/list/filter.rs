@@ -0,0 +1,110 @@
+//! Filters for model listings
+//!
+//! A pattern containing `*` or `?` is a glob which must match the whole model
+//! ID, where `*` matches any run of characters and `?` matches a single
+//! character. Any other pattern matches model IDs which contain it. Both are
+//! matched without regard to case.
+
+/// Selects the models shown in a listing
+#[derive(Debug, Default)]
+pub(crate) struct ModelFilter {
+    pub(crate) pattern: Option<String>,
+    /// The smallest context length shown. Models whose context length is not
+    /// known are left out.
+    pub(crate) context_min: Option<u64>,
+}
+
+impl ModelFilter {
+    pub(crate) fn matches(&self, model_id: &str, context: Option<u64>) -> bool {
+        let pattern_matches = match &self.pattern {
+            Some(pattern) => matches_pattern(pattern, model_id),
+            None => true,
+        };
+
+        let context_matches = match self.context_min {
+            Some(min) => context.is_some_and(|context| context >= min),
+            None => true,
+        };
+
+        pattern_matches && context_matches
+    }
+}
+
+fn matches_pattern(pattern: &str, model_id: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let model_id = model_id.to_lowercase();
+
+    if pattern.contains(['*', '?']) {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let text: Vec<char> = model_id.chars().collect();
+
+        glob(&pattern, &text)
+    } else {
+        model_id.contains(&pattern)
+    }
+}
+
+/// Matches the text against the glob, backtracking to the last `*` on a
+/// mismatch
+fn glob(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // The position after the last `*` and the text it has consumed up to
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, t));
+                p += 1;
+            }
+            Some('?') => {
+                p += 1;
+                t += 1;
+            }
+            Some(&c) if c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((after, consumed)) => {
+                    p = after;
+                    t = consumed + 1;
+                    star = Some((after, consumed + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern("gpt-4*", "gpt-4o-mini"));
+        assert!(matches_pattern("gpt-4*", "gpt-4"));
+        assert!(!matches_pattern("gpt-4*", "chatgpt-4o"));
+        assert!(matches_pattern("*:7b", "codellama:7b"));
+        assert!(matches_pattern("gemma:?b", "gemma:2b"));
+        assert!(!matches_pattern("gemma:?b", "gemma:27b"));
+        assert!(matches_pattern("*a*a*", "llama3"));
+        assert!(matches_pattern("LLAMA", "codellama:7b"));
+        assert!(!matches_pattern("mistral", "mixtral:8x7b"));
+    }
+
+    #[test]
+    fn test_context_min() {
+        let filter = ModelFilter {
+            pattern: None,
+            context_min: Some(32000),
+        };
+
+        assert!(filter.matches("gpt-4o", Some(128000)));
+        assert!(!filter.matches("gpt-4", Some(8192)));
+        assert!(!filter.matches("mock:1b", None));
+    }
+}
@@ -32,6 +32,9 @@
 //!
 //! print!({}, tab);
 //! ```
+//!
+//! Tables can also be written as CSV, for spreadsheets, with
+//! [`Table::to_csv`]. Styles are not carried over.
 
 use nu_ansi_term::{AnsiGenericString, Style};
 use std::fmt::{self, Write};
@@ -66,6 +69,16 @@ impl Cell {
         self.style = style;
         self
     }
+
+    /// Writes the content as a field of a CSV record, quoting it if it
+    /// contains a delimiter, a quote, or a line break (RFC 4180)
+    fn csv_field(&self) -> String {
+        if self.content.contains([',', '"', '\r', '\n']) {
+            format!("\"{}\"", self.content.replace('"', "\"\""))
+        } else {
+            self.content.clone()
+        }
+    }
 }
 
 impl From<String> for Cell {
@@ -96,6 +109,12 @@ impl Row {
         self.cells.len()
     }
 
+    fn csv_record(&self) -> String {
+        let fields: Vec<String> = self.cells.iter().map(Cell::csv_field).collect();
+
+        fields.join(",")
+    }
+
     /// Helper to add style to all cells in the row
     pub(crate) fn with_style(mut self, style: Style) -> Self {
         for cell in &mut self.cells {
@@ -197,6 +216,18 @@ impl Table {
         self.header.iter().chain(self.body.iter())
     }
 
+    /// Writes the table as CSV, with the header as the first record unless
+    /// it is hidden. Records end with CRLF, as RFC 4180 specifies.
+    pub(crate) fn to_csv(&self) -> String {
+        let rows: Box<dyn Iterator<Item = &Row>> = if self.print_header {
+            Box::new(self.iter_rows())
+        } else {
+            Box::new(self.body.iter())
+        };
+
+        rows.map(|row| row.csv_record() + "\r\n").collect()
+    }
+
     fn column_widths(&self, include_header: bool) -> Vec<usize> {
         let n_cols = match self.num_columns {
             Some(n_cols) => n_cols,
@@ -380,6 +411,25 @@ mod tests {
         assert_eq!(format!("{}", tab), expected);
     }
 
+    #[test]
+    fn test_csv() {
+        let mut tab = Table::new();
+
+        tab.set_header(vec!["MODEL", "DESCRIPTION"]);
+        tab.add_row(vec!["gpt-4o", "fast, \"omni\""]);
+        tab.add_row(vec!["llama3", "line\nbreak"]);
+        tab.add_row(vec!["gemma", ""]);
+
+        assert_eq!(
+            tab.to_csv(),
+            "MODEL,DESCRIPTION\r\ngpt-4o,\"fast, \"\"omni\"\"\"\r\nllama3,\"line\nbreak\"\r\ngemma,\r\n"
+        );
+
+        tab.print_header(false);
+
+        assert!(tab.to_csv().starts_with("gpt-4o,"));
+    }
+
     #[test]
     #[should_panic(
         expected = "Table header is not awk safe. One of the cells contains a whitespace character or is empty."
@@ -0,0 +1,345 @@
+//! A language server offering inline completions
+//!
+//! `xtalk lsp` speaks the Language Server Protocol over standard input and
+//! output. It tracks the documents open in the editor and answers
+//! `textDocument/inlineCompletion` requests by asking a fill-in-the-middle
+//! model for the text at the cursor, which editors display as ghost text.
+//! The model is taken from the `[lsp]` section of the configuration, falling
+//! back to the default model.
+
+use std::collections::HashMap;
+use std::io::Write;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader, Stdin};
+
+use crate::providers::providers::ProviderIdentifier;
+use crate::registry::model_id::ModelId;
+use crate::registry::populate::resolve_spec;
+use crate::registry::registry::{ModelSpec, Registry};
+use crate::utils::jsonrpc::{
+    Request, Response, ResponseError, INTERNAL_ERROR, INVALID_PARAMS, METHOD_NOT_FOUND, PARSE_ERROR,
+};
+use crate::{die, die_with, version};
+
+// Structures to deserialize document synchronization and completion requests
+
+#[derive(Deserialize, Debug)]
+struct TextDocumentIdentifier {
+    uri: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct TextDocumentItem {
+    uri: String,
+    text: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct DidOpenParams {
+    text_document: TextDocumentItem,
+}
+
+#[derive(Deserialize, Debug)]
+struct ContentChange {
+    text: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct DidChangeParams {
+    text_document: TextDocumentIdentifier,
+    content_changes: Vec<ContentChange>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct DidCloseParams {
+    text_document: TextDocumentIdentifier,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+struct Position {
+    line: usize,
+    character: usize,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct InlineCompletionParams {
+    text_document: TextDocumentIdentifier,
+    position: Position,
+}
+
+/// Converts a position into a byte offset within the text. Per the protocol,
+/// characters are counted in UTF-16 code units. Positions beyond the end of
+/// a line are clamped to the end of the line.
+fn offset(text: &str, position: Position) -> Option<usize> {
+    let mut start = 0;
+
+    for _ in 0..position.line {
+        start += text[start..].find('\n')? + 1;
+    }
+
+    let mut units = 0;
+
+    for (i, c) in text[start..].char_indices() {
+        if units >= position.character || c == '\n' {
+            return Some(start + i);
+        }
+
+        units += c.len_utf16();
+    }
+
+    Some(text.len())
+}
+
+struct Server {
+    registry: Registry,
+    provider_id: ProviderIdentifier,
+    model_id: ModelId,
+    documents: HashMap<String, String>,
+}
+
+impl Server {
+    fn initialize(&self) -> Value {
+        json!({
+            "capabilities": {
+                "textDocumentSync": {
+                    "openClose": true,
+                    // Full synchronization
+                    "change": 1
+                },
+                "inlineCompletionProvider": {}
+            },
+            "serverInfo": {
+                "name": version::NAME,
+                "version": version::VERSION
+            }
+        })
+    }
+
+    fn did_open(&mut self, params: Value) {
+        if let Ok(params) = serde_json::from_value::<DidOpenParams>(params) {
+            self.documents
+                .insert(params.text_document.uri, params.text_document.text);
+        }
+    }
+
+    fn did_change(&mut self, params: Value) {
+        if let Ok(mut params) = serde_json::from_value::<DidChangeParams>(params) {
+            // With full synchronization, the last change holds the entire document
+            if let Some(change) = params.content_changes.pop() {
+                self.documents.insert(params.text_document.uri, change.text);
+            }
+        }
+    }
+
+    fn did_close(&mut self, params: Value) {
+        if let Ok(params) = serde_json::from_value::<DidCloseParams>(params) {
+            self.documents.remove(&params.text_document.uri);
+        }
+    }
+
+    async fn inline_completion(&self, params: Value) -> Result<Value, ResponseError> {
+        let params: InlineCompletionParams = serde_json::from_value(params)
+            .map_err(|err| ResponseError::new(INVALID_PARAMS, err.to_string()))?;
+
+        let text = self
+            .documents
+            .get(&params.text_document.uri)
+            .ok_or_else(|| {
+                ResponseError::new(
+                    INVALID_PARAMS,
+                    format!("document \"{}\" is not open", params.text_document.uri),
+                )
+            })?;
+
+        let offset = offset(text, params.position).ok_or_else(|| {
+            ResponseError::new(INVALID_PARAMS, "the position is outside of the document")
+        })?;
+
+        let (prefix, suffix) = text.split_at(offset);
+
+        let provider = self.registry.provider(self.provider_id).unwrap();
+
+        let infill = provider
+            .fill_in_middle(&self.model_id, prefix, suffix)
+            .await
+            .map_err(|err| {
+                let spec = ModelSpec::resolved(self.provider_id, self.model_id.clone());
+
+                ResponseError::new(
+                    INTERNAL_ERROR,
+                    format!("completion for {} failed: {}", spec, err),
+                )
+            })?;
+
+        if infill.is_empty() {
+            return Ok(json!({ "items": [] }));
+        }
+
+        Ok(json!({
+            "items": [{
+                "insertText": infill,
+                "range": {
+                    "start": { "line": params.position.line, "character": params.position.character },
+                    "end": { "line": params.position.line, "character": params.position.character }
+                }
+            }]
+        }))
+    }
+}
+
+/// Reads a message framed by a `Content-Length` header. Returns `None` at
+/// the end of the input.
+async fn read_message(reader: &mut BufReader<Stdin>) -> Option<Vec<u8>> {
+    let mut content_length = None;
+
+    loop {
+        let mut header = String::new();
+
+        match reader.read_line(&mut header).await {
+            Ok(0) => return None,
+            Ok(_) => {}
+            Err(err) => die!("failed to read from standard input: {}", err),
+        }
+
+        let header = header.trim_end();
+
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let content_length = match content_length {
+        Some(content_length) => content_length,
+        None => die!("received a message without a valid Content-Length header"),
+    };
+
+    let mut content = vec![0; content_length];
+
+    if let Err(err) = reader.read_exact(&mut content).await {
+        die!("failed to read from standard input: {}", err);
+    }
+
+    Some(content)
+}
+
+fn send(response: &Response) {
+    let serialized = serde_json::to_string(response).expect("failed to serialize response");
+
+    let mut stdout = std::io::stdout().lock();
+
+    let written = write!(
+        stdout,
+        "Content-Length: {}\r\n\r\n{}",
+        serialized.len(),
+        serialized
+    )
+    .and_then(|_| stdout.flush());
+
+    if let Err(err) = written {
+        die!("failed to write to standard output: {}", err);
+    }
+}
+
+pub(crate) async fn lsp_cmd(registry: Registry, model: Option<String>) {
+    let resolved = resolve_spec(&registry, model)
+        .await
+        .and_then(ModelSpec::provider_model_ids);
+
+    let (provider_id, model_id) = match resolved {
+        Ok(ids) => ids,
+        Err(err) => die_with!(err.exit_code(), "failed to resolve model: {}", err),
+    };
+
+    let mut server = Server {
+        registry,
+        provider_id,
+        model_id,
+        documents: HashMap::new(),
+    };
+
+    let mut reader = BufReader::new(tokio::io::stdin());
+
+    let mut shutdown = false;
+
+    while let Some(content) = read_message(&mut reader).await {
+        let request: Request = match serde_json::from_slice(&content) {
+            Ok(request) => request,
+            Err(err) => {
+                send(&Response::new(
+                    Value::Null,
+                    Err(ResponseError::new(PARSE_ERROR, err.to_string())),
+                ));
+
+                continue;
+            }
+        };
+
+        let result = match request.method.as_str() {
+            "initialize" => Ok(server.initialize()),
+            "shutdown" => {
+                shutdown = true;
+                Ok(Value::Null)
+            }
+            "exit" => break,
+            "textDocument/didOpen" => {
+                server.did_open(request.params);
+                continue;
+            }
+            "textDocument/didChange" => {
+                server.did_change(request.params);
+                continue;
+            }
+            "textDocument/didClose" => {
+                server.did_close(request.params);
+                continue;
+            }
+            "textDocument/inlineCompletion" => server.inline_completion(request.params).await,
+            method => Err(ResponseError::new(
+                METHOD_NOT_FOUND,
+                format!("method \"{}\" is not supported", method),
+            )),
+        };
+
+        // Notifications are never answered
+        if let Some(id) = request.id {
+            send(&Response::new(id, result));
+        }
+    }
+
+    // Per the protocol, exiting without a shutdown request is an error
+    if !shutdown {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offset() {
+        let text = "fn main() {\n    let s = \"héllo😀\";\n}\n";
+
+        let at = |line, character| offset(text, Position { line, character });
+
+        assert_eq!(at(0, 0), Some(0));
+        assert_eq!(at(1, 4), Some(16));
+        // The emoji is two UTF-16 code units
+        assert_eq!(&text[at(1, 20).unwrap()..], "\";\n}\n");
+        // Positions past the end of the line are clamped
+        assert_eq!(at(2, 10), Some(text.len() - 1));
+        assert_eq!(at(5, 0), None);
+    }
+}
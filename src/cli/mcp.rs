@@ -0,0 +1,365 @@
+//! Serve the model registry to MCP hosts
+//!
+//! `xtalk mcp-serve` speaks the Model Context Protocol over standard input
+//! and output, using newline-delimited JSON-RPC 2.0 messages. Hosts can
+//! delegate completions through crosstalk's provider configuration in two
+//! ways:
+//!
+//! - `sampling/createMessage`: accepts the same request an MCP client serves
+//!   for sampling. The first model hint which resolves to an active model is
+//!   used, falling back to the default model.
+//! - The `complete` tool, for hosts which only route tool calls to servers.
+//!
+//! Requests are served one at a time, in the order they are received.
+
+use std::io::Write;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::chat::{Message, Role};
+use crate::cli::chat::{self, MessageBuffer, RequestDefaults};
+use crate::providers::FinishReason;
+use crate::registry::populate::resolve_spec;
+use crate::registry::registry::{ModelSpec, Registry};
+use crate::utils::jsonrpc::{
+    Request, Response, ResponseError, INTERNAL_ERROR, INVALID_PARAMS, METHOD_NOT_FOUND, PARSE_ERROR,
+};
+use crate::{die, version};
+
+const PROTOCOL_VERSION: &str = "2024-11-05";
+
+// Structures to deserialize sampling/createMessage
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+enum SamplingRole {
+    User,
+    Assistant,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum SamplingContent {
+    Text { text: String },
+    Image {},
+    Audio {},
+}
+
+#[derive(Deserialize, Debug)]
+struct SamplingMessage {
+    role: SamplingRole,
+    content: SamplingContent,
+}
+
+#[derive(Deserialize, Debug)]
+struct ModelHint {
+    name: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct ModelPreferences {
+    #[serde(default)]
+    hints: Vec<ModelHint>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct CreateMessageParams {
+    messages: Vec<SamplingMessage>,
+    #[serde(default)]
+    model_preferences: Option<ModelPreferences>,
+    #[serde(default)]
+    system_prompt: Option<String>,
+}
+
+// Structures to deserialize tools/call
+
+#[derive(Deserialize, Debug)]
+struct CompleteArguments {
+    prompt: String,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    system: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "name", content = "arguments", rename_all = "lowercase")]
+enum ToolCall {
+    Complete(CompleteArguments),
+}
+
+struct Completion {
+    spec: ModelSpec,
+    content: String,
+    finish_reason: FinishReason,
+}
+
+struct Server {
+    registry: Registry,
+    default_model: Option<String>,
+    /// The configured instructions and temperature of chats
+    defaults: RequestDefaults,
+}
+
+impl Server {
+    /// Resolves the first spec which names an active model, falling back to the default model
+    async fn resolve(&self, specs: &[String]) -> Result<ModelSpec, String> {
+        for spec in specs {
+            if let Ok(spec) = resolve_spec(&self.registry, Some(spec.clone())).await {
+                return Ok(spec);
+            }
+        }
+
+        resolve_spec(&self.registry, self.default_model.clone())
+            .await
+            .map_err(|err| format!("failed to resolve model: {}", err))
+    }
+
+    /// Completes the conversation, following the host's instructions in place
+    /// of the configured ones if it gives any
+    async fn complete(
+        &self,
+        specs: &[String],
+        instructions: Option<String>,
+        conversation: Vec<Message>,
+    ) -> Result<Completion, String> {
+        let spec = self.resolve(specs).await?;
+
+        let (provider_id, model_id) = spec
+            .provider_model_ids()
+            .map_err(|err| format!("failed to resolve model: {}", err))?;
+
+        let spec = ModelSpec::resolved(provider_id, model_id.clone());
+
+        let provider = self
+            .registry
+            .active_provider(provider_id)
+            .map_err(|err| format!("completion for {} failed: {}", spec, err))?;
+
+        let defaults = self.defaults.with_instructions(instructions);
+
+        let mut msg_buf = MessageBuffer::new();
+
+        for message in conversation {
+            msg_buf.add_message(chat::Message::Chat(message, None, Default::default()));
+        }
+
+        let mut completion = provider
+            .stream_completion(&model_id, &defaults.messages(&msg_buf), &defaults.options())
+            .await
+            .map_err(|err| format!("completion for {} failed: {}", spec, err))?;
+
+        let mut content = String::new();
+
+        while let Some(delta) = completion.next().await {
+            let delta =
+                delta.map_err(|err| format!("failed to decode streaming response: {}", err))?;
+
+            content.push_str(&delta.content);
+        }
+
+        Ok(Completion {
+            spec,
+            content,
+            finish_reason: completion.finish_reason(),
+        })
+    }
+
+    fn initialize(&self, params: &Value) -> Value {
+        // Agree to the client's protocol version, since the subset of the
+        // protocol used here has not changed between versions
+        let protocol_version = params
+            .get("protocolVersion")
+            .and_then(Value::as_str)
+            .unwrap_or(PROTOCOL_VERSION);
+
+        json!({
+            "protocolVersion": protocol_version,
+            "capabilities": {
+                "tools": {},
+                "experimental": {
+                    "sampling": {}
+                }
+            },
+            "serverInfo": {
+                "name": version::NAME,
+                "version": version::VERSION
+            }
+        })
+    }
+
+    fn list_tools(&self) -> Value {
+        json!({
+            "tools": [{
+                "name": "complete",
+                "description": "Generate a response to a prompt with a chat model",
+                "inputSchema": {
+                    "type": "object",
+                    "properties": {
+                        "prompt": {
+                            "type": "string",
+                            "description": "The prompt for the model"
+                        },
+                        "model": {
+                            "type": "string",
+                            "description": "A model spec, such as \"ollama/gemma:2b\". The default model is used if unspecified."
+                        },
+                        "system": {
+                            "type": "string",
+                            "description": "A system message instructing the model"
+                        }
+                    },
+                    "required": ["prompt"]
+                }
+            }]
+        })
+    }
+
+    async fn call_tool(&self, params: Value) -> Result<Value, ResponseError> {
+        let ToolCall::Complete(args) = serde_json::from_value(params)
+            .map_err(|err| ResponseError::new(INVALID_PARAMS, err.to_string()))?;
+
+        let messages = vec![Message::new(Role::User, args.prompt)];
+
+        let specs: Vec<String> = args.model.into_iter().collect();
+
+        // Tool failures are reported to the model rather than the host
+        let result = match self.complete(&specs, args.system, messages).await {
+            Ok(completion) => json!({
+                "content": [{ "type": "text", "text": completion.content }],
+                "isError": false
+            }),
+            Err(err) => json!({
+                "content": [{ "type": "text", "text": err }],
+                "isError": true
+            }),
+        };
+
+        Ok(result)
+    }
+
+    async fn create_message(&self, params: Value) -> Result<Value, ResponseError> {
+        let params: CreateMessageParams = serde_json::from_value(params)
+            .map_err(|err| ResponseError::new(INVALID_PARAMS, err.to_string()))?;
+
+        let mut messages = Vec::new();
+
+        for msg in params.messages {
+            let text = match msg.content {
+                SamplingContent::Text { text } => text,
+                _ => {
+                    return Err(ResponseError::new(
+                        INVALID_PARAMS,
+                        "only text content is supported",
+                    ))
+                }
+            };
+
+            let role = match msg.role {
+                SamplingRole::User => Role::User,
+                SamplingRole::Assistant => Role::Model,
+            };
+
+            messages.push(Message::new(role, text));
+        }
+
+        let hints: Vec<String> = params
+            .model_preferences
+            .unwrap_or_default()
+            .hints
+            .into_iter()
+            .filter_map(|hint| hint.name)
+            .collect();
+
+        let completion = self
+            .complete(&hints, params.system_prompt, messages)
+            .await
+            .map_err(|err| ResponseError::new(INTERNAL_ERROR, err))?;
+
+        let stop_reason = match completion.finish_reason {
+            FinishReason::Stop => "endTurn",
+            FinishReason::Length => "maxTokens",
+            FinishReason::ContentFilter => "contentFilter",
+        };
+
+        Ok(json!({
+            "role": "assistant",
+            "content": { "type": "text", "text": completion.content },
+            "model": completion.spec.to_string(),
+            "stopReason": stop_reason
+        }))
+    }
+
+    async fn handle(&self, request: Request) -> Option<Response> {
+        let id = request.id?;
+
+        let result = match request.method.as_str() {
+            "initialize" => Ok(self.initialize(&request.params)),
+            "ping" => Ok(json!({})),
+            "tools/list" => Ok(self.list_tools()),
+            "tools/call" => self.call_tool(request.params).await,
+            "sampling/createMessage" => self.create_message(request.params).await,
+            method => Err(ResponseError::new(
+                METHOD_NOT_FOUND,
+                format!("method \"{}\" is not supported", method),
+            )),
+        };
+
+        Some(Response::new(id, result))
+    }
+}
+
+fn send(response: &Response) {
+    let serialized = serde_json::to_string(response).expect("failed to serialize response");
+
+    let mut stdout = std::io::stdout().lock();
+
+    if let Err(err) = writeln!(stdout, "{}", serialized).and_then(|_| stdout.flush()) {
+        die!("failed to write to standard output: {}", err);
+    }
+}
+
+pub(crate) async fn mcp_serve_cmd(
+    registry: Registry,
+    default_model: Option<String>,
+    defaults: RequestDefaults,
+) {
+    let server = Server {
+        registry,
+        default_model,
+        defaults,
+    };
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(err) => die!("failed to read from standard input: {}", err),
+        };
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let request: Request = match serde_json::from_str(&line) {
+            Ok(request) => request,
+            Err(err) => {
+                send(&Response::new(
+                    Value::Null,
+                    Err(ResponseError::new(PARSE_ERROR, err.to_string())),
+                ));
+
+                continue;
+            }
+        };
+
+        if let Some(response) = server.handle(request).await {
+            send(&response);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use strum::IntoEnumIterator;
+
+use super::list::{format_output, standard_body, standard_header, table::Table};
+use crate::providers::providers::ProviderIdentifier;
+use crate::registry::registry::{ModelSpec, Registry};
+use crate::utils::errors::{NOT_FOUND_EXIT_CODE, USAGE_EXIT_CODE};
+use crate::utils::format::human_bytes;
+use crate::{die_with, ColorMode, ModelsAction, ModelsArgs};
+
+#[derive(serde::Serialize)]
+struct LoadedModel {
+    model_id: String,
+    provider: ProviderIdentifier,
+    size: u64,
+    size_vram: u64,
+    processor: String,
+    expires_at: Option<String>,
+}
+
+impl From<Vec<LoadedModel>> for Table {
+    fn from(value: Vec<LoadedModel>) -> Self {
+        let mut tab = Table::new();
+
+        tab.set_header(standard_header(vec![
+            "MODEL",
+            "PROVIDER",
+            "SIZE",
+            "PROCESSOR",
+            "EXPIRES",
+        ]));
+
+        for model in value {
+            tab.add_row(standard_body(vec![
+                model.model_id,
+                model.provider.to_string(),
+                human_bytes(model.size),
+                model.processor,
+                model.expires_at.unwrap_or_else(|| "unknown".to_string()),
+            ]));
+        }
+
+        tab
+    }
+}
+
+async fn get_loaded_models(registry: &Registry) -> Vec<LoadedModel> {
+    let mut loaded = Vec::new();
+
+    for id in ProviderIdentifier::iter() {
+        let provider = match registry.provider(id) {
+            Some(provider) => provider,
+            None => continue,
+        };
+
+        let models = match provider.loaded_models().await {
+            Ok(models) => models,
+            Err(err) => die_with!(
+                err.exit_code(),
+                "failed to query loaded models from \"{}\": {}",
+                id,
+                err
+            ),
+        };
+
+        for model in models {
+            loaded.push(LoadedModel {
+                processor: model.processor(),
+                model_id: model.id,
+                provider: id,
+                size: model.size,
+                size_vram: model.size_vram,
+                expires_at: model.expires_at,
+            });
+        }
+    }
+
+    loaded
+}
+
+async fn stop_model(registry: &Registry, raw_spec: &str) {
+    let spec = match ModelSpec::parse(Some(raw_spec.to_string())) {
+        Ok(spec) => spec,
+        Err(err) => die_with!(USAGE_EXIT_CODE, "failed to parse model spec: {}", err),
+    };
+
+    let model_id = spec.model().unwrap();
+
+    let candidates: Vec<LoadedModel> = get_loaded_models(registry)
+        .await
+        .into_iter()
+        .filter(|m| m.model_id == model_id)
+        .filter(|m| spec.provider().is_none_or(|id| id == m.provider))
+        .collect();
+
+    if candidates.is_empty() {
+        die_with!(NOT_FOUND_EXIT_CODE, "model \"{}\" is not loaded", raw_spec);
+    }
+
+    for model in candidates {
+        let provider = registry.provider(model.provider).unwrap();
+
+        if let Err(err) = provider.unload_model(&model.model_id).await {
+            die_with!(
+                err.exit_code(),
+                "failed to unload \"{}/{}\": {}",
+                model.provider,
+                model.model_id,
+                err
+            );
+        }
+    }
+}
+
+pub(crate) async fn models_cmd(color: ColorMode, registry: Registry, args: &ModelsArgs) {
+    match &args.action {
+        ModelsAction::Ps => {
+            let models = get_loaded_models(&registry).await;
+            format_output(models, "models", args.format, color);
+        }
+        ModelsAction::Stop { model } => stop_model(&registry, model).await,
+    }
+}
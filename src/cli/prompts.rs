@@ -0,0 +1,328 @@
+//! Checks of prompt templates, for use in the CI of prompt repositories
+//!
+//! `xtalk prompts lint` checks the templates in the files and directories it
+//! is given or, if it is given none, those in the configuration, the prompts
+//! directory, and the libraries. Every file is checked, including those which
+//! cannot be parsed or whose template is overridden by another. A
+//! template has a problem if it cannot be parsed, if its placeholders do not
+//! match the `variables` it declares, or if its prompt leaves too little of
+//! the context of a target model for the values and the response. The
+//! command exits with an error if any template has a problem.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::chat::trim::{self, CHARS_PER_TOKEN};
+use crate::config::{self, Config};
+use crate::registry::populate::{populated_registry, resolve_spec};
+use crate::registry::registry::ModelSpec;
+use crate::templates::{self, Template, BUILTIN_PLACEHOLDERS};
+use crate::{cache::Cache, die, die_with, LintArgs, PromptsAction, PromptsArgs};
+
+/// The longest prompt allowed for a model, relative to its context
+enum Limit {
+    /// A number of tokens given on the command line
+    Tokens(usize),
+    /// Half of the context of the model, leaving the other half for the
+    /// values of the placeholders and the response
+    Context { spec: String, context: usize },
+}
+
+impl Limit {
+    fn max_tokens(&self) -> usize {
+        match self {
+            Limit::Tokens(tokens) => *tokens,
+            Limit::Context { context, .. } => context / 2,
+        }
+    }
+}
+
+/// The declared variable closest to the name, if it is close enough to be a
+/// misspelling of it
+fn suggestion<'a>(name: &str, variables: &'a [String]) -> Option<&'a str> {
+    variables
+        .iter()
+        .map(|variable| (strsim::levenshtein(name, variable), variable))
+        .filter(|(distance, _)| *distance <= 2)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, variable)| variable.as_str())
+}
+
+/// Describes each problem with the template
+fn lint(template: &Template, limits: &[Limit]) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let placeholders = match template.placeholders() {
+        Ok(placeholders) => placeholders,
+        Err(err) => {
+            problems.push(format!("the prompt cannot be parsed: {}", err));
+            return problems;
+        }
+    };
+
+    if let Some(variables) = &template.variables {
+        for placeholder in &placeholders {
+            if variables.iter().any(|v| v == placeholder)
+                || BUILTIN_PLACEHOLDERS.contains(placeholder)
+            {
+                continue;
+            }
+
+            problems.push(match suggestion(placeholder, variables) {
+                Some(variable) => format!(
+                    "{{{}}} is not a declared variable, did you mean {{{}}}?",
+                    placeholder, variable
+                ),
+                None => format!(
+                    "{{{}}} is not a declared variable, add \"{}\" to `variables` or remove the placeholder",
+                    placeholder, placeholder
+                ),
+            });
+        }
+
+        for variable in variables {
+            if !placeholders.contains(&variable.as_str()) {
+                problems.push(format!(
+                    "the variable \"{}\" is declared but the prompt has no {{{}}} placeholder, add one or remove it from `variables`",
+                    variable, variable
+                ));
+            }
+        }
+    }
+
+    let tokens = template.prompt.chars().count().div_ceil(CHARS_PER_TOKEN);
+
+    for limit in limits {
+        if tokens <= limit.max_tokens() {
+            continue;
+        }
+
+        problems.push(match limit {
+            Limit::Tokens(max) => format!(
+                "the prompt is estimated at {} tokens, more than the limit of {}, shorten it",
+                tokens, max
+            ),
+            Limit::Context { spec, context } => format!(
+                "the prompt is estimated at {} tokens, which leaves too little of the {} token context of {} for the values and the response, shorten it to {} tokens",
+                tokens,
+                context,
+                spec,
+                limit.max_tokens()
+            ),
+        });
+    }
+
+    problems
+}
+
+/// Reads the templates in the files, and in the `.toml` files of the
+/// directories, labeling each with its path. A file which cannot be read or
+/// parsed is labeled with the reason.
+fn read_templates(paths: &[PathBuf]) -> Vec<(String, Result<Template, String>)> {
+    let mut templates = Vec::new();
+    let mut files = Vec::new();
+
+    for path in paths {
+        if path.is_dir() {
+            match fs::read_dir(path) {
+                Ok(entries) => {
+                    let mut entries: Vec<PathBuf> = entries
+                        .flatten()
+                        .map(|entry| entry.path())
+                        .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+                        .collect();
+
+                    entries.sort();
+                    files.extend(entries);
+                }
+                Err(err) => templates.push((path.display().to_string(), Err(err.to_string()))),
+            }
+        } else {
+            files.push(path.clone());
+        }
+    }
+
+    for file in files {
+        let label = file.display().to_string();
+
+        let template = fs::read_to_string(&file)
+            .map_err(|err| err.to_string())
+            .and_then(|contents| {
+                toml::from_str::<config::Template>(&contents).map_err(|err| err.to_string())
+            });
+
+        let template = template
+            .map(|template| Template {
+                name: name_of(&file),
+                description: template.description,
+                prompt: template.prompt,
+                variables: template.variables,
+            })
+            .map_err(|err| err.trim().to_string());
+
+        templates.push((label, template));
+    }
+
+    templates
+}
+
+fn name_of(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// The limits of the models, which are resolved to find their context
+async fn model_limits(config: &Config, cache: Option<Cache>, models: &[String]) -> Vec<Limit> {
+    if models.is_empty() {
+        return Vec::new();
+    }
+
+    let registry = populated_registry(config, cache).await;
+
+    let mut limits = Vec::new();
+
+    for model in models {
+        let resolved = resolve_spec(&registry, Some(model.clone()))
+            .await
+            .and_then(ModelSpec::provider_model_ids);
+
+        let (provider_id, model_id) = match resolved {
+            Ok(ids) => ids,
+            Err(err) => die_with!(err.exit_code(), "failed to resolve model: {}", err),
+        };
+
+        let spec = ModelSpec::resolved(provider_id, model_id.clone());
+
+        let provider = match registry.active_provider(provider_id) {
+            Ok(provider) => provider.as_ref(),
+            Err(err) => die_with!(err.exit_code(), "failed to list {}: {}", spec, err),
+        };
+
+        match trim::context_length(provider, &model_id).await {
+            Some(context) => limits.push(Limit::Context {
+                spec: spec.to_string(),
+                context,
+            }),
+            None => die!(
+                "the context of {} is not known, give a limit with --max-tokens instead",
+                spec
+            ),
+        }
+    }
+
+    limits
+}
+
+async fn lint_cmd(config: &Config, cache: Option<Cache>, args: &LintArgs) {
+    let mut limits = model_limits(config, cache, &args.model).await;
+
+    if let Some(max_tokens) = args.max_tokens {
+        limits.push(Limit::Tokens(max_tokens as usize));
+    }
+
+    let templates = if args.paths.is_empty() {
+        let dirs: Vec<PathBuf> = templates::library_dirs(&config.prompt_paths)
+            .into_iter()
+            .filter(|dir| dir.is_dir())
+            .collect();
+
+        let mut templates: Vec<_> = config
+            .templates
+            .iter()
+            .map(|(name, template)| {
+                (
+                    format!("template \"{}\"", name),
+                    Ok(Template {
+                        name: name.clone(),
+                        description: template.description.clone(),
+                        prompt: template.prompt.clone(),
+                        variables: template.variables.clone(),
+                    }),
+                )
+            })
+            .collect();
+
+        templates.extend(read_templates(&dirs));
+
+        templates
+    } else {
+        read_templates(&args.paths)
+    };
+
+    let mut count = 0;
+
+    for (label, template) in &templates {
+        let problems = match template {
+            Ok(template) => lint(template, &limits),
+            Err(err) => vec![err.clone()],
+        };
+
+        for problem in problems {
+            println!("{}: {}", label, problem);
+            count += 1;
+        }
+    }
+
+    if count > 0 {
+        die!(
+            "found {} {}",
+            count,
+            if count == 1 { "problem" } else { "problems" }
+        );
+    }
+}
+
+pub(crate) async fn prompts_cmd(config: &Config, cache: Option<Cache>, args: &PromptsArgs) {
+    match &args.action {
+        PromptsAction::Lint(args) => lint_cmd(config, cache, args).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(prompt: &str, variables: Option<&[&str]>) -> Template {
+        Template {
+            name: "test".to_string(),
+            description: None,
+            prompt: prompt.to_string(),
+            variables: variables.map(|v| v.iter().map(|s| s.to_string()).collect()),
+        }
+    }
+
+    #[test]
+    fn test_lint() {
+        assert!(lint(&template("Translate {text} on {date}.", None), &[]).is_empty());
+
+        assert!(lint(
+            &template("Translate {text} on {date}.", Some(&["text"])),
+            &[]
+        )
+        .is_empty());
+
+        assert_eq!(
+            lint(&template("Translate {txt} to {lang}.", Some(&["text"])), &[]),
+            vec![
+                "{txt} is not a declared variable, did you mean {text}?",
+                "{lang} is not a declared variable, add \"lang\" to `variables` or remove the placeholder",
+                "the variable \"text\" is declared but the prompt has no {text} placeholder, add one or remove it from `variables`",
+            ]
+        );
+
+        assert_eq!(
+            lint(&template("Translate {text", None), &[]),
+            vec!["the prompt cannot be parsed: unterminated placeholder at byte 10"]
+        );
+
+        let limits = [Limit::Context {
+            spec: "ollama/gemma:2b".to_string(),
+            context: 8,
+        }];
+
+        // Half of the context is four tokens, or sixteen characters
+        assert!(lint(&template(&"x".repeat(16), None), &limits).is_empty());
+        assert_eq!(lint(&template(&"x".repeat(17), None), &limits).len(), 1);
+    }
+}
@@ -0,0 +1,367 @@
+//! Summaries of documents longer than the context of the model
+//!
+//! `xtalk summarize` reads a document the way `/attach` does, so the text of
+//! PDF documents is extracted and a range of pages may be selected. With the
+//! `map-reduce` strategy, the document is split into chunks which fit the
+//! context of the model, and the chunks are summarized concurrently. The
+//! summaries are then merged into one, after being summarized again if
+//! together they do not fit. With the `single` strategy, the document is
+//! summarized in a single request. The final summary is streamed to standard
+//! output.
+
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::Path;
+
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+use tracing::debug;
+
+use crate::chat::{Message, Role};
+use crate::cli::chat::attach::Attachment;
+use crate::cli::chat::trim::{self, CHARS_PER_TOKEN};
+use crate::providers::{self, ChatProvider, CompletionOptions, ContextManagement};
+use crate::registry::populate::resolve_spec;
+use crate::registry::registry::{ModelSpec, Registry};
+use crate::utils::errors::{CONTEXT_EXCEEDED_EXIT_CODE, USAGE_EXIT_CODE};
+use crate::{die, die_with, SummarizeArgs, SummaryStrategy};
+
+/// The size of chunks when the context of the model is not known, or is
+/// managed by the provider. Ollama reports the context the model was trained
+/// with, but only loads a fraction of it unless asked to.
+const DEFAULT_CHUNK_TOKENS: usize = 1536;
+
+const CHUNK_INSTRUCTIONS: &str = "Summarize the following excerpt of a longer document. Keep the key facts, figures, names, and conclusions, since the summary will be combined with the summaries of the other excerpts.";
+
+const MERGE_INSTRUCTIONS: &str = "The following are summaries of consecutive excerpts of a document, in order. Combine them into a single coherent summary of the whole document without repeating yourself.";
+
+const DOCUMENT_INSTRUCTIONS: &str =
+    "Summarize the following document. Keep the key facts, figures, names, and conclusions.";
+
+/// Reads the document from the path, or from standard input if there is none
+fn read_document(path: Option<&Path>) -> String {
+    let content = match path {
+        Some(path) => match Attachment::read(path) {
+            Ok(attachment) => attachment.content,
+            Err(err) => die!("failed to read the document: {}", err),
+        },
+        None if io::stdin().is_terminal() => die_with!(
+            USAGE_EXIT_CODE,
+            "no document was provided, either as a path or through standard input"
+        ),
+        None => {
+            let mut buf = String::new();
+
+            if let Err(err) = io::stdin().read_to_string(&mut buf) {
+                die!("failed to read the document from standard input: {}", err);
+            }
+
+            buf
+        }
+    };
+
+    if content.trim().is_empty() {
+        die!("the document is empty");
+    }
+
+    content
+}
+
+/// Splits text into chunks of at most `max_chars` characters. Chunks end
+/// between paragraphs where possible, then between lines, and otherwise
+/// wherever the limit is reached.
+fn chunk(text: &str, max_chars: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut current_chars = 0;
+
+    for paragraph in text.split("\n\n").filter(|p| !p.trim().is_empty()) {
+        for piece in split_paragraph(paragraph, max_chars) {
+            let chars = piece.chars().count();
+
+            // Account for the blank line which separates it from the previous
+            if current_chars > 0 && current_chars + 2 + chars > max_chars {
+                chunks.push(std::mem::take(&mut current));
+                current_chars = 0;
+            }
+
+            if current_chars > 0 {
+                current.push_str("\n\n");
+                current_chars += 2;
+            }
+
+            current.push_str(&piece);
+            current_chars += chars;
+        }
+    }
+
+    if current_chars > 0 {
+        chunks.push(current);
+    }
+
+    chunks
+}
+
+/// Splits a paragraph which is too long to fit in a chunk between lines, and
+/// lines which are too long to fit wherever the limit is reached
+fn split_paragraph(paragraph: &str, max_chars: usize) -> Vec<String> {
+    if paragraph.chars().count() <= max_chars {
+        return vec![paragraph.to_string()];
+    }
+
+    let mut pieces: Vec<String> = Vec::new();
+    let mut current = String::new();
+
+    for line in paragraph.lines() {
+        let chars: Vec<char> = line.chars().collect();
+
+        for part in chars.chunks(max_chars.max(1)) {
+            let part: String = part.iter().collect();
+
+            if !current.is_empty() && current.chars().count() + 1 + part.chars().count() > max_chars
+            {
+                pieces.push(std::mem::take(&mut current));
+            }
+
+            if !current.is_empty() {
+                current.push('\n');
+            }
+
+            current.push_str(&part);
+        }
+    }
+
+    if !current.is_empty() {
+        pieces.push(current);
+    }
+
+    pieces
+}
+
+/// A request which asks the model to follow the instructions, after the
+/// configured system prompt
+fn request(system_prompt: Option<&str>, instructions: &str, text: String) -> Vec<Message> {
+    let mut request = Vec::new();
+
+    if let Some(system_prompt) = system_prompt {
+        request.push(Message::new(Role::System, system_prompt.to_string()));
+    }
+
+    request.push(Message::new(Role::System, instructions.to_string()));
+    request.push(Message::new(Role::User, text));
+
+    request
+}
+
+/// Sends the request, returning the response in full
+async fn ask(
+    provider: &dyn ChatProvider,
+    model_id: &str,
+    options: &CompletionOptions,
+    request: Vec<Message>,
+) -> Result<String, providers::Error> {
+    let mut completion = provider
+        .stream_completion(model_id, &request, options)
+        .await?;
+
+    let mut response = String::new();
+
+    while let Some(delta) = completion.next().await {
+        response.push_str(&delta?.content);
+    }
+
+    Ok(response.trim().to_string())
+}
+
+/// Summarizes each chunk, running up to `concurrency` requests at a time.
+/// The summaries are returned in the order of the chunks.
+async fn summarize_chunks(
+    provider: &dyn ChatProvider,
+    model_id: &str,
+    options: &CompletionOptions,
+    system_prompt: Option<&str>,
+    chunks: Vec<String>,
+    concurrency: usize,
+) -> Result<Vec<String>, providers::Error> {
+    stream::iter(chunks)
+        .map(|chunk| {
+            let request = request(system_prompt, CHUNK_INSTRUCTIONS, chunk);
+
+            ask(provider, model_id, options, request)
+        })
+        .buffered(concurrency.max(1))
+        .try_collect()
+        .await
+}
+
+/// Streams the response to the final request to standard output
+async fn stream_summary(
+    provider: &dyn ChatProvider,
+    model_id: &str,
+    options: &CompletionOptions,
+    request: Vec<Message>,
+) -> Result<(), providers::Error> {
+    let mut completion = provider
+        .stream_completion(model_id, &request, options)
+        .await?;
+
+    let mut stdout = io::stdout().lock();
+    let mut ends_with_newline = false;
+
+    while let Some(delta) = completion.next().await {
+        let delta = delta?;
+
+        if !delta.content.is_empty() {
+            ends_with_newline = delta.content.ends_with('\n');
+        }
+
+        let _ = write!(stdout, "{}", delta.content).and_then(|_| stdout.flush());
+    }
+
+    if !ends_with_newline {
+        let _ = writeln!(stdout);
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn summarize_cmd(
+    registry: Registry,
+    default_model: Option<String>,
+    system_prompt: Option<String>,
+    temperature: Option<f64>,
+    args: &SummarizeArgs,
+) {
+    let document = read_document(args.path.as_deref());
+
+    let resolved = resolve_spec(&registry, args.model.clone().or(default_model))
+        .await
+        .and_then(ModelSpec::provider_model_ids);
+
+    let (provider_id, model_id) = match resolved {
+        Ok(ids) => ids,
+        Err(err) => die_with!(err.exit_code(), "failed to resolve model: {}", err),
+    };
+
+    let spec = ModelSpec::resolved(provider_id, model_id.clone());
+
+    let provider = match registry.active_provider(provider_id) {
+        Ok(provider) => provider.as_ref(),
+        Err(err) => die_with!(err.exit_code(), "summary by {} failed: {}", spec, err),
+    };
+
+    // Half of the context is left for the instructions and the summary
+    let chunk_tokens = match args.chunk_tokens {
+        Some(tokens) => tokens as usize,
+        None => match provider.context_management() {
+            ContextManagement::Explicit => trim::context_length(provider, &model_id)
+                .await
+                .map(|context| context / 2)
+                .unwrap_or(DEFAULT_CHUNK_TOKENS),
+            _ => DEFAULT_CHUNK_TOKENS,
+        },
+    };
+
+    let max_chars = chunk_tokens * CHARS_PER_TOKEN;
+
+    let options = CompletionOptions {
+        temperature,
+        ..Default::default()
+    };
+
+    let mut chunks = chunk(&document, max_chars);
+
+    if chunks.len() > 1 && matches!(args.strategy, SummaryStrategy::Single) {
+        die_with!(
+            CONTEXT_EXCEEDED_EXIT_CODE,
+            "the document is estimated at {} tokens, which is more than the {} tokens which fit in a request, use the map-reduce strategy",
+            document.chars().count().div_ceil(CHARS_PER_TOKEN),
+            chunk_tokens
+        );
+    }
+
+    if chunks.len() == 1 {
+        let request = request(
+            system_prompt.as_deref(),
+            DOCUMENT_INSTRUCTIONS,
+            chunks.remove(0),
+        );
+
+        if let Err(err) = stream_summary(provider, &model_id, &options, request).await {
+            die_with!(err.exit_code(), "summary by {} failed: {}", spec, err);
+        }
+
+        return;
+    }
+
+    // Summaries are merged once they fit in a single request, and summarized
+    // again until they do. Each round must shorten the text, or it would
+    // never fit.
+    let mut length = document.chars().count();
+
+    for round in 1.. {
+        debug!(
+            round,
+            chunks = chunks.len(),
+            chunk_tokens,
+            "summarizing chunks"
+        );
+
+        let summaries = match summarize_chunks(
+            provider,
+            &model_id,
+            &options,
+            system_prompt.as_deref(),
+            chunks,
+            args.concurrency as usize,
+        )
+        .await
+        {
+            Ok(summaries) => summaries,
+            Err(err) => die_with!(err.exit_code(), "summary by {} failed: {}", spec, err),
+        };
+
+        let merged = summaries.join("\n\n");
+        let merged_length = merged.chars().count();
+
+        if merged_length >= length {
+            die!(
+                "the summaries of {} are no shorter than the text they summarize, so they cannot be merged",
+                spec
+            );
+        }
+
+        length = merged_length;
+        chunks = chunk(&merged, max_chars);
+
+        if chunks.len() <= 1 {
+            let request = request(system_prompt.as_deref(), MERGE_INSTRUCTIONS, merged);
+
+            if let Err(err) = stream_summary(provider, &model_id, &options, request).await {
+                die_with!(err.exit_code(), "summary by {} failed: {}", spec, err);
+            }
+
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk() {
+        assert_eq!(
+            chunk("one\n\ntwo\n\nthree", 10),
+            vec!["one\n\ntwo", "three"]
+        );
+
+        assert_eq!(
+            chunk("a long line\nshort", 11),
+            vec!["a long line", "short"]
+        );
+
+        assert_eq!(chunk("abcdefgh", 3), vec!["abc", "def", "gh"]);
+
+        assert!(chunk("\n\n  \n\n", 10).is_empty());
+    }
+}
@@ -0,0 +1,166 @@
+//! Synchronization of sessions and prompts with the configured remote
+//!
+//! `xtalk sync` pulls the files which are newer on the remote, then pushes
+//! those which are newer locally. `pull` and `push` go in one direction only;
+//! a push still fetches the remote first so files pushed from other machines
+//! are kept.
+
+use std::env;
+use std::process::Command;
+
+use age::secrecy::SecretString;
+
+use crate::config::{Config, SyncBackend};
+use crate::sessions::SessionStore;
+use crate::sync::{self, Bundle, Dir, GitRemote, Remote, RsyncRemote};
+use crate::templates::prompts_dir;
+use crate::utils::dirs::cache_dir;
+use crate::utils::errors::{AUTH_EXIT_CODE, USAGE_EXIT_CODE};
+use crate::{die, die_with, SyncAction, SyncArgs};
+
+/// Overrides the passphrase command of the configuration
+const PASSPHRASE_VAR: &str = "XTALK_SYNC_PASSPHRASE";
+
+fn passphrase(config: &Config) -> SecretString {
+    if let Some(passphrase) = env::var(PASSPHRASE_VAR).ok().filter(|p| !p.is_empty()) {
+        return SecretString::from(passphrase);
+    }
+
+    let Some(command) = &config.sync.passphrase_command else {
+        die_with!(
+            USAGE_EXIT_CODE,
+            "no passphrase was given, set {} or passphrase_command in the [sync] section",
+            PASSPHRASE_VAR
+        );
+    };
+
+    let output = match Command::new("sh").args(["-c", command]).output() {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => die!(
+            "the passphrase command exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(err) => die!("failed to run the passphrase command: {}", err),
+    };
+
+    // Only the line ending is removed, since spaces may be part of it
+    let passphrase = String::from_utf8_lossy(&output.stdout)
+        .trim_end_matches(['\n', '\r'])
+        .to_string();
+
+    if passphrase.is_empty() {
+        die!("the passphrase command printed an empty passphrase");
+    }
+
+    SecretString::from(passphrase)
+}
+
+/// The directories which are synchronized
+fn local_dirs() -> Vec<Dir> {
+    let sessions = match SessionStore::open() {
+        Ok(store) => store.dir().to_path_buf(),
+        Err(err) => die!("failed to locate the sessions: {}", err),
+    };
+
+    let Some(prompts) = prompts_dir() else {
+        die!("failed to locate the prompts, neither XDG_CONFIG_HOME nor HOME is set");
+    };
+
+    vec![
+        Dir {
+            name: "sessions",
+            path: sessions,
+            extensions: &["json", "jsonl"],
+        },
+        Dir {
+            name: "personas",
+            path: prompts.join("personas"),
+            extensions: &["toml"],
+        },
+        Dir {
+            name: "prompts",
+            path: prompts,
+            extensions: &["toml"],
+        },
+    ]
+}
+
+fn files(count: usize) -> String {
+    format!("{} {}", count, if count == 1 { "file" } else { "files" })
+}
+
+fn sync(remote: &mut dyn Remote, passphrase: &SecretString, action: Option<SyncAction>) {
+    let fail = |err: sync::Error| -> ! {
+        match err {
+            sync::Error::Decrypt(_) => die_with!(AUTH_EXIT_CODE, "sync failed: {}", err),
+            _ => die!("sync failed: {}", err),
+        }
+    };
+
+    let mut remote_bundle = match remote.fetch() {
+        Ok(Some(sealed)) => Bundle::open(&sealed, passphrase).unwrap_or_else(|err| fail(err)),
+        Ok(None) => Bundle::default(),
+        Err(err) => fail(err),
+    };
+
+    let dirs = local_dirs();
+
+    let mut local = Bundle::collect(&dirs).unwrap_or_else(|err| fail(err));
+
+    let mut report = Vec::new();
+
+    if !matches!(action, Some(SyncAction::Push)) {
+        let pulled = local.merge(&remote_bundle);
+
+        if let Err(err) = local.unpack(&pulled, &dirs) {
+            fail(err);
+        }
+
+        report.push(format!("pulled {}", files(pulled.len())));
+    }
+
+    if !matches!(action, Some(SyncAction::Pull)) {
+        let pushed = remote_bundle.merge(&local);
+
+        if !pushed.is_empty() {
+            let stored = remote_bundle
+                .seal(passphrase)
+                .and_then(|sealed| remote.store(&sealed));
+
+            if let Err(err) = stored {
+                fail(err);
+            }
+        }
+
+        report.push(format!("pushed {}", files(pushed.len())));
+    }
+
+    println!("{}", report.join(", "));
+}
+
+pub(crate) fn sync_cmd(config: &Config, args: &SyncArgs) {
+    let Some(remote) = &config.sync.remote else {
+        die_with!(
+            USAGE_EXIT_CODE,
+            "no remote is configured, set remote in the [sync] section"
+        );
+    };
+
+    let Some(staging) = cache_dir().map(|dir| dir.join("xtalk/sync")) else {
+        die!("failed to locate the cache, neither XDG_CACHE_HOME nor HOME is set");
+    };
+
+    let passphrase = passphrase(config);
+
+    let mut remote: Box<dyn Remote> = match config.sync.backend {
+        SyncBackend::Git => Box::new(GitRemote::new(
+            remote.clone(),
+            staging.join("git"),
+            whoami::fallible::hostname().unwrap_or_else(|_| "unknown".to_string()),
+        )),
+        SyncBackend::Rsync => Box::new(RsyncRemote::new(remote, staging.join("rsync"))),
+    };
+
+    sync(remote.as_mut(), &passphrase, args.action);
+}
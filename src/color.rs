@@ -2,8 +2,11 @@ use crate::cli::ColorMode;
 use lazy_static::lazy_static;
 use nu_ansi_term::{AnsiGenericString, Color, Style};
 use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
 
 lazy_static! {
     pub(crate) static ref USER_PROMPT: Style = Color::Blue.bold();
@@ -14,10 +17,38 @@ lazy_static! {
     pub(crate) static ref WARNING_INDICATOR: Style = Color::Yellow.bold();
     pub(crate) static ref ERROR_TEXT: Style = Color::Default.bold();
     pub(crate) static ref WARNING_TEXT: Style = Color::Default.bold();
+    pub(crate) static ref COMMAND: Style = Color::Cyan.bold();
+    pub(crate) static ref MODEL_SPEC: Style = Color::Green.bold();
+    pub(crate) static ref FILE_REFERENCE: Style = Color::Magenta.bold().underline();
+    pub(crate) static ref PLACEHOLDER: Style = Color::Yellow.bold();
+    pub(crate) static ref INVALID_INPUT: Style = Color::Red.bold();
+    pub(crate) static ref FOLD_MARKER: Style = Color::Default.dimmed();
+    pub(crate) static ref LINE_COUNT: Style = Color::Default.dimmed();
+    pub(crate) static ref CLOCK: Style = Color::Default.dimmed();
+    pub(crate) static ref SEARCH_MATCH: Style = Color::Yellow.bold();
+    pub(crate) static ref DIFF_ADDED: Style = Color::Green.normal();
+    pub(crate) static ref DIFF_REMOVED: Style = Color::Red.normal();
+    pub(crate) static ref CODE_KEYWORD: Style = Color::Magenta.normal();
+    pub(crate) static ref CODE_STRING: Style = Color::Green.normal();
+    pub(crate) static ref CODE_COMMENT: Style = Color::Default.dimmed().italic();
+    pub(crate) static ref CODE_NUMBER: Style = Color::Cyan.normal();
 }
 
 static mut USE_COLOR: AtomicBool = AtomicBool::new(true);
 
+/// The colors of the model prompt which are configured, keyed by model
+/// spec, model, or provider, along with the provider of each model which
+/// has been resolved
+#[derive(Default)]
+struct Accents {
+    colors: BTreeMap<String, Accent>,
+    providers: HashMap<String, String>,
+}
+
+lazy_static! {
+    static ref ACCENTS: RwLock<Accents> = RwLock::default();
+}
+
 pub(crate) fn configure_color(cmode: ColorMode) {
     match cmode {
         ColorMode::On => unsafe {
@@ -60,3 +91,151 @@ impl MaybePaint for Style {
         }
     }
 }
+
+/// A color given in the configuration: a name, such as "magenta" or
+/// "bright-blue", a 256-color index, or a hex color such as "#ff8700"
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct Accent(Color);
+
+/// The named colors, the bright ones prefixed with "bright-"
+const NAMED_COLORS: [(&str, Color, Color); 8] = [
+    ("black", Color::Black, Color::DarkGray),
+    ("red", Color::Red, Color::LightRed),
+    ("green", Color::Green, Color::LightGreen),
+    ("yellow", Color::Yellow, Color::LightYellow),
+    ("blue", Color::Blue, Color::LightBlue),
+    ("magenta", Color::Purple, Color::LightPurple),
+    ("cyan", Color::Cyan, Color::LightCyan),
+    ("white", Color::LightGray, Color::White),
+];
+
+impl FromStr for Accent {
+    type Err = String;
+
+    fn from_str(accent: &str) -> Result<Self, Self::Err> {
+        let name = accent.trim().to_lowercase().replace('_', "-");
+
+        let (bright, base) = match name.strip_prefix("bright-") {
+            Some(base) => (true, base),
+            None => (false, name.as_str()),
+        };
+
+        if let Some((_, normal, light)) = NAMED_COLORS.iter().find(|(n, ..)| *n == base) {
+            return Ok(Accent(if bright { *light } else { *normal }));
+        }
+
+        if let Ok(index) = name.parse::<u8>() {
+            return Ok(Accent(Color::Fixed(index)));
+        }
+
+        let rgb = name
+            .strip_prefix('#')
+            .filter(|hex| hex.len() == 6)
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok());
+
+        match rgb {
+            Some(rgb) => Ok(Accent(Color::Rgb(
+                (rgb >> 16) as u8,
+                (rgb >> 8) as u8,
+                rgb as u8,
+            ))),
+            None => Err(format!(
+                "expected a color name (e.g., magenta or bright-blue), a 256-color index, or a hex color (e.g., #ff8700), found \"{}\"",
+                accent
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Accent {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for Accent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let named = NAMED_COLORS.iter().find_map(|(name, normal, light)| {
+            if *normal == self.0 {
+                Some(name.to_string())
+            } else if *light == self.0 {
+                Some(format!("bright-{}", name))
+            } else {
+                None
+            }
+        });
+
+        match (named, self.0) {
+            (Some(name), _) => write!(f, "{}", name),
+            (None, Color::Fixed(index)) => write!(f, "{}", index),
+            (None, Color::Rgb(r, g, b)) => write!(f, "#{:02x}{:02x}{:02x}", r, g, b),
+            (None, color) => write!(f, "{:?}", color),
+        }
+    }
+}
+
+impl From<Accent> for String {
+    fn from(value: Accent) -> Self {
+        value.to_string()
+    }
+}
+
+/// Sets the colors of the model prompt, keyed by model spec, model, or
+/// provider
+pub(crate) fn configure_accents(colors: &BTreeMap<String, Accent>) {
+    ACCENTS.write().unwrap().colors = colors.clone();
+}
+
+/// Records the provider of a model, so the model is given the color of its
+/// provider
+pub(crate) fn note_provider(model_id: &str, provider: &str) {
+    ACCENTS
+        .write()
+        .unwrap()
+        .providers
+        .insert(model_id.to_string(), provider.to_string());
+}
+
+/// The style of the model prompt for the model. The color configured for
+/// its model spec takes precedence over that of the model, and the color of
+/// the model over that of its provider.
+pub(crate) fn model_prompt_style(model_id: &str) -> Style {
+    let accents = ACCENTS.read().unwrap();
+
+    let provider = accents.providers.get(model_id);
+
+    let keys = [
+        provider.map(|provider| format!("{}/{}", provider, model_id)),
+        Some(model_id.to_string()),
+        provider.cloned(),
+    ];
+
+    keys.iter()
+        .flatten()
+        .find_map(|key| accents.colors.get(key))
+        .map_or(*MODEL_PROMPT, |accent| accent.0.bold())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accent() {
+        let parse = |accent: &str| accent.parse::<Accent>().map(|accent| accent.0);
+
+        assert_eq!(parse("magenta"), Ok(Color::Purple));
+        assert_eq!(parse("Bright_Blue"), Ok(Color::LightBlue));
+        assert_eq!(parse("208"), Ok(Color::Fixed(208)));
+        assert_eq!(parse("#FF8700"), Ok(Color::Rgb(255, 135, 0)));
+        assert!(parse("#ff87").is_err());
+        assert!(parse("mauve").is_err());
+
+        for accent in ["bright-cyan", "white", "17", "#0a0b0c"] {
+            assert_eq!(accent.parse::<Accent>().unwrap().to_string(), accent);
+        }
+    }
+}
@@ -1,10 +1,41 @@
+use crate::color::Accent;
 use crate::die;
+use crate::providers::KeepAlive;
+use crate::registry::model_id::ModelId;
+use crate::utils::dirs;
 use crate::warn;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::default;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
 use toml;
 
+/// A commented configuration which sets every option to its default
+pub(crate) const DEFAULT_CONFIG: &str = include_str!("config/default.toml");
+
+/// The path of the user's configuration, relative to a configuration directory
+const USER_CONFIG_PATH: &str = "xtalk/config.toml";
+
+#[derive(Debug, Error)]
+pub(crate) enum Error {
+    #[error("failed to read {}: {source}", path.display())]
+    Read { path: PathBuf, source: io::Error },
+    #[error("failed to parse {}: {source}", path.display())]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("there is no profile named \"{0}\"")]
+    UnknownProfile(String),
+    #[error("the providers of the \"{name}\" profile are invalid: {source}")]
+    Profile {
+        name: String,
+        source: toml::de::Error,
+    },
+}
+
 /// Specifies when the provider should activate.
 #[derive(Deserialize, Serialize, Default, Clone, Copy, Debug)]
 #[serde(rename_all = "lowercase")]
@@ -29,8 +60,22 @@ pub(crate) enum Keybindings {
     Vi,
 }
 
+/// Specifies what is done when a conversation no longer fits in the context
+/// of the model.
+#[derive(Deserialize, Serialize, Default, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Trimming {
+    /// Remove the oldest messages (default).
+    #[default]
+    Drop,
+    /// Replace the oldest messages with a summary written by the model.
+    Summarize,
+    /// Report the error, leaving the conversation as it is.
+    Fail,
+}
+
 /// Configuration for the Ollama provider.
-#[derive(Deserialize, Serialize, Default, Debug)]
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
 pub(crate) struct Ollama {
     /// The activation policy for Ollama.
     ///
@@ -41,34 +86,86 @@ pub(crate) struct Ollama {
     pub activate: ProviderActivationPolicy,
 
     /// Specifies the default model to be used when Ollama is the preferred provider.
-    pub default_model: Option<String>,
+    pub default_model: Option<ModelId>,
 
     /// Specifies the base URL for the Ollama API.
     pub api_base: Option<String>,
 
     /// Sets the priority for the Ollama provider.
     pub priority: Option<u8>,
+    /// Limits the number of requests sent to Ollama each minute. Requests
+    /// which would exceed the limit are delayed. Zero disables the limit.
+    pub requests_per_minute: Option<u32>,
+    /// How long models stay loaded after a request (e.g., "10m", "0s" to
+    /// unload them immediately, or "forever"). Ollama's default is used if
+    /// unset.
+    pub keep_alive: Option<KeepAlive>,
+    /// Overrides the global timeouts for requests to Ollama.
+    #[serde(default)]
+    pub timeouts: Timeouts,
 }
 
 /// Configuration for the OpenAI provider.
-#[derive(Deserialize, Serialize, Default, Debug)]
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
 pub(crate) struct OpenAI {
     /// The activation policy for OpenAI.
     #[serde(default)]
     pub activate: ProviderActivationPolicy,
 
     /// Specifies the default model to be used when OpenAI is the preferred provider.
-    pub default_model: Option<String>,
+    pub default_model: Option<ModelId>,
 
     /// Sets the OpenAI API key. This takes precedence over the OPENAI_API_KEY environment variable, if set.
     pub api_key: Option<String>,
 
+    /// Verify the API key at startup when the activation policy is "auto".
+    ///
+    /// If the key is rejected, the provider is deactivated with a warning
+    /// rather than failing later mid-chat.
+    #[serde(default)]
+    pub probe: bool,
+
     /// Sets the priority for the OpenAI provider.
     pub priority: Option<u8>,
+    /// Limits the number of requests sent to OpenAI each minute. Requests
+    /// which would exceed the limit are delayed. Zero disables the limit.
+    pub requests_per_minute: Option<u32>,
+    /// Overrides the global timeouts for requests to OpenAI.
+    #[serde(default)]
+    pub timeouts: Timeouts,
+}
+
+/// Configuration for the Azure OpenAI provider.
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub(crate) struct AzureOpenAI {
+    /// The activation policy for Azure OpenAI.
+    #[serde(default)]
+    pub activate: ProviderActivationPolicy,
+
+    /// Specifies the endpoint of the Azure OpenAI resource (e.g., https://NAME.openai.azure.com).
+    pub endpoint: Option<String>,
+
+    /// Specifies the name of the deployment which serves as the default model.
+    pub deployment: Option<ModelId>,
+
+    /// Specifies the version of the Azure OpenAI API used for requests.
+    pub api_version: Option<String>,
+
+    /// Sets the Azure OpenAI API key. This takes precedence over the AZURE_OPENAI_API_KEY environment variable, if set.
+    pub api_key: Option<String>,
+
+    /// Sets the priority for the Azure OpenAI provider.
+    pub priority: Option<u8>,
+    /// Limits the number of requests sent to Azure OpenAI each minute. Requests
+    /// which would exceed the limit are delayed. Zero disables the limit.
+    pub requests_per_minute: Option<u32>,
+    /// Overrides the global timeouts for requests to Azure OpenAI.
+    #[serde(default)]
+    pub timeouts: Timeouts,
 }
 
 /// Configuration for the providers.
-#[derive(Deserialize, Serialize, Default, Debug)]
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
 pub(crate) struct Providers {
     /// Configuration for the Ollama provider.
     #[serde(default)]
@@ -77,10 +174,273 @@ pub(crate) struct Providers {
     /// Configuration for the OpenAI provider.
     #[serde(default)]
     pub openai: OpenAI,
+
+    /// Configuration for the Azure OpenAI provider.
+    #[serde(default)]
+    pub azure_openai: AzureOpenAI,
+}
+
+/// Configuration for retrying requests which were rate limited or rejected
+/// by an overloaded server.
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub(crate) struct Retry {
+    /// The maximum number of attempts, including the first. Setting this to
+    /// one disables retries.
+    pub max_attempts: Option<u32>,
+
+    /// The delay before the first retry in milliseconds. The delay doubles
+    /// with each subsequent retry.
+    pub base_delay_ms: Option<u64>,
+}
+
+/// Configuration for how long requests wait on an unresponsive server.
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub(crate) struct Timeouts {
+    /// The time allowed to establish a connection in milliseconds. Setting
+    /// this to zero disables the timeout.
+    pub connect_ms: Option<u64>,
+
+    /// The time allowed between reads from the server in milliseconds. A
+    /// streamed response may take longer in total so long as data keeps
+    /// arriving. Setting this to zero disables the timeout.
+    pub read_ms: Option<u64>,
+}
+
+/// Configuration for the input history of the chat REPL.
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub(crate) struct History {
+    /// Save the history so it is available in later sessions. By default,
+    /// the history is saved.
+    pub persist: Option<bool>,
+
+    /// Specifies the file the history is saved in. By default, it is saved
+    /// in `$XDG_DATA_HOME/xtalk/history`.
+    pub path: Option<PathBuf>,
+
+    /// The maximum number of entries kept in the history.
+    pub max_entries: Option<usize>,
+
+    /// Prompts which always appear at the top of the history menu.
+    #[serde(default)]
+    pub pinned: Vec<String>,
+}
+
+/// Configuration for files generated by models, such as images.
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub(crate) struct Files {
+    /// Specifies the directory generated files are saved in. By default,
+    /// they are saved in `$XDG_DATA_HOME/xtalk/files`.
+    pub dir: Option<PathBuf>,
+
+    /// Preview generated images, and images attached in the chat REPL,
+    /// inline in terminals which support a graphics protocol. By default,
+    /// images are previewed.
+    pub preview: Option<bool>,
+}
+
+/// System prompts for individual subcommands. Each replaces the general
+/// system prompt for its subcommand.
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub(crate) struct SystemPrompts {
+    /// Instructions given to the model before a chat.
+    pub chat: Option<String>,
+
+    /// Instructions given to the model by `xtalk complete`.
+    pub complete: Option<String>,
+
+    /// Instructions given to the model by `xtalk summarize`, before those
+    /// which ask for the summary.
+    pub summarize: Option<String>,
+
+    /// Instructions given to the model by `xtalk history tasks`, before
+    /// those which ask for the tasks.
+    pub tasks: Option<String>,
+}
+
+impl SystemPrompts {
+    fn is_empty(&self) -> bool {
+        self.chat.is_none()
+            && self.complete.is_none()
+            && self.summarize.is_none()
+            && self.tasks.is_none()
+    }
+
+    /// Replaces the prompts which are set in the overrides
+    fn merge(&mut self, overrides: SystemPrompts) {
+        let SystemPrompts {
+            chat,
+            complete,
+            summarize,
+            tasks,
+        } = overrides;
+
+        self.chat = chat.or(self.chat.take());
+        self.complete = complete.or(self.complete.take());
+        self.summarize = summarize.or(self.summarize.take());
+        self.tasks = tasks.or(self.tasks.take());
+    }
+}
+
+/// A prompt template.
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub(crate) struct Template {
+    /// A short description of the template.
+    pub description: Option<String>,
+
+    /// The prompt, which may contain placeholders written as `{name}`.
+    pub prompt: String,
+
+    /// The placeholders which must be given a value. When they are
+    /// declared, `xtalk prompts lint` reports placeholders which are not
+    /// among them, and those among them which the prompt does not use.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variables: Option<Vec<String>>,
+}
+
+/// A persona: a system prompt and model settings used together.
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub(crate) struct Persona {
+    /// Instructions given to the model before the conversation.
+    pub system_prompt: Option<String>,
+
+    /// The model used by the persona, in the form of a model spec. The
+    /// current model is kept if unset.
+    pub model: Option<String>,
+
+    /// The sampling temperature.
+    pub temperature: Option<f64>,
+
+    /// A message shown when the persona is chosen.
+    pub greeting: Option<String>,
+}
+
+/// A profile: settings which are merged into the configuration when it is
+/// selected with `--profile`.
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub(crate) struct Profile {
+    /// The default model, in the form of a model spec.
+    pub model: Option<String>,
+
+    /// Instructions given to the model before the conversation.
+    pub system_prompt: Option<String>,
+
+    /// System prompts for individual subcommands, which replace those of
+    /// the configuration.
+    #[serde(default, skip_serializing_if = "SystemPrompts::is_empty")]
+    pub system_prompts: SystemPrompts,
+
+    /// The sampling temperature.
+    pub temperature: Option<f64>,
+
+    /// Overrides of the configuration of the providers, in the form of the
+    /// `[providers]` section.
+    #[serde(default, skip_serializing_if = "toml::Table::is_empty")]
+    pub providers: toml::Table,
+}
+
+/// Configuration for the cache of model listings.
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub(crate) struct Cache {
+    /// How long model listings are cached, in seconds. Setting this to zero
+    /// disables the cache.
+    pub ttl_secs: Option<u64>,
+}
+
+/// Configuration for the management of the context.
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub(crate) struct Context {
+    /// What is done when a conversation no longer fits in the context of
+    /// the model. This applies to providers which do not manage the context
+    /// themselves.
+    #[serde(default)]
+    pub trimming: Trimming,
+    /// With the `summarize` strategy, the older exchanges are summarized
+    /// once the conversation is estimated to fill this fraction of the
+    /// context, rather than when it no longer fits. They are summarized
+    /// until the conversation fills half of the fraction.
+    pub summarize_at: Option<f64>,
+    /// The model spec of the model which writes the summaries, such as a
+    /// cheaper model. By default, the model of the chat writes them.
+    pub summary_model: Option<String>,
+}
+
+/// Configuration for the titles and tags generated for saved sessions.
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub(crate) struct Labels {
+    /// Specifies the model which labels sessions, ideally a fast and
+    /// inexpensive one. Sessions are only labeled when a model is set.
+    pub model: Option<String>,
+
+    /// The maximum number of sessions labeled each time sessions are
+    /// listed, which bounds the requests made by a single listing.
+    pub max_per_run: Option<usize>,
+
+    /// The number of messages a session must gain before it is labeled
+    /// again.
+    pub relabel_after: Option<usize>,
+}
+
+/// How the remote of the synchronization is reached.
+#[derive(Deserialize, Serialize, Default, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SyncBackend {
+    /// A directory reached with rsync, on another host or mounted locally
+    /// (default).
+    #[default]
+    Rsync,
+    /// A git repository.
+    Git,
+}
+
+/// Configuration for synchronizing sessions and prompts between machines.
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub(crate) struct Synchronization {
+    /// Specifies where the encrypted bundle is kept: a directory which rsync
+    /// can reach, such as `host:xtalk`, or the URL of a git repository.
+    pub remote: Option<String>,
+
+    /// How the remote is reached.
+    #[serde(default)]
+    pub backend: SyncBackend,
+
+    /// A command which prints the passphrase the bundle is encrypted with,
+    /// such as `pass show xtalk`. The `XTALK_SYNC_PASSPHRASE` environment
+    /// variable takes precedence.
+    pub passphrase_command: Option<String>,
+}
+
+/// Configuration for the colors of the output.
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub(crate) struct Theme {
+    /// Colors of the model prompt, keyed by model spec, model, or provider,
+    /// so responses from different models are told apart at a glance.
+    ///
+    /// A model spec takes precedence over a model, and a model over its
+    /// provider. Colors are names such as "magenta" or "bright-blue",
+    /// 256-color indices, or hex colors such as "#ff8700".
+    #[serde(default)]
+    pub models: BTreeMap<String, Accent>,
+    /// Show the current time in the right prompt, formatted with the
+    /// strftime format, such as "%H:%M".
+    pub clock: Option<String>,
+    /// Show the time elapsed while a response is generated.
+    #[serde(default)]
+    pub timer: bool,
+}
+
+/// Configuration for the language server.
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub(crate) struct Lsp {
+    /// Specifies the model used for inline completions.
+    ///
+    /// It should be set in the form of a model spec and name a model which
+    /// supports fill-in-the-middle completion. The default model is used if
+    /// unset.
+    pub model: Option<String>,
 }
 
 /// Main configuration structure.
-#[derive(Deserialize, Serialize, Default, Debug)]
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
 pub(crate) struct Config {
     /// Specifies the command used to launch an external editor.
     ///
@@ -104,35 +464,212 @@ pub(crate) struct Config {
     #[serde(default)]
     pub keybindings: Keybindings,
 
+    /// The maximum number of characters of tool output which is added to
+    /// the conversation.
+    ///
+    /// Tool output (e.g., a tmux pane capture) which exceeds this limit is
+    /// truncated from the beginning, and a marker is left in its place.
+    pub max_tool_output: Option<usize>,
+
+    /// The number of lines after which responses in the chat REPL are
+    /// folded.
+    ///
+    /// The middle of a longer response is replaced by a marker, and the full
+    /// response can be shown with `/expand`. Set to 0 to disable folding.
+    pub fold_lines: Option<usize>,
+
+    /// Keep responses which are cancelled with Ctrl-C in the conversation.
+    ///
+    /// The part of the response received before it was cancelled is then
+    /// seen by the model in later turns. By default, it is discarded.
+    #[serde(default)]
+    pub keep_partial_responses: bool,
+
+    /// Print streamed responses in batches, at most once per this many
+    /// milliseconds.
+    ///
+    /// This reduces the output sent to the terminal over slow connections,
+    /// such as SSH to a distant host. Unset or 0 prints each chunk as it
+    /// arrives.
+    pub coalesce_ms: Option<u64>,
+
+    /// Instructions given to the model before the conversation.
+    ///
+    /// A persona replaces them with its own.
+    pub system_prompt: Option<String>,
+
+    /// System prompts for individual subcommands.
+    ///
+    /// Each replaces `system_prompt` for its subcommand.
+    #[serde(default)]
+    pub system_prompts: SystemPrompts,
+
+    /// The sampling temperature.
+    ///
+    /// The provider's default is used if unset. A persona replaces it with
+    /// its own.
+    pub temperature: Option<f64>,
+
     /// Configuration for the providers.
     #[serde(default)]
     pub providers: Providers,
+
+    /// Configuration for the input history.
+    #[serde(default)]
+    pub history: History,
+
+    /// Configuration for files generated by models.
+    #[serde(default)]
+    pub files: Files,
+
+    /// Directories of shared templates and personas, which are loaded along
+    /// with those of the user. Earlier directories take precedence.
+    #[serde(default)]
+    pub prompt_paths: Vec<PathBuf>,
+
+    /// Prompt templates, keyed by name.
+    #[serde(default)]
+    pub templates: BTreeMap<String, Template>,
+
+    /// Personas, keyed by name.
+    #[serde(default)]
+    pub personas: BTreeMap<String, Persona>,
+
+    /// Profiles, keyed by name.
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+
+    /// Configuration for the management of the context.
+    #[serde(default)]
+    pub context: Context,
+
+    /// Configuration for the cache.
+    #[serde(default)]
+    pub cache: Cache,
+
+    /// Configuration for retrying failed requests.
+    #[serde(default)]
+    pub retry: Retry,
+
+    /// Timeouts for requests to the providers.
+    #[serde(default)]
+    pub timeouts: Timeouts,
+
+    /// Configuration for the language server.
+    #[serde(default)]
+    pub lsp: Lsp,
+
+    /// Configuration for labeling saved sessions.
+    #[serde(default)]
+    pub labels: Labels,
+
+    /// Configuration for synchronizing sessions and prompts.
+    #[serde(default)]
+    pub sync: Synchronization,
+
+    /// Configuration for the colors of the output.
+    #[serde(default)]
+    pub theme: Theme,
 }
 
-fn get_config_path() -> Option<PathBuf> {
-    let home = std::env::var_os("HOME");
+/// Merges the tables, replacing the values of the base with the overrides
+fn merge_tables(base: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overrides)) => {
+                merge_tables(base, overrides)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Merges the named profile into the configuration. The keys of the
+    /// provider overrides which are not options are returned.
+    pub(crate) fn with_profile(mut self, name: &str) -> Result<(Config, Vec<String>), Error> {
+        let profile = match self.profiles.get(name) {
+            Some(profile) => profile.clone(),
+            None => return Err(Error::UnknownProfile(name.to_string())),
+        };
+
+        if profile.model.is_some() {
+            self.default_model = profile.model;
+        }
+
+        if profile.system_prompt.is_some() {
+            self.system_prompt = profile.system_prompt;
+        }
+
+        self.system_prompts.merge(profile.system_prompts);
+
+        if profile.temperature.is_some() {
+            self.temperature = profile.temperature;
+        }
 
-    if let Some(home) = home {
-        let home = PathBuf::from(home);
+        let mut extra = Vec::new();
 
-        const USER_PATHS: [&str; 2] = [".config/xtalk/config.toml", ".xtalk.toml"];
+        if !profile.providers.is_empty() {
+            let mut providers =
+                toml::Table::try_from(&self.providers).expect("failed to serialize the providers");
 
-        for &path in USER_PATHS.iter() {
-            let fullpath = home.join(path);
+            merge_tables(&mut providers, profile.providers.clone());
 
-            if fullpath.exists() {
-                return Some(fullpath);
+            self.providers = providers.try_into().map_err(|source| Error::Profile {
+                name: name.to_string(),
+                source,
+            })?;
+
+            // Overrides which are options are kept when the providers are
+            // serialized again
+            let providers =
+                toml::Table::try_from(&self.providers).expect("failed to serialize the providers");
+
+            let mut path = Vec::new();
+
+            extra_fields_helper(&mut path, &profile.providers, &providers, &mut extra);
+
+            for key in &mut extra {
+                *key = format!("profiles.{}.providers.{}", name, key);
             }
         }
+
+        Ok((self, extra))
     }
+}
 
-    let system_config = PathBuf::from("/etc/xtalk.toml");
+/// The path where `xtalk config init` writes the configuration
+pub(crate) fn user_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join(USER_CONFIG_PATH))
+}
 
-    if system_config.exists() {
-        Some(system_config)
-    } else {
-        None
+/// The paths searched for the configuration, in order of preference
+pub(crate) fn config_search_paths() -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = user_config_path().into_iter().collect();
+
+    if let Some(dir) = dirs::platform_config_dir() {
+        let path = dir.join(USER_CONFIG_PATH);
+
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+
+    if let Some(home) = dirs::home_dir() {
+        paths.push(home.join(".xtalk.toml"));
     }
+
+    if cfg!(unix) {
+        paths.push(PathBuf::from("/etc/xtalk.toml"));
+    }
+
+    paths
+}
+
+fn get_config_path() -> Option<PathBuf> {
+    config_search_paths().into_iter().find(|path| path.exists())
 }
 
 fn parse_config_or_die<'de, S: serde::de::DeserializeOwned>(config: &str) -> S {
@@ -144,40 +681,45 @@ fn parse_config_or_die<'de, S: serde::de::DeserializeOwned>(config: &str) -> S {
     }
 }
 
-fn warn_on_extra_fields_helper<'a>(
+fn extra_fields_helper<'a>(
     path: &mut Vec<&'a String>,
     user_config: &'a toml::Table,
     config: &'a toml::Table,
+    extra: &mut Vec<String>,
 ) {
     for (user_key, user_value) in user_config {
         path.push(user_key);
 
         if let Some(config_value) = config.get(user_key) {
+            // Integers are accepted for floating point options
             assert!(
-                user_value.same_type(config_value),
+                user_value.same_type(config_value)
+                    || matches!(
+                        (user_value, config_value),
+                        (toml::Value::Integer(_), toml::Value::Float(_))
+                    ),
                 "user value doesn't match config value"
             );
 
             match (user_value, config_value) {
                 (toml::Value::Table(user_value), toml::Value::Table(config_value)) => {
-                    warn_on_extra_fields_helper(path, user_value, config_value)
+                    extra_fields_helper(path, user_value, config_value, extra)
                 }
                 _ => {}
             }
         } else {
             let path: Vec<&str> = path.iter().map(|&s| s.as_str()).collect();
 
-            warn!(
-                "config contains extraneous key \"{}\", ignoring",
-                path.join(".")
-            );
+            extra.push(path.join("."));
         }
 
         path.pop();
     }
 }
 
-fn warn_on_extra_fields(config: &Config, raw_config: &str) {
+/// Lists the keys of the user's configuration which are not options, such
+/// as misspelled options
+fn extra_fields(config: &Config, raw_config: &str) -> Vec<String> {
     let user_config: toml::Table = parse_config_or_die(raw_config);
 
     let config: toml::Table = {
@@ -187,22 +729,154 @@ fn warn_on_extra_fields(config: &Config, raw_config: &str) {
     };
 
     let mut path = Vec::new();
+    let mut extra = Vec::new();
+
+    extra_fields_helper(&mut path, &user_config, &config, &mut extra);
 
-    warn_on_extra_fields_helper(&mut path, &user_config, &config);
+    extra
 }
 
-pub(crate) fn read_config(config: Option<PathBuf>) -> Config {
-    let config_path = config.or_else(get_config_path);
+/// The configuration file which is used, either the one given on the
+/// command line or the first one found in the search paths
+pub(crate) fn config_path(config: Option<PathBuf>) -> Option<PathBuf> {
+    config.or_else(get_config_path)
+}
+
+/// Loads the configuration at the path, along with the keys which are not
+/// options and are ignored
+pub(crate) fn load_config(path: &Path) -> Result<(Config, Vec<String>), Error> {
+    let raw_config = std::fs::read_to_string(path).map_err(|source| Error::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
 
-    if let Some(path) = config_path {
-        let raw_config = std::fs::read_to_string(path).expect("failed to read config");
+    let config: Config = toml::de::from_str(&raw_config).map_err(|source| Error::Parse {
+        path: path.to_path_buf(),
+        source,
+    })?;
 
-        let config: Config = parse_config_or_die(&raw_config);
+    let extra = extra_fields(&config, &raw_config);
 
-        warn_on_extra_fields(&config, &raw_config);
+    Ok((config, extra))
+}
+
+pub(crate) fn read_config(config: Option<PathBuf>) -> Config {
+    if let Some(path) = config_path(config) {
+        let (config, extra) = match load_config(&path) {
+            Ok(loaded) => loaded,
+            Err(err) => die!("{}", err),
+        };
+
+        for key in extra {
+            warn!("config contains extraneous key \"{}\", ignoring", key);
+        }
 
         config
     } else {
         Config::default()
     }
 }
+
+/// Merges the named profile into the configuration, exiting if it cannot be
+pub(crate) fn select_profile(config: Config, name: &str) -> Config {
+    let (config, extra) = match config.with_profile(name) {
+        Ok(selected) => selected,
+        Err(err) => die!("{}", err),
+    };
+
+    for key in extra {
+        warn!("config contains extraneous key \"{}\", ignoring", key);
+    }
+
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Uncomments the options, keeping the prose comments
+    fn uncommented(config: &str) -> String {
+        config
+            .lines()
+            .map(|line| match line.strip_prefix("# ") {
+                Some(option) if option.starts_with('[') || option.contains(" = ") => option,
+                _ => line,
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_default_config_lists_options() {
+        let raw_config = uncommented(DEFAULT_CONFIG);
+
+        let config: Config = toml::de::from_str(&raw_config).unwrap();
+
+        assert!(extra_fields(&config, &raw_config).is_empty());
+        assert_eq!(config.fold_lines, Some(100));
+        assert!(config.providers.openai.api_key.is_some());
+    }
+
+    #[test]
+    fn test_with_profile() {
+        let config: Config = toml::de::from_str(
+            r#"
+            default_model = "gpt-4o-mini"
+            temperature = 0.7
+
+            [system_prompts]
+            chat = "Be friendly."
+            summarize = "Write in English."
+
+            [providers.ollama]
+            api_base = "http://localhost:11434"
+            priority = 20
+
+            [profiles.work]
+            system_prompt = "Be brief."
+
+            [profiles.work.system_prompts]
+            summarize = "Write for executives."
+
+            [profiles.work.providers.ollama]
+            api_base = "http://gpu:11434"
+            color = "blue"
+            "#,
+        )
+        .unwrap();
+
+        let (config, extra) = config.with_profile("work").unwrap();
+
+        assert_eq!(config.default_model.as_deref(), Some("gpt-4o-mini"));
+        assert_eq!(config.system_prompt.as_deref(), Some("Be brief."));
+        assert_eq!(config.system_prompts.chat.as_deref(), Some("Be friendly."));
+        assert_eq!(
+            config.system_prompts.summarize.as_deref(),
+            Some("Write for executives.")
+        );
+        assert_eq!(config.temperature, Some(0.7));
+        assert_eq!(
+            config.providers.ollama.api_base.as_deref(),
+            Some("http://gpu:11434")
+        );
+        assert_eq!(config.providers.ollama.priority, Some(20));
+        assert_eq!(extra, ["profiles.work.providers.ollama.color"]);
+
+        assert!(matches!(
+            config.with_profile("home"),
+            Err(Error::UnknownProfile(_))
+        ));
+    }
+
+    #[test]
+    fn test_integer_for_float_option() {
+        let raw_config = "temperature = 1\n\n[context]\nsummarize_at = 1\n";
+
+        let config: Config = toml::de::from_str(raw_config).unwrap();
+
+        assert!(extra_fields(&config, raw_config).is_empty());
+        assert_eq!(config.temperature, Some(1.0));
+        assert_eq!(config.context.summarize_at, Some(1.0));
+    }
+}
@@ -0,0 +1,49 @@
+//! Diagnostic logging
+//!
+//! Providers, API requests, and the registry emit `tracing` events. They are
+//! written to stderr when logging is requested with `-v` (info), `-vv`
+//! (debug), `-vvv` (trace), or `--debug`. The `XTALK_LOG` environment
+//! variable takes precedence and accepts a full filter directive, such as
+//! `XTALK_LOG=xtalk::providers=trace`.
+
+use tracing_subscriber::EnvFilter;
+
+use crate::cli::ColorMode;
+use crate::color;
+
+/// The environment variable holding the filter directive
+pub(crate) const LOG_ENV: &str = "XTALK_LOG";
+
+/// The filter directive corresponding to the number of `-v` flags
+fn verbosity_directive(verbosity: u8) -> Option<&'static str> {
+    match verbosity {
+        0 => None,
+        1 => Some("xtalk=info"),
+        2 => Some("xtalk=debug"),
+        _ => Some("xtalk=trace"),
+    }
+}
+
+/// Installs the global subscriber. Nothing is logged unless `XTALK_LOG` is
+/// set or the verbosity is nonzero.
+pub(crate) fn init(verbosity: u8) {
+    let filter = match std::env::var(LOG_ENV) {
+        Ok(directive) if !directive.is_empty() => match EnvFilter::try_new(&directive) {
+            Ok(filter) => filter,
+            Err(err) => {
+                crate::warn!("ignoring invalid {} \"{}\": {}", LOG_ENV, directive, err);
+                return;
+            }
+        },
+        _ => match verbosity_directive(verbosity) {
+            Some(directive) => EnvFilter::new(directive),
+            None => return,
+        },
+    };
+
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(matches!(color::color_mode(), ColorMode::On))
+        .try_init();
+}
@@ -1,18 +1,42 @@
+mod cache;
 mod chat;
 mod cli;
 mod color;
 mod config;
+mod logging;
 mod providers;
 mod registry;
+mod sessions;
+mod sync;
+mod templates;
 mod utils;
 mod version;
 
 use std::path::PathBuf;
+use std::time::Duration;
 
-use clap::{Parser, Subcommand, ValueEnum};
-use cli::{chat::chat_cmd, list::list_cmd, ColorMode};
-use config::read_config;
+use cache::Cache;
+use clap::{ArgAction, CommandFactory, Parser, Subcommand, ValueEnum};
+use cli::chat::{chat_cmd, ExportFormat, ReplConfig, RequestDefaults, DEFAULT_FOLD_LINES};
+use cli::init::{init_cmd, Shell};
+use cli::{
+    cache::cache_cmd,
+    complete::complete_cmd,
+    config::config_cmd,
+    eval::eval_cmd,
+    history::{history_cmd, search_resume_session},
+    list::list_cmd,
+    lsp::lsp_cmd,
+    mcp::mcp_serve_cmd,
+    models::models_cmd,
+    prompts::prompts_cmd,
+    summarize::summarize_cmd,
+    sync::sync_cmd,
+    ColorMode,
+};
+use config::{read_config, select_profile};
 use providers::providers::ProviderIdentifier;
+use providers::KeepAlive;
 use registry::populate::populated_registry;
 
 #[derive(
@@ -26,6 +50,9 @@ pub(crate) enum RequestedColorMode {
     Off,
 }
 
+/// How long model listings are cached unless configured otherwise
+const DEFAULT_CACHE_TTL_SECS: u64 = 300;
+
 #[derive(Parser)]
 #[command(name = version::NAME)]
 #[command(
@@ -38,6 +65,18 @@ struct Cli {
     color: RequestedColorMode,
     #[arg(long)]
     config: Option<PathBuf>,
+    /// Merge a profile from the configuration into it
+    #[arg(long, value_name = "NAME", global = true)]
+    profile: Option<String>,
+    /// Ignore and do not update the cache
+    #[arg(long, global = true)]
+    no_cache: bool,
+    /// Log diagnostics to stderr, repeat for more detail (-v, -vv, -vvv)
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Log debug diagnostics to stderr, equivalent to -vv
+    #[arg(long, global = true)]
+    debug: bool,
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -45,9 +84,37 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Start a chat
-    Chat(ChatArgs),
+    Chat(Box<ChatArgs>),
+    /// Print the response to a single prompt, for use in scripts
+    Complete(CompleteArgs),
+    /// Summarize a document, even one which does not fit in the context of the model
+    Summarize(SummarizeArgs),
     /// List available models
     List(ListArgs),
+    /// Manage models loaded by local runtimes
+    Models(ModelsArgs),
+    /// Manage the cache of model listings and provider health
+    Cache(CacheArgs),
+    /// Create, locate, and check the configuration
+    Config(ConfigArgs),
+    /// Act on saved chat sessions
+    #[command(visible_alias = "sessions")]
+    History(HistoryArgs),
+    /// Share sessions and prompts with other machines through an encrypted remote
+    Sync(SyncArgs),
+    /// Check prompt templates
+    Prompts(PromptsArgs),
+    /// Compare the responses to prompt templates with golden responses
+    Eval(EvalArgs),
+    /// Serve completions to MCP hosts over standard input and output
+    McpServe,
+    /// Serve inline completions to editors over the Language Server Protocol
+    Lsp,
+    /// Print shell integration, e.g. `eval "$(xtalk init zsh)"`
+    Init {
+        /// The shell to integrate with
+        shell: Shell,
+    },
 }
 
 #[derive(Parser, Default)]
@@ -58,10 +125,169 @@ pub(crate) struct ChatArgs {
     /// Enter interactive mode
     #[arg(short, long)]
     interactive: bool,
+    /// Report token usage, timing, and runtime metrics after each response
+    #[arg(long)]
+    stats: bool,
+    /// Report the token usage of each response along with the running total
+    #[arg(long)]
+    show_usage: bool,
+    /// Provide the contents of a tmux pane as context (defaults to the current pane)
+    #[arg(long, value_name = "TARGET", require_equals = true)]
+    tmux_pane: Option<Option<String>>,
+    /// Resume a saved session
+    #[arg(short, long, value_name = "SESSION_ID")]
+    resume: Option<String>,
+    /// Exchange length-prefixed frames with an editor plugin over standard input and output
+    #[arg(long, conflicts_with_all = ["prompt", "interactive", "resume", "json", "output", "template"])]
+    editor_protocol: bool,
+    /// Read messages from standard input and write responses to standard output as JSON lines
+    #[arg(long, conflicts_with_all = ["prompt", "interactive", "resume", "json", "output", "template", "editor_protocol"])]
+    json_lines: bool,
+    /// Write the transcript to a file after each response
+    #[arg(short, long, value_name = "PATH")]
+    output: Option<PathBuf>,
+    /// The format of the transcript (inferred from the extension by default)
+    #[arg(long, value_name = "FORMAT", requires = "output")]
+    output_format: Option<ExportFormat>,
+    /// Require the response to be valid JSON, re-prompting the model if it is not
+    #[arg(long)]
+    json: bool,
+    /// The number of times the model is re-prompted after responding with invalid JSON
+    #[arg(long, value_name = "N", default_value_t = 2, requires = "json")]
+    json_retries: u32,
+    /// Render a prompt template as the initial prompt
+    #[arg(long, value_name = "NAME", conflicts_with = "prompt")]
+    template: Option<String>,
+    /// Set a placeholder of the template, reading the value from standard input if it is "-"
+    #[arg(long = "var", value_name = "NAME=VALUE", value_parser = parse_var, requires = "template")]
+    vars: Vec<(String, String)>,
+    /// Abort the chat with an error if it runs longer than this (e.g., 90s, 5m, or 1h)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, conflicts_with_all = ["interactive", "editor_protocol", "json_lines"])]
+    max_runtime: Option<Duration>,
+    /// Limit the length of each response, cutting it off if the provider does not
+    #[arg(long, value_name = "N", conflicts_with_all = ["editor_protocol", "json_lines"])]
+    max_output_tokens: Option<u32>,
+    /// Request each response in full rather than streaming it, for proxies which break streaming
+    #[arg(long, conflicts_with_all = ["editor_protocol", "json_lines"])]
+    no_stream: bool,
+    /// Print streamed responses in batches, at most every MS milliseconds (100 by default), for slow connections
+    #[arg(long, value_name = "MS", require_equals = true, conflicts_with_all = ["editor_protocol", "json_lines", "no_stream"])]
+    coalesce: Option<Option<u64>>,
+    /// Sample responses with this seed, so they can be reproduced where the provider allows
+    #[arg(long, value_name = "N", conflicts_with_all = ["editor_protocol", "json_lines"])]
+    seed: Option<u32>,
+    /// End each response when the model generates this sequence (may be repeated)
+    #[arg(long, value_name = "SEQUENCE", conflicts_with_all = ["editor_protocol", "json_lines"])]
+    stop: Vec<String>,
+    /// Keep the model loaded this long after each response (e.g., 10m, 0s, or forever)
+    #[arg(long, value_name = "DURATION", conflicts_with_all = ["editor_protocol", "json_lines"])]
+    keep_alive: Option<KeepAlive>,
+    /// Exit with an error if the response is empty
+    #[arg(long, conflicts_with_all = ["interactive", "editor_protocol", "json_lines"])]
+    fail_on_empty: bool,
+    /// Attach a file to the initial prompt
+    #[arg(short, long = "file", value_name = "PATH", conflicts_with_all = ["editor_protocol", "json_lines"])]
+    files: Vec<PathBuf>,
+    /// Attach an image to the initial prompt, for models which accept images
+    #[arg(long = "image", value_name = "PATH", conflicts_with_all = ["editor_protocol", "json_lines"])]
+    images: Vec<PathBuf>,
+    /// Adopt a persona from the configuration
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["editor_protocol", "json_lines"])]
+    persona: Option<String>,
+    /// Append the events of the chat to a file as JSON lines
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["editor_protocol", "json_lines"])]
+    event_log: Option<PathBuf>,
     /// Specify the initial prompt
     prompt: Option<String>,
 }
 
+/// Output formats of a completion
+#[derive(ValueEnum, Default, Clone, Copy, strum_macros::Display)]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum CompletionFormat {
+    /// Print the response as it is generated
+    #[default]
+    Text,
+    /// Print the response along with its finish reason and token usage as JSON
+    Json,
+}
+
+#[derive(Parser)]
+pub(crate) struct CompleteArgs {
+    /// Specifies the model to be used
+    #[arg(short, long)]
+    model: Option<String>,
+    /// Print the response without the model prompt or a trailing newline
+    #[arg(long, conflicts_with = "format")]
+    raw: bool,
+    /// Output the response with the specified format
+    #[arg(short, long, default_value_t = CompletionFormat::default())]
+    format: CompletionFormat,
+    /// Instruct the model with this system prompt rather than the configured one
+    #[arg(long, value_name = "PROMPT")]
+    system: Option<String>,
+    /// Limit the length of the response, cutting it off if the provider does not
+    #[arg(long, value_name = "N")]
+    max_output_tokens: Option<u32>,
+    /// Sample the response with this seed, so it can be reproduced where the provider allows
+    #[arg(long, value_name = "N")]
+    seed: Option<u32>,
+    /// End the response when the model generates this sequence (may be repeated)
+    #[arg(long, value_name = "SEQUENCE")]
+    stop: Vec<String>,
+    /// The prompt, which is followed by anything piped through standard input
+    prompt: Option<String>,
+}
+
+/// How a document is summarized
+#[derive(ValueEnum, Default, Clone, Copy, strum_macros::Display)]
+#[strum(serialize_all = "kebab-case")]
+pub(crate) enum SummaryStrategy {
+    /// Summarize chunks of the document concurrently, then merge the summaries
+    #[default]
+    MapReduce,
+    /// Summarize the document in a single request, failing if it does not fit
+    Single,
+}
+
+#[derive(Parser)]
+pub(crate) struct SummarizeArgs {
+    /// Specifies the model to be used
+    #[arg(short, long)]
+    model: Option<String>,
+    /// How the document is summarized
+    #[arg(short, long, default_value_t = SummaryStrategy::default())]
+    strategy: SummaryStrategy,
+    /// The estimated number of tokens in each chunk (half the context of the model by default, where it is known)
+    #[arg(long, value_name = "TOKENS", value_parser = clap::value_parser!(u64).range(1..))]
+    chunk_tokens: Option<u64>,
+    /// The number of chunks which are summarized at once
+    #[arg(short = 'j', long, value_name = "N", default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..))]
+    concurrency: u64,
+    /// The document, which may be a PDF followed by a range of pages (e.g., report.pdf#3-7); read from standard input if omitted
+    path: Option<PathBuf>,
+}
+
+/// Parses a placeholder assignment of the form `NAME=VALUE`
+fn parse_var(var: &str) -> Result<(String, String), String> {
+    match var.split_once('=') {
+        Some((name, value)) => Ok((name.to_string(), value.to_string())),
+        None => Err(format!("expected NAME=VALUE, found \"{}\"", var)),
+    }
+}
+
+/// Parses a duration given in seconds, optionally with a unit of `s`, `m`,
+/// or `h`
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    match utils::format::parse_duration(duration)? {
+        parsed if parsed.is_zero() => Err(format!(
+            "expected a positive duration, found \"{}\"",
+            duration
+        )),
+        parsed => Ok(parsed),
+    }
+}
+
 /// Possible listings
 #[derive(Subcommand)]
 pub(crate) enum ListObject {
@@ -69,6 +295,12 @@ pub(crate) enum ListObject {
     Models(ListModelArgs),
     /// Providers
     Providers,
+    /// Saved chat sessions
+    Sessions,
+    /// Prompt templates
+    Templates,
+    /// Personas
+    Personas,
 }
 
 /// Output formats
@@ -84,12 +316,18 @@ pub(crate) enum ListingFormat {
     Json,
     /// Format the output as a table without a header
     HeaderlessTable,
+    /// Format the output as YAML
+    Yaml,
+    /// Format the output as TOML, with the listing under a key named after the object
+    Toml,
+    /// Format the output as CSV with the columns of the table, for spreadsheets
+    Csv,
 }
 
 #[derive(Parser)]
 pub(crate) struct ListArgs {
     /// Output the listing with the specified format
-    #[arg(short, long, default_value_t = ListingFormat::default())]
+    #[arg(short, long, global = true, default_value_t = ListingFormat::default())]
     format: ListingFormat,
     /// List the specified object
     #[command(subcommand)]
@@ -101,6 +339,238 @@ pub(crate) struct ListModelArgs {
     /// Limit listing to the specified provider
     #[arg(short, long)]
     provider: Option<ProviderIdentifier>,
+    /// Include the models of providers which are not activated, where they can be listed
+    #[arg(short, long, conflicts_with = "provider")]
+    all: bool,
+    /// Only list models whose ID matches the glob, or contains the text if it has no wildcards
+    #[arg(long, value_name = "PATTERN")]
+    filter: Option<String>,
+    /// Only list models whose context length is known to be at least this many tokens
+    #[arg(long, value_name = "TOKENS")]
+    context_min: Option<u64>,
+}
+
+/// Model lifecycle actions
+#[derive(Subcommand)]
+pub(crate) enum ModelsAction {
+    /// Show the models which are currently loaded into memory
+    Ps,
+    /// Unload a model from memory
+    Stop {
+        /// The model to unload, specified as a model spec
+        model: String,
+    },
+}
+
+#[derive(Parser)]
+pub(crate) struct ModelsArgs {
+    /// Output the listing with the specified format
+    #[arg(short, long, default_value_t = ListingFormat::default())]
+    format: ListingFormat,
+    /// The action to perform
+    #[command(subcommand)]
+    action: ModelsAction,
+}
+
+#[derive(Parser)]
+pub(crate) struct EvalArgs {
+    /// The directory in which golden responses are kept
+    #[arg(long, value_name = "DIR")]
+    golden: PathBuf,
+    /// Evaluate the named template, which may be given more than once (defaults to every template)
+    #[arg(short, long = "template", value_name = "NAME")]
+    templates: Vec<String>,
+    /// Evaluate with the specified model, which may be given more than once (defaults to the default model)
+    #[arg(short, long = "model", value_name = "MODEL")]
+    models: Vec<String>,
+    /// The seed responses are sampled with
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    seed: u32,
+    /// Set a placeholder of the templates
+    #[arg(long = "var", value_name = "NAME=VALUE", value_parser = parse_var)]
+    vars: Vec<(String, String)>,
+    /// Replace golden responses which differ with the new responses
+    #[arg(long)]
+    update: bool,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum CacheAction {
+    /// Remove every cached entry
+    Clear,
+}
+
+#[derive(Parser)]
+pub(crate) struct CacheArgs {
+    /// The action to perform
+    #[command(subcommand)]
+    action: CacheAction,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum HistoryAction {
+    /// List saved sessions with their titles and tags, labeling new sessions
+    List(HistoryListArgs),
+    /// Show the details and turns of a saved session
+    Show {
+        /// The identifier of the session, see `xtalk list sessions`
+        session: String,
+        /// Print the session and its labels as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Give a saved session a title, which labeling will not replace
+    Rename {
+        /// The identifier of the session, see `xtalk list sessions`
+        session: String,
+        /// The new title
+        title: String,
+    },
+    /// Delete saved sessions
+    Delete {
+        /// The identifiers of the sessions, see `xtalk list sessions`
+        #[arg(required = true)]
+        sessions: Vec<String>,
+    },
+    /// Export a saved session, e.g. to share it
+    Export {
+        /// The identifier of the session, see `xtalk list sessions`
+        session: String,
+        /// The format of the transcript, inferred from the output path by default
+        #[arg(short, long)]
+        format: Option<ExportFormat>,
+        /// Write the transcript to a file rather than standard output
+        #[arg(short, long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Search the transcripts of the saved sessions
+    Search(HistorySearchArgs),
+    /// Extract the tasks which remain from a saved session
+    Tasks(TasksArgs),
+}
+
+#[derive(Parser)]
+pub(crate) struct HistorySearchArgs {
+    /// The text to search for, ignoring case
+    query: String,
+    /// The most matches shown per session
+    #[arg(short = 'n', long, default_value_t = 3)]
+    max_matches: usize,
+    /// Resume the most recently updated session which matches in a chat
+    #[arg(short, long, conflicts_with = "json")]
+    resume: bool,
+    /// Print the matching sessions as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Parser)]
+pub(crate) struct HistoryListArgs {
+    /// Only list sessions with the tag, which may be repeated to require several
+    #[arg(short, long, value_name = "TAG")]
+    tag: Vec<String>,
+    /// List the sessions without labeling those which are new or have grown
+    #[arg(long)]
+    no_label: bool,
+    /// Output the listing with the specified format
+    #[arg(short, long, default_value_t = ListingFormat::default())]
+    format: ListingFormat,
+}
+
+#[derive(Parser)]
+pub(crate) struct TasksArgs {
+    /// The identifier of the session, see `xtalk list sessions`
+    session: String,
+    /// The model which extracts the tasks (the model of the session by default)
+    #[arg(short, long)]
+    model: Option<String>,
+    /// Output the tasks with the specified format
+    #[arg(short, long, default_value_t = TaskFormat::default())]
+    format: TaskFormat,
+    /// The number of times the model is re-prompted after responding with malformed tasks
+    #[arg(long, value_name = "N", default_value_t = 2)]
+    retries: u32,
+}
+
+/// Output formats of extracted tasks
+#[derive(ValueEnum, Default, Clone, Copy, strum_macros::Display)]
+#[strum(serialize_all = "snake_case")]
+pub(crate) enum TaskFormat {
+    /// A markdown checklist
+    #[default]
+    Markdown,
+    /// A JSON array of tasks
+    Json,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum PromptsAction {
+    /// Report templates with problems, exiting with an error if there are any
+    Lint(LintArgs),
+}
+
+#[derive(Parser)]
+pub(crate) struct LintArgs {
+    /// Template files, or directories of them, to check instead of the configured templates and those in the prompts directory and libraries
+    paths: Vec<PathBuf>,
+    /// Check that prompts leave half of the context of the model for the values and the response
+    #[arg(short, long)]
+    model: Vec<String>,
+    /// The most tokens a prompt may be estimated at
+    #[arg(long, value_name = "N")]
+    max_tokens: Option<u64>,
+}
+
+#[derive(Parser)]
+pub(crate) struct PromptsArgs {
+    /// The action to perform
+    #[command(subcommand)]
+    action: PromptsAction,
+}
+
+#[derive(Subcommand, Clone, Copy)]
+pub(crate) enum SyncAction {
+    /// Only take the files which are newer on the remote
+    Pull,
+    /// Only send the files which are newer locally
+    Push,
+}
+
+#[derive(Parser)]
+pub(crate) struct SyncArgs {
+    /// Synchronize in one direction only, both by default
+    #[command(subcommand)]
+    action: Option<SyncAction>,
+}
+
+#[derive(Parser)]
+pub(crate) struct HistoryArgs {
+    /// The action to perform
+    #[command(subcommand)]
+    action: HistoryAction,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum ConfigAction {
+    /// Write a commented configuration with the default options
+    Init {
+        /// Overwrite an existing configuration
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print the path of the configuration which is loaded
+    Path,
+    /// Check the configuration for errors without starting a chat
+    Validate,
+    /// Print the effective configuration, including defaults and the selected profile
+    Show,
+}
+
+#[derive(Parser)]
+pub(crate) struct ConfigArgs {
+    /// The action to perform
+    #[command(subcommand)]
+    action: ConfigAction,
 }
 
 fn hook_panics_with_reporting() {
@@ -127,33 +597,163 @@ async fn main() {
 
     color::configure_color(color);
 
-    let config = read_config(cli.config);
+    logging::init(if cli.debug {
+        cli.verbose.max(2)
+    } else {
+        cli.verbose
+    });
+
+    // The configuration is inspected without being loaded, since it may not
+    // exist or may fail to parse
+    if let Some(Commands::Config(args)) = &cli.command {
+        return config_cmd(cli.config, cli.profile.as_deref(), args);
+    }
+
+    let mut config = read_config(cli.config);
+
+    // The profile is merged before the registry is populated, so its
+    // provider overrides take effect
+    if let Some(name) = &cli.profile {
+        config = select_profile(config, name);
+    }
+
+    color::configure_accents(&config.theme.models);
+
+    // A subcommand's own system prompt replaces the general one
+    let system_prompt = |own: &Option<String>| own.clone().or(config.system_prompt.clone());
 
-    let registry = populated_registry(&config).await;
+    let complete_system_prompt = system_prompt(&config.system_prompts.complete);
+    let summarize_system_prompt = system_prompt(&config.system_prompts.summarize);
+    let tasks_system_prompt = system_prompt(&config.system_prompts.tasks);
 
-    let editor: Option<PathBuf> = config.editor.map(|s| s.into());
+    let repl_config = ReplConfig {
+        editor: config.editor.clone().map(|s| s.into()),
+        keybindings: config.keybindings,
+        history: config.history.clone(),
+        files: config.files.clone(),
+        templates: templates::load_templates(&config.templates, &config.prompt_paths),
+        fold_lines: config.fold_lines.unwrap_or(DEFAULT_FOLD_LINES),
+        keep_partial_responses: config.keep_partial_responses,
+        personas: templates::load_personas(&config.personas, &config.prompt_paths),
+        context: config.context.clone(),
+        clock: config.theme.clock.clone(),
+        timer: config.theme.timer,
+        coalesce_ms: config.coalesce_ms,
+        defaults: RequestDefaults::for_chat(&config),
+    };
+    let default_model = config.default_model.clone();
+    let max_tool_output = config.max_tool_output;
+
+    let default_chat_args = ChatArgs::default();
+
+    let cache = if cli.no_cache {
+        None
+    } else {
+        Cache::open(
+            Duration::from_secs(config.cache.ttl_secs.unwrap_or(DEFAULT_CACHE_TTL_SECS)),
+            cache::fingerprint(&config),
+        )
+    };
 
     match &cli.command {
-        Some(Commands::Chat(args)) => {
-            chat_cmd(
-                editor,
-                config.keybindings,
-                config.default_model,
+        Some(Commands::Init { shell }) => init_cmd(*shell, Cli::command()),
+        Some(Commands::Cache(args)) => cache_cmd(args),
+        Some(Commands::Sync(args)) => sync_cmd(&config, args),
+        Some(Commands::Prompts(args)) => prompts_cmd(&config, cache, args).await,
+        Some(Commands::History(HistoryArgs {
+            action: HistoryAction::Search(search),
+        })) if search.resume => {
+            let args = ChatArgs {
+                resume: Some(search_resume_session(search)),
+                ..Default::default()
+            };
+
+            let registry = tokio::spawn(async move { populated_registry(&config, cache).await });
+
+            chat_cmd(repl_config, default_model, max_tool_output, registry, &args).await
+        }
+        Some(Commands::History(args)) => {
+            history_cmd(
+                color,
+                &config,
+                cache,
+                default_model,
+                tasks_system_prompt,
+                config.temperature,
+                args,
+            )
+            .await
+        }
+        Some(Commands::Eval(args)) => {
+            let registry = populated_registry(&config, cache).await;
+
+            eval_cmd(registry, &repl_config.templates, default_model, args).await
+        }
+        Some(Commands::List(args)) => {
+            let registry = populated_registry(&config, cache).await;
+
+            list_cmd(
+                color,
+                &config,
                 registry,
+                &repl_config.templates,
+                &repl_config.personas,
                 args,
             )
             .await
         }
-        Some(Commands::List(args)) => list_cmd(color, registry, args).await,
-        None => {
-            chat_cmd(
-                editor,
-                config.keybindings,
-                config.default_model,
+        Some(Commands::Models(args)) => {
+            let registry = populated_registry(&config, cache).await;
+
+            models_cmd(color, registry, args).await
+        }
+        Some(Commands::Complete(args)) => {
+            let registry = populated_registry(&config, cache).await;
+
+            complete_cmd(
                 registry,
-                &ChatArgs::default(),
+                default_model,
+                complete_system_prompt,
+                config.temperature,
+                args,
+            )
+            .await
+        }
+        Some(Commands::Summarize(args)) => {
+            let registry = populated_registry(&config, cache).await;
+
+            summarize_cmd(
+                registry,
+                default_model,
+                summarize_system_prompt,
+                config.temperature,
+                args,
             )
             .await
         }
+        Some(Commands::McpServe) => {
+            let registry = populated_registry(&config, cache).await;
+
+            mcp_serve_cmd(registry, default_model, repl_config.defaults).await
+        }
+        Some(Commands::Lsp) => {
+            let registry = populated_registry(&config, cache).await;
+
+            let model = config.lsp.model.clone().or(default_model);
+
+            lsp_cmd(registry, model).await
+        }
+        command => {
+            let args = match command {
+                Some(Commands::Chat(args)) => args,
+                _ => &default_chat_args,
+            };
+
+            // Probing the providers can be slow, so the registry is populated
+            // while the chat starts up.
+            let registry = tokio::spawn(async move { populated_registry(&config, cache).await });
+
+            chat_cmd(repl_config, default_model, max_tool_output, registry, args).await
+        }
     }
 }
@@ -28,17 +28,28 @@
 //! and the [`ErrorKind`] enum provides an indication of the category of error that was raised.
 
 mod apireq;
+pub(crate) mod cache;
 mod ollama;
 mod openai;
 
 pub(crate) mod providers;
+pub(crate) mod retry;
 
 use async_trait::async_trait;
 use std::error::Error as StdError;
 use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
 
 use self::providers::ProviderIdentifier;
 use crate::chat::{Message, Role};
+use crate::utils::errors::{
+    AUTH_EXIT_CODE, CONNECTION_EXIT_CODE, CONTEXT_EXCEEDED_EXIT_CODE, DEFAULT_EXIT_CODE,
+    NOT_FOUND_EXIT_CODE, UNAVAILABLE_EXIT_CODE,
+};
+use crate::utils::format::parse_duration;
+
+pub(crate) use self::apireq::{HttpClient, Logging, Middleware, RateLimit, Timeouts};
 
 /// This is a list specifying general categories of errors that
 /// can be returned by a [`ChatProvider`]. This list may be updated
@@ -75,6 +86,8 @@ pub(crate) enum ErrorKind {
     /// The number of tokens in the request exceeds the maximum limit
     /// imposed on the model.
     ContextExceeded,
+    /// The provider does not support the requested operation.
+    Unsupported,
     /// An error that does not fit into any of the other categories.
     UnspecifiedError,
 }
@@ -101,6 +114,22 @@ impl Error {
         self.kind
     }
 
+    /// The status to exit with when the error is fatal
+    pub(crate) fn exit_code(&self) -> i32 {
+        match self.kind {
+            ErrorKind::Connection | ErrorKind::TimedOut => CONNECTION_EXIT_CODE,
+            ErrorKind::Authentication => AUTH_EXIT_CODE,
+            ErrorKind::NotFound => NOT_FOUND_EXIT_CODE,
+            ErrorKind::ContextExceeded => CONTEXT_EXCEEDED_EXIT_CODE,
+            ErrorKind::ExcessUsage | ErrorKind::ApiOverloaded => UNAVAILABLE_EXIT_CODE,
+            ErrorKind::BadRequest
+            | ErrorKind::InternalError
+            | ErrorKind::UnexpectedResponse
+            | ErrorKind::Unsupported
+            | ErrorKind::UnspecifiedError => DEFAULT_EXIT_CODE,
+        }
+    }
+
     fn message(&self) -> &'static str {
         match self.kind {
             ErrorKind::Connection => "failed to connect to the API service",
@@ -114,6 +143,7 @@ impl Error {
             ErrorKind::UnexpectedResponse => "API response was unexpected or malformed",
             ErrorKind::UnspecifiedError => "an unspecified error occurred",
             ErrorKind::ContextExceeded => "the model context was exceeded",
+            ErrorKind::Unsupported => "the operation is not supported by the provider",
         }
     }
 }
@@ -131,7 +161,8 @@ impl StdError for Error {
 }
 
 /// The reason why the model stopped generating.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub(crate) enum FinishReason {
     /// The model generated a stop token, terminating
     /// its response.
@@ -142,6 +173,27 @@ pub(crate) enum FinishReason {
     Length,
 }
 
+/// A source cited by the model, such as a web page retrieved by a search tool.
+#[derive(Debug, Clone)]
+pub(crate) struct Citation {
+    /// The URL of the source.
+    pub url: String,
+    /// The title of the source, if known.
+    pub title: Option<String>,
+    /// The character offset in the message content immediately after the
+    /// text which cites the source, if known.
+    pub end_index: Option<usize>,
+}
+
+/// A file generated by the model, such as an image.
+#[derive(Debug, Clone)]
+pub(crate) struct GeneratedFile {
+    /// The media type of the file (e.g., "image/png").
+    pub media_type: String,
+    /// The contents of the file.
+    pub data: Vec<u8>,
+}
+
 /// A message delta represents a "chunk" of a streamed message.
 /// Usually, this consists of a single token.
 #[derive(Debug, Clone)]
@@ -150,18 +202,77 @@ pub(crate) struct MessageDelta {
     pub role: Role,
     /// The content of the message.
     pub content: String,
+    /// Sources cited in the message. Providers which do not support
+    /// citations leave this empty.
+    pub citations: Vec<Citation>,
+    /// Files generated along with the message. Providers which cannot
+    /// generate files leave this empty.
+    pub files: Vec<GeneratedFile>,
+}
+
+/// A message which was generated in full before it was returned.
+#[derive(Debug, Clone)]
+pub(crate) struct Completion {
+    /// The generated message.
+    pub message: MessageDelta,
+    /// The reason the model stopped generating.
+    pub finish_reason: FinishReason,
+    /// The usage for the request.
+    pub usage: Usage,
+}
+
+impl Completion {
+    /// Presents the completion as a stream consisting of a single chunk, so
+    /// it can be consumed in the same way as a streamed completion.
+    pub(crate) fn into_stream(self) -> Box<dyn AsyncMessageIterator> {
+        Box::new(CompletedStream {
+            message: Some(self.message),
+            finish_reason: self.finish_reason,
+            usage: self.usage,
+        })
+    }
+}
+
+struct CompletedStream {
+    message: Option<MessageDelta>,
+    finish_reason: FinishReason,
+    usage: Usage,
+}
+
+#[async_trait]
+impl AsyncMessageIterator for CompletedStream {
+    async fn next(&mut self) -> Option<Result<MessageDelta, Error>> {
+        self.message.take().map(Ok)
+    }
+
+    fn finish_reason(&self) -> FinishReason {
+        self.finish_reason
+    }
+
+    fn usage(&self) -> &Usage {
+        &self.usage
+    }
+
+    // The response is already complete, so there is nothing to wait for
+    fn on_heartbeat(&mut self, _hook: HeartbeatHook) {}
 }
 
 /// The context usage metadata.
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
 pub(crate) struct Usage {
     /// The number of tokens in the prompt.
-    prompt_tokens: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_tokens: Option<usize>,
     /// The number of tokens in the response.
-    completion_tokens: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completion_tokens: Option<usize>,
 }
 
-/// A streamed response from a completion.
+/// Called when the server signals that a response is still being generated
+pub(crate) type HeartbeatHook = Box<dyn Fn() + Send + Sync>;
+
+/// A streamed response from a completion. Dropping the iterator before it
+/// is exhausted aborts the request.
 #[async_trait]
 pub(crate) trait AsyncMessageIterator {
     /// The next chunk of the message.
@@ -174,15 +285,217 @@ pub(crate) trait AsyncMessageIterator {
     /// The usage for this request. This can only be called once the
     /// iterator is exhausted.
     fn usage(&self) -> &Usage;
+
+    /// Sets a hook which is called whenever the server sends a heartbeat
+    /// instead of a chunk, showing that it is still generating.
+    fn on_heartbeat(&mut self, hook: HeartbeatHook);
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub(crate) struct Model {
     /// The ID of the model. This must be an acceptable parameter to
     /// [`ChatProvider::stream_completion`].
     pub id: String,
     /// The context length of the model, if known.
     pub context_length: Option<u64>,
+    /// Details which are reported by local runtimes.
+    #[serde(default)]
+    pub details: ModelDetails,
+}
+
+/// Details of a model which are reported by local runtimes.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub(crate) struct ModelDetails {
+    /// The number of parameters (e.g., "8.0B").
+    pub parameter_size: Option<String>,
+    /// The family of the model (e.g., "llama").
+    pub family: Option<String>,
+    /// The quantization of the weights (e.g., "Q4_K_M").
+    pub quantization: Option<String>,
+}
+
+/// A model which is currently loaded into memory by a local runtime.
+#[derive(Debug, Clone)]
+pub(crate) struct LoadedModel {
+    /// The ID of the model.
+    pub id: String,
+    /// The total number of bytes the model occupies in memory.
+    pub size: u64,
+    /// The number of bytes the model occupies in video memory.
+    pub size_vram: u64,
+    /// The time at which the runtime will unload the model, if known.
+    pub expires_at: Option<String>,
+}
+
+impl LoadedModel {
+    /// Describes where the model is placed, in the style of `ollama ps`
+    /// (e.g., "100% GPU", "100% CPU", or "27%/73% CPU/GPU").
+    pub(crate) fn processor(&self) -> String {
+        if self.size == 0 || self.size_vram == 0 {
+            return "100% CPU".to_string();
+        }
+
+        if self.size_vram >= self.size {
+            return "100% GPU".to_string();
+        }
+
+        let gpu = (self.size_vram as f64 / self.size as f64 * 100.0).round() as u64;
+        let cpu = 100 - gpu;
+
+        format!("{}%/{}% CPU/GPU", cpu, gpu)
+    }
+}
+
+/// The format in which the model is instructed to respond.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum ResponseFormat {
+    /// Free-form text.
+    #[default]
+    Text,
+    /// A syntactically valid JSON value. Providers constrain the model's
+    /// output where supported, but the output should still be validated.
+    Json,
+}
+
+/// How long a model stays loaded after a request, for providers which load
+/// models on demand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) enum KeepAlive {
+    /// Unload the model once it has been idle this long. A duration of zero
+    /// unloads the model as soon as the response is complete.
+    For(Duration),
+    /// Never unload the model.
+    Forever,
+}
+
+impl FromStr for KeepAlive {
+    type Err = String;
+
+    /// Parses a duration in seconds, minutes, or hours (e.g., 90s, 10m, or
+    /// 1h), or "forever"
+    fn from_str(keep_alive: &str) -> Result<Self, Self::Err> {
+        if keep_alive == "forever" {
+            return Ok(KeepAlive::Forever);
+        }
+
+        parse_duration(keep_alive).map(KeepAlive::For)
+    }
+}
+
+impl TryFrom<String> for KeepAlive {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for KeepAlive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeepAlive::For(duration) => match duration.as_secs() {
+                secs if secs > 0 && secs % 3600 == 0 => write!(f, "{}h", secs / 3600),
+                secs if secs > 0 && secs % 60 == 0 => write!(f, "{}m", secs / 60),
+                secs => write!(f, "{}s", secs),
+            },
+            KeepAlive::Forever => write!(f, "forever"),
+        }
+    }
+}
+
+impl From<KeepAlive> for String {
+    fn from(value: KeepAlive) -> Self {
+        value.to_string()
+    }
+}
+
+/// Options which influence how a completion is generated. Options which a
+/// provider does not support are ignored, see [`unsupported_options`].
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CompletionOptions {
+    /// The format of the response.
+    pub response_format: ResponseFormat,
+    /// The sampling temperature, or the model's default if unset.
+    pub temperature: Option<f64>,
+    /// The maximum number of tokens generated in the response.
+    pub max_tokens: Option<u32>,
+    /// Sequences which end the response when they are generated. They are
+    /// not included in the response.
+    pub stop: Vec<String>,
+    /// The seed used to sample the response, so it can be reproduced.
+    pub seed: Option<u32>,
+    /// How long the model stays loaded after the response, or the
+    /// provider's default if unset.
+    pub keep_alive: Option<KeepAlive>,
+}
+
+/// An option of [`CompletionOptions`] which a model may not support
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum_macros::Display)]
+pub(crate) enum CompletionOption {
+    #[strum(serialize = "JSON responses")]
+    ResponseFormat,
+    #[strum(serialize = "the temperature")]
+    Temperature,
+    #[strum(serialize = "a token limit")]
+    MaxTokens,
+    #[strum(serialize = "stop sequences")]
+    Stop,
+    #[strum(serialize = "a seed")]
+    Seed,
+    #[strum(serialize = "a keep-alive")]
+    KeepAlive,
+}
+
+impl CompletionOption {
+    /// Every option
+    pub(crate) const ALL: &'static [CompletionOption] = &[
+        CompletionOption::ResponseFormat,
+        CompletionOption::Temperature,
+        CompletionOption::MaxTokens,
+        CompletionOption::Stop,
+        CompletionOption::Seed,
+        CompletionOption::KeepAlive,
+    ];
+
+    /// Every option which applies to sampling the response
+    pub(crate) const SAMPLING: &'static [CompletionOption] = &[
+        CompletionOption::ResponseFormat,
+        CompletionOption::Temperature,
+        CompletionOption::MaxTokens,
+        CompletionOption::Stop,
+        CompletionOption::Seed,
+    ];
+}
+
+impl CompletionOptions {
+    /// Returns true if the option is set to something other than its default
+    fn is_set(&self, option: CompletionOption) -> bool {
+        match option {
+            CompletionOption::ResponseFormat => self.response_format != ResponseFormat::Text,
+            CompletionOption::Temperature => self.temperature.is_some(),
+            CompletionOption::MaxTokens => self.max_tokens.is_some(),
+            CompletionOption::Stop => !self.stop.is_empty(),
+            CompletionOption::Seed => self.seed.is_some(),
+            CompletionOption::KeepAlive => self.keep_alive.is_some(),
+        }
+    }
+}
+
+/// Lists the options which are set but which the model does not support,
+/// and so are ignored
+pub(crate) fn unsupported_options(
+    provider: &dyn ChatProvider,
+    model: &str,
+    options: &CompletionOptions,
+) -> Vec<CompletionOption> {
+    let supported = provider.supported_options(model);
+
+    CompletionOption::ALL
+        .iter()
+        .copied()
+        .filter(|option| options.is_set(*option) && !supported.contains(option))
+        .collect()
 }
 
 /// Provides instructions on how the context should be managed between API
@@ -201,27 +514,67 @@ pub(crate) enum ContextManagement {
 
 /// A trait implemented by all chat providers.
 #[async_trait]
-pub(crate) trait ChatProvider {
+pub(crate) trait ChatProvider: Send + Sync {
     /// Returns the provider identifier.
     fn id(&self) -> ProviderIdentifier;
 
     /// Returns the context management strategy.
     fn context_management(&self) -> ContextManagement;
 
+    /// Returns true if responses sampled with the same seed are guaranteed to
+    /// be the same. Providers which only make a best effort return false.
+    fn honors_seed(&self) -> bool;
+
+    /// Returns the completion options the model supports. Other options are
+    /// ignored when the model is prompted.
+    fn supported_options(&self, model: &str) -> &'static [CompletionOption];
+
     /// Returns a list of models the chat provider supports.
     async fn models(&self) -> Result<Vec<Model>, Error>;
 
     /// Returns the default model, or None if no default is designated.
     async fn default_model(&self) -> Result<Option<Model>, Error>;
 
+    /// Returns the models which are currently loaded into memory. Only local
+    /// runtimes track this, so remote providers return an empty list.
+    async fn loaded_models(&self) -> Result<Vec<LoadedModel>, Error>;
+
+    /// Unloads a model from memory. This returns an error of kind
+    /// [`ErrorKind::NotFound`] if the model is not loaded.
+    async fn unload_model(&self, model: &str) -> Result<(), Error>;
+
     /// Takes a series of messages that are part of a chat conversation
     /// and produces a new message generated by the model in response.
     ///
     /// `model`: The id of the model.
     /// `messages`: A series of messages in the conversation.
+    /// `options`: Options which influence how the completion is generated.
     async fn stream_completion(
         &self,
         model: &str,
         messages: &[Message],
+        options: &CompletionOptions,
     ) -> Result<Box<dyn AsyncMessageIterator>, Error>;
+
+    /// Produces a new message in response to the conversation, like
+    /// [`ChatProvider::stream_completion`], but returns the message once it
+    /// has been generated in full rather than streaming it. This works
+    /// through proxies which do not pass streamed responses through.
+    async fn completion(
+        &self,
+        model: &str,
+        messages: &[Message],
+        options: &CompletionOptions,
+    ) -> Result<Completion, Error>;
+
+    /// Generates the text which belongs between a prefix and a suffix, as is
+    /// needed to complete code at a cursor. This requires a model trained for
+    /// fill-in-the-middle completion. Providers which cannot perform infilling
+    /// return an error of kind [`ErrorKind::Unsupported`].
+    async fn fill_in_middle(
+        &self,
+        model: &str,
+        prefix: &str,
+        suffix: &str,
+    ) -> Result<String, Error>;
 }
@@ -2,6 +2,7 @@
 
 mod error;
 mod json_stream_parser;
+mod middleware;
 mod provider;
 mod stream_ext;
 
@@ -9,6 +10,9 @@ pub(crate) use error::Error as ReqwestError;
 pub(crate) use reqwest::Url;
 
 pub(crate) use json_stream_parser::Error as JsonStreamError;
+pub(crate) use json_stream_parser::JsonStream;
 pub(crate) use json_stream_parser::JsonStreamParser;
 pub(crate) use json_stream_parser::StreamFormat;
+pub(crate) use json_stream_parser::MESSAGE_EVENT;
+pub(crate) use middleware::{HttpClient, Logging, Middleware, RateLimit, Timeouts};
 pub(crate) use stream_ext::ReqwestResponseStreamExt;
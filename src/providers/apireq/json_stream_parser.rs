@@ -1,17 +1,45 @@
 //! This module parses streams of JSON objects from an HTTP response. It supports two
-//! formats, newline-delimited JSON and a subset of server-side events. It expects a
-//! byte stream, as produced by the [`reqwest::Response::bytes_stream`] method. This
-//! can be incrementally parsed, object by object.
+//! formats, newline-delimited JSON and server-sent events. It expects a byte stream,
+//! as produced by the [`reqwest::Response::bytes_stream`] method. This can be
+//! incrementally parsed, object by object: [`JsonStreamParser::into_stream`] adapts
+//! the parser into a [`Stream`] of deserialized objects.
+//!
+//! Server-sent events are parsed as described by the HTML standard: lines may end
+//! in CRLF, LF, or CR, fields other than `data`, `event`, `id`, and `retry` are
+//! ignored, and the data lines of an event are joined with newlines. The type, ID,
+//! and reconnection time of each event are exposed by [`JsonStreamParser::next_event`].
+//! OpenAI terminates its streams with a `[DONE]` data line, which is skipped.
+//!
+//! Some gateways keep idle connections open during long generations by sending
+//! comments (e.g., `: keep-alive`) or `ping` events. These carry no objects, but
+//! are reported to the heartbeat hook, if one is set, so callers know that the
+//! server is still responding.
 
 use bytes::Bytes;
 use core::fmt;
 use futures_core::stream::Stream;
-use futures_util::StreamExt;
+use futures_util::future::poll_fn;
+use futures_util::{ready, StreamExt};
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use std::error::Error as StdError;
-use std::marker::Unpin;
+use std::marker::{PhantomData, Unpin};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tracing::{debug, trace};
 
 use super::ReqwestError;
+use crate::providers::HeartbeatHook;
+
+/// The type of events which are only sent to keep the connection open
+const PING_EVENT: &str = "ping";
+
+/// The type of events which do not specify one
+pub(crate) const MESSAGE_EVENT: &str = "message";
+
+/// A byte order mark, which may precede an event stream
+const BOM: &[u8] = b"\xEF\xBB\xBF";
 
 trait RemoveFirstN {
     fn remove_first(&mut self, n: usize);
@@ -29,8 +57,9 @@ pub(crate) enum StreamFormat {
     /// Newline-delimited Json
     /// See https://github.com/ndjson/ndjson-spec
     Ndjson,
-    /// Limited server-side events
-    LSSE,
+    /// Server-sent events
+    /// See https://html.spec.whatwg.org/multipage/server-sent-events.html
+    Sse,
 }
 
 #[derive(Debug)]
@@ -39,15 +68,8 @@ pub(crate) struct DeseralizationFailedError {
     error: serde_json::error::Error,
 }
 
-// "The Server-Sent-Events parser embedded in crosstalk
-// is not spec-compliant. As of 2024 the OpenAI
-// only uses it to stream the data buffer so this is all we
-// support. If this is changed at some future time, this will
-// have to be updated."
 #[derive(Debug)]
 pub(crate) enum Error {
-    // stream is not supported by the parser
-    UnsupportedSseFieldName,
     ResponseExceededBuffer,
     DeseralizationFailed(DeseralizationFailedError),
     StreamFailed(ReqwestError),
@@ -56,8 +78,6 @@ pub(crate) enum Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::UnsupportedSseFieldName =>
-                write!(f, "the limited SSE parser only supports \"data\" field, an unsupported field name was received"),
             Self::ResponseExceededBuffer =>
                 write!(f, "the response overflowed the streaming buffer, this could indicate a malicious server"),
             Self::DeseralizationFailed(e) => write!(f, "failed to deseralized a streamed JSON object \"{}\": {}", e.blob, e.error),
@@ -76,7 +96,36 @@ impl StdError for Error {
     }
 }
 
+fn decode<'de, T: Deserialize<'de>>(bytes: &'de [u8]) -> Result<T, Error> {
+    serde_json::from_slice::<T>(bytes).map_err(|e| {
+        debug!(error = %e, blob = %String::from_utf8_lossy(bytes), "failed to parse chunk");
+        Error::DeseralizationFailed(DeseralizationFailedError {
+            blob: String::from_utf8_lossy(bytes).into_owned(),
+            error: e,
+        })
+    })
+}
+
+/// A server-sent event
 #[derive(Debug)]
+pub(crate) struct SseEvent<'a> {
+    /// The type of the event, [`MESSAGE_EVENT`] if none was given
+    pub event: &'a str,
+    /// The last event ID sent by the server, if any
+    pub id: Option<&'a str>,
+    /// The reconnection time last requested by the server, if any
+    pub retry: Option<Duration>,
+    /// The data lines of the event, joined with newlines
+    pub data: &'a [u8],
+}
+
+impl<'a> SseEvent<'a> {
+    /// Deserializes the data of the event as JSON
+    pub(crate) fn decode<T: Deserialize<'a>>(&self) -> Result<T, Error> {
+        decode(self.data)
+    }
+}
+
 pub(crate) struct JsonStreamParser<S>
 where
     S: Stream<Item = reqwest::Result<Bytes>> + Unpin,
@@ -87,6 +136,17 @@ where
     format: StreamFormat,
     i: usize,
     data: Vec<u8>,
+    /// Whether the data buffer holds a chunk which was returned
+    chunk_ready: bool,
+    /// Whether the start of the stream has been checked for a byte order mark
+    started: bool,
+    /// The type of the event being parsed, if it was given
+    event: Option<String>,
+    /// The type of the last event which was dispatched
+    dispatched: Option<String>,
+    last_event_id: Option<String>,
+    retry: Option<Duration>,
+    heartbeat: Option<HeartbeatHook>,
 }
 
 impl<S: Stream<Item = reqwest::Result<Bytes>> + Unpin> JsonStreamParser<S> {
@@ -112,26 +172,52 @@ impl<S: Stream<Item = reqwest::Result<Bytes>> + Unpin> JsonStreamParser<S> {
             format,
             i: 0,
             data: Vec::<u8>::new(),
+            chunk_ready: false,
+            started: false,
+            event: None,
+            dispatched: None,
+            last_event_id: None,
+            retry: None,
+            heartbeat: None,
         }
     }
 
-    async fn refill_buffer(&mut self) -> Result<bool, Error> {
-        if let Some(b) = self.stream.next().await {
-            match b {
-                Ok(b) => {
-                    if b.len() + self.buf.len() > self.max_size {
-                        return Err(Error::ResponseExceededBuffer);
-                    }
+    /// Sets the hook which is called for each heartbeat
+    pub(crate) fn on_heartbeat(&mut self, hook: HeartbeatHook) {
+        self.heartbeat = Some(hook);
+    }
 
-                    self.buf.extend(b);
+    fn beat(&self) {
+        if let Some(hook) = &self.heartbeat {
+            hook();
+        }
+    }
+
+    fn poll_refill_buffer(&mut self, cx: &mut Context<'_>) -> Poll<Result<bool, Error>> {
+        let refilled = match ready!(self.stream.poll_next_unpin(cx)) {
+            Some(Ok(b)) => {
+                trace!(bytes = b.len(), "received stream data");
 
-                    Ok(true)
+                if b.len() + self.buf.len() > self.max_size {
+                    debug!(max_size = self.max_size, "stream exceeded the buffer");
+                    return Poll::Ready(Err(Error::ResponseExceededBuffer));
                 }
-                Err(err) => Err(Error::StreamFailed(err.into())),
+
+                self.buf.extend(b);
+
+                Ok(true)
             }
-        } else {
-            Ok(false)
-        }
+            Some(Err(err)) => {
+                debug!(error = %err, "stream failed");
+                Err(Error::StreamFailed(err.into()))
+            }
+            None => {
+                trace!("stream ended");
+                Ok(false)
+            }
+        };
+
+        Poll::Ready(refilled)
     }
 
     // Advance cursor to the next line
@@ -184,87 +270,166 @@ impl<S: Stream<Item = reqwest::Result<Bytes>> + Unpin> JsonStreamParser<S> {
         }
     }
 
-    fn extract_lsse_data(&mut self) -> Result<bool, Error> {
-        loop {
-            if !self.advance_to_line() {
+    // Advance the cursor to the end of the next line, which may be
+    // terminated by CRLF, LF, or CR
+    //
+    // Returns the length of the line and its terminator. Otherwise,
+    // more data is needed
+    fn advance_to_sse_line(&mut self) -> Option<(usize, usize)> {
+        while self.i < self.buf.len() {
+            match self.buf[self.i] {
+                b'\n' => return Some((self.i, 1)),
+                b'\r' => {
+                    // The LF of a CRLF may arrive with the next chunk
+                    let next = self.buf.get(self.i + 1)?;
+
+                    return Some((self.i, if *next == b'\n' { 2 } else { 1 }));
+                }
+                _ => self.i += 1,
+            }
+        }
+
+        None
+    }
+
+    // Processes a line of an event stream
+    //
+    // Returns true when the line completes an event which should be dispatched
+    fn process_sse_line(&mut self, line: &[u8]) -> Result<bool, Error> {
+        // A blank line ends the event
+        if line.is_empty() {
+            let event = self.event.take();
+
+            if event.as_deref() == Some(PING_EVENT) {
+                trace!("received ping event");
+                self.data.clear();
+                self.beat();
                 return Ok(false);
             }
 
-            let line_content = Self::striped_line(self.i, &self.buf);
+            // Events without data are not dispatched
+            if self.data.is_empty() {
+                return Ok(false);
+            }
 
-            // Got data: CONTEXT, append to data buffer
-            let end_of_event = if line_content.len() == 0 {
-                // If there is no data, the event was just a comment
-                Ok(self.data.len() > 0)
-            } else {
-                let mut split = line_content.splitn(2, |x| *x == b':');
-
-                let field_name = split.next().unwrap();
-                let value = split.next().unwrap_or_default();
-
-                // Comment, skip
-                if field_name.len() == 0 {
-                    Ok(false)
-                // Add to data buffer
-                } else if field_name == b"data" {
-                    // Remove the leading space (if it exists)
-                    let value = value.strip_prefix(b" ").unwrap_or(value);
-
-                    if value == b"[DONE]" {
-                        // Skip terminal [DATA]
-                        Ok(false)
-                    } else {
-                        self.data.extend_from_slice(value);
-                        self.data.push(b'\n');
+            // Remove the trailing \n
+            self.data.pop();
+            self.dispatched = event;
 
-                        Ok(false)
-                    }
+            return Ok(true);
+        }
+
+        let (field_name, value) = match line.iter().position(|x| *x == b':') {
+            // Comment, skip
+            Some(0) => {
+                trace!(comment = %String::from_utf8_lossy(&line[1..]), "skipped event comment");
+                self.beat();
+                return Ok(false);
+            }
+            Some(i) => {
+                let value = &line[i + 1..];
 
-                // Unknown field name
-                } else {
-                    Err(Error::UnsupportedSseFieldName)
+                // Remove the leading space (if it exists)
+                (&line[..i], value.strip_prefix(b" ").unwrap_or(value))
+            }
+            // A line without a colon is a field without a value
+            None => (line, &line[line.len()..]),
+        };
+
+        match field_name {
+            b"data" if value == b"[DONE]" => {
+                // Skip terminal [DATA]
+                trace!("received end of event stream");
+            }
+            b"data" => {
+                if self.data.len() + value.len() + 1 > self.max_size {
+                    debug!(max_size = self.max_size, "event exceeded the buffer");
+                    return Err(Error::ResponseExceededBuffer);
                 }
-            };
 
-            self.buf.remove_first(self.i + 1);
-            self.i = 0;
+                self.data.extend_from_slice(value);
+                self.data.push(b'\n');
+            }
+            b"event" => self.event = Some(String::from_utf8_lossy(value).into_owned()),
+            // IDs containing NULL are ignored, per the standard
+            b"id" if !value.contains(&0) => {
+                self.last_event_id = Some(String::from_utf8_lossy(value).into_owned())
+            }
+            b"retry" if !value.is_empty() && value.iter().all(u8::is_ascii_digit) => {
+                // Reconnection times too long to represent are ignored
+                if let Ok(ms) = std::str::from_utf8(value).unwrap().parse::<u64>() {
+                    self.retry = Some(Duration::from_millis(ms));
+                }
+            }
+            _ => trace!(
+                field = %String::from_utf8_lossy(field_name),
+                "ignored event field"
+            ),
+        }
 
-            if !end_of_event? {
-                continue;
+        Ok(false)
+    }
+
+    fn extract_sse_data(&mut self) -> Result<bool, Error> {
+        if !self.started {
+            // Wait until a byte order mark can be ruled out
+            if self.buf.len() < BOM.len() && BOM.starts_with(&self.buf) {
+                return Ok(false);
             }
 
-            // remove trailing \n
-            if self.data.len() > 0 {
-                self.data.pop();
+            if self.buf.starts_with(BOM) {
+                self.buf.remove_first(BOM.len());
             }
 
-            return Ok(true);
+            self.started = true;
         }
+
+        while let Some((len, terminator)) = self.advance_to_sse_line() {
+            // The buffer is taken so the line can be borrowed while the
+            // rest of the parser is updated
+            let buf = std::mem::take(&mut self.buf);
+
+            let dispatch = self.process_sse_line(&buf[..len]);
+
+            self.buf = buf;
+            self.buf.remove_first(len + terminator);
+            self.i = 0;
+
+            if dispatch? {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
     }
 
-    async fn parse_chunk<'d>(&'d mut self) -> Option<Result<&'d [u8], Error>> {
+    // Reads from the stream until the next chunk is in the data buffer
+    //
+    // The state of a partially read chunk is kept in the parser, so polling
+    // can be resumed after the source stream is pending
+    fn poll_chunk(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<(), Error>>> {
         // Clear the previous chunk
-        self.data.clear();
+        if self.chunk_ready {
+            self.data.clear();
+            self.chunk_ready = false;
+        }
 
         loop {
             let extracted = match self.format {
                 StreamFormat::Ndjson => self.extract_json_line(),
-                StreamFormat::LSSE => {
-                    let extracted = self.extract_lsse_data();
-
-                    if let Err(err) = extracted {
-                        return Some(Err(err));
-                    }
-
-                    extracted.unwrap()
-                }
+                StreamFormat::Sse => match self.extract_sse_data() {
+                    Ok(extracted) => extracted,
+                    Err(err) => return Poll::Ready(Some(Err(err))),
+                },
             };
 
             if extracted {
-                return Some(Ok(&self.data));
+                trace!(chunk = %String::from_utf8_lossy(&self.data), "parsed chunk");
+                self.chunk_ready = true;
+                return Poll::Ready(Some(Ok(())));
             }
 
-            match self.refill_buffer().await {
+            match ready!(self.poll_refill_buffer(cx)) {
                 Ok(has_data) => {
                     if has_data {
                         continue;
@@ -272,27 +437,65 @@ impl<S: Stream<Item = reqwest::Result<Bytes>> + Unpin> JsonStreamParser<S> {
                         break;
                     }
                 }
-                Err(err) => return Some(Err(err)),
+                Err(err) => return Poll::Ready(Some(Err(err))),
             }
         }
 
-        None
+        Poll::Ready(None)
     }
 
-    pub(crate) async fn parse<'de, T: Deserialize<'de>>(&'de mut self) -> Option<Result<T, Error>> {
-        let c = self.parse_chunk().await;
-
-        c.and_then(|r| {
-            Some(match r {
-                Ok(bytes) => serde_json::from_slice::<T>(&bytes).map_err(|e| {
-                    Error::DeseralizationFailed(DeseralizationFailedError {
-                        blob: String::from_utf8_lossy(bytes).into_owned(),
-                        error: e,
-                    })
-                }),
-                Err(err) => Err(err),
-            })
-        })
+    async fn fill_chunk(&mut self) -> Option<Result<(), Error>> {
+        poll_fn(|cx| self.poll_chunk(cx)).await
+    }
+
+    /// Reads the next server-sent event. Unlike [`JsonStream`], the data is
+    /// left undecoded so it can be handled by the type of the event.
+    pub(crate) async fn next_event(&mut self) -> Option<Result<SseEvent<'_>, Error>> {
+        debug_assert!(matches!(self.format, StreamFormat::Sse));
+
+        match self.fill_chunk().await? {
+            Ok(()) => Some(Ok(SseEvent {
+                event: self.dispatched.as_deref().unwrap_or(MESSAGE_EVENT),
+                id: self.last_event_id.as_deref(),
+                retry: self.retry,
+                data: &self.data,
+            })),
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+/// A stream of the objects parsed by a [`JsonStreamParser`], each of which is
+/// deserialized as `T`
+pub(crate) struct JsonStream<S, T>
+where
+    S: Stream<Item = reqwest::Result<Bytes>> + Unpin,
+{
+    parser: JsonStreamParser<S>,
+    _item: PhantomData<fn() -> T>,
+}
+
+impl<S: Stream<Item = reqwest::Result<Bytes>> + Unpin> JsonStreamParser<S> {
+    /// Adapts the parser into a stream of objects of type `T`
+    pub(crate) fn into_stream<T: DeserializeOwned>(self) -> JsonStream<S, T> {
+        JsonStream {
+            parser: self,
+            _item: PhantomData,
+        }
+    }
+}
+
+impl<S: Stream<Item = reqwest::Result<Bytes>> + Unpin, T: DeserializeOwned> Stream
+    for JsonStream<S, T>
+{
+    type Item = Result<T, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let parser = &mut self.get_mut().parser;
+
+        let chunk = ready!(parser.poll_chunk(cx));
+
+        Poll::Ready(chunk.map(|r| r.and_then(|()| decode(&parser.data))))
     }
 }
 
@@ -308,41 +511,59 @@ mod tests {
 {"model":"llama:7b","done":true}
 "#;
 
-    const LSEE_STREAM1: &'static str = r#"
+    const SSE_STREAM1: &'static str = r#"
 data: {"model":"gemma:2b","done":false}
 
 data:{"model":"llama:7b","done":true}
 
 "#;
 
-    const LSEE_STREAM2: &'static str = r#"
+    const SSE_STREAM2: &'static str = r#"
 : Comment
 
 data: {"model":"gemma:2b","done":false}
 
 "#;
 
-    const LSEE_STREAM3: &'static str = r#"
+    const SSE_STREAM3: &'static str = r#"
 data: {"model":"gemma:2b","
 data: done":false}
 
 "#;
 
-    const LSEE_STREAM4: &'static str = r#"
+    const SSE_STREAM4: &'static str = r#"
 data: {"model":"gemma:2b",
 data: "done":false}
 
 "#;
 
     // This should cause an error (MalformattedStreamError::UnsupportedSseFieldName)
-    const LSEE_STREAM5: &'static str = r#"
+    // Unknown fields are ignored, so this has no events
+    const SSE_STREAM5: &'static str = r#"
 hello: {"model":"gemma:2b"," data: done":false}
 
 "#;
 
-    const LSSE_STREAM6: &'static str = r#"
+    const SSE_STREAM6: &'static str = r#"
+data: {"model":"gemma:2b","done":false}
+
+data: [DONE]
+
+"#;
+
+    // Heartbeats sent by gateways between events
+    const SSE_STREAM7: &str = r#"
+: keep-alive
+
+event: ping
+data: {"type":"ping"}
+
+id: 1
+retry: 1000
 data: {"model":"gemma:2b","done":false}
 
+: keep-alive
+
 data: [DONE]
 
 "#;
@@ -366,33 +587,47 @@ data: [DONE]
         return JsonStreamParser::new(stream, typ);
     }
 
+    async fn next_chunk<S: Stream<Item = reqwest::Result<Bytes>> + Unpin>(
+        parser: &mut JsonStreamParser<S>,
+    ) -> Option<Result<Vec<u8>, Error>> {
+        let chunk = parser.fill_chunk().await?;
+
+        Some(chunk.map(|()| parser.data.clone()))
+    }
+
     #[tokio::test]
     async fn test_json_stream_chunking() {
         for chunk_size in 1..NDJSON_STREAM.len() {
             let mut parser = stream_parser(chunk_size, NDJSON_STREAM, StreamFormat::Ndjson);
 
-            let chunk1 = parser.parse_chunk().await.unwrap().expect("should parse");
+            let chunk1 = next_chunk(&mut parser)
+                .await
+                .unwrap()
+                .expect("should parse");
 
             assert_eq!(
-                String::from_utf8(chunk1.to_vec()).unwrap(),
+                String::from_utf8(chunk1).unwrap(),
                 r#"{"model":"gemma:2b","done":false}"#
             );
 
-            let chunk2 = parser.parse_chunk().await.unwrap().expect("should parse");
+            let chunk2 = next_chunk(&mut parser)
+                .await
+                .unwrap()
+                .expect("should parse");
 
             assert_eq!(
-                String::from_utf8(chunk2.to_vec()).unwrap(),
+                String::from_utf8(chunk2).unwrap(),
                 r#"{"model":"llama:7b","done":true}"#
             );
 
-            let chunk3 = parser.parse_chunk().await;
+            let chunk3 = next_chunk(&mut parser).await;
             assert!(chunk3.is_none());
         }
     }
 
     #[derive(Debug, Deserialize)]
-    struct ModelJson<'c> {
-        model: &'c str,
+    struct ModelJson {
+        model: String,
         done: bool,
     }
 
@@ -400,58 +635,57 @@ data: [DONE]
     async fn test_json_stream_parser() {
         for chunk_size in 1..NDJSON_STREAM.len() {
             // Mock a stream to pass to the parser
-            let mut parser = stream_parser(chunk_size, NDJSON_STREAM, StreamFormat::Ndjson);
-
-            let result1 = parser.parse::<ModelJson>().await.unwrap();
-            assert_eq!(result1.unwrap().model, "gemma:2b");
+            let stream = stream_parser(chunk_size, NDJSON_STREAM, StreamFormat::Ndjson)
+                .into_stream::<ModelJson>();
 
-            let result2 = parser.parse::<ModelJson>().await.unwrap();
-            assert_eq!(result2.unwrap().model, "llama:7b");
+            let models: Vec<String> = stream.map(|result| result.unwrap().model).collect().await;
 
-            let result3 = parser.parse::<ModelJson>().await;
-            assert!(result3.is_none());
+            assert_eq!(models, vec!["gemma:2b", "llama:7b"]);
         }
     }
 
     #[tokio::test]
     async fn test_stream_parse() {
         for chunk_size in 1..=10 {
-            // LSSE_STREAM1
+            // SSE_STREAM1
             {
-                let mut parser = stream_parser(chunk_size, LSEE_STREAM1, StreamFormat::LSSE);
+                let mut parser = stream_parser(chunk_size, SSE_STREAM1, StreamFormat::Sse)
+                    .into_stream::<ModelJson>();
 
-                let result = parser.parse::<ModelJson>().await.unwrap();
+                let result = parser.next().await.unwrap();
                 assert!(result.is_ok());
 
                 let result = result.unwrap();
                 assert_eq!(result.model, "gemma:2b");
 
-                let result = parser.parse::<ModelJson>().await.unwrap();
+                let result = parser.next().await.unwrap();
                 assert!(result.is_ok());
 
                 let result = result.unwrap();
                 assert_eq!(result.model, "llama:7b");
 
-                let result = parser.parse::<ModelJson>().await;
+                let result = parser.next().await;
                 assert!(result.is_none());
             }
 
             {
-                let mut parser = stream_parser(chunk_size, LSEE_STREAM2, StreamFormat::LSSE);
+                let mut parser = stream_parser(chunk_size, SSE_STREAM2, StreamFormat::Sse)
+                    .into_stream::<ModelJson>();
 
-                let result = parser.parse::<ModelJson>().await.unwrap();
+                let result = parser.next().await.unwrap();
                 assert!(result.is_ok());
                 let result = result.unwrap();
                 assert_eq!(result.model, "gemma:2b");
 
-                let result = parser.parse::<ModelJson>().await;
+                let result = parser.next().await;
                 assert!(result.is_none());
             }
 
             {
-                let mut parser = stream_parser(chunk_size, LSEE_STREAM3, StreamFormat::LSSE);
+                let mut parser = stream_parser(chunk_size, SSE_STREAM3, StreamFormat::Sse)
+                    .into_stream::<ModelJson>();
 
-                let result = parser.parse::<ModelJson>().await.unwrap();
+                let result = parser.next().await.unwrap();
 
                 // Newline injected between json
                 assert!(result.is_err());
@@ -462,39 +696,114 @@ data: [DONE]
             }
 
             {
-                let mut parser = stream_parser(chunk_size, LSEE_STREAM4, StreamFormat::LSSE);
+                let mut parser = stream_parser(chunk_size, SSE_STREAM4, StreamFormat::Sse)
+                    .into_stream::<ModelJson>();
 
-                let result = parser.parse::<ModelJson>().await.unwrap();
+                let result = parser.next().await.unwrap();
                 assert!(result.is_ok());
                 let result = result.unwrap();
                 assert_eq!(result.model, "gemma:2b");
 
-                let result = parser.parse::<ModelJson>().await;
+                let result = parser.next().await;
                 assert!(result.is_none());
             }
 
             {
-                let mut parser = stream_parser(chunk_size, LSSE_STREAM6, StreamFormat::LSSE);
+                let mut parser = stream_parser(chunk_size, SSE_STREAM6, StreamFormat::Sse)
+                    .into_stream::<ModelJson>();
 
-                let result = parser.parse::<ModelJson>().await.unwrap();
+                let result = parser.next().await.unwrap();
                 assert!(result.is_ok());
                 let result = result.unwrap();
                 assert_eq!(result.model, "gemma:2b");
 
-                let result = parser.parse::<ModelJson>().await;
+                let result = parser.next().await;
                 assert!(result.is_none());
             }
 
             {
-                let mut parser = stream_parser(chunk_size, LSEE_STREAM5, StreamFormat::LSSE);
+                let mut parser = stream_parser(chunk_size, SSE_STREAM7, StreamFormat::Sse);
 
-                let result = parser.parse::<ModelJson>().await.unwrap();
-                assert!(result.is_err());
-                assert!(matches!(
-                    result.unwrap_err(),
-                    Error::UnsupportedSseFieldName
-                ));
+                let heartbeats = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+                let counter = heartbeats.clone();
+
+                parser.on_heartbeat(Box::new(move || {
+                    counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                }));
+
+                let mut parser = parser.into_stream::<ModelJson>();
+
+                let result = parser.next().await.unwrap();
+                assert!(result.is_ok());
+                let result = result.unwrap();
+                assert_eq!(result.model, "gemma:2b");
+
+                let result = parser.next().await;
+                assert!(result.is_none());
+
+                assert_eq!(heartbeats.load(std::sync::atomic::Ordering::Relaxed), 3);
+            }
+
+            {
+                let mut parser = stream_parser(chunk_size, SSE_STREAM5, StreamFormat::Sse)
+                    .into_stream::<ModelJson>();
+
+                let result = parser.next().await;
+                assert!(result.is_none());
             }
         }
     }
+
+    // Mixed line endings, a byte order mark, and each of the fields
+    const SSE_STREAM8: &str = "\u{feff}retry: 3000\r\nid: 7\r\nevent: delta\rdata: {\"model\":\"gemma:2b\",\r\ndata:\"done\":false}\r\n\r\ndata\nid\n\n: id: 8\nevent: error\ndata: {}\n\n";
+
+    #[tokio::test]
+    async fn test_sse_events() {
+        for chunk_size in 1..=10 {
+            let mut parser = stream_parser(chunk_size, SSE_STREAM8, StreamFormat::Sse);
+
+            let event = parser.next_event().await.unwrap().unwrap();
+            assert_eq!(event.event, "delta");
+            assert_eq!(event.id, Some("7"));
+            assert_eq!(event.retry, Some(Duration::from_millis(3000)));
+            assert_eq!(event.data, b"{\"model\":\"gemma:2b\",\n\"done\":false}");
+
+            let result = event.decode::<ModelJson>().unwrap();
+            assert_eq!(result.model, "gemma:2b");
+
+            // A field without a colon has an empty value
+            let event = parser.next_event().await.unwrap().unwrap();
+            assert_eq!(event.event, MESSAGE_EVENT);
+            assert_eq!(event.id, Some(""));
+            assert_eq!(event.data, b"");
+
+            let event = parser.next_event().await.unwrap().unwrap();
+            assert_eq!(event.event, "error");
+            assert_eq!(event.data, b"{}");
+
+            assert!(parser.next_event().await.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sse_data_limit() {
+        // Each line fits in the buffer, but the data of the event does not
+        let lines: Vec<Result<Bytes, reqwest::Error>> = vec![
+            Ok(Bytes::from("data: 1234567890\n")),
+            Ok(Bytes::from("data: 1234567890\n")),
+            Ok(Bytes::from("data: 1234567890\n\n")),
+        ];
+
+        let mut parser = JsonStreamParser::with_max_size_and_capacity(
+            stream::iter(lines),
+            StreamFormat::Sse,
+            24,
+            24,
+        );
+
+        assert!(matches!(
+            parser.next_event().await,
+            Some(Err(Error::ResponseExceededBuffer))
+        ));
+    }
 }
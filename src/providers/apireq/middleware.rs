@@ -0,0 +1,133 @@
+//! A chain of middlewares through which API requests are sent
+//!
+//! Behaviors which apply to every request, such as logging and rate
+//! limiting, are written once as a [`Middleware`] rather than in each
+//! provider's API client. An [`HttpClient`] sends each request through its
+//! chain of middlewares in order: each middleware may inspect or change the
+//! request, delay it, or answer it itself, and passes it on by running
+//! [`Next`]. The last middleware passes the request to the network.
+//!
+//! Credentials are redacted from everything which is logged: the headers
+//! which carry API keys, and API keys and passwords in URLs.
+//!
+//! Timeouts are enforced by the underlying client rather than a middleware,
+//! since the read timeout also applies to the body of a streamed response
+//! after the middlewares have returned.
+
+mod logging;
+mod rate_limit;
+mod redact;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::{Client, IntoUrl, Request, RequestBuilder, Response};
+
+pub(crate) use logging::Logging;
+pub(crate) use rate_limit::RateLimit;
+
+#[async_trait]
+pub(crate) trait Middleware: Send + Sync {
+    /// Handles the request, passing it to the rest of the chain with `next`
+    async fn handle(&self, request: Request, next: Next<'_>) -> reqwest::Result<Response>;
+}
+
+/// The rest of the chain after a middleware
+#[derive(Clone, Copy)]
+pub(crate) struct Next<'a> {
+    client: &'a Client,
+    middlewares: &'a [Arc<dyn Middleware>],
+}
+
+impl Next<'_> {
+    pub(crate) async fn run(self, request: Request) -> reqwest::Result<Response> {
+        match self.middlewares.split_first() {
+            Some((middleware, middlewares)) => {
+                let next = Next {
+                    client: self.client,
+                    middlewares,
+                };
+
+                middleware.handle(request, next).await
+            }
+            None => self.client.execute(request).await,
+        }
+    }
+}
+
+/// How long requests wait on an unresponsive server. Unset timeouts are
+/// disabled.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Timeouts {
+    /// The time allowed to establish a connection
+    pub connect: Option<Duration>,
+    /// The time allowed between reads from the server. Streamed responses
+    /// may take longer in total so long as data keeps arriving.
+    pub read: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            connect: Some(Duration::from_secs(10)),
+            // Local runtimes may take a while to load a model before responding
+            read: Some(Duration::from_secs(300)),
+        }
+    }
+}
+
+/// A client which sends requests through a chain of middlewares
+#[derive(Clone)]
+pub(crate) struct HttpClient {
+    client: Client,
+    middlewares: Arc<[Arc<dyn Middleware>]>,
+}
+
+impl HttpClient {
+    /// Creates a client with the middlewares, the first of which handles
+    /// each request first
+    pub(crate) fn new(middlewares: Vec<Arc<dyn Middleware>>, timeouts: Timeouts) -> HttpClient {
+        let mut builder = Client::builder();
+
+        if let Some(timeout) = timeouts.connect {
+            builder = builder.connect_timeout(timeout);
+        }
+
+        if let Some(timeout) = timeouts.read {
+            builder = builder.read_timeout(timeout);
+        }
+
+        HttpClient {
+            client: builder.build().expect("failed to build the HTTP client"),
+            middlewares: middlewares.into(),
+        }
+    }
+
+    pub(crate) fn get<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    pub(crate) fn post<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.client.post(url)
+    }
+
+    /// Sends the request through the middlewares
+    pub(crate) async fn send(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        let request = request.build()?;
+
+        let next = Next {
+            client: &self.client,
+            middlewares: &self.middlewares,
+        };
+
+        next.run(request).await
+    }
+}
+
+impl Default for HttpClient {
+    /// A client which logs each request
+    fn default() -> HttpClient {
+        HttpClient::new(vec![Arc::new(Logging)], Timeouts::default())
+    }
+}
@@ -0,0 +1,51 @@
+use std::time::Instant;
+
+use async_trait::async_trait;
+use reqwest::{Request, Response};
+use tracing::{debug, trace};
+
+use super::{redact, Middleware, Next};
+
+/// Logs the method, URL, and response status of each request. The headers
+/// and body are only logged at the trace level. Credentials in the URL and
+/// headers are redacted.
+pub(crate) struct Logging;
+
+#[async_trait]
+impl Middleware for Logging {
+    async fn handle(&self, request: Request, next: Next<'_>) -> reqwest::Result<Response> {
+        let method = request.method().clone();
+        let url = redact::url(request.url());
+
+        debug!(%method, %url, "sending request");
+
+        trace!(%url, headers = %redact::headers(request.headers()), "request headers");
+
+        if let Some(body) = request.body().and_then(|body| body.as_bytes()) {
+            trace!(%url, body = %String::from_utf8_lossy(body), "request body");
+        }
+
+        let start = Instant::now();
+
+        let request_url = request.url().clone();
+
+        match next.run(request).await {
+            Ok(res) => {
+                debug!(
+                    %method,
+                    %url,
+                    status = %res.status(),
+                    elapsed_ms = start.elapsed().as_millis() as u64,
+                    "received response"
+                );
+                Ok(res)
+            }
+            Err(err) => {
+                let error = redact::error(&err, &request_url);
+
+                debug!(%method, %url, %error, "request failed");
+                Err(err)
+            }
+        }
+    }
+}
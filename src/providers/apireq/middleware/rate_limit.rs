@@ -0,0 +1,77 @@
+use std::num::NonZeroU32;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use reqwest::{Request, Response};
+use tokio::time::Instant;
+use tracing::debug;
+
+use super::{Middleware, Next};
+
+/// Spaces requests evenly so no more than a number of requests are sent
+/// each minute. Requests which would exceed the limit wait for their turn.
+pub(crate) struct RateLimit {
+    interval: Duration,
+    /// The earliest time the next request may be sent
+    next_slot: Mutex<Option<Instant>>,
+}
+
+impl RateLimit {
+    pub(crate) fn per_minute(requests: NonZeroU32) -> RateLimit {
+        RateLimit {
+            interval: Duration::from_secs(60) / requests.get(),
+            next_slot: Mutex::new(None),
+        }
+    }
+
+    /// Reserves the earliest slot at or after `now`
+    fn reserve(&self, now: Instant) -> Instant {
+        let mut next_slot = self.next_slot.lock().unwrap();
+
+        let slot = next_slot.map_or(now, |next_slot| next_slot.max(now));
+
+        *next_slot = Some(slot + self.interval);
+
+        slot
+    }
+}
+
+#[async_trait]
+impl Middleware for RateLimit {
+    async fn handle(&self, request: Request, next: Next<'_>) -> reqwest::Result<Response> {
+        let now = Instant::now();
+        let slot = self.reserve(now);
+
+        if slot > now {
+            debug!(
+                url = %request.url(),
+                delay_ms = (slot - now).as_millis() as u64,
+                "delaying request for the rate limit"
+            );
+
+            tokio::time::sleep_until(slot).await;
+        }
+
+        next.run(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserve() {
+        let limit = RateLimit::per_minute(NonZeroU32::new(30).unwrap());
+        let now = Instant::now();
+
+        assert_eq!(limit.reserve(now), now);
+        assert_eq!(limit.reserve(now), now + Duration::from_secs(2));
+
+        // Slots which passed while idle are not saved up
+        let later = now + Duration::from_secs(60);
+
+        assert_eq!(limit.reserve(later), later);
+    }
+}
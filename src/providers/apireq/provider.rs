@@ -8,9 +8,15 @@ use crate::providers::{Error, ErrorKind};
 impl From<JsonStreamError> for Error {
     fn from(value: JsonStreamError) -> Self {
         let kind = match &value {
-            JsonStreamError::DeseralizationFailed(_)
-            | JsonStreamError::UnsupportedSseFieldName
-            | JsonStreamError::ResponseExceededBuffer => ErrorKind::UnexpectedResponse,
+            JsonStreamError::DeseralizationFailed(_) | JsonStreamError::ResponseExceededBuffer => {
+                ErrorKind::UnexpectedResponse
+            }
+            // The server stopped sending the response
+            JsonStreamError::StreamFailed(err)
+                if matches!(err.kind(), ReqwestErrorKind::TimedOut) =>
+            {
+                ErrorKind::TimedOut
+            }
             // This might fit better as "unexpected response"
             JsonStreamError::StreamFailed(_) => ErrorKind::UnspecifiedError,
         };
@@ -6,16 +6,16 @@ pub(crate) trait ReqwestResponseStreamExt {
     fn stream_ndjson(
         self,
     ) -> JsonStreamParser<impl Stream<Item = Result<bytes::Bytes, reqwest::Error>> + Unpin>;
-    fn stream_lsse(
+    fn stream_sse(
         self,
     ) -> JsonStreamParser<impl Stream<Item = Result<bytes::Bytes, reqwest::Error>> + Unpin>;
 }
 
 impl ReqwestResponseStreamExt for reqwest::Response {
-    fn stream_lsse(
+    fn stream_sse(
         self,
     ) -> JsonStreamParser<impl Stream<Item = Result<bytes::Bytes, reqwest::Error>> + Unpin> {
-        JsonStreamParser::new(self.bytes_stream(), StreamFormat::LSSE)
+        JsonStreamParser::new(self.bytes_stream(), StreamFormat::Sse)
     }
 
     fn stream_ndjson(
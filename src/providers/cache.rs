@@ -0,0 +1,117 @@
+//! Caching of model listings and provider health
+//!
+//! Listing models can be slow: a local runtime may need to be woken up and
+//! remote providers are queried over the network. [`CachingProvider`] wraps a
+//! [`ChatProvider`], serving [`ChatProvider::models`] from the disk-backed
+//! [`Cache`] while the listing is fresh. All other requests are passed
+//! through to the provider.
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use async_trait::async_trait;
+
+use super::providers::ProviderIdentifier;
+use super::{
+    AsyncMessageIterator, ChatProvider, Completion, CompletionOption, CompletionOptions,
+    ContextManagement, Error, LoadedModel, Message, Model,
+};
+use crate::cache::Cache;
+
+/// The key under which the models of a provider are cached
+pub(crate) fn models_key(provider: ProviderIdentifier) -> String {
+    format!("models-{}", provider)
+}
+
+/// The key under which the health of a provider is cached. The credential
+/// is hashed into the key, so a new credential is probed again.
+pub(crate) fn health_key(provider: ProviderIdentifier, credential: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+
+    credential.hash(&mut hasher);
+
+    format!("health-{}-{:016x}", provider, hasher.finish())
+}
+
+/// A provider which caches its model listing
+pub(crate) struct CachingProvider {
+    inner: Box<dyn ChatProvider>,
+    cache: Cache,
+}
+
+impl CachingProvider {
+    pub(crate) fn new(inner: Box<dyn ChatProvider>, cache: Cache) -> CachingProvider {
+        CachingProvider { inner, cache }
+    }
+}
+
+#[async_trait]
+impl ChatProvider for CachingProvider {
+    fn id(&self) -> ProviderIdentifier {
+        self.inner.id()
+    }
+
+    fn context_management(&self) -> ContextManagement {
+        self.inner.context_management()
+    }
+
+    fn honors_seed(&self) -> bool {
+        self.inner.honors_seed()
+    }
+
+    fn supported_options(&self, model: &str) -> &'static [CompletionOption] {
+        self.inner.supported_options(model)
+    }
+
+    async fn models(&self) -> Result<Vec<Model>, Error> {
+        let key = models_key(self.id());
+
+        if let Some(models) = self.cache.get(&key) {
+            return Ok(models);
+        }
+
+        let models = self.inner.models().await?;
+
+        self.cache.put(&key, &models);
+
+        Ok(models)
+    }
+
+    async fn default_model(&self) -> Result<Option<Model>, Error> {
+        self.inner.default_model().await
+    }
+
+    async fn loaded_models(&self) -> Result<Vec<LoadedModel>, Error> {
+        self.inner.loaded_models().await
+    }
+
+    async fn unload_model(&self, model: &str) -> Result<(), Error> {
+        self.inner.unload_model(model).await
+    }
+
+    async fn stream_completion(
+        &self,
+        model: &str,
+        messages: &[Message],
+        options: &CompletionOptions,
+    ) -> Result<Box<dyn AsyncMessageIterator>, Error> {
+        self.inner.stream_completion(model, messages, options).await
+    }
+
+    async fn completion(
+        &self,
+        model: &str,
+        messages: &[Message],
+        options: &CompletionOptions,
+    ) -> Result<Completion, Error> {
+        self.inner.completion(model, messages, options).await
+    }
+
+    async fn fill_in_middle(
+        &self,
+        model: &str,
+        prefix: &str,
+        suffix: &str,
+    ) -> Result<String, Error> {
+        self.inner.fill_in_middle(model, prefix, suffix).await
+    }
+}
@@ -1,11 +1,12 @@
 use bytes::Bytes;
 use futures_core::Stream;
-use reqwest::{Client, IntoUrl, Response, StatusCode};
+use futures_util::StreamExt;
+use reqwest::{IntoUrl, Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::providers::apireq::{
-    self, JsonStreamError, JsonStreamParser, ReqwestResponseStreamExt, Url,
+    self, HttpClient, JsonStream, JsonStreamError, ReqwestResponseStreamExt, Url,
 };
 
 const OLLAMA_DEFAULT_ENDPOINT: &'static str = "http://localhost:11434";
@@ -30,6 +31,9 @@ pub(super) enum Error {
     #[error("ollama encountered an internal error: {0}")]
     InternalError(String),
 
+    #[error("ollama is overloaded: {0}")]
+    Overloaded(String),
+
     #[error("the ollama API returned an unspecified error: {0}")]
     UnspecifiedError(String),
 
@@ -52,12 +56,73 @@ pub(super) enum Role {
 pub(super) struct ChatMessage {
     pub role: Role,
     pub content: String,
+    /// Images encoded in base64
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum Format {
+    Json,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub(super) struct ChatOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<Format>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<ModelOptions>,
+    /// Seconds the model stays loaded after the request, or indefinitely if
+    /// negative
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_alive: Option<i64>,
+}
+
+/// Parameters of the model which override those of its Modelfile
+#[derive(Serialize, Debug, Default)]
+pub(super) struct ModelOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    /// The maximum number of tokens to generate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_predict: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Debug)]
 struct ChatRequest<'m> {
     model: &'m str,
     messages: &'m [ChatMessage],
+    #[serde(flatten)]
+    options: &'m ChatOptions,
+    stream: bool,
+}
+
+// Structures to serialize /api/generate
+#[derive(Serialize, Debug)]
+struct UnloadRequest<'m> {
+    model: &'m str,
+    keep_alive: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct GenerateRequest<'m> {
+    model: &'m str,
+    prompt: &'m str,
+    suffix: &'m str,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<i64>,
+}
+
+// Structures to deseralize /api/generate
+#[derive(Deserialize, Debug)]
+struct GenerateResponse {
+    response: String,
 }
 
 // Structures to deseralize /api/chat
@@ -128,6 +193,51 @@ struct TagsList {
     models: Vec<Tag>,
 }
 
+// Structures to serialize and deseralize /api/show
+
+#[derive(Debug, Serialize)]
+struct ShowRequest<'m> {
+    model: &'m str,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct ShowResponse {
+    /// Metadata of the model file, keyed by names such as
+    /// `llama.context_length` which are prefixed with the architecture
+    #[serde(default)]
+    pub model_info: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ShowResponse {
+    /// The context length the model was trained with
+    pub(super) fn context_length(&self) -> Option<u64> {
+        let architecture = self.model_info.get("general.architecture")?.as_str()?;
+
+        self.model_info
+            .get(&format!("{}.context_length", architecture))?
+            .as_u64()
+    }
+}
+
+// Structures to deseralize /api/ps
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(super) struct ProcessModel {
+    pub name: String,
+    pub model: String,
+    pub size: u64,
+    pub digest: String,
+    pub details: Details,
+    pub expires_at: String,
+    #[serde(default)]
+    pub size_vram: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ProcessList {
+    models: Vec<ProcessModel>,
+}
+
 // Errors
 #[derive(Debug, Deserialize)]
 struct ApiError {
@@ -138,12 +248,12 @@ pub(super) struct StreamingChatResponse<S>
 where
     S: Stream<Item = reqwest::Result<Bytes>> + Unpin,
 {
-    stream: JsonStreamParser<S>,
+    stream: JsonStream<S, StreamChatChunk>,
 }
 
 impl<S: Stream<Item = reqwest::Result<Bytes>> + Unpin> StreamingChatResponse<S> {
     pub(crate) async fn next(&mut self) -> Option<Result<StreamingChatDelta, Error>> {
-        let delta = self.stream.parse::<StreamChatChunk>().await;
+        let delta = self.stream.next().await;
 
         delta.map(|r| {
             r.map_err(|e| Error::StreamParser(e))
@@ -157,15 +267,22 @@ impl<S: Stream<Item = reqwest::Result<Bytes>> + Unpin> StreamingChatResponse<S>
 
 pub(super) struct OllamaApi {
     api_base: Url,
+    http: HttpClient,
 }
 
 impl OllamaApi {
     pub(super) fn with_api_base<U: IntoUrl>(api_base: U) -> Result<OllamaApi, Error> {
         Ok(OllamaApi {
             api_base: api_base.into_url().map_err(|e| Error::InvalidApiBase(e))?,
+            http: HttpClient::default(),
         })
     }
 
+    /// Sends requests through the client's middlewares
+    pub(super) fn set_http_client(&mut self, http: HttpClient) {
+        self.http = http;
+    }
+
     pub(super) fn new() -> OllamaApi {
         Self::with_api_base(OLLAMA_DEFAULT_ENDPOINT).unwrap()
     }
@@ -183,6 +300,8 @@ impl OllamaApi {
 
             match status {
                 StatusCode::NOT_FOUND => Err(Error::NotFound(err.error)),
+                // Returned when the request queue is full
+                StatusCode::SERVICE_UNAVAILABLE => Err(Error::Overloaded(err.error)),
                 code => match code.as_u16() {
                     400..=499 => Err(Error::BadRequest(err.error)),
                     500..=599 => Err(Error::InternalError(err.error)),
@@ -195,9 +314,11 @@ impl OllamaApi {
     pub(super) async fn tags(&self) -> Result<Vec<Tag>, Error> {
         let url = self.api_base.join("/api/tags")?;
 
-        let res = Client::new()
-            .get(url)
-            .send()
+        let request = self.http.get(url);
+
+        let res = self
+            .http
+            .send(request)
             .await
             .map_err(|e| Error::RequestFailed(e.into()))?;
 
@@ -211,27 +332,156 @@ impl OllamaApi {
         Ok(tags.models)
     }
 
+    /// Shows the metadata of a model
+    pub(super) async fn show(&self, model: &str) -> Result<ShowResponse, Error> {
+        let url = self.api_base.join("/api/show")?;
+
+        let request = self.http.post(url).json(&ShowRequest { model });
+
+        let res = self
+            .http
+            .send(request)
+            .await
+            .map_err(|e| Error::RequestFailed(e.into()))?;
+
+        let res = Self::maybe_parse_api_error(res).await?;
+
+        res.json().await.map_err(|e| Error::RequestFailed(e.into()))
+    }
+
+    pub(super) async fn ps(&self) -> Result<Vec<ProcessModel>, Error> {
+        let url = self.api_base.join("/api/ps")?;
+
+        let request = self.http.get(url);
+
+        let res = self
+            .http
+            .send(request)
+            .await
+            .map_err(|e| Error::RequestFailed(e.into()))?;
+
+        let res = Self::maybe_parse_api_error(res).await?;
+
+        let processes: ProcessList = res
+            .json()
+            .await
+            .map_err(|e| Error::RequestFailed(e.into()))?;
+
+        Ok(processes.models)
+    }
+
+    /// Unload a model from memory. Ollama unloads a model when a generation
+    /// request is issued without a prompt and a zero keep-alive.
+    pub(super) async fn unload(&self, model: &str) -> Result<(), Error> {
+        let url = self.api_base.join("/api/generate")?;
+
+        let request = self.http.post(url).json(&UnloadRequest {
+            model,
+            keep_alive: 0,
+        });
+
+        let res = self
+            .http
+            .send(request)
+            .await
+            .map_err(|e| Error::RequestFailed(e.into()))?;
+
+        Self::maybe_parse_api_error(res).await?;
+
+        Ok(())
+    }
+
+    /// Generates the text between a prompt and a suffix. This is only
+    /// supported by models trained for infilling.
+    pub(super) async fn generate_infill(
+        &self,
+        model: &str,
+        prompt: &str,
+        suffix: &str,
+        keep_alive: Option<i64>,
+    ) -> Result<String, Error> {
+        let url = self.api_base.join("/api/generate")?;
+
+        let request = self.http.post(url).json(&GenerateRequest {
+            model,
+            prompt,
+            suffix,
+            stream: false,
+            keep_alive,
+        });
+
+        let res = self
+            .http
+            .send(request)
+            .await
+            .map_err(|e| Error::RequestFailed(e.into()))?;
+
+        let res = Self::maybe_parse_api_error(res).await?;
+
+        let generated: GenerateResponse = res
+            .json()
+            .await
+            .map_err(|e| Error::RequestFailed(e.into()))?;
+
+        Ok(generated.response)
+    }
+
     pub(super) async fn chat(
         &self,
         model: &str,
         messages: &[ChatMessage],
+        options: &ChatOptions,
     ) -> Result<StreamingChatResponse<impl Stream<Item = reqwest::Result<bytes::Bytes>>>, Error>
     {
         let url = self.api_base.join("/api/chat")?;
 
-        let res = Client::new()
-            .post(url)
-            .json(&ChatRequest { messages, model })
-            .send()
+        let request = self.http.post(url).json(&ChatRequest {
+            messages,
+            model,
+            options,
+            stream: true,
+        });
+
+        let res = self
+            .http
+            .send(request)
             .await
             .map_err(|e| Error::RequestFailed(e.into()))?;
 
         let res = Self::maybe_parse_api_error(res).await?;
 
-        let stream = res.stream_ndjson();
+        let stream = res.stream_ndjson().into_stream();
 
         Ok(StreamingChatResponse { stream })
     }
+
+    /// Requests a chat completion without streaming it. The response has the
+    /// shape of the final chunk of a stream, with the message in full.
+    pub(super) async fn chat_completion(
+        &self,
+        model: &str,
+        messages: &[ChatMessage],
+        options: &ChatOptions,
+    ) -> Result<StreamingChatDelta, Error> {
+        let url = self.api_base.join("/api/chat")?;
+
+        let request = self.http.post(url).json(&ChatRequest {
+            messages,
+            model,
+            options,
+            stream: false,
+        });
+
+        let res = self
+            .http
+            .send(request)
+            .await
+            .map_err(|e| Error::RequestFailed(e.into()))?;
+
+        let res = Self::maybe_parse_api_error(res).await?;
+
+        res.json().await.map_err(|e| Error::RequestFailed(e.into()))
+    }
 }
 
 // Must have gemma:2b
@@ -280,9 +530,12 @@ mod tests {
         let messages = [ChatMessage {
             role: Role::User,
             content: "Hello!".to_string(),
+            images: Vec::new(),
         }];
 
-        let stream = api.chat("_nonexistent_", &messages).await;
+        let stream = api
+            .chat("_nonexistent_", &messages, &ChatOptions::default())
+            .await;
 
         assert!(stream.is_err());
 
@@ -298,9 +551,13 @@ mod tests {
         let messages = [ChatMessage {
             role: Role::User,
             content: "Hello!".to_string(),
+            images: Vec::new(),
         }];
 
-        let mut res_stream = api.chat("gemma:2b", &messages).await.unwrap();
+        let mut res_stream = api
+            .chat("gemma:2b", &messages, &ChatOptions::default())
+            .await
+            .unwrap();
 
         let mut first: Option<StreamingChatDelta> = None;
         let mut last: Option<StreamingChatDelta> = None;
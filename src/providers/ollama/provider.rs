@@ -3,10 +3,15 @@ use bytes::Bytes;
 use futures_core::Stream;
 use reqwest::IntoUrl;
 
+use futures_util::future::join_all;
+use tracing::debug;
+
 use super::api;
 use crate::providers::{
-    providers::ProviderIdentifier, AsyncMessageIterator, ChatProvider, ContextManagement, Error,
-    ErrorKind, FinishReason, Message, MessageDelta, Model, Role, Usage,
+    providers::ProviderIdentifier, AsyncMessageIterator, ChatProvider, Completion,
+    CompletionOption, CompletionOptions, ContextManagement, Error, ErrorKind, FinishReason,
+    HeartbeatHook, HttpClient, KeepAlive, LoadedModel, Message, MessageDelta, Model, ModelDetails,
+    ResponseFormat, Role, Usage,
 };
 
 impl From<api::Role> for Role {
@@ -39,11 +44,36 @@ impl From<api::DoneReason> for FinishReason {
     }
 }
 
+impl From<api::Details> for ModelDetails {
+    fn from(value: api::Details) -> Self {
+        // Fields which are unknown are reported as empty strings
+        let known = |field: String| (!field.is_empty()).then_some(field);
+
+        ModelDetails {
+            parameter_size: known(value.parameter_size),
+            family: known(value.family),
+            quantization: known(value.quantization_level),
+        }
+    }
+}
+
 impl From<api::Tag> for Model {
     fn from(value: api::Tag) -> Self {
         Model {
             id: value.name,
             context_length: None,
+            details: value.details.into(),
+        }
+    }
+}
+
+impl From<api::ProcessModel> for LoadedModel {
+    fn from(value: api::ProcessModel) -> Self {
+        LoadedModel {
+            id: value.name,
+            size: value.size,
+            size_vram: value.size_vram,
+            expires_at: Some(value.expires_at),
         }
     }
 }
@@ -53,6 +83,8 @@ impl From<api::StreamingChatDelta> for MessageDelta {
         MessageDelta {
             role: value.message.role.into(),
             content: value.message.content,
+            citations: Vec::new(),
+            files: Vec::new(),
         }
     }
 }
@@ -61,6 +93,7 @@ impl From<api::Error> for Error {
     fn from(value: api::Error) -> Self {
         let kind = match &value {
             api::Error::InternalError(_) => Some(ErrorKind::InternalError),
+            api::Error::Overloaded(_) => Some(ErrorKind::ApiOverloaded),
             api::Error::InvalidApiBase(_) | api::Error::InvalidEndpoint(_) => {
                 Some(ErrorKind::Connection)
             }
@@ -80,19 +113,53 @@ impl From<api::Error> for Error {
 
 pub(crate) struct OllamaProvider {
     api: api::OllamaApi,
+    keep_alive: Option<KeepAlive>,
 }
 
 impl OllamaProvider {
     pub(crate) fn with_api_base<U: IntoUrl>(api_base: U) -> Result<OllamaProvider, Error> {
         Ok(OllamaProvider {
             api: api::OllamaApi::with_api_base(api_base)?,
+            keep_alive: None,
         })
     }
 
     pub(crate) fn new() -> OllamaProvider {
         OllamaProvider {
             api: api::OllamaApi::new(),
+            keep_alive: None,
+        }
+    }
+
+    /// Sets how long models stay loaded after requests which do not specify
+    /// it. Otherwise, the server's default is used.
+    pub(crate) fn with_keep_alive(mut self, keep_alive: Option<KeepAlive>) -> OllamaProvider {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Checks that the server responds, without querying each model
+    pub(crate) async fn probe(&self) -> Result<(), Error> {
+        self.api.tags().await?;
+
+        Ok(())
+    }
+
+    /// Fills in the context length of the model, which is only reported
+    /// when the model is shown
+    async fn with_context_length(&self, mut model: Model) -> Model {
+        match self.api.show(&model.id).await {
+            Ok(shown) => model.context_length = shown.context_length(),
+            Err(err) => debug!(model = model.id, error = %err, "failed to show the model"),
         }
+
+        model
+    }
+
+    /// Sends requests through the client's middlewares
+    pub(crate) fn with_http_client(mut self, http: HttpClient) -> OllamaProvider {
+        self.api.set_http_client(http);
+        self
     }
 }
 
@@ -131,6 +198,8 @@ impl<S: Stream<Item = reqwest::Result<Bytes>> + Unpin + Send> AsyncMessageIterat
                     Some(Ok(MessageDelta {
                         role: msg.message.role.into(),
                         content: msg.message.content,
+                        citations: Vec::new(),
+                        files: Vec::new(),
                     }))
                 }
             }
@@ -145,6 +214,9 @@ impl<S: Stream<Item = reqwest::Result<Bytes>> + Unpin + Send> AsyncMessageIterat
     fn usage(&self) -> &Usage {
         self.usage.as_ref().unwrap()
     }
+
+    // Ollama streams newline-delimited JSON, which has no heartbeats
+    fn on_heartbeat(&mut self, _hook: HeartbeatHook) {}
 }
 
 #[async_trait]
@@ -157,6 +229,14 @@ impl ChatProvider for OllamaProvider {
         ContextManagement::Implicit
     }
 
+    fn honors_seed(&self) -> bool {
+        true
+    }
+
+    fn supported_options(&self, _model: &str) -> &'static [CompletionOption] {
+        CompletionOption::ALL
+    }
+
     async fn default_model(&self) -> Result<Option<Model>, Error> {
         Ok(None)
     }
@@ -164,25 +244,52 @@ impl ChatProvider for OllamaProvider {
     async fn models(&self) -> Result<Vec<Model>, Error> {
         let tags = self.api.tags().await?;
 
-        let models: Vec<Model> = tags.into_iter().map(|t| t.into()).collect();
+        let models = tags
+            .into_iter()
+            .map(|tag| self.with_context_length(tag.into()));
+
+        Ok(join_all(models).await)
+    }
+
+    async fn loaded_models(&self) -> Result<Vec<LoadedModel>, Error> {
+        let processes = self.api.ps().await?;
+
+        let models: Vec<LoadedModel> = processes.into_iter().map(|p| p.into()).collect();
 
         Ok(models)
     }
 
+    async fn unload_model(&self, model: &str) -> Result<(), Error> {
+        self.api.unload(model).await?;
+
+        Ok(())
+    }
+
+    async fn fill_in_middle(
+        &self,
+        model: &str,
+        prefix: &str,
+        suffix: &str,
+    ) -> Result<String, Error> {
+        let keep_alive = self.keep_alive.map(keep_alive_secs);
+
+        let infill = self
+            .api
+            .generate_infill(model, prefix, suffix, keep_alive)
+            .await?;
+
+        Ok(infill)
+    }
+
     async fn stream_completion(
         &self,
         model: &str,
         messages: &[Message],
+        options: &CompletionOptions,
     ) -> Result<Box<dyn AsyncMessageIterator>, Error> {
-        let messages: Vec<api::ChatMessage> = messages
-            .iter()
-            .map(|m| api::ChatMessage {
-                role: m.role.clone().into(),
-                content: m.content.clone(),
-            })
-            .collect();
+        let (messages, options) = request(messages, options, self.keep_alive);
 
-        let completion = self.api.chat(model, &messages).await?;
+        let completion = self.api.chat(model, &messages, &options).await?;
 
         Ok(Box::new(OllamaCompletionResponse {
             inner: completion,
@@ -190,4 +297,73 @@ impl ChatProvider for OllamaProvider {
             usage: None,
         }))
     }
+
+    async fn completion(
+        &self,
+        model: &str,
+        messages: &[Message],
+        options: &CompletionOptions,
+    ) -> Result<Completion, Error> {
+        let (messages, options) = request(messages, options, self.keep_alive);
+
+        let completion = self.api.chat_completion(model, &messages, &options).await?;
+
+        Ok(Completion {
+            finish_reason: completion.done_reason.into(),
+            usage: Usage {
+                prompt_tokens: completion.prompt_eval_count,
+                completion_tokens: completion.eval_count,
+            },
+            message: MessageDelta {
+                role: completion.message.role.into(),
+                content: completion.message.content,
+                citations: Vec::new(),
+                files: Vec::new(),
+            },
+        })
+    }
+}
+
+/// Ollama keeps models loaded indefinitely when the keep-alive is negative
+fn keep_alive_secs(keep_alive: KeepAlive) -> i64 {
+    match keep_alive {
+        KeepAlive::For(duration) => duration.as_secs().try_into().unwrap_or(i64::MAX),
+        KeepAlive::Forever => -1,
+    }
+}
+
+/// Translates the conversation and options into a chat request
+fn request(
+    messages: &[Message],
+    options: &CompletionOptions,
+    keep_alive: Option<KeepAlive>,
+) -> (Vec<api::ChatMessage>, api::ChatOptions) {
+    let messages: Vec<api::ChatMessage> = messages
+        .iter()
+        .map(|m| api::ChatMessage {
+            role: m.role.clone().into(),
+            content: m.content.clone(),
+            images: m.images.iter().map(|image| image.data.clone()).collect(),
+        })
+        .collect();
+
+    let options = api::ChatOptions {
+        format: match options.response_format {
+            ResponseFormat::Text => None,
+            ResponseFormat::Json => Some(api::Format::Json),
+        },
+        options: (options.temperature.is_some()
+            || options.max_tokens.is_some()
+            || options.seed.is_some()
+            || !options.stop.is_empty())
+        .then_some(api::ModelOptions {
+            temperature: options.temperature,
+            num_predict: options.max_tokens,
+            seed: options.seed,
+            stop: (!options.stop.is_empty()).then(|| options.stop.clone()),
+        }),
+        keep_alive: options.keep_alive.or(keep_alive).map(keep_alive_secs),
+    };
+
+    (messages, options)
 }
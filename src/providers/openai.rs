@@ -4,4 +4,4 @@ mod api;
 mod models;
 mod provider;
 
-pub(crate) use self::provider::OpenAIProvider;
+pub(crate) use self::provider::{AzureOpenAIProvider, OpenAIProvider};
@@ -1,10 +1,14 @@
 use bytes::Bytes;
 use futures_core::Stream;
-use reqwest::{Client, IntoUrl};
+use reqwest::{IntoUrl, RequestBuilder, StatusCode};
 use serde::{Deserialize, Serialize};
+use tracing::trace;
 
 use crate::providers::apireq;
-use crate::providers::apireq::{JsonStreamParser, ReqwestResponseStreamExt, Url};
+use crate::providers::apireq::{
+    HttpClient, JsonStreamParser, ReqwestResponseStreamExt, Url, MESSAGE_EVENT,
+};
+use crate::providers::HeartbeatHook;
 
 #[derive(thiserror::Error, Debug)]
 pub(super) enum Error {
@@ -94,10 +98,53 @@ impl Error {
             429 => Error::RateLimit(payload),
             500 => Error::InternalError(payload),
             503 => Error::ApiOverloaded(payload),
-            400..=599 => Error::UnknownStatus(payload),
-            _ => unimplemented!("unknown error code for OpenAI API"),
+            _ => Error::UnknownStatus(payload),
         }
     }
+
+    /// Reads the error in the body of a response with an unsuccessful
+    /// status. Gateways in front of the API may respond with a body which is
+    /// not an error of the API, such as an HTML page, which is reported with
+    /// the status as it is.
+    fn from_body(status: StatusCode, body: &str) -> Error {
+        let payload = match serde_json::from_str::<ApiErrorResponse>(body) {
+            Ok(response) => response.error,
+            Err(_) => {
+                let body = body.trim();
+
+                let mut message = format!("the API responded with {}", status);
+
+                if !body.is_empty() {
+                    let excerpt: String = body.chars().take(MAX_ERROR_BODY_CHARS).collect();
+
+                    message.push_str(&format!(": {}", excerpt));
+
+                    if excerpt.len() < body.len() {
+                        message.push('…');
+                    }
+                }
+
+                ApiErrorPayload {
+                    message,
+                    typ: "unknown".to_string(),
+                    code: None,
+                }
+            }
+        };
+
+        Error::from_status(status.as_u16(), payload)
+    }
+}
+
+/// The most characters of an unexpected error body which are reported
+const MAX_ERROR_BODY_CHARS: usize = 200;
+
+/// Reads the error returned with an unsuccessful status
+async fn error_response(status: StatusCode, res: reqwest::Response) -> Error {
+    match res.text().await {
+        Ok(body) => Error::from_body(status, &body),
+        Err(err) => Error::RequestFailed(err.into()),
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
@@ -111,34 +158,68 @@ pub(super) enum Role {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub(super) struct ChatMessage {
-    pub content: String,
+    pub content: Content,
     pub role: Role,
 }
 
+/// The content of a message is either text or, for messages with images,
+/// a list of parts
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub(super) enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(super) enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct ImageUrl {
+    /// A URL or a data URL containing the image
+    pub url: String,
+}
+
 /* Structures to serialize /chat/completions */
 
 #[derive(Serialize, Debug)]
-struct ChatCompletionOptions {
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(super) enum ResponseFormat {
+    JsonObject,
+}
+
+#[derive(Serialize, Debug)]
+pub(super) struct ChatCompletionOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    temperature: Option<f64>,
+    pub n: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    top_p: Option<f64>,
+    pub stop: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    n: Option<u32>,
+    pub max_tokens: Option<u32>,
+    /// Replaces `max_tokens` for reasoning models, counting the reasoning
+    /// tokens along with the response
     #[serde(skip_serializing_if = "Option::is_none")]
-    stop: Option<String>,
+    pub max_completion_tokens: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    max_tokens: Option<u32>,
+    pub seed: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    seed: Option<i32>,
+    pub presence_penalty: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    presence_penalty: Option<f64>,
+    pub frequency_penalty: Option<f64>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    frequency_penalty: Option<f64>,
+    pub logit_bias: Option<std::collections::HashMap<String, f64>>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    logit_bias: Option<std::collections::HashMap<String, f64>>,
+    pub user: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    user: Option<String>,
+    pub response_format: Option<ResponseFormat>,
 }
 
 #[derive(Serialize, Debug)]
@@ -153,7 +234,8 @@ struct ChatCompletionRequest<'o> {
     #[serde(flatten)]
     options: &'o ChatCompletionOptions,
     stream: bool,
-    stream_options: StreamOptions,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
 }
 
 impl Default for ChatCompletionOptions {
@@ -164,11 +246,13 @@ impl Default for ChatCompletionOptions {
             n: None,
             stop: None,
             max_tokens: None,
+            max_completion_tokens: None,
             seed: None,
             presence_penalty: None,
             frequency_penalty: None,
             logit_bias: None,
             user: None,
+            response_format: None,
         }
     }
 }
@@ -185,11 +269,49 @@ pub(super) enum FinishReason {
     ContentFilter,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct UrlCitation {
+    pub url: String,
+    #[serde(default)]
+    pub title: Option<String>,
+    pub start_index: usize,
+    pub end_index: usize,
+}
+
+/// Annotations are attached to messages generated with the web search tool
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(super) enum Annotation {
+    UrlCitation {
+        url_citation: UrlCitation,
+    },
+    /// Annotations of other types, which the API may add, are ignored
+    #[serde(other)]
+    Unknown,
+}
+
+/// OpenAI does not generate images in chat completions, but some compatible
+/// gateways (e.g., OpenRouter) send them alongside the message
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(super) enum GeneratedImage {
+    ImageUrl {
+        image_url: ImageUrl,
+    },
+    /// Images of other types are skipped
+    #[serde(other)]
+    Unknown,
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub(super) struct Delta {
     pub role: Option<Role>,
     #[serde(default)]
     pub content: String,
+    #[serde(default)]
+    pub annotations: Vec<Annotation>,
+    #[serde(default)]
+    pub images: Vec<GeneratedImage>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -214,6 +336,59 @@ pub(super) struct ChatCompletionChunk {
     pub model: String,
     pub choices: Vec<Choice>,
     pub usage: Option<Usage>,
+    /// Perplexity lists the sources it cites by URL, in the order of the
+    /// markers (e.g., "[1]") in the content, with every chunk
+    #[serde(default)]
+    pub citations: Vec<String>,
+}
+
+/* Structures to deseralize non-streamed /chat/completions */
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct ResponseMessage {
+    pub role: Role,
+    /// The content is null if the model only called tools
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default)]
+    pub annotations: Vec<Annotation>,
+    #[serde(default)]
+    pub images: Vec<GeneratedImage>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct CompletionChoice {
+    pub index: u32,
+    pub message: ResponseMessage,
+    pub finish_reason: FinishReason,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct ChatCompletion {
+    pub id: String,
+    pub object: String,
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<CompletionChoice>,
+    pub usage: Usage,
+    /// Perplexity lists the sources it cites by URL
+    #[serde(default)]
+    pub citations: Vec<String>,
+}
+
+/* Structures to deseralize /models */
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct ApiModel {
+    pub id: String,
+    pub object: String,
+    pub created: u64,
+    pub owned_by: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ModelList {
+    data: Vec<ApiModel>,
 }
 
 /* API Errors */
@@ -223,6 +398,15 @@ pub(super) struct ApiErrorPayload {
     message: String,
     #[serde(rename = "type")]
     typ: String,
+    #[serde(default)]
+    code: Option<String>,
+}
+
+impl ApiErrorPayload {
+    /// The request did not fit in the context of the model
+    pub(super) fn is_context_exceeded(&self) -> bool {
+        self.code.as_deref() == Some("context_length_exceeded")
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -239,17 +423,54 @@ where
 
 impl<S: Stream<Item = reqwest::Result<Bytes>> + Unpin> StreamingChatResponse<S> {
     pub(super) async fn next(&mut self) -> Option<Result<ChatCompletionChunk, Error>> {
-        let delta = self.stream.parse::<ChatCompletionChunk>().await;
+        loop {
+            let event = match self.stream.next_event().await? {
+                Ok(event) => event,
+                Err(err) => return Some(Err(err.into())),
+            };
+
+            match event.event {
+                MESSAGE_EVENT => return Some(event.decode().map_err(|e| e.into())),
+                // Some compatible servers report failures mid-stream as error events
+                "error" => {
+                    return Some(match event.decode::<ApiErrorResponse>() {
+                        Ok(res) => Err(Error::UnknownStatus(res.error)),
+                        Err(err) => Err(err.into()),
+                    })
+                }
+                _ => trace!(
+                    event = event.event,
+                    id = ?event.id,
+                    retry = ?event.retry,
+                    "skipped event"
+                ),
+            }
+        }
+    }
 
-        delta.map(|e| e.map_err(|e| e.into()))
+    pub(super) fn on_heartbeat(&mut self, hook: HeartbeatHook) {
+        self.stream.on_heartbeat(hook);
     }
 }
 
 const DEFAULT_API_BASE: &'static str = "https://api.openai.com";
 
+/// The scheme used to route and authenticate requests
+enum Service {
+    /// The OpenAI platform: the model is named in the request body and the
+    /// API key is sent as a bearer token
+    OpenAI,
+    /// An Azure OpenAI resource: the model is a deployment named in the URL,
+    /// each request carries an `api-version` query parameter, and the API key
+    /// is sent in an `api-key` header
+    Azure { api_version: String },
+}
+
 pub(super) struct OpenAIApi {
     api_base: Url,
     api_key: String,
+    service: Service,
+    http: HttpClient,
 }
 
 impl OpenAIApi {
@@ -259,52 +480,155 @@ impl OpenAIApi {
         Ok(OpenAIApi {
             api_base,
             api_key: api_key.to_string(),
+            service: Service::OpenAI,
+            http: HttpClient::default(),
+        })
+    }
+
+    /// Creates a client for an Azure OpenAI resource, where `endpoint` is the
+    /// base URL of the resource (e.g., `https://NAME.openai.azure.com`)
+    pub(super) fn azure<U: IntoUrl>(
+        api_key: &str,
+        endpoint: U,
+        api_version: &str,
+    ) -> Result<OpenAIApi, Error> {
+        let api_base = endpoint.into_url().map_err(Error::InvalidApiBase)?;
+
+        Ok(OpenAIApi {
+            api_base,
+            api_key: api_key.to_string(),
+            service: Service::Azure {
+                api_version: api_version.to_string(),
+            },
+            http: HttpClient::default(),
         })
     }
 
+    /// Sends requests through the client's middlewares
+    pub(super) fn set_http_client(&mut self, http: HttpClient) {
+        self.http = http;
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match self.service {
+            Service::OpenAI => request.bearer_auth(&self.api_key),
+            Service::Azure { .. } => request.header("api-key", &self.api_key),
+        }
+    }
+
+    fn chat_completions_url(&self, model: &str) -> Result<Url, Error> {
+        match &self.service {
+            Service::OpenAI => Ok(self.api_base.join("/v1/chat/completions")?),
+            Service::Azure { api_version } => {
+                let mut url = self
+                    .api_base
+                    .join(&format!("/openai/deployments/{}/chat/completions", model))?;
+
+                url.query_pairs_mut()
+                    .append_pair("api-version", api_version);
+
+                Ok(url)
+            }
+        }
+    }
+
     pub(super) fn with_api_key(api_key: &str) -> OpenAIApi {
         Self::new(api_key, DEFAULT_API_BASE).unwrap()
     }
 
+    pub(super) async fn models(&self) -> Result<Vec<ApiModel>, Error> {
+        let url = self.api_base.join("/v1/models")?;
+
+        let request = self.authorize(self.http.get(url));
+
+        let res = self
+            .http
+            .send(request)
+            .await
+            .map_err(|e| Error::RequestFailed(e.into()))?;
+
+        let status = res.status();
+
+        if status.is_success() {
+            let models: ModelList = res
+                .json()
+                .await
+                .map_err(|e| Error::RequestFailed(e.into()))?;
+
+            Ok(models.data)
+        } else {
+            Err(error_response(status, res).await)
+        }
+    }
+
     pub(super) async fn streaming_chat_completion(
         &self,
         model: &str,
         messages: &[ChatMessage],
+        options: &ChatCompletionOptions,
     ) -> Result<StreamingChatResponse<impl Stream<Item = reqwest::Result<bytes::Bytes>>>, Error>
     {
-        let url = self.api_base.join("/v1/chat/completions")?;
-
-        let options = ChatCompletionOptions::default();
+        let url = self.chat_completions_url(model)?;
 
-        let res = Client::new()
-            .post(url)
-            .bearer_auth(&self.api_key)
+        let request = self
+            .authorize(self.http.post(url))
             .json(&ChatCompletionRequest {
                 model,
                 messages,
-                options: &options,
+                options,
                 stream: true,
-                stream_options: StreamOptions {
+                stream_options: Some(StreamOptions {
                     include_usage: true,
-                },
-            })
-            .send()
+                }),
+            });
+
+        let res = self
+            .http
+            .send(request)
             .await
             .map_err(|e| Error::RequestFailed(e.into()))?;
 
         let status = res.status();
 
         if status.is_success() {
-            let res = res.stream_lsse();
+            let res = res.stream_sse();
 
             Ok(StreamingChatResponse { stream: res })
         } else {
-            let err: ApiErrorResponse = res
-                .json()
-                .await
-                .expect("failed to deseralize an error message from the OpenAI API");
+            Err(error_response(status, res).await)
+        }
+    }
+
+    pub(super) async fn chat_completion(
+        &self,
+        model: &str,
+        messages: &[ChatMessage],
+        options: &ChatCompletionOptions,
+    ) -> Result<ChatCompletion, Error> {
+        let url = self.chat_completions_url(model)?;
+
+        let request = self
+            .authorize(self.http.post(url))
+            .json(&ChatCompletionRequest {
+                model,
+                messages,
+                options,
+                stream: false,
+                stream_options: None,
+            });
+
+        let res = self
+            .http
+            .send(request)
+            .await
+            .map_err(|e| Error::RequestFailed(e.into()))?;
 
-            Err(Error::from_status(status.as_u16(), err.error))
+        let status = res.status();
+
+        if status.is_success() {
+            res.json().await.map_err(|e| Error::RequestFailed(e.into()))
+        } else {
+            Err(error_response(status, res).await)
         }
     }
 }
@@ -324,12 +648,12 @@ mod tests {
         let api = OpenAIApi::with_api_key(&api_key);
 
         let messages = [ChatMessage {
-            content: "Hello".to_string(),
+            content: Content::Text("Hello".to_string()),
             role: Role::User,
         }];
 
         let mut iterator = api
-            .streaming_chat_completion("gpt-4o-mini", &messages)
+            .streaming_chat_completion("gpt-4o-mini", &messages, &ChatCompletionOptions::default())
             .await
             .expect("failed to stream response");
 
@@ -396,12 +720,16 @@ mod tests {
         let api = OpenAIApi::with_api_key(&api_key);
 
         let messages = [ChatMessage {
-            content: "Hello".to_string(),
+            content: Content::Text("Hello".to_string()),
             role: Role::User,
         }];
 
         let it = api
-            .streaming_chat_completion("__model_does_not_exist__", &messages)
+            .streaming_chat_completion(
+                "__model_does_not_exist__",
+                &messages,
+                &ChatCompletionOptions::default(),
+            )
             .await;
 
         assert!(matches!(it, Err(Error::NotFound(_))));
@@ -412,14 +740,85 @@ mod tests {
         let api = OpenAIApi::with_api_key("not_a_valid_key");
 
         let messages = [ChatMessage {
-            content: "Hello".to_string(),
+            content: Content::Text("Hello".to_string()),
             role: Role::User,
         }];
 
         let it = api
-            .streaming_chat_completion("__model_does_not_exist__", &messages)
+            .streaming_chat_completion(
+                "__model_does_not_exist__",
+                &messages,
+                &ChatCompletionOptions::default(),
+            )
             .await;
 
         assert!(matches!(it, Err(Error::Authentication(_))));
     }
+
+    #[test]
+    fn test_azure_chat_completions_url() {
+        let api = OpenAIApi::azure("key", "https://example.openai.azure.com/", "2024-10-21")
+            .expect("failed to parse endpoint");
+
+        let url = api
+            .chat_completions_url("gpt-4o")
+            .expect("failed to build url");
+
+        assert_eq!(
+            url.as_str(),
+            "https://example.openai.azure.com/openai/deployments/gpt-4o/chat/completions?api-version=2024-10-21"
+        );
+    }
+
+    #[test]
+    fn test_unknown_image() {
+        let delta: Delta = serde_json::from_str(
+            r#"{"content": "", "images": [
+                {"type": "image_file", "image_file": {"file_id": "file-1"}},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,AA=="}}
+            ]}"#,
+        )
+        .expect("failed to deserialize the delta");
+
+        assert!(matches!(
+            delta.images[..],
+            [GeneratedImage::Unknown, GeneratedImage::ImageUrl { .. }]
+        ));
+    }
+
+    #[test]
+    fn test_unknown_annotation() {
+        let delta: Delta = serde_json::from_str(
+            r#"{"content": "", "annotations": [
+                {"type": "file_citation", "file_citation": {"file_id": "file-1"}},
+                {"type": "url_citation", "url_citation": {"url": "https://example.com", "start_index": 0, "end_index": 4}}
+            ]}"#,
+        )
+        .expect("failed to deserialize the delta");
+
+        assert!(matches!(
+            delta.annotations[..],
+            [Annotation::Unknown, Annotation::UrlCitation { .. }]
+        ));
+    }
+
+    #[test]
+    fn test_error_from_body() {
+        let err = Error::from_body(
+            StatusCode::UNAUTHORIZED,
+            r#"{"error": {"message": "Incorrect API key provided", "type": "invalid_request_error"}}"#,
+        );
+
+        assert!(matches!(err, Error::Authentication(_)));
+        assert_eq!(err.to_string(), "Incorrect API key provided");
+
+        // A gateway may respond with a page rather than an error of the API
+        let err = Error::from_body(StatusCode::BAD_GATEWAY, "<html>Bad Gateway</html>\n");
+
+        assert!(matches!(err, Error::UnknownStatus(_)));
+        assert_eq!(
+            err.to_string(),
+            "the API responded with 502 Bad Gateway: <html>Bad Gateway</html>"
+        );
+    }
 }
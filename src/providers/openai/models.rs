@@ -1,32 +1,38 @@
 use lazy_static::lazy_static;
 
-use crate::providers::Model;
+use crate::providers::{Model, ModelDetails};
 
 lazy_static! {
-    // The OpenAI API does not include an API route to list their active models. This
-    // limits release stability (since any of chat model could be deprecated and pulled.)
-    // It also means that this list needs to be updated whenever new models are added or
-    // the context length of a model changes.
+    // The OpenAI API lists the models available to an account, but it does not report
+    // their context lengths or distinguish chat models from other kinds of models. This
+    // list supplies the context lengths of the listed models, and it serves as the
+    // model list if the API cannot be reached. It needs to be updated whenever new
+    // models are added or the context length of a model changes.
     pub(super) static ref OPENAI_MODELS: [Model; 5] = [
         Model {
             id: "gpt-4o-mini".to_string(),
             context_length: Some(128000),
+            details: ModelDetails::default(),
         },
         Model {
             id: "gpt-4o".to_string(),
             context_length: Some(128000),
+            details: ModelDetails::default(),
         },
         Model {
             id: "gpt-4-turbo".to_string(),
             context_length: Some(128000),
+            details: ModelDetails::default(),
         },
         Model {
             id: "gpt-4".to_string(),
             context_length: Some(8192),
+            details: ModelDetails::default(),
         },
         Model {
             id: "gpt-3.5-turbo".to_string(),
-            context_length: Some(16385)
+            context_length: Some(16385),
+            details: ModelDetails::default(),
         },
     ];
 
@@ -34,3 +40,154 @@ lazy_static! {
     // This should default to the cheepest flagship model.
     pub(super) static ref DEFAULT_MODEL: &'static Model = &OPENAI_MODELS[0];
 }
+
+/// Returns true if the model is served through the chat completions API.
+/// Fine-tuned models are named after the model they were trained from
+/// (e.g., `ft:gpt-4o-mini-2024-07-18:org::id`).
+fn is_chat_model(id: &str) -> bool {
+    let base = id.strip_prefix("ft:").unwrap_or(id);
+
+    let is_family = ["gpt-", "chatgpt-", "o1", "o3", "o4"]
+        .iter()
+        .any(|family| base.starts_with(family));
+
+    // These share a family with chat models but are served by other APIs
+    let is_excluded = [
+        "instruct",
+        "realtime",
+        "audio",
+        "transcribe",
+        "tts",
+        "image",
+    ]
+    .iter()
+    .any(|kind| base.contains(kind));
+
+    is_family && !is_excluded
+}
+
+/// Looks up the context length of a model in the static list. Snapshots
+/// (e.g., `gpt-4o-2024-08-06`) and fine-tuned models inherit the context
+/// length of the model they are derived from.
+fn context_length(id: &str) -> Option<u64> {
+    let base = id.strip_prefix("ft:").unwrap_or(id);
+
+    OPENAI_MODELS
+        .iter()
+        .filter(|model| {
+            base == model.id
+                || base
+                    .strip_prefix(model.id.as_str())
+                    .and_then(|rest| rest.strip_prefix(['-', ':']))
+                    .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit()))
+        })
+        .max_by_key(|model| model.id.len())
+        .and_then(|model| model.context_length)
+}
+
+/// Returns false for chat models which only accept text. Models which are
+/// not known to be text-only are assumed to accept images, and the API
+/// rejects them if they do not.
+pub(super) fn accepts_images(id: &str) -> bool {
+    let base = id.strip_prefix("ft:").unwrap_or(id);
+
+    let is_text_only = ["gpt-3.5-turbo", "o1-mini", "o1-preview", "o3-mini"]
+        .iter()
+        .any(|family| base.starts_with(family));
+
+    // GPT-4 and its dated snapshots, but not GPT-4 Turbo, GPT-4o, or GPT-4.1
+    let is_gpt4 = base == "gpt-4"
+        || base
+            .strip_prefix("gpt-4-")
+            .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit()))
+            && !base.contains("vision");
+
+    !is_text_only && !is_gpt4
+}
+
+/// Returns true for reasoning models, which do not accept a temperature or
+/// stop sequences and limit the response with `max_completion_tokens`
+pub(super) fn is_reasoning_model(id: &str) -> bool {
+    let base = id.strip_prefix("ft:").unwrap_or(id);
+
+    let is_family = ["o1", "o3", "o4", "gpt-5"]
+        .iter()
+        .any(|family| base.starts_with(family));
+
+    // GPT-5 Chat is tuned for conversation rather than reasoning
+    is_family && !base.contains("chat")
+}
+
+/// Converts the models listed by the API into chat models, sorted by id
+pub(super) fn chat_models<I: IntoIterator<Item = String>>(ids: I) -> Vec<Model> {
+    let mut models: Vec<Model> = ids
+        .into_iter()
+        .filter(|id| is_chat_model(id))
+        .map(|id| Model {
+            context_length: context_length(&id),
+            details: ModelDetails::default(),
+            id,
+        })
+        .collect();
+
+    models.sort_by(|a, b| a.id.cmp(&b.id));
+
+    models
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accepts_images() {
+        assert!(accepts_images("gpt-4o-mini"));
+        assert!(accepts_images("gpt-4-turbo-2024-04-09"));
+        assert!(accepts_images("gpt-4.1"));
+        assert!(accepts_images("ft:gpt-4o-2024-08-06:acme::abc123"));
+        assert!(!accepts_images("gpt-4"));
+        assert!(!accepts_images("gpt-4-0613"));
+        assert!(!accepts_images("gpt-3.5-turbo"));
+        assert!(!accepts_images("o3-mini"));
+    }
+
+    #[test]
+    fn test_is_reasoning_model() {
+        assert!(is_reasoning_model("o1-mini"));
+        assert!(is_reasoning_model("o3-2025-04-16"));
+        assert!(is_reasoning_model("gpt-5-mini"));
+        assert!(!is_reasoning_model("gpt-5-chat-latest"));
+        assert!(!is_reasoning_model("gpt-4o"));
+    }
+
+    #[test]
+    fn test_chat_models() {
+        let ids = [
+            "gpt-4o-2024-08-06",
+            "text-embedding-3-small",
+            "gpt-3.5-turbo-instruct",
+            "ft:gpt-4o-mini-2024-07-18:acme::abc123",
+            "gpt-4",
+            "whisper-1",
+            "o1-mini",
+        ];
+
+        let models: Vec<(String, Option<u64>)> = chat_models(ids.map(String::from))
+            .into_iter()
+            .map(|model| (model.id, model.context_length))
+            .collect();
+
+        assert_eq!(
+            models,
+            vec![
+                (
+                    "ft:gpt-4o-mini-2024-07-18:acme::abc123".to_string(),
+                    Some(128000)
+                ),
+                ("gpt-4".to_string(), Some(8192)),
+                ("gpt-4o-2024-08-06".to_string(), Some(128000)),
+                ("o1-mini".to_string(), None),
+            ]
+        );
+    }
+}
@@ -1,20 +1,33 @@
+use std::time::Duration;
+
 use async_trait::async_trait;
+use base64::prelude::{Engine, BASE64_STANDARD};
 use bytes::Bytes;
 use futures_core::Stream;
 use reqwest::IntoUrl;
+use tokio::sync::OnceCell;
+use tracing::debug;
 
 use crate::chat::{Message, Role};
-use crate::providers::openai::models::{DEFAULT_MODEL, OPENAI_MODELS};
+use crate::providers::openai::models::{
+    accepts_images, chat_models, is_reasoning_model, DEFAULT_MODEL, OPENAI_MODELS,
+};
 use crate::providers::{
     openai::api, providers::ProviderIdentifier, ChatProvider, Error, ErrorKind, Model,
 };
 use crate::providers::{
-    AsyncMessageIterator, ContextManagement, FinishReason, MessageDelta, Usage,
+    AsyncMessageIterator, Citation, Completion, CompletionOption, CompletionOptions,
+    ContextManagement, FinishReason, GeneratedFile, HeartbeatHook, HttpClient, LoadedModel,
+    MessageDelta, ResponseFormat, Usage,
 };
+use crate::warn;
 
 impl From<api::Error> for Error {
     fn from(value: api::Error) -> Self {
         let kind = match &value {
+            api::Error::BadRequest(payload) if payload.is_context_exceeded() => {
+                Some(ErrorKind::ContextExceeded)
+            }
             api::Error::Authentication(_) | api::Error::PermissionDenied(_) => {
                 Some(ErrorKind::Authentication)
             }
@@ -42,22 +55,103 @@ impl From<api::Error> for Error {
     }
 }
 
+/// Images were sent to a model which only accepts text
+#[derive(thiserror::Error, Debug)]
+#[error("{0} does not accept images")]
+struct ImagesNotAccepted(String);
+
+/// A completion was returned without any choices
+#[derive(thiserror::Error, Debug)]
+#[error("the completion has no choices")]
+struct NoChoices;
+
 pub(crate) struct OpenAIProvider {
     api: api::OpenAIApi,
+    /// The chat models available to the account, listed once per process
+    models: OnceCell<Vec<Model>>,
 }
 
 impl OpenAIProvider {
     pub(crate) fn new<U: IntoUrl>(api_key: &str, api_base: U) -> Result<OpenAIProvider, Error> {
         Ok(OpenAIProvider {
             api: api::OpenAIApi::new(api_key, api_base)?,
+            models: OnceCell::new(),
         })
     }
 
     pub(crate) fn with_api_key(api_key: &str) -> OpenAIProvider {
         OpenAIProvider {
             api: api::OpenAIApi::with_api_key(api_key),
+            models: OnceCell::new(),
+        }
+    }
+
+    /// Sends requests through the client's middlewares
+    pub(crate) fn with_http_client(mut self, http: HttpClient) -> OpenAIProvider {
+        self.api.set_http_client(http);
+        self
+    }
+
+    /// The models known to be served by OpenAI, which can be listed without
+    /// an API key
+    pub(crate) fn known_models() -> Vec<Model> {
+        OPENAI_MODELS.to_vec()
+    }
+
+    /// Lists the chat models available to the account, falling back to the
+    /// static list of models if the API cannot be queried
+    async fn discover_models(&self) -> Vec<Model> {
+        match self.api.models().await {
+            Ok(models) => chat_models(models.into_iter().map(|model| model.id)),
+            Err(err) => {
+                let err: Error = err.into();
+
+                warn!(
+                    "failed to list the OpenAI models, falling back to the known models: {}",
+                    err
+                );
+
+                OPENAI_MODELS.to_vec()
+            }
         }
     }
+
+    /// Issues a lightweight authenticated request to verify that the API
+    /// key is accepted. If the API does not respond within `timeout`, an
+    /// error of kind [`ErrorKind::TimedOut`] is returned.
+    pub(crate) async fn probe(&self, timeout: Duration) -> Result<(), Error> {
+        match tokio::time::timeout(timeout, self.api.models()).await {
+            Ok(models) => models.map(|_| ()).map_err(|e| e.into()),
+            Err(_) => Err(Error::from_kind(ErrorKind::TimedOut)),
+        }
+    }
+}
+
+/// A provider serving models deployed to an Azure OpenAI resource. Models
+/// are referred to by their deployment names.
+pub(crate) struct AzureOpenAIProvider {
+    api: api::OpenAIApi,
+    deployment: String,
+}
+
+impl AzureOpenAIProvider {
+    pub(crate) fn new<U: IntoUrl>(
+        api_key: &str,
+        endpoint: U,
+        deployment: &str,
+        api_version: &str,
+    ) -> Result<AzureOpenAIProvider, Error> {
+        Ok(AzureOpenAIProvider {
+            api: api::OpenAIApi::azure(api_key, endpoint, api_version)?,
+            deployment: deployment.to_string(),
+        })
+    }
+
+    /// Sends requests through the client's middlewares
+    pub(crate) fn with_http_client(mut self, http: HttpClient) -> AzureOpenAIProvider {
+        self.api.set_http_client(http);
+        self
+    }
 }
 
 impl From<api::FinishReason> for FinishReason {
@@ -81,6 +175,99 @@ impl From<api::Role> for Role {
     }
 }
 
+/// The sources cited by a message, whether they are annotations of the
+/// content (OpenAI) or listed alongside it (Perplexity). Sources which are
+/// only listed have no position, since the content already marks them.
+fn citations(annotations: Vec<api::Annotation>, urls: Vec<String>) -> Vec<Citation> {
+    let listed = urls.into_iter().map(|url| Citation {
+        url,
+        title: None,
+        end_index: None,
+    });
+
+    annotations
+        .into_iter()
+        .filter_map(citation)
+        .chain(listed)
+        .collect()
+}
+
+/// Converts an annotation to a citation, skipping annotations which are not
+/// citations
+fn citation(annotation: api::Annotation) -> Option<Citation> {
+    match annotation {
+        api::Annotation::UrlCitation { url_citation } => Some(Citation {
+            url: url_citation.url,
+            title: url_citation.title,
+            end_index: Some(url_citation.end_index),
+        }),
+        api::Annotation::Unknown => {
+            debug!("skipping an annotation of an unknown type");
+            None
+        }
+    }
+}
+
+/// Decodes an image generated by the model. Only images sent inline as data
+/// URLs are kept; the gateways which generate images send them this way.
+fn generated_file(image: api::GeneratedImage) -> Option<GeneratedFile> {
+    let api::GeneratedImage::ImageUrl { image_url } = image else {
+        warn!("skipping a generated image of an unknown type");
+        return None;
+    };
+
+    let decoded = image_url
+        .url
+        .strip_prefix("data:")
+        .and_then(|url| url.split_once(";base64,"))
+        .and_then(|(media_type, data)| {
+            let data = BASE64_STANDARD.decode(data).ok()?;
+
+            Some(GeneratedFile {
+                media_type: media_type.to_string(),
+                data,
+            })
+        });
+
+    if decoded.is_none() {
+        debug!("skipping a generated image which is not a base64 data URL");
+    }
+
+    decoded
+}
+
+impl TryFrom<api::ChatCompletion> for Completion {
+    type Error = Error;
+
+    fn try_from(value: api::ChatCompletion) -> Result<Self, Self::Error> {
+        let Some(choice) = value.choices.into_iter().next() else {
+            return Err(Error::from_source(
+                ErrorKind::UnexpectedResponse,
+                Box::new(NoChoices),
+            ));
+        };
+
+        Ok(Completion {
+            message: MessageDelta {
+                role: choice.message.role.into(),
+                content: choice.message.content.unwrap_or_default(),
+                citations: citations(choice.message.annotations, value.citations),
+                files: choice
+                    .message
+                    .images
+                    .into_iter()
+                    .filter_map(generated_file)
+                    .collect(),
+            },
+            finish_reason: choice.finish_reason.into(),
+            usage: Usage {
+                prompt_tokens: Some(value.usage.prompt_tokens),
+                completion_tokens: Some(value.usage.completion_tokens),
+            },
+        })
+    }
+}
+
 pub(crate) struct OpenAICompletionResponse<S>
 where
     S: Stream<Item = reqwest::Result<Bytes>> + Unpin,
@@ -89,6 +276,9 @@ where
     role: Option<Role>,
     finish_reason: Option<FinishReason>,
     usage: Option<Usage>,
+    /// Whether the sources listed with each chunk have been reported, so
+    /// they are reported only once
+    listed_citations: bool,
 }
 
 impl<S: Stream<Item = reqwest::Result<Bytes>> + Unpin + Send> OpenAICompletionResponse<S> {
@@ -98,6 +288,7 @@ impl<S: Stream<Item = reqwest::Result<Bytes>> + Unpin + Send> OpenAICompletionRe
             role: None,
             finish_reason: None,
             usage: None,
+            listed_citations: false,
         }
     }
 }
@@ -144,9 +335,27 @@ impl<S: Stream<Item = reqwest::Result<Bytes>> + Unpin + Send> AsyncMessageIterat
                             self.role = Some(role.into());
                         }
 
+                        let listed = if self.listed_citations {
+                            Vec::new()
+                        } else {
+                            self.listed_citations = !chunk.citations.is_empty();
+                            std::mem::take(&mut chunk.citations)
+                        };
+
+                        let citations = citations(choice.delta.annotations, listed);
+
+                        let files = choice
+                            .delta
+                            .images
+                            .into_iter()
+                            .filter_map(generated_file)
+                            .collect();
+
                         Some(Ok(MessageDelta {
                             role: self.role.clone().unwrap(),
                             content: choice.delta.content,
+                            citations,
+                            files,
                         }))
                     }
                 }
@@ -164,6 +373,10 @@ impl<S: Stream<Item = reqwest::Result<Bytes>> + Unpin + Send> AsyncMessageIterat
     fn usage(&self) -> &Usage {
         self.usage.as_ref().unwrap()
     }
+
+    fn on_heartbeat(&mut self, hook: HeartbeatHook) {
+        self.inner.on_heartbeat(hook);
+    }
 }
 
 impl From<Role> for api::Role {
@@ -186,29 +399,333 @@ impl ChatProvider for OpenAIProvider {
         ContextManagement::Explicit
     }
 
+    fn honors_seed(&self) -> bool {
+        // Sampling with a seed is only a best effort
+        false
+    }
+
+    fn supported_options(&self, model: &str) -> &'static [CompletionOption] {
+        if is_reasoning_model(model) {
+            REASONING_MODEL_OPTIONS
+        } else {
+            CompletionOption::SAMPLING
+        }
+    }
+
     async fn default_model(&self) -> Result<Option<Model>, Error> {
         Ok(Some(DEFAULT_MODEL.clone()))
     }
 
     async fn models(&self) -> Result<Vec<Model>, Error> {
-        Ok(OPENAI_MODELS.to_vec())
+        let models = self.models.get_or_init(|| self.discover_models()).await;
+
+        Ok(models.clone())
+    }
+
+    async fn loaded_models(&self) -> Result<Vec<LoadedModel>, Error> {
+        Ok(Vec::new())
+    }
+
+    async fn unload_model(&self, _model: &str) -> Result<(), Error> {
+        // Remote models are never loaded on behalf of the user
+        Err(Error::from_kind(ErrorKind::NotFound))
+    }
+
+    async fn fill_in_middle(
+        &self,
+        _model: &str,
+        _prefix: &str,
+        _suffix: &str,
+    ) -> Result<String, Error> {
+        // Chat models served through the chat completions API cannot infill
+        Err(Error::from_kind(ErrorKind::Unsupported))
+    }
+
+    async fn stream_completion(
+        &self,
+        model: &str,
+        messages: &[Message],
+        options: &CompletionOptions,
+    ) -> Result<Box<dyn AsyncMessageIterator>, Error> {
+        check_images(model, messages)?;
+
+        stream_completion(&self.api, model, messages, options).await
+    }
+
+    async fn completion(
+        &self,
+        model: &str,
+        messages: &[Message],
+        options: &CompletionOptions,
+    ) -> Result<Completion, Error> {
+        check_images(model, messages)?;
+
+        completion(&self.api, model, messages, options).await
+    }
+}
+
+#[async_trait]
+impl ChatProvider for AzureOpenAIProvider {
+    fn id(&self) -> ProviderIdentifier {
+        ProviderIdentifier::AzureOpenAI
+    }
+
+    fn context_management(&self) -> ContextManagement {
+        ContextManagement::Explicit
+    }
+
+    fn honors_seed(&self) -> bool {
+        false
+    }
+
+    // Deployments are named by the user, so the model they serve is unknown
+    fn supported_options(&self, _model: &str) -> &'static [CompletionOption] {
+        CompletionOption::SAMPLING
+    }
+
+    async fn default_model(&self) -> Result<Option<Model>, Error> {
+        Ok(self.models().await?.into_iter().next())
+    }
+
+    async fn models(&self) -> Result<Vec<Model>, Error> {
+        // Deployments cannot be listed with an API key, so only the
+        // configured deployment is advertised
+        Ok(vec![Model {
+            id: self.deployment.clone(),
+            context_length: None,
+            details: Default::default(),
+        }])
+    }
+
+    async fn loaded_models(&self) -> Result<Vec<LoadedModel>, Error> {
+        Ok(Vec::new())
+    }
+
+    async fn unload_model(&self, _model: &str) -> Result<(), Error> {
+        Err(Error::from_kind(ErrorKind::NotFound))
+    }
+
+    async fn fill_in_middle(
+        &self,
+        _model: &str,
+        _prefix: &str,
+        _suffix: &str,
+    ) -> Result<String, Error> {
+        Err(Error::from_kind(ErrorKind::Unsupported))
     }
 
     async fn stream_completion(
         &self,
         model: &str,
         messages: &[Message],
+        options: &CompletionOptions,
     ) -> Result<Box<dyn AsyncMessageIterator>, Error> {
-        let messages: Vec<api::ChatMessage> = messages
+        stream_completion(&self.api, model, messages, options).await
+    }
+
+    async fn completion(
+        &self,
+        model: &str,
+        messages: &[Message],
+        options: &CompletionOptions,
+    ) -> Result<Completion, Error> {
+        completion(&self.api, model, messages, options).await
+    }
+}
+
+/// Messages with images are sent as a list of parts, the text followed by
+/// the images
+fn content(message: &Message) -> api::Content {
+    if message.images.is_empty() {
+        return api::Content::Text(message.content.clone());
+    }
+
+    let mut parts = vec![api::ContentPart::Text {
+        text: message.content.clone(),
+    }];
+
+    parts.extend(
+        message
+            .images
             .iter()
-            .map(|m| api::ChatMessage {
-                role: m.role.clone().into(),
-                content: m.content.clone(),
-            })
+            .map(|image| api::ContentPart::ImageUrl {
+                image_url: api::ImageUrl {
+                    url: image.data_url(),
+                },
+            }),
+    );
+
+    api::Content::Parts(parts)
+}
+
+/// Rejects images sent to a model which only accepts text
+fn check_images(model: &str, messages: &[Message]) -> Result<(), Error> {
+    if !accepts_images(model) && messages.iter().any(|m| !m.images.is_empty()) {
+        return Err(Error::from_source(
+            ErrorKind::BadRequest,
+            Box::new(ImagesNotAccepted(model.to_string())),
+        ));
+    }
+
+    Ok(())
+}
+
+/// The options supported by reasoning models
+const REASONING_MODEL_OPTIONS: &[CompletionOption] = &[
+    CompletionOption::ResponseFormat,
+    CompletionOption::MaxTokens,
+    CompletionOption::Seed,
+];
+
+/// Translates the conversation and options into a chat completions request.
+/// Options which the model does not support are left out.
+fn request(
+    model: &str,
+    messages: &[Message],
+    options: &CompletionOptions,
+) -> (Vec<api::ChatMessage>, api::ChatCompletionOptions) {
+    let messages: Vec<api::ChatMessage> = messages
+        .iter()
+        .map(|m| api::ChatMessage {
+            role: m.role.clone().into(),
+            content: content(m),
+        })
+        .collect();
+
+    let stop = (!options.stop.is_empty()).then(|| options.stop.clone());
+
+    let mut request_options = api::ChatCompletionOptions {
+        response_format: match options.response_format {
+            ResponseFormat::Text => None,
+            ResponseFormat::Json => Some(api::ResponseFormat::JsonObject),
+        },
+        seed: options.seed,
+        ..Default::default()
+    };
+
+    if is_reasoning_model(model) {
+        request_options.max_completion_tokens = options.max_tokens;
+    } else {
+        request_options.temperature = options.temperature;
+        request_options.max_tokens = options.max_tokens;
+        request_options.stop = stop;
+    }
+
+    (messages, request_options)
+}
+
+/// Streams a completion through the chat completions API, which is shared
+/// by OpenAI and Azure OpenAI
+async fn stream_completion(
+    api: &api::OpenAIApi,
+    model: &str,
+    messages: &[Message],
+    options: &CompletionOptions,
+) -> Result<Box<dyn AsyncMessageIterator>, Error> {
+    let (messages, options) = request(model, messages, options);
+
+    let iterator = api
+        .streaming_chat_completion(model, &messages, &options)
+        .await?;
+
+    Ok(Box::new(OpenAICompletionResponse::new(iterator)))
+}
+
+/// Requests a completion from the chat completions API without streaming it
+async fn completion(
+    api: &api::OpenAIApi,
+    model: &str,
+    messages: &[Message],
+    options: &CompletionOptions,
+) -> Result<Completion, Error> {
+    let (messages, options) = request(model, messages, options);
+
+    let completion = api.chat_completion(model, &messages, &options).await?;
+
+    completion.try_into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn completion(json: &str) -> Completion {
+        let completion: api::ChatCompletion = serde_json::from_str(json).unwrap();
+
+        completion.try_into().unwrap()
+    }
+
+    #[test]
+    fn test_annotated_citations() {
+        let completion = completion(
+            r#"{
+                "id": "chatcmpl-1", "object": "chat.completion", "created": 0, "model": "gpt-4o-search-preview",
+                "choices": [{"index": 0, "finish_reason": "stop", "message": {
+                    "role": "assistant",
+                    "content": "It is sunny.",
+                    "annotations": [{"type": "url_citation", "url_citation": {
+                        "url": "https://weather.example", "title": "Weather", "start_index": 0, "end_index": 12
+                    }}]
+                }}],
+                "usage": {"prompt_tokens": 5, "completion_tokens": 4, "total_tokens": 9}
+            }"#,
+        );
+
+        let [citation] = &completion.message.citations[..] else {
+            panic!("expected one citation");
+        };
+
+        assert_eq!(citation.url, "https://weather.example");
+        assert_eq!(citation.title.as_deref(), Some("Weather"));
+        assert_eq!(citation.end_index, Some(12));
+    }
+
+    #[test]
+    fn test_listed_citations() {
+        let completion = completion(
+            r#"{
+                "id": "1", "object": "chat.completion", "created": 0, "model": "sonar",
+                "citations": ["https://a.example", "https://b.example"],
+                "choices": [{"index": 0, "finish_reason": "stop", "message": {
+                    "role": "assistant", "content": "It is sunny[1][2]."
+                }}],
+                "usage": {"prompt_tokens": 5, "completion_tokens": 4, "total_tokens": 9}
+            }"#,
+        );
+
+        let urls: Vec<&str> = completion
+            .message
+            .citations
+            .iter()
+            .map(|citation| citation.url.as_str())
             .collect();
 
-        let iterator = self.api.streaming_chat_completion(model, &messages).await?;
+        assert_eq!(urls, ["https://a.example", "https://b.example"]);
+
+        // The content already marks where the sources are cited
+        assert!(completion
+            .message
+            .citations
+            .iter()
+            .all(|citation| citation.end_index.is_none()));
+    }
+
+    #[test]
+    fn test_listed_citations_in_chunk() {
+        let chunk: api::ChatCompletionChunk = serde_json::from_str(
+            r#"{
+                "id": "1", "object": "chat.completion.chunk", "created": 0, "model": "sonar",
+                "citations": ["https://a.example"],
+                "choices": [{"index": 0, "delta": {"role": "assistant", "content": "It is"}}]
+            }"#,
+        )
+        .unwrap();
+
+        let choice = chunk.choices.into_iter().next().unwrap();
+
+        let cited = citations(choice.delta.annotations, chunk.citations);
 
-        Ok(Box::new(OpenAICompletionResponse::new(iterator)))
+        assert_eq!(cited.len(), 1);
+        assert_eq!(cited[0].url, "https://a.example");
     }
 }
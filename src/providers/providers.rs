@@ -24,7 +24,10 @@ use strum_macros;
 pub(crate) enum ProviderIdentifier {
     Ollama,
     OpenAI,
+    #[strum(serialize = "azure_openai")]
+    #[serde(rename = "azure_openai")]
+    AzureOpenAI,
 }
 
 pub(crate) use super::ollama::OllamaProvider;
-pub(crate) use super::openai::OpenAIProvider;
+pub(crate) use super::openai::{AzureOpenAIProvider, OpenAIProvider};
@@ -0,0 +1,207 @@
+//! Retries for requests which fail due to rate limits or overloaded servers
+//!
+//! [`RetryingProvider`] wraps a [`ChatProvider`], retrying requests which fail
+//! with [`ErrorKind::ExcessUsage`] or [`ErrorKind::ApiOverloaded`]. Retries are
+//! spaced with exponential backoff and jitter, so clients which were limited
+//! at the same time do not retry in lockstep. Only the initial request of a
+//! streamed completion is retried; an error in the middle of a stream is
+//! surfaced as usual.
+
+use std::future::Future;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rand::Rng;
+use tracing::{debug, info};
+
+use super::providers::ProviderIdentifier;
+use super::{
+    AsyncMessageIterator, ChatProvider, Completion, CompletionOption, CompletionOptions,
+    ContextManagement, Error, ErrorKind, LoadedModel, Message, Model,
+};
+use crate::warn;
+
+/// The longest delay between two attempts
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RetryPolicy {
+    /// The maximum number of attempts, including the first
+    pub max_attempts: u32,
+    /// The delay before the first retry, which doubles with each attempt
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before the specified retry, where the first retry is zero.
+    /// The delay is drawn from the upper half of the backoff interval.
+    fn delay(&self, retry: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(MAX_DELAY);
+
+        backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+
+    fn is_retryable(err: &Error) -> bool {
+        matches!(
+            err.kind(),
+            ErrorKind::ExcessUsage | ErrorKind::ApiOverloaded
+        )
+    }
+
+    async fn run<T, F, Fut>(&self, mut request: F) -> Result<T, Error>
+    where
+        F: FnMut() -> Fut + Send,
+        Fut: Future<Output = Result<T, Error>> + Send,
+    {
+        let mut attempt = 1;
+
+        loop {
+            let err = match request().await {
+                Err(err) if attempt < self.max_attempts && Self::is_retryable(&err) => err,
+                Err(err) => {
+                    if attempt > 1 {
+                        debug!(attempt, error = %err, "giving up on the request");
+                    }
+                    return Err(err);
+                }
+                result => return result,
+            };
+
+            let delay = self.delay(attempt - 1);
+
+            warn!(
+                "{}, retrying in {:.1}s (attempt {}/{})",
+                err,
+                delay.as_secs_f64(),
+                attempt + 1,
+                self.max_attempts
+            );
+
+            info!(
+                attempt = attempt + 1,
+                max_attempts = self.max_attempts,
+                delay_ms = delay.as_millis() as u64,
+                error = %err,
+                "retrying request"
+            );
+
+            tokio::time::sleep(delay).await;
+
+            attempt += 1;
+        }
+    }
+}
+
+/// A provider which retries requests according to a [`RetryPolicy`]
+pub(crate) struct RetryingProvider {
+    inner: Box<dyn ChatProvider>,
+    policy: RetryPolicy,
+}
+
+impl RetryingProvider {
+    pub(crate) fn new(inner: Box<dyn ChatProvider>, policy: RetryPolicy) -> RetryingProvider {
+        RetryingProvider { inner, policy }
+    }
+}
+
+#[async_trait]
+impl ChatProvider for RetryingProvider {
+    fn id(&self) -> ProviderIdentifier {
+        self.inner.id()
+    }
+
+    fn context_management(&self) -> ContextManagement {
+        self.inner.context_management()
+    }
+
+    fn honors_seed(&self) -> bool {
+        self.inner.honors_seed()
+    }
+
+    fn supported_options(&self, model: &str) -> &'static [CompletionOption] {
+        self.inner.supported_options(model)
+    }
+
+    async fn models(&self) -> Result<Vec<Model>, Error> {
+        self.policy.run(|| self.inner.models()).await
+    }
+
+    async fn default_model(&self) -> Result<Option<Model>, Error> {
+        self.policy.run(|| self.inner.default_model()).await
+    }
+
+    async fn loaded_models(&self) -> Result<Vec<LoadedModel>, Error> {
+        self.policy.run(|| self.inner.loaded_models()).await
+    }
+
+    async fn unload_model(&self, model: &str) -> Result<(), Error> {
+        self.policy.run(|| self.inner.unload_model(model)).await
+    }
+
+    async fn stream_completion(
+        &self,
+        model: &str,
+        messages: &[Message],
+        options: &CompletionOptions,
+    ) -> Result<Box<dyn AsyncMessageIterator>, Error> {
+        self.policy
+            .run(|| self.inner.stream_completion(model, messages, options))
+            .await
+    }
+
+    async fn completion(
+        &self,
+        model: &str,
+        messages: &[Message],
+        options: &CompletionOptions,
+    ) -> Result<Completion, Error> {
+        self.policy
+            .run(|| self.inner.completion(model, messages, options))
+            .await
+    }
+
+    async fn fill_in_middle(
+        &self,
+        model: &str,
+        prefix: &str,
+        suffix: &str,
+    ) -> Result<String, Error> {
+        self.policy
+            .run(|| self.inner.fill_in_middle(model, prefix, suffix))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_is_bounded() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+        };
+
+        for retry in 0..3 {
+            let backoff = Duration::from_millis(100 * 2u64.pow(retry));
+            let delay = policy.delay(retry);
+
+            assert!(delay >= backoff / 2 && delay <= backoff);
+        }
+
+        assert!(policy.delay(20) <= MAX_DELAY);
+    }
+}
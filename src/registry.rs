@@ -25,8 +25,9 @@
 //! provider is chosen. If two providers offer the same model and are assigned the same priority,
 //! resolution is implementation-dependent.
 //!
-//! To see how model resolution works, see [`populate::resolve_once`].
+//! To see how model resolution works, see [`populate::resolve_spec`].
 
+pub(crate) mod model_id;
 pub(crate) mod populate;
 pub(crate) mod registry;
 
@@ -0,0 +1,172 @@
+//! Validated model identifiers
+//!
+//! A model identifier names a model served by a provider, such as
+//! `llama3:latest` or `gpt-4o`. Identifiers are validated when they are
+//! parsed so that a typo, such as stray whitespace, is reported where it was
+//! written rather than surfacing later as a model which cannot be found.
+//! Identifiers may begin with the name of a provider, as those of gateways
+//! behind the OpenAI API do (e.g. `openai/gpt-4o`), so a doubled provider
+//! prefix is only caught where a model spec is parsed.
+
+use core::fmt;
+use std::borrow::Borrow;
+use std::ops::Deref;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::providers::providers::ProviderIdentifier;
+
+/// The longest identifier which is accepted
+pub(crate) const MAX_MODEL_ID_LEN: usize = 256;
+
+#[derive(Error, Debug, PartialEq)]
+pub(crate) enum Error {
+    #[error("the model identifier is empty")]
+    Empty,
+    #[error("\"{0}\" is longer than {MAX_MODEL_ID_LEN} characters")]
+    TooLong(String),
+    #[error("\"{0}\" contains the character {1:?}, which is not allowed in model identifiers")]
+    InvalidCharacter(String, char),
+    #[error("\"{0}\" contains an empty path segment")]
+    EmptySegment(String),
+    #[error(
+        "\"{0}\" begins with the provider \"{1}/\", which is not part of the model identifier"
+    )]
+    ProviderPrefix(String, ProviderIdentifier),
+}
+
+/// The identifier of a model, as it is known to its provider
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct ModelId(String);
+
+/// Characters which appear in model identifiers besides ASCII letters and
+/// digits, e.g. `hf.co/org/model:Q4_K_M` or `ft:gpt-4o-mini:org::id`
+fn is_allowed(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':' | '/' | '@' | '+')
+}
+
+impl ModelId {
+    pub(crate) fn new<S: Into<String>>(id: S) -> Result<ModelId, Error> {
+        let id = id.into();
+
+        if id.is_empty() {
+            return Err(Error::Empty);
+        }
+
+        if id.chars().count() > MAX_MODEL_ID_LEN {
+            return Err(Error::TooLong(id));
+        }
+
+        if let Some(c) = id.chars().find(|&c| !is_allowed(c)) {
+            return Err(Error::InvalidCharacter(id, c));
+        }
+
+        // Ollama models may be namespaced (e.g. `org/model`), but the
+        // namespaces are never empty
+        if id.split('/').any(str::is_empty) {
+            return Err(Error::EmptySegment(id));
+        }
+
+        Ok(ModelId(id))
+    }
+
+    /// The provider the identifier begins with, if its first segment names one
+    pub(crate) fn provider_prefix(&self) -> Option<ProviderIdentifier> {
+        let (prefix, _) = self.0.split_once('/')?;
+
+        ProviderIdentifier::from_str(prefix).ok()
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for ModelId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for ModelId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ModelId::new(s)
+    }
+}
+
+impl TryFrom<String> for ModelId {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        ModelId::new(value)
+    }
+}
+
+impl From<ModelId> for String {
+    fn from(value: ModelId) -> Self {
+        value.0
+    }
+}
+
+impl Deref for ModelId {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for ModelId {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for ModelId {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_id() {
+        for id in [
+            "llama3",
+            "llama3:latest",
+            "hf.co/bartowski/Llama-3.2-1B-Instruct-GGUF:Q4_K_M",
+            "ft:gpt-4o-mini-2024-07-18:org::9abc",
+            "gpt-4o",
+            "openai/gpt-4o",
+            "meta-llama/llama-3.1-8b-instruct",
+        ] {
+            assert_eq!(ModelId::new(id).unwrap().as_str(), id);
+        }
+
+        assert_eq!(ModelId::new(""), Err(Error::Empty));
+        assert_eq!(
+            ModelId::new("llama3 "),
+            Err(Error::InvalidCharacter("llama3 ".into(), ' '))
+        );
+        assert_eq!(
+            ModelId::new("org//model"),
+            Err(Error::EmptySegment("org//model".into()))
+        );
+        assert_eq!(
+            ModelId::new("openai/gpt-4o").unwrap().provider_prefix(),
+            Some(ProviderIdentifier::OpenAI)
+        );
+        assert!(matches!(
+            ModelId::new("a".repeat(MAX_MODEL_ID_LEN + 1)),
+            Err(Error::TooLong(_))
+        ));
+    }
+}
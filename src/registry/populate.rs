@@ -1,17 +1,33 @@
 use std::env::VarError;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::die;
+use strum::IntoEnumIterator;
+use tracing::debug;
 
-use super::registry::{Error, ModelResolver, ModelSpec, Registry};
-use crate::config::{Config, ProviderActivationPolicy};
-use crate::providers::providers::{OllamaProvider, OpenAIProvider};
-use crate::providers::{ChatProvider, ErrorKind};
+use crate::utils::errors::AUTH_EXIT_CODE;
+use crate::{die, die_with, warn};
+
+use super::model_id::ModelId;
+use super::registry::{Error, ModelResolver, ModelSpec, ProvidedModel, Registry};
+use crate::cache::Cache;
+use crate::config::{self, Config, ProviderActivationPolicy};
+use crate::providers::cache::{health_key, CachingProvider};
+use crate::providers::providers::ProviderIdentifier;
+use crate::providers::providers::{AzureOpenAIProvider, OllamaProvider, OpenAIProvider};
+use crate::providers::retry::{RetryPolicy, RetryingProvider};
+use crate::providers::Model;
+use crate::providers::{
+    ChatProvider, ErrorKind, HttpClient, Logging, Middleware, RateLimit, Timeouts,
+};
 
 async fn ollama_is_awake(ollama: &OllamaProvider) -> bool {
-    let models = ollama.models().await;
+    let probe = ollama.probe().await;
 
-    if let Err(err) = models {
+    if let Err(err) = probe {
         if matches!(err.kind(), ErrorKind::Connection | ErrorKind::TimedOut) {
+            debug!(error = %err, "ollama did not respond to the probe");
             return false;
         }
 
@@ -26,61 +42,167 @@ async fn ollama_is_awake(ollama: &OllamaProvider) -> bool {
 
 const OPENAI_ENV_KEY_VAR: &'static str = "OPENAI_API_KEY";
 
-fn openai_api_key() -> Option<String> {
-    match std::env::var(OPENAI_ENV_KEY_VAR) {
+const OPENAI_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Returns false if the API key was rejected. Probes which fail for any other
+/// reason are inconclusive, so the provider remains active.
+async fn openai_key_is_accepted(openai: &OpenAIProvider) -> bool {
+    let probe = openai.probe(OPENAI_PROBE_TIMEOUT).await;
+
+    if let Err(err) = &probe {
+        debug!(error = %err, "openai probe failed");
+    }
+
+    !matches!(probe, Err(err) if matches!(err.kind(), ErrorKind::Authentication))
+}
+
+fn env_api_key(var: &str) -> Option<String> {
+    match std::env::var(var) {
         Ok(api_key) => Some(api_key),
         Err(err) => match err {
-            VarError::NotUnicode(_) => die!("failed to parse {}", OPENAI_ENV_KEY_VAR),
+            VarError::NotUnicode(_) => die!("failed to parse {}", var),
             VarError::NotPresent => None,
         },
     }
 }
 
-/// Populate a registry with the available providers
-pub(crate) async fn populated_registry(config: &Config) -> Registry {
+const AZURE_OPENAI_ENV_KEY_VAR: &str = "AZURE_OPENAI_API_KEY";
+
+const AZURE_OPENAI_DEFAULT_API_VERSION: &str = "2024-10-21";
+
+fn retry_policy(config: &config::Retry) -> RetryPolicy {
+    let default = RetryPolicy::default();
+
+    RetryPolicy {
+        max_attempts: config.max_attempts.unwrap_or(default.max_attempts).max(1),
+        base_delay: config
+            .base_delay_ms
+            .map(Duration::from_millis)
+            .unwrap_or(default.base_delay),
+    }
+}
+
+/// The timeouts of a provider. Its own settings take precedence over the
+/// global ones.
+fn timeouts(global: &config::Timeouts, provider: &config::Timeouts) -> Timeouts {
+    let default = Timeouts::default();
+
+    let timeout = |ms: Option<u64>, default: Option<Duration>| match ms {
+        Some(0) => None,
+        Some(ms) => Some(Duration::from_millis(ms)),
+        None => default,
+    };
+
+    Timeouts {
+        connect: timeout(provider.connect_ms.or(global.connect_ms), default.connect),
+        read: timeout(provider.read_ms.or(global.read_ms), default.read),
+    }
+}
+
+/// The client through which a provider sends requests. Each provider has
+/// its own client, so its rate limit is its own.
+fn http_client(requests_per_minute: Option<u32>, timeouts: Timeouts) -> HttpClient {
+    let mut middlewares: Vec<Arc<dyn Middleware>> = Vec::new();
+
+    // Requests are logged once they are sent, after waiting for the limit.
+    // A limit of zero disables it.
+    if let Some(requests_per_minute) = requests_per_minute.and_then(NonZeroU32::new) {
+        middlewares.push(Arc::new(RateLimit::per_minute(requests_per_minute)));
+    }
+
+    middlewares.push(Arc::new(Logging));
+
+    HttpClient::new(middlewares, timeouts)
+}
+
+/// The Ollama provider, as it is configured
+fn ollama_provider(config: &Config) -> OllamaProvider {
+    let ollama = &config.providers.ollama;
+
+    let provider = match &ollama.api_base {
+        Some(api_base) => match OllamaProvider::with_api_base(api_base) {
+            Ok(provider) => provider,
+            Err(err) => die!("ollama API base failed to parse: {}", err),
+        },
+        None => OllamaProvider::new(),
+    };
+
+    provider
+        .with_http_client(http_client(
+            ollama.requests_per_minute,
+            timeouts(&config.timeouts, &ollama.timeouts),
+        ))
+        .with_keep_alive(ollama.keep_alive)
+}
+
+/// Adds a provider to the registry. Each provider is only configured once,
+/// so it cannot already be present.
+fn activate(
+    registry: &mut Registry,
+    provider: Box<dyn ChatProvider>,
+    priority: Option<u8>,
+    default_model: Option<ModelId>,
+) {
+    if let Err(err) = registry.add_provider(provider, priority, default_model) {
+        die!("{}", err);
+    }
+}
+
+/// Populate a registry with the available providers. If a cache is
+/// provided, model listings are cached.
+pub(crate) async fn populated_registry(config: &Config, cache: Option<Cache>) -> Registry {
     let mut registry = Registry::new();
 
+    let policy = retry_policy(&config.retry);
+
+    // Probes are not retried, so providers are wrapped once they are activated
+    let with_retries = |provider: Box<dyn ChatProvider>| -> Box<dyn ChatProvider> {
+        let provider = Box::new(RetryingProvider::new(provider, policy));
+
+        match &cache {
+            Some(cache) => Box::new(CachingProvider::new(provider, cache.clone())),
+            None => provider,
+        }
+    };
+
     {
         let ollama = &config.providers.ollama;
 
         let provider = match ollama.activate {
             ProviderActivationPolicy::Auto | ProviderActivationPolicy::Enabled => {
-                if let Some(api_base) = &ollama.api_base {
-                    match OllamaProvider::with_api_base(api_base) {
-                        Ok(ollama) => Some(ollama),
-                        Err(err) => die!("ollama API base failed to parse: {}", err),
-                    }
-                } else {
-                    Some(OllamaProvider::new())
-                }
+                Some(ollama_provider(config))
             }
             ProviderActivationPolicy::Disabled => None,
         };
 
         match (provider, ollama.activate) {
+            // The server is probed even when its models are cached, since
+            // the cache only shows that it was running when they were listed
             (Some(provider), ProviderActivationPolicy::Auto)
                 if ollama_is_awake(&provider).await =>
             {
-                registry.add_provider(
-                    Box::new(provider),
+                activate(
+                    &mut registry,
+                    with_retries(Box::new(provider)),
                     ollama.priority,
                     ollama.default_model.clone(),
                 );
             }
             (Some(provider), ProviderActivationPolicy::Enabled) => {
-                registry.add_provider(
-                    Box::new(provider),
+                activate(
+                    &mut registry,
+                    with_retries(Box::new(provider)),
                     ollama.priority,
                     ollama.default_model.clone(),
                 );
             }
-            _ => {}
+            (_, policy) => debug!(?policy, "ollama was not activated"),
         }
     }
 
     {
         let openai = &config.providers.openai;
-        let openai_env_var = openai_api_key();
+        let openai_env_var = env_api_key(OPENAI_ENV_KEY_VAR);
 
         let api_key = if let Some(api_key) = &openai.api_key {
             Some(api_key)
@@ -97,7 +219,7 @@ pub(crate) async fn populated_registry(config: &Config) -> Registry {
             }
             ProviderActivationPolicy::Enabled => {
                 if api_key.is_none() {
-                    die!("the \"openai\" provider is activated but the API key is not defined, either add it to the config or define {}", OPENAI_ENV_KEY_VAR);
+                    die_with!(AUTH_EXIT_CODE, "the \"openai\" provider is activated but the API key is not defined, either add it to the config or define {}", OPENAI_ENV_KEY_VAR);
                 }
 
                 api_key
@@ -106,33 +228,201 @@ pub(crate) async fn populated_registry(config: &Config) -> Registry {
         };
 
         if let Some(api_key) = activated {
-            let provider = Box::new(OpenAIProvider::with_api_key(&api_key));
+            let provider = OpenAIProvider::with_api_key(&api_key).with_http_client(http_client(
+                openai.requests_per_minute,
+                timeouts(&config.timeouts, &openai.timeouts),
+            ));
+
+            let probe = openai.probe && matches!(openai.activate, ProviderActivationPolicy::Auto);
+
+            // The verdict on the key is cached, so it is not probed on each run
+            let accepted = || async {
+                let key = health_key(ProviderIdentifier::OpenAI, api_key);
+
+                if let Some(accepted) = cache.as_ref().and_then(|cache| cache.get(&key)) {
+                    debug!(accepted, "using the cached verdict on the OpenAI API key");
+                    return accepted;
+                }
+
+                let accepted = openai_key_is_accepted(&provider).await;
+
+                debug!(accepted, "probed the OpenAI API key");
+
+                if let Some(cache) = &cache {
+                    cache.put(&key, &accepted);
+                }
 
-            registry.add_provider(provider, openai.priority, openai.default_model.clone());
+                accepted
+            };
+
+            if probe && !accepted().await {
+                warn!("the OpenAI API key was rejected, deactivating the \"openai\" provider");
+            } else {
+                activate(
+                    &mut registry,
+                    with_retries(Box::new(provider)),
+                    openai.priority,
+                    openai.default_model.clone(),
+                );
+            }
+        } else {
+            debug!(policy = ?openai.activate, "openai was not activated");
+        }
+    }
+
+    {
+        let azure = &config.providers.azure_openai;
+        let azure_env_var = env_api_key(AZURE_OPENAI_ENV_KEY_VAR);
+
+        let api_key = azure.api_key.as_ref().or(azure_env_var.as_ref());
+
+        let settings = match (&azure.endpoint, &azure.deployment, api_key) {
+            (Some(endpoint), Some(deployment), Some(api_key)) => {
+                Some((endpoint, deployment, api_key))
+            }
+            _ => None,
+        };
+
+        let activated = match azure.activate {
+            // Activate if the resource is fully configured
+            ProviderActivationPolicy::Auto => settings,
+            ProviderActivationPolicy::Enabled => {
+                if settings.is_none() {
+                    die!("the \"azure_openai\" provider is activated but it is not configured, define the endpoint and deployment in the config and either add the API key to the config or define {}", AZURE_OPENAI_ENV_KEY_VAR);
+                }
+
+                settings
+            }
+            ProviderActivationPolicy::Disabled => None,
+        };
+
+        if let Some((endpoint, deployment, api_key)) = activated {
+            let api_version = azure
+                .api_version
+                .as_deref()
+                .unwrap_or(AZURE_OPENAI_DEFAULT_API_VERSION);
+
+            let provider =
+                match AzureOpenAIProvider::new(api_key, endpoint, deployment, api_version) {
+                    Ok(provider) => provider.with_http_client(http_client(
+                        azure.requests_per_minute,
+                        timeouts(&config.timeouts, &azure.timeouts),
+                    )),
+                    Err(err) => die!("azure openai endpoint failed to parse: {}", err),
+                };
+
+            activate(
+                &mut registry,
+                with_retries(Box::new(provider)),
+                azure.priority,
+                Some(deployment.clone()),
+            );
+        } else {
+            debug!(policy = ?azure.activate, "azure_openai was not activated");
         }
     }
 
+    if let Some(cache) = cache {
+        registry.cache_resolutions(cache);
+    }
+
     registry
 }
 
-/// Resolve a single model
-pub(crate) async fn resolve_once<'r>(
-    registry: &'r Registry,
+/// Lists the models of the providers which are not active, where this is
+/// possible without activating them: the models known to be served by
+/// OpenAI, the configured Azure OpenAI deployment, and the models of an
+/// Ollama server which responds even though it is not active
+pub(crate) async fn inactive_models(config: &Config, registry: &Registry) -> Vec<ProvidedModel> {
+    let mut models = Vec::new();
+
+    for id in ProviderIdentifier::iter() {
+        if registry.provider(id).is_some() {
+            continue;
+        }
+
+        let listed = match id {
+            ProviderIdentifier::Ollama => match ollama_provider(config).models().await {
+                Ok(models) => models,
+                Err(err) => {
+                    debug!(error = %err, "the models of the inactive ollama server cannot be listed");
+                    Vec::new()
+                }
+            },
+            ProviderIdentifier::OpenAI => OpenAIProvider::known_models(),
+            ProviderIdentifier::AzureOpenAI => config
+                .providers
+                .azure_openai
+                .deployment
+                .iter()
+                .map(|deployment| Model {
+                    id: deployment.to_string(),
+                    context_length: None,
+                    details: Default::default(),
+                })
+                .collect(),
+        };
+
+        models.extend(listed.into_iter().map(|model| ProvidedModel {
+            provider: id,
+            model,
+        }));
+    }
+
+    models
+}
+
+/// Resolve a model spec to a specific provider and model
+pub(crate) async fn resolve_spec(
+    registry: &Registry,
     raw_spec: Option<String>,
-) -> Result<(&'r Box<dyn ChatProvider>, String), Error> {
+) -> Result<ModelSpec, Error> {
     let spec = ModelSpec::parse(raw_spec)?;
 
     let spec = if spec.is_ambiguous() {
-        let resolver = ModelResolver::build(&registry).await?;
+        let cache = registry.resolution_cache(&spec);
+
+        // A cached resolution is only used while its provider is active
+        let cached = cache
+            .as_ref()
+            .and_then(|(cache, key)| cache.get::<String>(key))
+            .and_then(|resolved| ModelSpec::parse(Some(resolved)).ok())
+            .filter(|resolved| {
+                resolved
+                    .provider()
+                    .is_some_and(|id| registry.provider(id).is_some())
+            });
 
-        resolver.resolve(spec)?
+        match cached {
+            Some(resolved) => {
+                debug!(%spec, %resolved, "using the cached resolution");
+                resolved
+            }
+            None => {
+                let resolver = ModelResolver::build(&registry).await?;
+
+                let requested = spec.to_string();
+
+                let resolved = resolver.resolve(spec)?;
+
+                debug!(spec = %requested, %resolved, "resolved model");
+
+                if let Some((cache, key)) = cache {
+                    cache.put(&key, &resolved.to_string());
+                }
+
+                resolved
+            }
+        }
     } else {
         spec
     };
 
-    let (id, model) = spec.unwrap_provider_model_ids();
-
-    let provider = registry.active_provider(id)?;
+    // Ensure the provider which serves the model is active
+    match spec.provider() {
+        Some(id) => registry.active_provider(id)?,
+        None => return Err(Error::UnresolvedSpec(spec.to_string())),
+    };
 
-    Ok((provider, model))
+    Ok(spec)
 }
@@ -1,12 +1,16 @@
 use super::default_priority::default_priority;
+use super::model_id::{self, ModelId};
 
+use crate::cache::Cache;
 use crate::providers::{self, providers::ProviderIdentifier, ChatProvider, Model};
+use crate::utils::errors::{DEFAULT_EXIT_CODE, NOT_FOUND_EXIT_CODE, USAGE_EXIT_CODE};
 use core::fmt;
 use std::collections::HashMap;
 use std::default;
 use std::str::FromStr;
 use strum::IntoEnumIterator;
 use thiserror::Error;
+use tracing::{debug, trace};
 
 #[derive(Error, Debug)]
 pub(crate) enum Error {
@@ -27,42 +31,72 @@ pub(crate) enum Error {
     ModelListingFailed(ProviderIdentifier, #[source] providers::Error),
     #[error("failed to obtain the default model for provider \"{0}\": \"{1}\"")]
     DefaultModelFailed(ProviderIdentifier, #[source] providers::Error),
+    /// The model identifier of the spec is malformed
+    #[error("invalid model identifier: {0}")]
+    InvalidModelId(#[from] model_id::Error),
+    /// The provider was added to the registry more than once
+    #[error("provider \"{0}\" was added to the registry twice")]
+    ProviderAlreadyAdded(ProviderIdentifier),
+    /// The spec was used as though it named a provider and model, but it
+    /// has not been resolved
+    #[error("model spec \"{0}\" has not been resolved to a provider and model")]
+    UnresolvedSpec(String),
+}
+
+impl Error {
+    /// The status to exit with when the error is fatal
+    pub(crate) fn exit_code(&self) -> i32 {
+        match self {
+            Error::ModelNotFound(_) | Error::ProviderNotFound(_) => NOT_FOUND_EXIT_CODE,
+            Error::InvalidModelId(_) => USAGE_EXIT_CODE,
+            Error::ModelListingFailed(_, err) | Error::DefaultModelFailed(_, err) => {
+                err.exit_code()
+            }
+            Error::ProviderNotActivated(_)
+            | Error::DefaultModelUnset
+            | Error::ProviderAlreadyAdded(_)
+            | Error::UnresolvedSpec(_) => DEFAULT_EXIT_CODE,
+        }
+    }
 }
 
 #[derive(Default)]
 pub(crate) struct ModelSpec {
     pub provider: Option<ProviderIdentifier>,
-    pub model: Option<String>,
+    pub model: Option<ModelId>,
 }
 
 impl fmt::Display for ModelSpec {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if self.model.is_none() {
-            return write!(f, "default_model");
-        }
+        let model = match &self.model {
+            Some(model) => model,
+            None => return write!(f, "default_model"),
+        };
 
         if let Some(provider) = self.provider {
             write!(f, "{}/", provider)?;
         }
 
-        write!(f, "{}", self.model.as_ref().unwrap())
+        write!(f, "{}", model)
     }
 }
 
 impl ModelSpec {
-    pub(crate) fn resolved(provider: ProviderIdentifier, model: String) -> ModelSpec {
+    pub(crate) fn resolved(provider: ProviderIdentifier, model: ModelId) -> ModelSpec {
         ModelSpec {
             provider: Some(provider),
             model: Some(model),
         }
     }
 
-    pub(crate) fn unwrap_provider_model_ids(self) -> (ProviderIdentifier, String) {
-        if self.is_ambiguous() {
-            panic!("Cannot unwrap an unresolved model spec");
+    /// The provider and model named by a resolved spec
+    pub(crate) fn provider_model_ids(self) -> Result<(ProviderIdentifier, ModelId), Error> {
+        match (self.provider, self.model) {
+            (Some(provider), Some(model)) => Ok((provider, model)),
+            (provider, model) => Err(Error::UnresolvedSpec(
+                ModelSpec { provider, model }.to_string(),
+            )),
         }
-
-        (self.provider.unwrap(), self.model.unwrap())
     }
 }
 
@@ -72,7 +106,7 @@ pub(crate) trait AsModelId {
 
 impl AsModelId for ModelSpec {
     fn model_id(&self) -> Option<&str> {
-        self.model.as_ref().map(|s| s.as_str())
+        self.model.as_deref()
     }
 }
 
@@ -90,14 +124,28 @@ impl ModelSpec {
                     let id = ProviderIdentifier::from_str(provider)
                         .map_err(|_| Error::ProviderNotFound(provider.to_string()))?;
 
+                    let model = ModelId::new(model)?;
+
+                    // Ollama models are never namespaced by a provider, so
+                    // the provider was written twice (`ollama/ollama/llama3`).
+                    // Gateways behind the OpenAI API do namespace models by
+                    // vendor, as in `openai/openai/gpt-4o`.
+                    if let (ProviderIdentifier::Ollama, Some(prefix)) =
+                        (id, model.provider_prefix())
+                    {
+                        return Err(
+                            model_id::Error::ProviderPrefix(model.to_string(), prefix).into()
+                        );
+                    }
+
                     Ok(ModelSpec {
                         provider: Some(id),
-                        model: Some(model.to_string()),
+                        model: Some(model),
                     })
                 } else {
                     Ok(ModelSpec {
                         provider: None,
-                        model: Some(spec),
+                        model: Some(ModelId::new(spec)?),
                     })
                 }
             }
@@ -114,18 +162,30 @@ impl ModelSpec {
     }
 
     pub(crate) fn model(&self) -> Option<&str> {
-        self.model.as_ref().map(|s| s.as_str())
+        self.model.as_deref()
     }
 }
 
 struct ProviderEntry {
     provider: Option<Box<dyn ChatProvider>>,
     priority: u8,
-    default_model: Option<String>,
+    default_model: Option<ModelId>,
+}
+
+impl ProviderEntry {
+    fn new(id: ProviderIdentifier) -> ProviderEntry {
+        ProviderEntry {
+            provider: None,
+            priority: default_priority(id),
+            default_model: None,
+        }
+    }
 }
 
 pub(crate) struct Registry {
     providers: HashMap<ProviderIdentifier, ProviderEntry>,
+    /// Caches the resolution of ambiguous model specs
+    resolutions: Option<Cache>,
 }
 
 pub(crate) struct ProvidedModel {
@@ -135,48 +195,64 @@ pub(crate) struct ProvidedModel {
 
 pub(crate) struct ProvidedDefaultModel {
     pub provider: ProviderIdentifier,
-    pub default_model_id: Option<String>,
+    pub default_model_id: Option<ModelId>,
 }
 
-impl From<ProvidedModel> for ModelSpec {
-    fn from(value: ProvidedModel) -> Self {
-        ModelSpec {
+impl TryFrom<ProvidedModel> for ModelSpec {
+    type Error = model_id::Error;
+
+    fn try_from(value: ProvidedModel) -> Result<Self, Self::Error> {
+        Ok(ModelSpec {
             provider: Some(value.provider),
-            model: Some(value.model.id),
-        }
+            model: Some(ModelId::new(value.model.id)?),
+        })
     }
 }
 
 impl Registry {
     pub(crate) fn new() -> Registry {
-        let providers = ProviderIdentifier::iter().map(|id| {
-            (
-                id,
-                ProviderEntry {
-                    provider: None,
-                    priority: default_priority(id),
-                    default_model: None,
-                },
-            )
-        });
+        let providers = ProviderIdentifier::iter().map(|id| (id, ProviderEntry::new(id)));
 
         Registry {
             providers: HashMap::from_iter(providers),
+            resolutions: None,
         }
     }
 
+    /// Caches the resolution of ambiguous model specs
+    pub(crate) fn cache_resolutions(&mut self, cache: Cache) {
+        self.resolutions = Some(cache);
+    }
+
+    /// The cache for the resolution of a spec, and the key it is kept under.
+    /// Resolutions depend on which providers are active, so the key names
+    /// them.
+    pub(crate) fn resolution_cache(&self, spec: &ModelSpec) -> Option<(&Cache, String)> {
+        let active: Vec<String> = ProviderIdentifier::iter()
+            .filter(|&id| self.provider(id).is_some())
+            .map(|id| id.to_string())
+            .collect();
+
+        self.resolutions
+            .as_ref()
+            .map(|cache| (cache, format!("resolution-{}-{}", active.join("+"), spec)))
+    }
+
     pub(crate) fn add_provider(
         &mut self,
         provider: Box<dyn ChatProvider>,
         priority: Option<u8>,
-        default_model: Option<String>,
-    ) {
+        default_model: Option<ModelId>,
+    ) -> Result<(), Error> {
         let id = provider.id();
 
-        let entry = self.providers.get_mut(&id).unwrap();
+        let entry = self
+            .providers
+            .entry(id)
+            .or_insert_with(|| ProviderEntry::new(id));
 
         if entry.provider.is_some() {
-            panic!("The same provider was added to the registry twice.");
+            return Err(Error::ProviderAlreadyAdded(id));
         }
 
         entry.provider.replace(provider);
@@ -186,6 +262,15 @@ impl Registry {
         }
 
         entry.default_model = default_model;
+
+        debug!(
+            provider = %id,
+            priority = entry.priority,
+            default_model = entry.default_model.as_deref(),
+            "activated provider"
+        );
+
+        Ok(())
     }
 
     pub(crate) fn empty(&self) -> bool {
@@ -199,9 +284,7 @@ impl Registry {
     }
 
     pub(crate) fn provider(&self, id: ProviderIdentifier) -> Option<&Box<dyn ChatProvider>> {
-        let ent = self.providers.get(&id).unwrap();
-
-        ent.provider.as_ref()
+        self.providers.get(&id)?.provider.as_ref()
     }
 
     pub(crate) fn active_provider(
@@ -215,9 +298,9 @@ impl Registry {
     }
 
     pub(crate) fn priority(&self, id: ProviderIdentifier) -> u8 {
-        let ent = self.providers.get(&id).unwrap();
-
-        ent.priority
+        self.providers
+            .get(&id)
+            .map_or_else(|| default_priority(id), |ent| ent.priority)
     }
 
     pub(crate) async fn registred_models(&self) -> Result<Vec<ProvidedModel>, Error> {
@@ -249,15 +332,13 @@ impl Registry {
         let mut models = Vec::new();
 
         for id in ProviderIdentifier::iter() {
-            let ProviderEntry {
-                provider,
-                priority: _,
-                default_model,
-            } = self.providers.get(&id).unwrap();
-
-            let provider = match provider {
-                Some(provider) => provider,
-                None => continue,
+            let (provider, default_model) = match self.providers.get(&id) {
+                Some(ProviderEntry {
+                    provider: Some(provider),
+                    default_model,
+                    ..
+                }) => (provider, default_model),
+                _ => continue,
             };
 
             let default_model = if default_model.is_none() {
@@ -265,7 +346,8 @@ impl Registry {
                     .default_model()
                     .await
                     .map_err(|e| Error::DefaultModelFailed(id, e))?
-                    .map(|model| model.id)
+                    .map(|model| ModelId::new(model.id))
+                    .transpose()?
             } else {
                 default_model.clone()
             };
@@ -281,8 +363,8 @@ impl Registry {
 }
 
 pub(crate) struct ModelResolver {
-    models: HashMap<String, ProviderIdentifier>,
-    default_model: Option<(String, ProviderIdentifier)>,
+    models: HashMap<ModelId, ProviderIdentifier>,
+    default_model: Option<(ModelId, ProviderIdentifier)>,
 }
 
 impl ModelResolver {
@@ -297,14 +379,25 @@ impl ModelResolver {
             model,
         } in registry.registred_models().await?
         {
-            if let Some(alt_id) = resolver.models.get_mut(&model.id) {
+            // Models which could never be named in a spec are not resolvable
+            let model_id = match ModelId::new(model.id) {
+                Ok(model_id) => model_id,
+                Err(err) => {
+                    debug!(provider = %id, error = %err, "skipping a model");
+                    continue;
+                }
+            };
+
+            if let Some(alt_id) = resolver.models.get_mut(&model_id) {
                 if registry.priority(*alt_id) >= registry.priority(id) {
+                    trace!(model = %model_id, provider = %alt_id, shadowed = %id, "model served by multiple providers");
                     continue;
                 }
 
+                trace!(model = %model_id, provider = %id, shadowed = %alt_id, "model served by multiple providers");
                 *alt_id = id;
             } else {
-                resolver.models.insert(model.id, id);
+                resolver.models.insert(model_id, id);
             }
         }
 
@@ -332,8 +425,8 @@ impl ModelResolver {
 
     pub(crate) fn resolve<S: AsModelId>(&self, spec: S) -> Result<ModelSpec, Error> {
         match spec.model_id() {
-            Some(model_id) => match self.models.get(model_id) {
-                Some(id) => Ok(ModelSpec::resolved(*id, model_id.to_string())),
+            Some(model_id) => match self.models.get_key_value(model_id) {
+                Some((model_id, id)) => Ok(ModelSpec::resolved(*id, model_id.clone())),
                 None => Err(Error::ModelNotFound(model_id.to_string())),
             },
             None => match &self.default_model {
@@ -343,3 +436,89 @@ impl ModelResolver {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::providers::providers::OllamaProvider;
+
+    #[test]
+    fn test_add_provider_twice() {
+        let mut registry = Registry::new();
+
+        assert!(registry
+            .add_provider(Box::new(OllamaProvider::new()), None, None)
+            .is_ok());
+
+        assert!(matches!(
+            registry.add_provider(Box::new(OllamaProvider::new()), Some(1), None),
+            Err(Error::ProviderAlreadyAdded(ProviderIdentifier::Ollama))
+        ));
+
+        // The provider which was added first is kept
+        assert_eq!(
+            registry.priority(ProviderIdentifier::Ollama),
+            default_priority(ProviderIdentifier::Ollama)
+        );
+    }
+
+    #[test]
+    fn test_unresolved_specs() {
+        let unresolved = |spec: Option<&str>| {
+            let spec = ModelSpec::parse(spec.map(str::to_string)).unwrap();
+
+            match spec.provider_model_ids() {
+                Err(Error::UnresolvedSpec(spec)) => spec,
+                _ => panic!("expected the spec to be unresolved"),
+            }
+        };
+
+        assert_eq!(unresolved(None), "default_model");
+        assert_eq!(unresolved(Some("llama3")), "llama3");
+
+        let (provider, model) = ModelSpec::parse(Some("ollama/llama3".into()))
+            .unwrap()
+            .provider_model_ids()
+            .unwrap();
+
+        assert_eq!(provider, ProviderIdentifier::Ollama);
+        assert_eq!(model.as_str(), "llama3");
+
+        assert!(matches!(
+            ModelSpec::parse(Some("olama/llama3".into())),
+            Err(Error::ProviderNotFound(provider)) if provider == "olama"
+        ));
+
+        // Gateways name models after their vendors
+        let (provider, model) = ModelSpec::parse(Some("openai/openai/gpt-4o".into()))
+            .unwrap()
+            .provider_model_ids()
+            .unwrap();
+
+        assert_eq!(provider, ProviderIdentifier::OpenAI);
+        assert_eq!(model.as_str(), "openai/gpt-4o");
+
+        assert!(ModelSpec::parse(Some("ollama/ollama/llama3".into())).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_empty_registry() {
+        let registry = Registry::new();
+
+        assert!(matches!(
+            registry.active_provider(ProviderIdentifier::OpenAI),
+            Err(Error::ProviderNotActivated(_))
+        ));
+
+        let resolver = ModelResolver::build(&registry).await.unwrap();
+
+        assert!(matches!(
+            resolver.resolve("llama3"),
+            Err(Error::ModelNotFound(model)) if model == "llama3"
+        ));
+        assert!(matches!(
+            resolver.resolve(ModelSpec::default()),
+            Err(Error::DefaultModelUnset)
+        ));
+    }
+}
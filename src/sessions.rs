@@ -0,0 +1,505 @@
+//! Persistence for chat conversations
+//!
+//! Each conversation is saved as a "session" in the session store. A session
+//! records the chat messages exchanged with the model, along with the model
+//! which produced each response, so the conversation can later be resumed.
+//!
+//! Sessions are stored as JSON documents, one file per session, under
+//! `$XDG_DATA_HOME/xtalk/sessions` (or `~/.local/share/xtalk/sessions` if
+//! `XDG_DATA_HOME` is unset). Session files are only readable by the current
+//! user since conversations may contain sensitive information.
+//!
+//! Titles and tags generated for sessions are kept apart from the sessions,
+//! in `labels.jsonl` in the same directory, so labeling a session never races
+//! with the chat which is recording it. Labels are appended, and the last
+//! labels of each session take precedence. Titles given by the user are
+//! recorded the same way. Sessions which have no title are titled by their
+//! first prompt.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::{DirBuilder, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::chat;
+use crate::providers::{AsyncMessageIterator, FinishReason, Usage};
+use crate::utils::dirs::data_dir;
+use crate::warn;
+
+#[derive(Error, Debug)]
+pub(crate) enum Error {
+    /// Neither `XDG_DATA_HOME` nor `HOME` is defined
+    #[error("could not determine the session directory, neither XDG_DATA_HOME nor HOME is set")]
+    NoDataDirectory,
+    /// No session exists with the specified identifier
+    #[error("session \"{0}\" does not exist")]
+    NotFound(String),
+    /// The session identifier contains characters which are not permitted
+    #[error("\"{0}\" is not a valid session identifier")]
+    InvalidId(String),
+    /// Reading or writing the session failed
+    #[error("failed to access session \"{0}\": {1}")]
+    Io(String, #[source] io::Error),
+    /// The session file could not be parsed
+    #[error("failed to parse session \"{0}\": {1}")]
+    Malformed(String, #[source] serde_json::Error),
+    /// The labels of the sessions could not be parsed
+    #[error("failed to parse the session labels on line {0}: {1}")]
+    MalformedLabels(usize, #[source] serde_json::Error),
+}
+
+/// A chat message as it is recorded in a session
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct SessionMessage {
+    #[serde(flatten)]
+    pub message: chat::Message,
+    /// The model which authored the message, if it was authored by a model
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model_id: Option<String>,
+    #[serde(flatten)]
+    pub response: ResponseMetadata,
+}
+
+/// What the provider reported about a response. Both are absent for
+/// messages which were not generated, and for responses which were cancelled.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub(crate) struct ResponseMetadata {
+    /// The tokens used to generate the response
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    /// Why the model stopped generating
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finish_reason: Option<FinishReason>,
+}
+
+impl ResponseMetadata {
+    /// Reads the metadata of a completion. This can only be called once the
+    /// completion is exhausted.
+    pub(crate) fn from_completion(completion: &dyn AsyncMessageIterator) -> ResponseMetadata {
+        ResponseMetadata {
+            usage: Some(completion.usage().clone()),
+            finish_reason: Some(completion.finish_reason()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Session {
+    /// A unique identifier for the session
+    pub id: String,
+    /// When the session was started
+    pub created: DateTime<Local>,
+    /// When the session was last saved
+    pub updated: DateTime<Local>,
+    /// The model spec of the model which was last used in the session
+    #[serde(default)]
+    pub model: Option<String>,
+    /// The seed the responses were sampled with, if one was given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u32>,
+    /// The messages exchanged in the conversation
+    #[serde(default)]
+    pub messages: Vec<SessionMessage>,
+}
+
+/// A title and tags generated for a session by a model
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct Labels {
+    pub session_id: String,
+    pub title: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// The number of messages in the session when it was labeled
+    pub messages: usize,
+    /// Whether the title was given by the user, in which case it is kept
+    /// when the session is labeled again
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub renamed: bool,
+}
+
+/// The longest title taken from the first prompt of a session, in characters
+const MAX_PROMPT_TITLE: usize = 60;
+
+fn generate_id() -> String {
+    let mut rng = StdRng::from_entropy();
+
+    let mut bytes = [0u8; 6];
+
+    rng.fill_bytes(&mut bytes);
+
+    let mut id = String::new();
+
+    for b in bytes {
+        write!(id, "{:02x}", b).unwrap();
+    }
+
+    id
+}
+
+impl Session {
+    pub(crate) fn new() -> Session {
+        let now = Local::now();
+
+        Session {
+            id: generate_id(),
+            created: now,
+            updated: now,
+            model: None,
+            seed: None,
+            messages: Vec::new(),
+        }
+    }
+
+    /// The title of the session: the one it was labeled with, or else the
+    /// first line of its first prompt, shortened
+    pub(crate) fn title(&self, labels: Option<&Labels>) -> Option<String> {
+        if let Some(labels) = labels {
+            return Some(labels.title.clone());
+        }
+
+        let prompt = self
+            .messages
+            .iter()
+            .find(|msg| matches!(msg.message.role, chat::Role::User))?;
+
+        let line = prompt.message.content.trim().lines().next()?;
+
+        if line.chars().count() > MAX_PROMPT_TITLE {
+            let shortened: String = line.chars().take(MAX_PROMPT_TITLE - 1).collect();
+
+            Some(format!("{}…", shortened.trim_end()))
+        } else {
+            Some(line.to_string())
+        }
+    }
+}
+
+fn validate_id(id: &str) -> Result<(), Error> {
+    let valid = !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidId(id.to_string()))
+    }
+}
+
+/// A directory of saved sessions
+pub(crate) struct SessionStore {
+    dir: PathBuf,
+}
+
+impl SessionStore {
+    /// Opens the session store in the default location
+    pub(crate) fn open() -> Result<SessionStore, Error> {
+        let dir = data_dir().ok_or(Error::NoDataDirectory)?;
+
+        Ok(SessionStore::with_dir(dir.join("xtalk/sessions")))
+    }
+
+    pub(crate) fn with_dir(dir: PathBuf) -> SessionStore {
+        SessionStore { dir }
+    }
+
+    /// The directory the sessions are saved in
+    pub(crate) fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    fn labels_path(&self) -> PathBuf {
+        self.dir.join("labels.jsonl")
+    }
+
+    fn create_dir(&self) -> io::Result<()> {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.dir)
+    }
+
+    /// Loads the session with the specified identifier
+    pub(crate) fn load(&self, id: &str) -> Result<Session, Error> {
+        validate_id(id)?;
+
+        let raw = std::fs::read_to_string(self.path(id)).map_err(|e| match e.kind() {
+            ErrorKind::NotFound => Error::NotFound(id.to_string()),
+            _ => Error::Io(id.to_string(), e),
+        })?;
+
+        serde_json::from_str(&raw).map_err(|e| Error::Malformed(id.to_string(), e))
+    }
+
+    /// Saves the session, updating its modification time. The session is
+    /// written to a temporary file which replaces the previous version so an
+    /// interrupted write cannot corrupt the session.
+    pub(crate) fn save(&self, session: &mut Session) -> Result<(), Error> {
+        let io_err = |e| Error::Io(session.id.clone(), e);
+
+        self.create_dir().map_err(io_err)?;
+
+        session.updated = Local::now();
+
+        let serialized =
+            serde_json::to_string_pretty(session).expect("failed to serialize session");
+
+        let path = self.path(&session.id);
+        let partial = path.with_extension("json.partial");
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&partial)
+            .map_err(io_err)?;
+
+        file.write_all(serialized.as_bytes()).map_err(io_err)?;
+
+        std::fs::rename(&partial, &path).map_err(io_err)?;
+
+        Ok(())
+    }
+
+    /// Deletes the session with the specified identifier. Its labels are
+    /// left behind, but are never listed without the session.
+    pub(crate) fn delete(&self, id: &str) -> Result<(), Error> {
+        validate_id(id)?;
+
+        std::fs::remove_file(self.path(id)).map_err(|e| match e.kind() {
+            ErrorKind::NotFound => Error::NotFound(id.to_string()),
+            _ => Error::Io(id.to_string(), e),
+        })
+    }
+
+    /// Lists all saved sessions, ordered from the least to the most recently
+    /// updated. Sessions which cannot be read are skipped with a warning.
+    pub(crate) fn list(&self) -> Result<Vec<Session>, Error> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Error::Io(self.dir.to_string_lossy().into_owned(), e)),
+        };
+
+        let mut sessions = Vec::new();
+
+        for entry in entries {
+            let entry = entry.map_err(|e| Error::Io(self.dir.to_string_lossy().into_owned(), e))?;
+
+            let path = entry.path();
+
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+
+            let id = match path.file_stem() {
+                Some(stem) => stem.to_string_lossy().into_owned(),
+                None => continue,
+            };
+
+            match self.load(&id) {
+                Ok(session) => sessions.push(session),
+                Err(err) => warn!("skipping a session: {}", err),
+            }
+        }
+
+        sessions.sort_by_key(|s| s.updated);
+
+        Ok(sessions)
+    }
+
+    /// Loads the latest labels of each labeled session, keyed by session.
+    /// Lines which cannot be parsed are skipped with a warning.
+    pub(crate) fn labels(&self) -> Result<HashMap<String, Labels>, Error> {
+        let path = self.labels_path();
+
+        let raw = match std::fs::read_to_string(&path) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(Error::Io(path.to_string_lossy().into_owned(), e)),
+        };
+
+        let mut labels = HashMap::new();
+
+        for (i, line) in raw.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let entry: Labels = match serde_json::from_str(line) {
+                Ok(entry) => entry,
+                Err(err) => {
+                    warn!("{}", Error::MalformedLabels(i + 1, err));
+                    continue;
+                }
+            };
+
+            labels.insert(entry.session_id.clone(), entry);
+        }
+
+        Ok(labels)
+    }
+
+    /// Records the labels of a session, replacing any it had before
+    pub(crate) fn add_labels(&self, labels: &Labels) -> Result<(), Error> {
+        let io_err = |e| Error::Io(labels.session_id.clone(), e);
+
+        self.create_dir().map_err(io_err)?;
+
+        let mut line = serde_json::to_string(labels).expect("failed to serialize labels");
+        line.push('\n');
+
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(self.labels_path())
+            .map_err(io_err)?;
+
+        file.write_all(line.as_bytes()).map_err(io_err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::{Message, Role};
+
+    fn temp_store() -> SessionStore {
+        let dir = std::env::temp_dir().join(format!("xtalk-sessions-{}", generate_id()));
+
+        SessionStore::with_dir(dir)
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let store = temp_store();
+
+        let mut session = Session::new();
+        session.model = Some("ollama/gemma:2b".to_string());
+        session.messages.push(SessionMessage {
+            message: Message::new(Role::User, "Hello!".to_string()),
+            model_id: None,
+            response: ResponseMetadata::default(),
+        });
+        session.messages.push(SessionMessage {
+            message: Message::new(Role::Model, "Hi!".to_string()),
+            model_id: Some("gemma:2b".to_string()),
+            response: ResponseMetadata {
+                usage: Some(Usage {
+                    prompt_tokens: Some(12),
+                    completion_tokens: Some(3),
+                }),
+                finish_reason: Some(FinishReason::Stop),
+            },
+        });
+
+        store.save(&mut session).unwrap();
+
+        let loaded = store.load(&session.id).unwrap();
+
+        assert_eq!(loaded.id, session.id);
+        assert_eq!(loaded.model.as_deref(), Some("ollama/gemma:2b"));
+        assert_eq!(loaded.messages.len(), 2);
+        assert!(matches!(loaded.messages[0].message.role, Role::User));
+        assert_eq!(loaded.messages[1].message.content, "Hi!");
+        assert_eq!(loaded.messages[1].model_id.as_deref(), Some("gemma:2b"));
+        assert!(loaded.messages[0].response.usage.is_none());
+
+        let response = &loaded.messages[1].response;
+        assert_eq!(
+            response.usage.as_ref().and_then(|u| u.completion_tokens),
+            Some(3)
+        );
+        assert!(matches!(response.finish_reason, Some(FinishReason::Stop)));
+
+        let sessions = store.list().unwrap();
+        assert_eq!(sessions.len(), 1);
+
+        // Without labels, the session is titled by its first prompt
+        assert_eq!(loaded.title(None).as_deref(), Some("Hello!"));
+
+        store.delete(&session.id).unwrap();
+        assert!(store.list().unwrap().is_empty());
+
+        std::fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_session() {
+        let store = temp_store();
+
+        assert!(matches!(store.load("abc123"), Err(Error::NotFound(_))));
+        assert!(matches!(store.load("../abc"), Err(Error::InvalidId(_))));
+        assert!(matches!(store.delete("abc123"), Err(Error::NotFound(_))));
+        assert!(store.list().unwrap().is_empty());
+        assert!(store.labels().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_list_skips_malformed() {
+        let store = temp_store();
+
+        let mut session = Session::new();
+        store.save(&mut session).unwrap();
+
+        std::fs::write(store.path("abc123"), "{").unwrap();
+
+        let sessions = store.list().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].id, session.id);
+
+        std::fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn test_labels() {
+        let store = temp_store();
+
+        let labels = |title: &str, messages| Labels {
+            session_id: "abc123".to_string(),
+            title: title.to_string(),
+            tags: vec!["rust".to_string()],
+            messages,
+            renamed: false,
+        };
+
+        store.add_labels(&labels("Borrowing", 2)).unwrap();
+        store.add_labels(&labels("Lifetimes", 12)).unwrap();
+
+        let loaded = store.labels().unwrap();
+
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded["abc123"].title, "Lifetimes");
+        assert_eq!(loaded["abc123"].messages, 12);
+
+        // A line which was cut short does not hide the labels before it
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(store.labels_path())
+            .unwrap();
+        file.write_all(b"{\"session_id\":\"abc").unwrap();
+
+        let loaded = store.labels().unwrap();
+
+        assert_eq!(loaded["abc123"].title, "Lifetimes");
+
+        // The labels are not mistaken for a session
+        assert!(store.list().unwrap().is_empty());
+
+        std::fs::remove_dir_all(&store.dir).unwrap();
+    }
+}
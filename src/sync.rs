@@ -0,0 +1,603 @@
+//! Encrypted synchronization of sessions and prompts between machines
+//!
+//! The saved sessions (with their labels) and the templates and personas
+//! kept in the prompts directory are collected into a bundle which is encrypted with a
+//! passphrase before it leaves the machine, so the remote only ever holds
+//! ciphertext. The bundle is a single file, `xtalk.age`, which also hides the
+//! names of the files. It is encrypted with age, so it can be decrypted by
+//! hand with `age --decrypt`.
+//!
+//! Bundles are merged file by file, the most recently modified version of
+//! each file taking precedence. Files are never deleted by a merge.
+//!
+//! The remote is either a git repository, which is cloned into
+//! `$XDG_CACHE_HOME/xtalk/sync/git`, or a directory which rsync can reach,
+//! such as `host:xtalk` or a WebDAV share mounted locally.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use age::secrecy::SecretString;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// The name of the bundle on the remote
+const BUNDLE_NAME: &str = "xtalk.age";
+
+/// The branch of the git repository the bundle is committed to
+const GIT_BRANCH: &str = "main";
+
+#[derive(Error, Debug)]
+pub(crate) enum Error {
+    /// Reading or writing a local file failed
+    #[error("failed to access {0}: {1}")]
+    Io(String, #[source] io::Error),
+    /// The bundle could not be encrypted
+    #[error("failed to encrypt the bundle: {0}")]
+    Encrypt(#[from] age::EncryptError),
+    /// The bundle could not be decrypted, usually due to a wrong passphrase
+    #[error("failed to decrypt the bundle, the passphrase may be wrong: {0}")]
+    Decrypt(#[from] age::DecryptError),
+    /// The decrypted bundle could not be parsed
+    #[error("failed to parse the bundle: {0}")]
+    Malformed(#[source] serde_json::Error),
+    /// A program which reaches the remote could not be run
+    #[error("failed to run {0}: {1}")]
+    Spawn(String, #[source] io::Error),
+    /// A command which reaches the remote failed
+    #[error("{0} failed: {1}")]
+    Command(String, String),
+}
+
+/// A local directory which is synchronized
+pub(crate) struct Dir {
+    /// The name of the directory in the bundle
+    pub name: &'static str,
+    pub path: PathBuf,
+    /// The extensions of the files which are synchronized
+    pub extensions: &'static [&'static str],
+}
+
+impl Dir {
+    fn includes(&self, file_name: &str) -> bool {
+        !file_name.contains('/')
+            && !file_name.starts_with('.')
+            && Path::new(file_name)
+                .extension()
+                .is_some_and(|ext| self.extensions.iter().any(|allowed| ext == *allowed))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct File {
+    modified: DateTime<Utc>,
+    content: String,
+}
+
+/// The synchronized files, keyed by `<dir>/<file name>`
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub(crate) struct Bundle {
+    files: BTreeMap<String, File>,
+}
+
+impl Bundle {
+    /// Reads the synchronized files of the directories
+    pub(crate) fn collect(dirs: &[Dir]) -> Result<Bundle, Error> {
+        let mut bundle = Bundle::default();
+
+        for dir in dirs {
+            let io_err = |e| Error::Io(dir.path.to_string_lossy().into_owned(), e);
+
+            let entries = match fs::read_dir(&dir.path) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(io_err(e)),
+            };
+
+            for entry in entries {
+                let entry = entry.map_err(io_err)?;
+                let file_name = entry.file_name().to_string_lossy().into_owned();
+
+                if !dir.includes(&file_name) || !entry.file_type().map_err(io_err)?.is_file() {
+                    continue;
+                }
+
+                let path = entry.path();
+                let io_err = |e| Error::Io(path.to_string_lossy().into_owned(), e);
+
+                let modified = entry
+                    .metadata()
+                    .and_then(|m| m.modified())
+                    .map_err(io_err)?;
+                let content = fs::read_to_string(&path).map_err(io_err)?;
+
+                bundle.files.insert(
+                    format!("{}/{}", dir.name, file_name),
+                    File {
+                        modified: modified.into(),
+                        content,
+                    },
+                );
+            }
+        }
+
+        Ok(bundle)
+    }
+
+    /// Takes the files of the other bundle which are missing from this one,
+    /// or were modified more recently, returning their keys. Logs, such as
+    /// the session labels, are appended to on each machine, so the lines of
+    /// both are kept instead.
+    pub(crate) fn merge(&mut self, other: &Bundle) -> Vec<String> {
+        let mut taken = Vec::new();
+
+        for (key, file) in &other.files {
+            let merged = match self.files.get(key) {
+                None => file.clone(),
+                Some(current) if key.ends_with(".jsonl") => {
+                    let merged = merge_lines(current, file);
+
+                    if merged.content == current.content {
+                        continue;
+                    }
+
+                    merged
+                }
+                Some(current) if file.modified > current.modified => file.clone(),
+                Some(_) => continue,
+            };
+
+            self.files.insert(key.clone(), merged);
+            taken.push(key.clone());
+        }
+
+        taken
+    }
+
+    /// Writes the files to the directories they belong in, keeping their
+    /// modification times so they are not mistaken for local changes
+    pub(crate) fn unpack(&self, keys: &[String], dirs: &[Dir]) -> Result<(), Error> {
+        for key in keys {
+            let Some(file) = self.files.get(key) else {
+                continue;
+            };
+
+            // Keys which do not name a synchronized file are ignored, rather
+            // than written somewhere they do not belong
+            let Some((dir, file_name)) = key
+                .split_once('/')
+                .and_then(|(name, file_name)| {
+                    Some((dirs.iter().find(|d| d.name == name)?, file_name))
+                })
+                .filter(|(dir, file_name)| dir.includes(file_name))
+            else {
+                continue;
+            };
+
+            write_file(&dir.path, file_name, file)?;
+        }
+
+        Ok(())
+    }
+
+    /// Encrypts the bundle with the passphrase
+    pub(crate) fn seal(&self, passphrase: &SecretString) -> Result<Vec<u8>, Error> {
+        let plaintext = serde_json::to_vec(self).expect("failed to serialize the bundle");
+
+        let recipient = age::scrypt::Recipient::new(passphrase.clone());
+
+        Ok(age::encrypt(&recipient, &plaintext)?)
+    }
+
+    /// Decrypts a bundle sealed with the passphrase
+    pub(crate) fn open(ciphertext: &[u8], passphrase: &SecretString) -> Result<Bundle, Error> {
+        let identity = age::scrypt::Identity::new(passphrase.clone());
+
+        let plaintext = age::decrypt(&identity, ciphertext)?;
+
+        serde_json::from_slice(&plaintext).map_err(Error::Malformed)
+    }
+}
+
+/// Takes the union of the lines of two logs. The lines of the more recently
+/// modified log come last, so its entries take precedence over those which
+/// are only in the other.
+fn merge_lines(a: &File, b: &File) -> File {
+    let (older, newer) = if b.modified > a.modified {
+        (a, b)
+    } else {
+        (b, a)
+    };
+
+    let newer_lines: BTreeSet<&str> = newer.content.lines().collect();
+
+    let mut content = String::new();
+
+    for line in older.content.lines() {
+        if !newer_lines.contains(line) {
+            content.push_str(line);
+            content.push('\n');
+        }
+    }
+
+    if content.is_empty() {
+        return newer.clone();
+    }
+
+    content.push_str(&newer.content);
+
+    if !content.ends_with('\n') {
+        content.push('\n');
+    }
+
+    File {
+        modified: newer.modified,
+        content,
+    }
+}
+
+/// Replaces the file through a temporary file, so an interrupted write
+/// cannot leave it truncated
+fn write_file(dir: &Path, file_name: &str, file: &File) -> Result<(), Error> {
+    let path = dir.join(file_name);
+    let io_err = |e| Error::Io(path.to_string_lossy().into_owned(), e);
+
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .map_err(io_err)?;
+
+    let partial = dir.join(format!("{}.partial", file_name));
+
+    let mut out = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&partial)
+        .map_err(io_err)?;
+
+    out.write_all(file.content.as_bytes()).map_err(io_err)?;
+    out.set_modified(file.modified.into()).map_err(io_err)?;
+
+    fs::rename(&partial, &path).map_err(io_err)
+}
+
+/// Runs the command, failing with its error output if it does not succeed
+fn run(command: &mut Command, name: &str) -> Result<Output, Error> {
+    let output = command
+        .output()
+        .map_err(|e| Error::Spawn(name.to_string(), e))?;
+
+    if output.status.success() {
+        Ok(output)
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+
+        Err(Error::Command(
+            name.to_string(),
+            if stderr.is_empty() {
+                output.status.to_string()
+            } else {
+                stderr
+            },
+        ))
+    }
+}
+
+fn read_staged(path: &Path) -> Result<Vec<u8>, Error> {
+    fs::read(path).map_err(|e| Error::Io(path.to_string_lossy().into_owned(), e))
+}
+
+fn write_staged(path: &Path, bundle: &[u8]) -> Result<(), Error> {
+    let io_err = |e| Error::Io(path.to_string_lossy().into_owned(), e);
+
+    if let Some(dir) = path.parent() {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .map_err(io_err)?;
+    }
+
+    fs::write(path, bundle).map_err(io_err)
+}
+
+/// Where the encrypted bundle is kept
+pub(crate) trait Remote {
+    /// Downloads the bundle, or returns `None` if none has been stored yet
+    fn fetch(&mut self) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Replaces the bundle on the remote
+    fn store(&mut self, bundle: &[u8]) -> Result<(), Error>;
+}
+
+/// A git repository, where each synchronization is a commit
+pub(crate) struct GitRemote {
+    url: String,
+    /// The local clone of the repository
+    dir: PathBuf,
+    /// Identifies the machine in commit messages
+    hostname: String,
+}
+
+impl GitRemote {
+    pub(crate) fn new(url: String, dir: PathBuf, hostname: String) -> GitRemote {
+        GitRemote { url, dir, hostname }
+    }
+
+    fn git(&self) -> Command {
+        let mut command = Command::new("git");
+        command.arg("-C").arg(&self.dir);
+        command
+    }
+}
+
+impl Remote for GitRemote {
+    fn fetch(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        if self.dir.join(".git").exists() {
+            run(self.git().args(["fetch", "-q", "origin"]), "git fetch")?;
+        } else {
+            run(
+                Command::new("git")
+                    .args(["clone", "-q", &self.url])
+                    .arg(&self.dir),
+                "git clone",
+            )?;
+        }
+
+        let remote_branch = format!("refs/remotes/origin/{}", GIT_BRANCH);
+
+        // Nothing has been pushed to a new repository
+        let exists = self
+            .git()
+            .args(["rev-parse", "-q", "--verify", &remote_branch])
+            .output()
+            .is_ok_and(|output| output.status.success());
+
+        if !exists {
+            return Ok(None);
+        }
+
+        // Commits which failed to push are discarded, since the bundle is
+        // built again from the local files
+        run(
+            self.git()
+                .args(["checkout", "-q", "-f", "--detach", &remote_branch]),
+            "git checkout",
+        )?;
+
+        let path = self.dir.join(BUNDLE_NAME);
+
+        if path.exists() {
+            read_staged(&path).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn store(&mut self, bundle: &[u8]) -> Result<(), Error> {
+        write_staged(&self.dir.join(BUNDLE_NAME), bundle)?;
+
+        run(self.git().args(["add", BUNDLE_NAME]), "git add")?;
+
+        let unchanged = self
+            .git()
+            .args(["diff", "--cached", "--quiet"])
+            .status()
+            .is_ok_and(|status| status.success());
+
+        if unchanged {
+            return Ok(());
+        }
+
+        let message = format!("Sync from {}", self.hostname);
+
+        // The commits are made by xtalk rather than the user, and must not
+        // wait on a signing key
+        run(
+            self.git().args([
+                "-c",
+                "user.name=xtalk",
+                "-c",
+                "user.email=xtalk@localhost",
+                "-c",
+                "commit.gpgsign=false",
+                "commit",
+                "-q",
+                "-m",
+                &message,
+            ]),
+            "git commit",
+        )?;
+
+        run(
+            self.git().args([
+                "push",
+                "-q",
+                "origin",
+                &format!("HEAD:refs/heads/{}", GIT_BRANCH),
+            ]),
+            "git push",
+        )?;
+
+        Ok(())
+    }
+}
+
+/// A directory reached with rsync, which may be local or on another host
+pub(crate) struct RsyncRemote {
+    /// The path of the bundle on the remote
+    remote_path: String,
+    /// Where the bundle is staged locally
+    staged: PathBuf,
+}
+
+impl RsyncRemote {
+    pub(crate) fn new(remote: &str, dir: PathBuf) -> RsyncRemote {
+        RsyncRemote {
+            remote_path: format!("{}/{}", remote.trim_end_matches('/'), BUNDLE_NAME),
+            staged: dir.join(BUNDLE_NAME),
+        }
+    }
+}
+
+impl Remote for RsyncRemote {
+    fn fetch(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        write_staged(&self.staged, &[])?;
+
+        // The messages are not translated, so that a missing bundle can be
+        // told apart from other failures
+        let fetched = run(
+            Command::new("rsync")
+                .env("LC_ALL", "C")
+                .args(["-q", &self.remote_path])
+                .arg(&self.staged),
+            "rsync",
+        );
+
+        match fetched {
+            Ok(_) => read_staged(&self.staged).map(Some),
+            Err(Error::Command(_, stderr)) if stderr.contains("No such file or directory") => {
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn store(&mut self, bundle: &[u8]) -> Result<(), Error> {
+        write_staged(&self.staged, bundle)?;
+
+        run(
+            Command::new("rsync")
+                .arg("-q")
+                .arg(&self.staged)
+                .arg(&self.remote_path),
+            "rsync",
+        )?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(modified: &str, content: &str) -> File {
+        File {
+            modified: modified.parse().unwrap(),
+            content: content.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut local = Bundle::default();
+        local.files.insert(
+            "sessions/a.json".to_string(),
+            file("2024-06-02T00:00:00Z", "local a"),
+        );
+        local.files.insert(
+            "sessions/b.json".to_string(),
+            file("2024-06-01T00:00:00Z", "local b"),
+        );
+
+        let mut remote = Bundle::default();
+        remote.files.insert(
+            "sessions/a.json".to_string(),
+            file("2024-06-01T00:00:00Z", "remote a"),
+        );
+        remote.files.insert(
+            "sessions/b.json".to_string(),
+            file("2024-06-03T00:00:00Z", "remote b"),
+        );
+        remote.files.insert(
+            "prompts/c.toml".to_string(),
+            file("2024-06-01T00:00:00Z", "remote c"),
+        );
+
+        assert_eq!(
+            local.merge(&remote),
+            vec!["prompts/c.toml", "sessions/b.json"]
+        );
+        assert_eq!(local.files["sessions/a.json"].content, "local a");
+        assert_eq!(local.files["sessions/b.json"].content, "remote b");
+        assert!(local.merge(&remote).is_empty());
+    }
+
+    #[test]
+    fn test_merge_labels() {
+        let labels = |id: &str, title: &str| {
+            format!(
+                "{{\"session_id\":\"{}\",\"title\":\"{}\",\"tags\":[],\"messages\":2}}\n",
+                id, title
+            )
+        };
+
+        let mut local = Bundle::default();
+        local.files.insert(
+            "sessions/labels.jsonl".to_string(),
+            file(
+                "2024-06-02T00:00:00Z",
+                &(labels("a", "Borrowing") + &labels("b", "Lifetimes")),
+            ),
+        );
+
+        let mut remote = Bundle::default();
+        remote.files.insert(
+            "sessions/labels.jsonl".to_string(),
+            file(
+                "2024-06-01T00:00:00Z",
+                &(labels("a", "Borrowing") + &labels("c", "Traits")),
+            ),
+        );
+
+        // The labels of both machines are kept, and those of the machine
+        // which labeled most recently come last
+        assert_eq!(local.merge(&remote), vec!["sessions/labels.jsonl"]);
+        assert_eq!(
+            local.files["sessions/labels.jsonl"].content,
+            labels("c", "Traits") + &labels("a", "Borrowing") + &labels("b", "Lifetimes")
+        );
+
+        assert_eq!(remote.merge(&local), vec!["sessions/labels.jsonl"]);
+        assert_eq!(
+            remote.files["sessions/labels.jsonl"].content,
+            local.files["sessions/labels.jsonl"].content
+        );
+
+        assert!(local.merge(&remote).is_empty());
+        assert!(remote.merge(&local).is_empty());
+    }
+
+    #[test]
+    fn test_seal() {
+        let passphrase = SecretString::from("correct horse".to_string());
+
+        let mut bundle = Bundle::default();
+        bundle.files.insert(
+            "prompts/a.toml".to_string(),
+            file("2024-06-01T00:00:00Z", "secret"),
+        );
+
+        let sealed = bundle.seal(&passphrase).unwrap();
+
+        assert!(!String::from_utf8_lossy(&sealed).contains("secret"));
+
+        let opened = Bundle::open(&sealed, &passphrase).unwrap();
+        assert_eq!(opened.files["prompts/a.toml"].content, "secret");
+
+        let wrong = SecretString::from("wrong".to_string());
+        assert!(matches!(
+            Bundle::open(&sealed, &wrong),
+            Err(Error::Decrypt(_))
+        ));
+    }
+}
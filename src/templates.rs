@@ -0,0 +1,452 @@
+//! Prompt templates
+//!
+//! A template is a reusable prompt defined in the configuration or in a file
+//! in `$XDG_CONFIG_HOME/xtalk/prompts` (by default, `~/.config/xtalk/prompts`), where `summarize.toml` defines the
+//! `summarize` template. Templates may contain placeholders, written as
+//! `{name}`, which are substituted when the template is rendered. In the
+//! REPL, placeholders without a value are left in place so they can be
+//! filled in by hand; on the command line, every placeholder needs a value.
+//! Literal braces are written as `{{` and `}}`.
+//!
+//! Teams can share libraries of templates and personas in directories listed
+//! in `prompt_paths`, which are only ever read. A library has the layout of
+//! the prompts directory, with personas in a `personas` subdirectory, e.g.
+//! `personas/reviewer.toml`. Where names collide, the configuration takes
+//! precedence over the prompts directory, which takes precedence over the
+//! libraries, and a library listed earlier takes precedence over one listed
+//! later.
+//!
+//! A few placeholders are built in, and are given a value when the template
+//! is rendered unless one is provided: `{date}` and `{time}` are the local
+//! date and time in ISO 8601 form, `{tz}` is the name of the local time zone,
+//! and `{hostname}` and `{user}` identify the machine and the user.
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+
+use chrono::Local;
+use thiserror::Error;
+
+use crate::utils::dirs;
+use crate::{config, warn};
+
+#[derive(Error, Debug, PartialEq)]
+pub(crate) enum Error {
+    /// A `{` which is not closed
+    #[error("unterminated placeholder at byte {0}")]
+    UnterminatedPlaceholder(usize),
+    /// A `}` which does not close a placeholder
+    #[error("unmatched \"}}\" at byte {0}, write \"}}}}\" for a literal brace")]
+    UnmatchedBrace(usize),
+    /// A placeholder name containing characters other than ASCII
+    /// alphanumerics, dashes, and underscores
+    #[error("invalid placeholder name \"{0}\"")]
+    InvalidPlaceholder(String),
+    /// Placeholders which must be substituted but have no value
+    #[error("no value was given for {}", .0.iter().map(|name| format!("{{{}}}", name)).collect::<Vec<_>>().join(", "))]
+    MissingValues(Vec<String>),
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment<'a> {
+    Text(&'a str),
+    Placeholder(&'a str),
+}
+
+fn is_placeholder_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Splits a template into literal text and placeholders
+fn parse(text: &str) -> Result<Vec<Segment<'_>>, Error> {
+    let mut segments = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '{' | '}' if chars.peek().map(|(_, next)| *next) == Some(c) => {
+                // Keep one of the two braces
+                segments.push(Segment::Text(&text[start..=i]));
+                chars.next();
+                start = i + 2;
+            }
+            '{' => {
+                let end = text[i..]
+                    .find('}')
+                    .map(|end| i + end)
+                    .ok_or(Error::UnterminatedPlaceholder(i))?;
+
+                let name = &text[i + 1..end];
+
+                if !is_placeholder_name(name) {
+                    return Err(Error::InvalidPlaceholder(name.to_string()));
+                }
+
+                segments.push(Segment::Text(&text[start..i]));
+                segments.push(Segment::Placeholder(name));
+
+                while chars.next_if(|(j, _)| *j <= end).is_some() {}
+
+                start = end + 1;
+            }
+            '}' => return Err(Error::UnmatchedBrace(i)),
+            _ => {}
+        }
+    }
+
+    segments.push(Segment::Text(&text[start..]));
+
+    segments.retain(|segment| *segment != Segment::Text(""));
+
+    Ok(segments)
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Template {
+    pub name: String,
+    pub description: Option<String>,
+    pub prompt: String,
+    /// The placeholders which must be given a value, if they are declared
+    pub variables: Option<Vec<String>>,
+}
+
+impl Template {
+    /// Substitutes the placeholders for which a value is provided
+    pub(crate) fn render(&self, vars: &HashMap<String, String>) -> Result<String, Error> {
+        let mut rendered = String::with_capacity(self.prompt.len());
+
+        for segment in parse(&self.prompt)? {
+            match segment {
+                Segment::Text(text) => rendered.push_str(text),
+                Segment::Placeholder(name) => match vars.get(name) {
+                    Some(value) => rendered.push_str(value),
+                    None => {
+                        rendered.push('{');
+                        rendered.push_str(name);
+                        rendered.push('}');
+                    }
+                },
+            }
+        }
+
+        Ok(rendered)
+    }
+
+    /// Lists the names of the placeholders in the order they first appear
+    pub(crate) fn placeholders(&self) -> Result<Vec<&str>, Error> {
+        let mut names = Vec::new();
+
+        for segment in parse(&self.prompt)? {
+            if let Segment::Placeholder(name) = segment {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+
+        Ok(names)
+    }
+
+    /// Substitutes every placeholder, failing if any lacks a value
+    pub(crate) fn render_all(&self, vars: &HashMap<String, String>) -> Result<String, Error> {
+        let missing: Vec<String> = self
+            .placeholders()?
+            .into_iter()
+            .filter(|name| !vars.contains_key(*name))
+            .map(str::to_string)
+            .collect();
+
+        if !missing.is_empty() {
+            return Err(Error::MissingValues(missing));
+        }
+
+        self.render(vars)
+    }
+}
+
+/// The names of the built-in placeholders
+pub(crate) const BUILTIN_PLACEHOLDERS: [&str; 5] = ["date", "time", "tz", "hostname", "user"];
+
+/// The values of the built-in placeholders at the current time
+pub(crate) fn builtin_vars() -> HashMap<String, String> {
+    let now = Local::now();
+
+    // Like the local time, the zone is taken from `TZ` when it is set. The UTC
+    // offset stands in for the zone when it cannot be named.
+    let tz = env::var("TZ")
+        .ok()
+        .map(|tz| tz.trim_start_matches(':').to_string())
+        .filter(|tz| !tz.is_empty())
+        .or_else(|| iana_time_zone::get_timezone().ok())
+        .unwrap_or_else(|| now.format("UTC%:z").to_string());
+
+    let mut vars = HashMap::from([
+        ("date".to_string(), now.format("%Y-%m-%d").to_string()),
+        ("time".to_string(), now.format("%H:%M").to_string()),
+        ("tz".to_string(), tz),
+        ("user".to_string(), whoami::username()),
+    ]);
+
+    if let Ok(hostname) = whoami::fallible::hostname() {
+        vars.insert("hostname".to_string(), hostname);
+    }
+
+    vars
+}
+
+/// Adds the built-in placeholders to the values, which take precedence
+pub(crate) fn with_builtins(vars: &HashMap<String, String>) -> HashMap<String, String> {
+    let mut all = builtin_vars();
+
+    all.extend(
+        vars.iter()
+            .map(|(name, value)| (name.clone(), value.clone())),
+    );
+
+    all
+}
+
+/// The directory containing templates defined in their own files
+pub(crate) fn prompts_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("xtalk/prompts"))
+}
+
+/// The directories templates and personas are read from, from the highest
+/// precedence to the lowest: the prompts directory, then the libraries
+pub(crate) fn library_dirs(prompt_paths: &[PathBuf]) -> Vec<PathBuf> {
+    prompts_dir()
+        .into_iter()
+        .chain(prompt_paths.iter().map(|path| dirs::expand_home(path)))
+        .collect()
+}
+
+/// Reads the definitions in `dir`, one per `.toml` file, such as templates.
+/// Files which cannot be read or parsed are skipped with a warning.
+fn file_definitions<T: DeserializeOwned>(dir: &Path, kind: &str) -> BTreeMap<String, T> {
+    let mut definitions = BTreeMap::new();
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return definitions,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+
+        if path.extension().is_none_or(|ext| ext != "toml") {
+            continue;
+        }
+
+        let name = match path.file_stem() {
+            Some(stem) => stem.to_string_lossy().into_owned(),
+            None => continue,
+        };
+
+        let definition = fs::read_to_string(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|contents| toml::from_str(&contents).map_err(|err| err.to_string()));
+
+        match definition {
+            Ok(definition) => {
+                definitions.insert(name, definition);
+            }
+            Err(err) => warn!("skipping the {} in {}: {}", kind, path.display(), err),
+        }
+    }
+
+    definitions
+}
+
+/// Merges the definitions in the directories, given from the highest
+/// precedence to the lowest, under those in the configuration
+fn merge_definitions<T: DeserializeOwned + Clone>(
+    dirs: &[PathBuf],
+    kind: &str,
+    configured: &BTreeMap<String, T>,
+) -> BTreeMap<String, T> {
+    let mut definitions = BTreeMap::new();
+
+    for dir in dirs.iter().rev() {
+        definitions.extend(file_definitions(dir, kind));
+    }
+
+    definitions.extend(
+        configured
+            .iter()
+            .map(|(name, definition)| (name.clone(), definition.clone())),
+    );
+
+    definitions
+}
+
+/// Collects the templates defined in the configuration, the prompts
+/// directory, and the libraries, ordered by name
+pub(crate) fn load_templates(
+    configured: &BTreeMap<String, config::Template>,
+    prompt_paths: &[PathBuf],
+) -> Vec<Template> {
+    merge_definitions(&library_dirs(prompt_paths), "template", configured)
+        .into_iter()
+        .map(|(name, template)| Template {
+            name,
+            description: template.description,
+            prompt: template.prompt,
+            variables: template.variables,
+        })
+        .collect()
+}
+
+/// Collects the personas defined in the configuration, and in the `personas`
+/// subdirectory of the prompts directory and of the libraries
+pub(crate) fn load_personas(
+    configured: &BTreeMap<String, config::Persona>,
+    prompt_paths: &[PathBuf],
+) -> BTreeMap<String, config::Persona> {
+    let dirs: Vec<PathBuf> = library_dirs(prompt_paths)
+        .into_iter()
+        .map(|dir| dir.join("personas"))
+        .collect();
+
+    merge_definitions(&dirs, "persona", configured)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(prompt: &str) -> Template {
+        Template {
+            name: "test".to_string(),
+            description: None,
+            prompt: prompt.to_string(),
+            variables: None,
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let vars = HashMap::from([("lang".to_string(), "French".to_string())]);
+
+        assert_eq!(
+            template("Translate {text} to {lang}.").render(&vars),
+            Ok("Translate {text} to French.".to_string())
+        );
+
+        assert_eq!(
+            template("fn main() {{ {lang} }}").render(&vars),
+            Ok("fn main() { French }".to_string())
+        );
+
+        assert_eq!(
+            template("Translate {text").render(&vars),
+            Err(Error::UnterminatedPlaceholder(10))
+        );
+
+        assert_eq!(
+            template("a } b").render(&vars),
+            Err(Error::UnmatchedBrace(2))
+        );
+
+        assert_eq!(
+            template("{two words}").render(&vars),
+            Err(Error::InvalidPlaceholder("two words".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_render_all() {
+        let vars = HashMap::from([("lang".to_string(), "French".to_string())]);
+
+        let translate = template("Translate {text} to {lang}, then {text} to {dialect}.");
+
+        assert_eq!(
+            translate.placeholders(),
+            Ok(vec!["text", "lang", "dialect"])
+        );
+
+        assert_eq!(
+            translate.render_all(&vars),
+            Err(Error::MissingValues(vec![
+                "text".to_string(),
+                "dialect".to_string()
+            ]))
+        );
+
+        assert_eq!(
+            template("Say hello in {lang}.").render_all(&vars),
+            Ok("Say hello in French.".to_string())
+        );
+    }
+
+    #[test]
+    fn test_merge_definitions() {
+        let root = env::temp_dir().join(format!("xtalk-prompts-{}", std::process::id()));
+        let (own, team) = (root.join("own"), root.join("team"));
+
+        for (dir, name, prompt) in [
+            (&own, "review", "own review"),
+            (&team, "review", "team review"),
+            (&team, "standup", "team standup"),
+            (&team, "explain", "team explain"),
+        ] {
+            fs::create_dir_all(dir).unwrap();
+            fs::write(
+                dir.join(format!("{}.toml", name)),
+                format!("prompt = \"{}\"", prompt),
+            )
+            .unwrap();
+        }
+
+        let configured = BTreeMap::from([(
+            "explain".to_string(),
+            config::Template {
+                description: None,
+                prompt: "configured explain".to_string(),
+                variables: None,
+            },
+        )]);
+
+        let merged = merge_definitions(&[own, team], "template", &configured);
+
+        let prompts: Vec<(&str, &str)> = merged
+            .iter()
+            .map(|(name, template)| (name.as_str(), template.prompt.as_str()))
+            .collect();
+
+        assert_eq!(
+            prompts,
+            vec![
+                ("explain", "configured explain"),
+                ("review", "own review"),
+                ("standup", "team standup"),
+            ]
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_with_builtins() {
+        let vars = HashMap::from([("date".to_string(), "yesterday".to_string())]);
+
+        let rendered = template("{date} {tz} {time}")
+            .render_all(&with_builtins(&vars))
+            .unwrap();
+
+        assert!(rendered.starts_with("yesterday "));
+        assert!(!rendered.contains('{'));
+
+        let builtins = builtin_vars();
+        assert!(BUILTIN_PLACEHOLDERS
+            .iter()
+            .all(|name| *name == "hostname" || builtins.contains_key(*name)));
+    }
+}
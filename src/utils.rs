@@ -1 +1,7 @@
+pub(crate) mod diff;
+pub(crate) mod dirs;
 pub(crate) mod errors;
+pub(crate) mod format;
+pub(crate) mod highlight;
+pub(crate) mod jsonrpc;
+pub(crate) mod markdown;
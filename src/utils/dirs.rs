@@ -0,0 +1,82 @@
+//! Locations of user files, per the XDG base directory specification
+//!
+//! The XDG variables are honored on every platform. When they are unset,
+//! Windows uses its conventional `%APPDATA%` and `%LOCALAPPDATA%`
+//! directories, while other platforms use the XDG defaults relative to the
+//! home directory.
+
+use std::path::{Path, PathBuf};
+
+/// Reads an absolute directory from the environment
+fn env_dir(var: &str) -> Option<PathBuf> {
+    let dir = PathBuf::from(std::env::var_os(var)?);
+
+    // Relative paths are invalid per the XDG base directory specification
+    dir.is_absolute().then_some(dir)
+}
+
+/// The home directory, which is `%USERPROFILE%` on Windows unless `HOME` is set
+pub(crate) fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| {
+            if cfg!(windows) {
+                std::env::var_os("USERPROFILE")
+            } else {
+                None
+            }
+        })
+        .map(PathBuf::from)
+}
+
+/// Reads a base directory from the environment, falling back to the
+/// conventional directory on Windows or a directory relative to the home
+/// directory elsewhere
+fn base_dir(var: &str, windows_var: &str, fallback: &str) -> Option<PathBuf> {
+    if let Some(dir) = env_dir(var) {
+        return Some(dir);
+    }
+
+    if cfg!(windows) {
+        if let Some(dir) = env_dir(windows_var) {
+            return Some(dir);
+        }
+    }
+
+    home_dir().map(|home| home.join(fallback))
+}
+
+/// `$XDG_CONFIG_HOME`, or `~/.config` if unset (`%APPDATA%` on Windows)
+pub(crate) fn config_dir() -> Option<PathBuf> {
+    base_dir("XDG_CONFIG_HOME", "APPDATA", ".config")
+}
+
+/// `$XDG_DATA_HOME`, or `~/.local/share` if unset (`%LOCALAPPDATA%` on Windows)
+pub(crate) fn data_dir() -> Option<PathBuf> {
+    base_dir("XDG_DATA_HOME", "LOCALAPPDATA", ".local/share")
+}
+
+/// `$XDG_CACHE_HOME`, or `~/.cache` if unset (`%LOCALAPPDATA%` on Windows)
+pub(crate) fn cache_dir() -> Option<PathBuf> {
+    base_dir("XDG_CACHE_HOME", "LOCALAPPDATA", ".cache")
+}
+
+/// The conventional configuration directory of the platform when it is not
+/// the XDG default: `~/Library/Application Support` on macOS and
+/// `%APPDATA%` on Windows. Configuration files are also searched for here.
+pub(crate) fn platform_config_dir() -> Option<PathBuf> {
+    if cfg!(target_os = "macos") {
+        home_dir().map(|home| home.join("Library/Application Support"))
+    } else if cfg!(windows) {
+        env_dir("APPDATA")
+    } else {
+        None
+    }
+}
+
+/// Expands a leading `~` to the home directory
+pub(crate) fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
@@ -1,6 +1,23 @@
 use crate::color::{self, MaybePaint};
 
+// Exit statuses, so scripts can tell classes of failures apart
+
+/// Any failure which does not fit into one of the classes below
 pub const DEFAULT_EXIT_CODE: i32 = 1;
+/// The command line was invalid or the input was missing. This is also the
+/// status with which argument parsing fails.
+pub const USAGE_EXIT_CODE: i32 = 2;
+/// The API key is missing or was rejected
+pub const AUTH_EXIT_CODE: i32 = 3;
+/// The model or provider does not exist
+pub const NOT_FOUND_EXIT_CODE: i32 = 4;
+/// The provider could not be reached or did not respond in time
+pub const CONNECTION_EXIT_CODE: i32 = 5;
+/// The conversation does not fit into the context of the model
+pub const CONTEXT_EXCEEDED_EXIT_CODE: i32 = 6;
+/// A rate limit was reached or the provider is overloaded, so the request
+/// may succeed later
+pub const UNAVAILABLE_EXIT_CODE: i32 = 7;
 
 pub(crate) fn fmt_error<S: AsRef<str>>(f: &mut std::fmt::Formatter, text: S) -> std::fmt::Result {
     let text: &str = text.as_ref();
@@ -59,8 +76,16 @@ macro_rules! error {
 #[macro_export]
 macro_rules! die {
     ($($arg:tt)*) => ({
+        $crate::die_with!($crate::utils::errors::DEFAULT_EXIT_CODE, $($arg)*)
+    })
+}
+
+/// Like `die!`, but exits with the specified status
+#[macro_export]
+macro_rules! die_with {
+    ($code:expr, $($arg:tt)*) => ({
         let formatted = format!($($arg)*);
         $crate::utils::errors::error_internal(&formatted);
-        ::std::process::exit($crate::utils::errors::DEFAULT_EXIT_CODE);
+        ::std::process::exit($code);
     })
 }
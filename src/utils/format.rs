@@ -0,0 +1,179 @@
+//! Helpers for presenting quantities and text to the user
+
+use std::borrow::Cow;
+use std::time::Duration;
+
+/// Formats a number of bytes using decimal units, as Ollama does.
+pub(crate) fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Keeps the last `max_chars` characters of the text
+fn last_chars(text: &str, max_chars: usize) -> &str {
+    let skipped = text.chars().count().saturating_sub(max_chars);
+
+    match text.char_indices().nth(skipped) {
+        Some((at, _)) => &text[at..],
+        None => "",
+    }
+}
+
+/// Truncates text to at most `max_chars` characters by dropping whole lines
+/// from the beginning, keeping the most recent output. A marker noting how
+/// many lines were dropped takes their place, and counts against the limit.
+/// If the last line does not fit on its own, its end is kept.
+pub(crate) fn truncate_start(text: &str, max_chars: usize) -> Cow<'_, str> {
+    if text.chars().count() <= max_chars {
+        return Cow::Borrowed(text);
+    }
+
+    let marker = |dropped: usize| {
+        format!(
+            "[{} {} truncated]\n",
+            dropped,
+            if dropped == 1 { "line" } else { "lines" }
+        )
+    };
+
+    let lines: Vec<&str> = text.lines().collect();
+
+    // Lines are counted without their carriage returns and a trailing
+    // newline, so the text may fit once they are removed
+    let normalized = lines.join("\n");
+
+    if normalized.chars().count() <= max_chars {
+        return Cow::Owned(normalized);
+    }
+
+    let mut kept = 0;
+    let mut chars = 0;
+
+    for line in lines.iter().rev() {
+        // Account for the newline which separates the line from the next
+        let len = line.chars().count() + usize::from(kept > 0);
+
+        let dropped = lines.len() - kept - 1;
+
+        if marker(dropped).chars().count() + chars + len > max_chars {
+            break;
+        }
+
+        chars += len;
+        kept += 1;
+    }
+
+    if kept == 0 {
+        // The line which is cut short is counted as dropped
+        let marker = marker(lines.len());
+        let room = max_chars.saturating_sub(marker.chars().count());
+
+        if room == 0 {
+            return Cow::Borrowed(last_chars(text, max_chars));
+        }
+
+        let last = lines.last().copied().unwrap_or_default();
+
+        return Cow::Owned(format!("{}{}", marker, last_chars(last, room)));
+    }
+
+    let dropped = lines.len() - kept;
+
+    Cow::Owned(format!(
+        "{}{}",
+        marker(dropped),
+        lines[dropped..].join("\n")
+    ))
+}
+
+/// Parses a duration given in seconds, optionally with a unit of `s`, `m`,
+/// or `h` (e.g., 90s, 10m, or 1h)
+pub(crate) fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let (value, unit) = match duration.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => duration.split_at(i),
+        None => (duration, "s"),
+    };
+
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return Err(format!("unknown unit \"{}\", expected s, m, or h", unit)),
+    };
+
+    let Ok(value) = value.parse::<u64>() else {
+        return Err(format!("expected a duration, found \"{}\"", duration));
+    };
+
+    match value.checked_mul(seconds) {
+        Some(seconds) => Ok(Duration::from_secs(seconds)),
+        None => Err(format!("the duration \"{}\" is too long", duration)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_start() {
+        assert_eq!(truncate_start("a\nb\nc", 10), "a\nb\nc");
+        let text = format!("{}\n{}\n{}", "a".repeat(30), "b".repeat(10), "c".repeat(10));
+
+        assert_eq!(
+            truncate_start(&text, 40),
+            format!("[1 line truncated]\n{}\n{}", "b".repeat(10), "c".repeat(10))
+        );
+        assert_eq!(
+            truncate_start(&text, 39),
+            format!("[2 lines truncated]\n{}", "c".repeat(10))
+        );
+
+        // A long last line keeps its end, with the marker within the limit
+        let long = format!("one\n{}", "x".repeat(50));
+        let truncated = truncate_start(&long, 30);
+
+        assert_eq!(
+            truncated,
+            format!("[2 lines truncated]\n{}", "x".repeat(10))
+        );
+        assert_eq!(truncated.chars().count(), 30);
+
+        // Without room for the marker, the end of the text is kept alone
+        assert_eq!(truncate_start("one\ntwo\nthree", 2), "ee");
+
+        // Lines are counted once their carriage returns are removed, and no
+        // marker is added if no line is dropped
+        let crlf = text.replace('\n', "\r\n");
+
+        assert_eq!(truncate_start(&crlf, 52), text);
+        assert_eq!(
+            truncate_start(&crlf, 40),
+            format!("[1 line truncated]\n{}\n{}", "b".repeat(10), "c".repeat(10))
+        );
+        assert_eq!(truncate_start("one\ntwo\n", 7), "one\ntwo");
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_duration("0h"), Ok(Duration::ZERO));
+        assert!(parse_duration("5d").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration(&format!("{}h", u64::MAX / 60)).is_err());
+    }
+}
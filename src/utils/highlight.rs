@@ -0,0 +1,311 @@
+//! Lexical syntax highlighting of code
+//!
+//! Code is split into comments, strings, numbers, and keywords using a
+//! small table of the syntax of common languages. This is no substitute for
+//! a parser, but it is enough to make code in a transcript easier to read.
+//! Code in a language which is not recognized is left plain.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Plain,
+    Keyword,
+    String,
+    Comment,
+    Number,
+}
+
+struct Syntax {
+    line_comments: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    quotes: &'static [char],
+    keywords: &'static [&'static str],
+    /// Keywords are matched regardless of case, as in SQL
+    case_insensitive: bool,
+    /// A single quote only starts a character literal, as lifetimes and
+    /// labels also begin with one in Rust
+    char_literals: bool,
+}
+
+const C_LIKE_KEYWORDS: &[&str] = &[
+    "as",
+    "async",
+    "await",
+    "break",
+    "case",
+    "catch",
+    "class",
+    "const",
+    "continue",
+    "default",
+    "defer",
+    "do",
+    "else",
+    "enum",
+    "export",
+    "extends",
+    "false",
+    "finally",
+    "fn",
+    "for",
+    "func",
+    "function",
+    "go",
+    "if",
+    "impl",
+    "import",
+    "in",
+    "interface",
+    "let",
+    "loop",
+    "match",
+    "mod",
+    "mut",
+    "new",
+    "nil",
+    "null",
+    "package",
+    "private",
+    "protected",
+    "pub",
+    "public",
+    "return",
+    "self",
+    "static",
+    "struct",
+    "super",
+    "switch",
+    "this",
+    "throw",
+    "trait",
+    "true",
+    "try",
+    "type",
+    "typedef",
+    "undefined",
+    "use",
+    "var",
+    "void",
+    "where",
+    "while",
+    "yield",
+];
+
+const SCRIPT_KEYWORDS: &[&str] = &[
+    "and", "as", "begin", "case", "class", "def", "del", "do", "done", "elif", "else", "end",
+    "esac", "except", "export", "False", "fi", "finally", "for", "from", "function", "if",
+    "import", "in", "lambda", "local", "module", "nil", "None", "not", "or", "pass", "raise",
+    "return", "then", "True", "try", "until", "while", "with", "yield",
+];
+
+const SQL_KEYWORDS: &[&str] = &[
+    "and", "as", "by", "create", "delete", "from", "group", "having", "insert", "into", "join",
+    "left", "limit", "not", "null", "on", "or", "order", "select", "set", "table", "update",
+    "values", "where",
+];
+
+fn syntax(lang: &str) -> Option<Syntax> {
+    let lang = lang.to_ascii_lowercase();
+
+    let syntax = match lang.as_str() {
+        "rust" | "rs" => Syntax {
+            line_comments: &["//"],
+            block_comment: Some(("/*", "*/")),
+            quotes: &['"', '\''],
+            keywords: C_LIKE_KEYWORDS,
+            case_insensitive: false,
+            char_literals: true,
+        },
+        "c" | "h" | "cpp" | "c++" | "cc" | "hpp" | "cs" | "csharp" | "java" | "kotlin" | "kt"
+        | "swift" | "go" | "golang" | "js" | "javascript" | "jsx" | "ts" | "typescript" | "tsx"
+        | "scala" | "dart" => Syntax {
+            line_comments: &["//"],
+            block_comment: Some(("/*", "*/")),
+            quotes: &['"', '\'', '`'],
+            keywords: C_LIKE_KEYWORDS,
+            case_insensitive: false,
+            char_literals: false,
+        },
+        "python" | "py" | "ruby" | "rb" | "sh" | "bash" | "zsh" | "shell" | "fish" | "perl"
+        | "pl" | "r" | "toml" | "yaml" | "yml" | "make" | "makefile" | "dockerfile" => Syntax {
+            line_comments: &["#"],
+            block_comment: None,
+            quotes: &['"', '\''],
+            keywords: SCRIPT_KEYWORDS,
+            case_insensitive: false,
+            char_literals: false,
+        },
+        "sql" | "lua" | "haskell" | "hs" => Syntax {
+            line_comments: &["--"],
+            block_comment: None,
+            quotes: &['"', '\''],
+            keywords: SQL_KEYWORDS,
+            case_insensitive: true,
+            char_literals: false,
+        },
+        _ => return None,
+    };
+
+    Some(syntax)
+}
+
+/// The length of the string starting with the quote, including the quotes.
+/// Strings end at the closing quote or at the end of the line.
+fn string_len(rest: &str, quote: char) -> usize {
+    let mut escaped = false;
+
+    for (i, c) in rest.char_indices().skip(1) {
+        match c {
+            '\n' => return i,
+            '\\' if !escaped => escaped = true,
+            c if c == quote && !escaped => return i + c.len_utf8(),
+            _ => escaped = false,
+        }
+    }
+
+    rest.len()
+}
+
+/// The length of the character literal starting with the quote, if it is one
+fn char_literal_len(rest: &str) -> Option<usize> {
+    let mut chars = rest.char_indices().skip(1);
+
+    let (_, c) = chars.next()?;
+
+    let (i, closing) = if c == '\\' {
+        chars.find(|&(_, c)| c == '\'' || c == '\n')?
+    } else {
+        chars.next()?
+    };
+
+    (closing == '\'').then_some(i + 1)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Splits the code into highlighted tokens. Concatenating the tokens yields
+/// the code.
+pub(crate) fn tokens<'a>(code: &'a str, lang: &str) -> Vec<(Kind, &'a str)> {
+    let syntax = match syntax(lang) {
+        Some(syntax) => syntax,
+        None => return vec![(Kind::Plain, code)],
+    };
+
+    let mut tokens = Vec::new();
+
+    let mut plain_start = 0;
+    let mut i = 0;
+
+    while i < code.len() {
+        let rest = &code[i..];
+        let c = rest.chars().next().unwrap();
+
+        let token = if syntax.line_comments.iter().any(|p| rest.starts_with(p)) {
+            Some((Kind::Comment, rest.find('\n').unwrap_or(rest.len())))
+        } else if let Some((open, close)) = syntax
+            .block_comment
+            .filter(|(open, _)| rest.starts_with(open))
+        {
+            let len = rest[open.len()..]
+                .find(close)
+                .map_or(rest.len(), |end| open.len() + end + close.len());
+
+            Some((Kind::Comment, len))
+        } else if c == '\'' && syntax.char_literals {
+            char_literal_len(rest).map(|len| (Kind::String, len))
+        } else if syntax.quotes.contains(&c) {
+            Some((Kind::String, string_len(rest, c)))
+        } else if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !(is_word_char(c) || c == '.'))
+                .unwrap_or(rest.len());
+
+            Some((Kind::Number, len))
+        } else if is_word_char(c) {
+            let len = rest.find(|c| !is_word_char(c)).unwrap_or(rest.len());
+
+            let word = &rest[..len];
+
+            let is_keyword = syntax.keywords.iter().any(|keyword| {
+                if syntax.case_insensitive {
+                    keyword.eq_ignore_ascii_case(word)
+                } else {
+                    *keyword == word
+                }
+            });
+
+            if is_keyword {
+                Some((Kind::Keyword, len))
+            } else {
+                // Words are skipped whole, so digits within them are not numbers
+                i += len;
+                continue;
+            }
+        } else {
+            None
+        };
+
+        match token {
+            Some((kind, len)) => {
+                if plain_start < i {
+                    tokens.push((Kind::Plain, &code[plain_start..i]));
+                }
+
+                tokens.push((kind, &code[i..i + len]));
+
+                i += len;
+                plain_start = i;
+            }
+            None => i += c.len_utf8(),
+        }
+    }
+
+    if plain_start < code.len() {
+        tokens.push((Kind::Plain, &code[plain_start..]));
+    }
+
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens() {
+        assert_eq!(
+            tokens("fn f<'a>(x: &'a str) -> char { 'x' } // done", "rust"),
+            [
+                (Kind::Keyword, "fn"),
+                (Kind::Plain, " f<'a>(x: &'a str) -> char { "),
+                (Kind::String, "'x'"),
+                (Kind::Plain, " } "),
+                (Kind::Comment, "// done"),
+            ]
+        );
+
+        assert_eq!(
+            tokens("x2 = \"a\\\"b\" # 42\ny = 42", "python"),
+            [
+                (Kind::Plain, "x2 = "),
+                (Kind::String, "\"a\\\"b\""),
+                (Kind::Plain, " "),
+                (Kind::Comment, "# 42"),
+                (Kind::Plain, "\ny = "),
+                (Kind::Number, "42"),
+            ]
+        );
+
+        assert_eq!(
+            tokens("SELECT 1", "sql"),
+            [
+                (Kind::Keyword, "SELECT"),
+                (Kind::Plain, " "),
+                (Kind::Number, "1")
+            ]
+        );
+
+        assert_eq!(tokens("# Title", "markdown"), [(Kind::Plain, "# Title")]);
+    }
+}
@@ -0,0 +1,59 @@
+//! JSON-RPC 2.0 messages, as used by the MCP and language servers
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub(crate) const PARSE_ERROR: i64 = -32700;
+pub(crate) const METHOD_NOT_FOUND: i64 = -32601;
+pub(crate) const INVALID_PARAMS: i64 = -32602;
+pub(crate) const INTERNAL_ERROR: i64 = -32603;
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct Request {
+    /// Notifications do not carry an identifier and are never answered
+    pub id: Option<Value>,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct ResponseError {
+    code: i64,
+    message: String,
+}
+
+impl ResponseError {
+    pub(crate) fn new(code: i64, message: impl Into<String>) -> ResponseError {
+        ResponseError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct Response {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ResponseError>,
+}
+
+impl Response {
+    pub(crate) fn new(id: Value, result: Result<Value, ResponseError>) -> Response {
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+
+        Response {
+            jsonrpc: "2.0",
+            id,
+            result,
+            error,
+        }
+    }
+}
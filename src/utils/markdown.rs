@@ -0,0 +1,122 @@
+//! Splitting of markdown into prose and fenced code blocks
+//!
+//! Only fenced code blocks are recognized, since they are how models
+//! delimit code. A fence is three or more backticks or tildes at the start
+//! of a line, optionally followed by the language. The block ends at a line
+//! holding a fence of the same character which is at least as long. A block
+//! which is never closed extends to the end of the text.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Block<'a> {
+    /// Text outside of code blocks, including its line endings
+    Text(&'a str),
+    /// A fenced code block, without its fences
+    Code { lang: &'a str, code: &'a str },
+}
+
+/// The character and length of the fence which opens or closes a block
+pub(crate) fn fence(line: &str) -> Option<(char, usize)> {
+    let line = line.trim_start_matches(' ');
+
+    let c = line.chars().next().filter(|&c| c == '`' || c == '~')?;
+
+    let len = line.chars().take_while(|&x| x == c).count();
+
+    (len >= 3).then_some((c, len))
+}
+
+/// Splits the text into prose and code blocks, in order
+pub(crate) fn blocks(text: &str) -> Vec<Block<'_>> {
+    let mut blocks = Vec::new();
+
+    // The start of the text which has not been added to a block
+    let mut text_start = 0;
+
+    // The fence, language, and start of the code of the open block
+    let mut open: Option<((char, usize), &str, usize)> = None;
+
+    let mut offset = 0;
+
+    for line in text.split_inclusive('\n') {
+        let start = offset;
+
+        offset += line.len();
+
+        let content = line.trim_end_matches(['\n', '\r']);
+
+        match (open, fence(content)) {
+            (None, Some(opened)) => {
+                if text_start < start {
+                    blocks.push(Block::Text(&text[text_start..start]));
+                }
+
+                let lang = content.trim_start_matches(' ')[opened.1..].trim();
+
+                open = Some((opened, lang, offset));
+            }
+            (Some(((c, len), lang, code_start)), Some((closing, closing_len)))
+                if closing == c && closing_len >= len && content.trim().chars().all(|x| x == c) =>
+            {
+                blocks.push(Block::Code {
+                    lang,
+                    code: &text[code_start..start],
+                });
+
+                open = None;
+                text_start = offset;
+            }
+            _ => {}
+        }
+    }
+
+    match open {
+        Some((_, lang, code_start)) => blocks.push(Block::Code {
+            lang,
+            code: &text[code_start..],
+        }),
+        None if text_start < text.len() => blocks.push(Block::Text(&text[text_start..])),
+        None => {}
+    }
+
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocks() {
+        assert_eq!(
+            blocks("Run:\n```bash\nls\n```\nDone."),
+            [
+                Block::Text("Run:\n"),
+                Block::Code {
+                    lang: "bash",
+                    code: "ls\n"
+                },
+                Block::Text("Done."),
+            ]
+        );
+
+        // A longer fence may contain shorter fences
+        assert_eq!(
+            blocks("````md\n```\nx\n```\n````\n"),
+            [Block::Code {
+                lang: "md",
+                code: "```\nx\n```\n"
+            }]
+        );
+
+        // An unclosed block extends to the end
+        assert_eq!(
+            blocks("~~~\nfn main() {}"),
+            [Block::Code {
+                lang: "",
+                code: "fn main() {}"
+            }]
+        );
+
+        assert_eq!(blocks("no code"), [Block::Text("no code")]);
+    }
+}
@@ -1,2 +1,2 @@
-pub(crate) const VERSION: &'static str = "0.0.1-alpha.3"; 
-pub(crate) const NAME: &'static str = "xtalk";
\ No newline at end of file
+pub(crate) const VERSION: &'static str = "0.0.1-alpha.3";
+pub(crate) const NAME: &'static str = "xtalk";